@@ -0,0 +1,66 @@
+//! Compares the two ways of handing connections to workers, with many
+//! short connections that each carry a single request.
+//!
+//! Run with `cargo bench --bench accept >/dev/null`: the server logs every
+//! request to stdout, and the results go to stderr.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rust_server::{AcceptStrategy, Router, Server, ServerConfig};
+
+const WORKERS: usize = 8;
+const CLIENTS: usize = 8;
+const REQUESTS_PER_CLIENT: usize = 500;
+
+fn main() {
+    for strategy in [AcceptStrategy::Dispatch, AcceptStrategy::Workers] {
+        // The first round warms up the workers and the system's caches.
+        run(strategy);
+        let elapsed = run(strategy);
+        let requests = CLIENTS * REQUESTS_PER_CLIENT;
+        eprintln!(
+            "{strategy:?}: {requests} connections in {elapsed:.2?}, {:.0} per second",
+            requests as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+/// Serve every client's connections with `strategy`, returning how long
+/// it took.
+fn run(strategy: AcceptStrategy) -> Duration {
+    let config = ServerConfig::new("127.0.0.1:0")
+        .pool_size(WORKERS)
+        .accept_strategy(strategy);
+    let server = Server::bind(config, Router::new().get("/", |_, _| "hi")).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    let started = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| thread::spawn(move || (0..REQUESTS_PER_CLIENT).for_each(|_| request(addr))))
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let elapsed = started.elapsed();
+
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+    elapsed
+}
+
+fn request(addr: SocketAddr) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.ends_with(b"hi"));
+}
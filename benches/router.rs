@@ -0,0 +1,58 @@
+//! Compares the cost of a lookup in `Router` and `RadixRouter`, for an
+//! application with a couple of hundred parameter routes.
+//!
+//! Run with `cargo bench --bench router`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use rust_server::{Method, RadixRouter, Request, Router, Service};
+
+const RESOURCES: usize = 100;
+const LOOKUPS: usize = 200_000;
+
+fn main() {
+    let mut router = Router::new();
+    let mut radix = RadixRouter::new();
+    for resource in 0..RESOURCES {
+        for path in [
+            format!("/api/v1/resource{resource}"),
+            format!("/api/v1/resource{resource}/:id"),
+            format!("/api/v1/resource{resource}/:id/items/:item"),
+        ] {
+            router = router.get(&path, |_, _| "");
+            radix = radix.get(&path, |_, _| "");
+        }
+    }
+
+    // Routes near the end of the table are the worst case for `Router`,
+    // which checks the parameter routes one by one.
+    let paths = [
+        "/api/v1/resource0",
+        "/api/v1/resource0/42",
+        "/api/v1/resource99/42",
+        "/api/v1/resource99/42/items/7",
+        "/api/v1/missing/42",
+    ];
+    for path in paths {
+        let simple = per_lookup(&router, path);
+        let trie = per_lookup(&radix, path);
+        println!("{path:<32} Router {simple:>9.2?}  RadixRouter {trie:>9.2?}");
+    }
+}
+
+/// The average time `service` takes to answer a `GET` for `path`.
+fn per_lookup(service: &dyn Service, path: &str) -> Duration {
+    let mut request = Request::new(Method::Get, path);
+    for _ in 0..LOOKUPS / 10 {
+        black_box(service.handle(&mut request));
+    }
+
+    let started = Instant::now();
+    for _ in 0..LOOKUPS {
+        black_box(service.handle(&mut request));
+    }
+    started.elapsed() / LOOKUPS as u32
+}
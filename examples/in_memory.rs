@@ -0,0 +1,26 @@
+//! Drive a router through a whole connection without opening a socket.
+//!
+//! Run it with `cargo run --example in_memory`.
+
+use rust_server::{serve_connection, Response, Router};
+
+fn main() {
+    let router = Router::new()
+        .get("/hello/:name", |request, _| {
+            Response::text(200, format!("Hello, {}!", request.param("name").unwrap()))
+        })
+        .post("/echo", |request, _| {
+            Response::new(200).with_body(request.body())
+        });
+
+    // Three pipelined requests on one keep-alive connection. The last one
+    // asks to close it, which ends `serve_connection`.
+    let input: &[u8] = b"GET /hello/ferris HTTP/1.1\r\n\r\n\
+        POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+        GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+    let mut output = Vec::new();
+    serve_connection(input, &mut output, &router);
+
+    print!("{}", String::from_utf8_lossy(&output));
+}
@@ -0,0 +1,244 @@
+//! The line logged for every request the server answers.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{date, request::Request};
+
+/// The format of the access log lines, chosen with
+/// [`ServerConfig::log_format`](crate::server::ServerConfig::log_format).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The method, path, status and body size, e.g.
+    /// `GET /index.html 200 1043`. This is the default.
+    #[default]
+    Short,
+    /// The NCSA Common Log Format understood by most log tooling, e.g.
+    /// `127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] "GET /index.html
+    /// HTTP/1.1" 200 1043`.
+    ///
+    /// The `ident` and `authuser` fields are always `-`: the server never
+    /// asks clients who they are, and whether a user name in the request
+    /// was actually checked is up to the handler.
+    Common,
+}
+
+/// What is known about an answered request when its line is logged.
+pub(crate) struct Entry<'a> {
+    /// The client's address, if the connection has one.
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) request: &'a Request,
+    /// When the request was received.
+    pub(crate) received: SystemTime,
+    /// How long it took from reading the request to writing the response.
+    pub(crate) elapsed: Duration,
+    pub(crate) status: u16,
+    /// The number of body bytes written to the client.
+    pub(crate) sent: u64,
+}
+
+impl LogFormat {
+    /// Format the log line for `entry`.
+    pub(crate) fn format(self, entry: &Entry<'_>) -> String {
+        let request = entry.request;
+        match self {
+            LogFormat::Short => format!(
+                "{} {} {} {}",
+                request.method(),
+                request.path(),
+                entry.status,
+                entry.sent
+            ),
+            LogFormat::Common => {
+                let host = request
+                    .client_ip()
+                    .or(entry.peer.map(|peer| peer.ip()))
+                    .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+                // A body of no bytes is logged as a dash.
+                let sent = match entry.sent {
+                    0 => "-".to_string(),
+                    sent => sent.to_string(),
+                };
+                format!(
+                    "{host} - - [{}] \"{} {} {}\" {} {sent}",
+                    date::format_clf_date(entry.received),
+                    request.method(),
+                    request.target(),
+                    request.version().as_str(),
+                    entry.status,
+                )
+            }
+        }
+    }
+}
+
+/// The warning logged for a request that took at least `threshold`, the
+/// [slow request threshold](crate::server::ServerConfig::slow_request_threshold).
+/// Faster requests aren't logged.
+pub(crate) fn format_slow(entry: &Entry<'_>, threshold: Duration) -> Option<String> {
+    if entry.elapsed < threshold {
+        return None;
+    }
+    Some(format!(
+        "WARN slow request: {} {} {} took {:.3}s",
+        entry.request.method(),
+        entry.request.path(),
+        entry.status,
+        entry.elapsed.as_secs_f64()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::{
+        request::Method,
+        response::{Body, Response},
+    };
+
+    /// Write `response` like the server does, and return what went out
+    /// after the headers along with the count the log line is made from.
+    fn send(response: Response, request: &Request) -> (Vec<u8>, u64) {
+        let mut output = Vec::new();
+        let sent = response
+            .write_for(&mut output, request.method(), request.version(), 0)
+            .unwrap();
+        let end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        (output.split_off(end), sent)
+    }
+
+    fn entry(request: &Request, status: u16, sent: u64) -> Entry<'_> {
+        Entry {
+            peer: None,
+            request,
+            received: SystemTime::now(),
+            elapsed: Duration::ZERO,
+            status,
+            sent,
+        }
+    }
+
+    #[test]
+    fn logs_the_bytes_that_went_out() {
+        let request = Request::new(Method::Get, "/page");
+
+        let (body, sent) = send(Response::text(200, "hello"), &request);
+        assert_eq!(sent, body.len() as u64);
+        assert_eq!(
+            LogFormat::Short.format(&entry(&request, 200, sent)),
+            "GET /page 200 5"
+        );
+
+        // Chunk framing counts too.
+        let reader = Response::new(200).with_body(Body::Reader(Box::new(&b"hello"[..])));
+        let (body, sent) = send(reader, &request);
+        assert_eq!(body, b"5\r\nhello\r\n0\r\n\r\n");
+        assert_eq!(sent, body.len() as u64);
+
+        // The answer to a HEAD request has no body to count.
+        let head = Request::new(Method::Head, "/page");
+        assert_eq!(send(Response::text(200, "hello"), &head).1, 0);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn logs_the_compressed_size() {
+        let text = "all work and no play makes a dull log line. ".repeat(200);
+        let mut request = Request::new(Method::Get, "/page");
+        request.set_header("Accept-Encoding", "gzip");
+
+        let response = Response::text(200, text.clone()).gzip(&request);
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        let (body, sent) = send(response, &request);
+        assert_eq!(sent, body.len() as u64);
+        assert!(sent < text.len() as u64 / 4, "{sent}");
+        assert_eq!(
+            LogFormat::Short.format(&entry(&request, 200, sent)),
+            format!("GET /page 200 {sent}")
+        );
+
+        // HTTP/1.0 clients get the compressed bytes without chunks.
+        let request =
+            Request::parse(&mut "GET /page HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n".as_bytes())
+                .unwrap();
+        assert_eq!(request.version(), crate::request::Version::Http10);
+        let (body, sent) = send(Response::text(200, text).gzip(&request), &request);
+        assert_eq!(sent, body.len() as u64);
+        assert!(body.starts_with(&[0x1f, 0x8b]));
+    }
+
+    fn parsed(request: &str) -> Request {
+        Request::parse(&mut request.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn formats_common_log_format_lines() {
+        let request = parsed("GET /index.html?q=1 HTTP/1.1\r\n\r\n");
+        let entry = Entry {
+            peer: Some("127.0.0.1:50000".parse().unwrap()),
+            received: UNIX_EPOCH + Duration::from_secs(784_111_777),
+            ..entry(&request, 200, 1043)
+        };
+        assert_eq!(
+            LogFormat::Common.format(&entry),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /index.html?q=1 HTTP/1.1\" 200 1043"
+        );
+        assert_eq!(LogFormat::Short.format(&entry), "GET /index.html 200 1043");
+    }
+
+    #[test]
+    fn fills_in_dashes_for_what_is_unknown() {
+        let request = parsed("HEAD / HTTP/1.0\r\n\r\n");
+        let line = LogFormat::Common.format(&entry(&request, 304, 0));
+        assert!(line.starts_with("- - - ["), "{line}");
+        assert!(line.ends_with("] \"HEAD / HTTP/1.0\" 304 -"), "{line}");
+    }
+
+    #[test]
+    fn logs_the_client_behind_a_trusted_proxy() {
+        let peer = Some("10.0.0.1:8080".parse().unwrap());
+        let mut request = parsed("GET / HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n");
+        crate::forwarded::resolve(&mut request, peer, &crate::forwarded::ProxyTrust::Any);
+        let entry = Entry {
+            peer,
+            ..entry(&request, 200, 5)
+        };
+        assert!(LogFormat::Common
+            .format(&entry)
+            .starts_with("203.0.113.7 - - ["));
+    }
+
+    fn took(request: &Request, elapsed: Duration) -> Entry<'_> {
+        Entry {
+            elapsed,
+            ..entry(request, 200, 10)
+        }
+    }
+
+    #[test]
+    fn requests_under_the_slow_threshold_are_not_logged() {
+        let request = parsed("GET /report HTTP/1.1\r\n\r\n");
+        let threshold = Duration::from_millis(500);
+        for elapsed in [Duration::ZERO, Duration::from_millis(499)] {
+            assert_eq!(format_slow(&took(&request, elapsed), threshold), None);
+        }
+    }
+
+    #[test]
+    fn requests_over_the_slow_threshold_log_a_warning() {
+        let request = parsed("GET /report?year=2024 HTTP/1.1\r\n\r\n");
+        let threshold = Duration::from_millis(500);
+        assert_eq!(
+            format_slow(&took(&request, Duration::from_millis(500)), threshold).as_deref(),
+            Some("WARN slow request: GET /report 200 took 0.500s")
+        );
+        assert_eq!(
+            format_slow(&took(&request, Duration::from_millis(2503)), threshold).as_deref(),
+            Some("WARN slow request: GET /report 200 took 2.503s")
+        );
+    }
+}
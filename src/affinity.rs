@@ -0,0 +1,64 @@
+//! Pinning worker threads to CPU cores, for
+//! [`ThreadPool::build_pinned`](crate::ThreadPool::build_pinned).
+//!
+//! The work is done by `core_affinity`, which supports Linux, Android,
+//! Windows, macOS and FreeBSD. Elsewhere [`cores`] finds no cores and
+//! nothing gets pinned. On macOS pinning is only a hint the scheduler may
+//! ignore.
+
+use core_affinity::CoreId;
+
+/// The cores the process is allowed to run on.
+///
+/// This is empty if they can't be found out, including on platforms
+/// without support for pinning.
+pub(crate) fn cores() -> Vec<usize> {
+    core_affinity::get_core_ids()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|core| core.id)
+        .collect()
+}
+
+/// Pin the calling thread to `core`, returning whether it worked.
+pub(crate) fn pin_current_thread(core: usize) -> bool {
+    core_affinity::set_for_current(CoreId { id: core })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{fs, sync::mpsc};
+
+    use crate::ThreadPool;
+
+    /// The cores the calling thread may run on, as the kernel lists them.
+    fn allowed_cores() -> String {
+        let status = fs::read_to_string("/proc/thread-self/status").unwrap();
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+            .unwrap()
+            .trim()
+            .to_string()
+    }
+
+    #[test]
+    fn workers_run_on_one_core_each() {
+        let cores = super::cores();
+        assert!(!cores.is_empty());
+
+        let pool = ThreadPool::build_pinned(cores.len()).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..cores.len() * 4 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(allowed_cores()).unwrap())
+                .unwrap();
+        }
+        drop(sender);
+
+        let names: Vec<String> = cores.iter().map(usize::to_string).collect();
+        for allowed in receiver {
+            assert!(names.contains(&allowed), "{allowed} isn't a single core");
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! HTTP Basic authentication.
+
+use crate::{request::Request, response::Response};
+
+type Verifier = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// A guard that only lets through requests carrying valid Basic
+/// credentials in their `Authorization` header.
+///
+/// Requests without valid credentials are answered with
+/// `401 Unauthorized` and a `WWW-Authenticate` header, which makes
+/// browsers show their login prompt.
+pub struct BasicAuth {
+    realm: String,
+    verifier: Verifier,
+}
+
+impl BasicAuth {
+    /// Create a guard accepting a fixed set of `(user, password)` pairs.
+    ///
+    /// Every pair is compared against the supplied credentials in constant
+    /// time, so the time it takes to reject a request doesn't reveal how
+    /// much of a user name or password was right.
+    pub fn new<I, U, P>(realm: &str, credentials: I) -> BasicAuth
+    where
+        I: IntoIterator<Item = (U, P)>,
+        U: Into<String>,
+        P: Into<String>,
+    {
+        let credentials: Vec<(String, String)> = credentials
+            .into_iter()
+            .map(|(user, password)| (user.into(), password.into()))
+            .collect();
+
+        BasicAuth::with_verifier(realm, move |user, password| {
+            // Look at every pair even after a match, so that the position
+            // of the matching pair doesn't change the timing either.
+            credentials
+                .iter()
+                .fold(false, |found, (known_user, known_password)| {
+                    let user_ok = constant_time_eq(user.as_bytes(), known_user.as_bytes());
+                    let password_ok =
+                        constant_time_eq(password.as_bytes(), known_password.as_bytes());
+                    found | (user_ok & password_ok)
+                })
+        })
+    }
+
+    /// Create a guard that asks `verifier` whether a user name and password
+    /// are valid.
+    ///
+    /// The verifier is responsible for comparing secrets in constant time.
+    pub fn with_verifier<F>(realm: &str, verifier: F) -> BasicAuth
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        BasicAuth {
+            realm: realm.to_string(),
+            verifier: Box::new(verifier),
+        }
+    }
+
+    /// Check the credentials of `request`.
+    ///
+    /// On success this returns the authenticated user name. Otherwise it
+    /// returns the `401 Unauthorized` response that should be sent instead
+    /// of running the protected handler.
+    pub fn check(&self, request: &Request) -> Result<String, Response> {
+        match credentials(request) {
+            Some((user, password)) if (self.verifier)(&user, &password) => Ok(user),
+            _ => Err(self.challenge()),
+        }
+    }
+
+    /// The response asking the client to authenticate.
+    fn challenge(&self) -> Response {
+        // Quotes and backslashes would end the quoted realm early.
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        Response::text(401, "401 Unauthorized").with_header(
+            "WWW-Authenticate",
+            &format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+        )
+    }
+}
+
+/// Pull the user name and password out of an `Authorization: Basic` header.
+fn credentials(request: &Request) -> Option<(String, String)> {
+    let (scheme, encoded) = request.header("Authorization")?.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+
+    let decoded = String::from_utf8(decode_base64(encoded.trim())?).ok()?;
+
+    // The password may contain colons, the user name may not.
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Compare two byte strings without stopping at the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    // Fold the length difference into the result and walk the longer of
+    // the two inputs, so that a wrong length takes as long as a wrong byte.
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= usize::from(x ^ y);
+    }
+    diff == 0
+}
+
+/// Decode standard base64 with padding, as used by Basic authentication.
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+    for (i, chunk) in input.chunks(4).enumerate() {
+        let last = i == input.len() / 4 - 1;
+
+        // Padding may only show up at the very end, and at most twice.
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut bits = 0;
+        for &c in &chunk[..4 - padding] {
+            bits = (bits << 6) | value(c)?;
+        }
+        bits <<= 6 * padding;
+
+        let bytes = bits.to_be_bytes();
+        output.extend_from_slice(&bytes[1..4 - padding]);
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request::Method, router::Router, serve_connection};
+
+    fn guard() -> BasicAuth {
+        BasicAuth::new("admin \"area\"", [("alice", "secret"), ("bob", "se:cret")])
+    }
+
+    fn request(authorization: Option<&str>) -> Request {
+        let request = Request::new(Method::Get, "/admin");
+        match authorization {
+            Some(value) => request.with_header("Authorization", value),
+            None => request,
+        }
+    }
+
+    fn assert_challenged(result: Result<String, Response>) {
+        let response = result.unwrap_err();
+        assert_eq!(response.status(), 401);
+        assert_eq!(
+            response.header("WWW-Authenticate"),
+            Some("Basic realm=\"admin \\\"area\\\"\", charset=\"UTF-8\"")
+        );
+    }
+
+    #[test]
+    fn rejects_missing_credentials() {
+        assert_challenged(guard().check(&request(None)));
+        assert_challenged(guard().check(&request(Some("Bearer YWxpY2U6c2VjcmV0"))));
+        assert_challenged(guard().check(&request(Some("Basic not base64!"))));
+    }
+
+    #[test]
+    fn rejects_wrong_credentials() {
+        // alice:wrong
+        assert_challenged(guard().check(&request(Some("Basic YWxpY2U6d3Jvbmc="))));
+        // alice:se:cret is bob's password.
+        assert_challenged(guard().check(&request(Some("Basic YWxpY2U6c2U6Y3JldA=="))));
+    }
+
+    #[test]
+    fn lets_correct_credentials_through() {
+        // alice:secret
+        let user = guard().check(&request(Some("basic  YWxpY2U6c2VjcmV0")));
+        assert_eq!(user.ok().as_deref(), Some("alice"));
+
+        let auth = BasicAuth::with_verifier("api", |user, password| {
+            user == "alice" && password == "secret"
+        });
+        let router = Router::new().get("/admin", move |request, _| match auth.check(request) {
+            Ok(user) => Response::text(200, format!("hello {user}")),
+            Err(challenge) => challenge,
+        });
+        let input = "GET /admin HTTP/1.1\r\n\r\n\
+                     GET /admin HTTP/1.1\r\nAuthorization: Basic YWxpY2U6c2VjcmV0\r\n\
+                     Connection: close\r\n\r\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &router);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(output.ends_with("\r\n\r\nhello alice"));
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("YWJj").as_deref(), Some(&b"abc"[..]));
+        assert_eq!(decode_base64("YWI=").as_deref(), Some(&b"ab"[..]));
+        assert_eq!(decode_base64("").as_deref(), Some(&b""[..]));
+        for invalid in ["YWI", "Y===", "YW==YWJj", "YW!j"] {
+            assert_eq!(decode_base64(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn compares_whole_strings() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret\0"));
+        assert!(!constant_time_eq(b"", b"\0"));
+    }
+}
@@ -0,0 +1,279 @@
+//! The growable pool behind
+//! [`ThreadPool::execute_blocking`](crate::ThreadPool::execute_blocking).
+//!
+//! Jobs that spend most of their time waiting on I/O would tie up the
+//! fixed set of workers, so they get threads of their own instead. A job
+//! is handed to an idle blocking thread if there is one, and otherwise a
+//! new thread is started for it, up to [`MAX_THREADS`]. Past that limit
+//! jobs wait in the queue for the next thread to become free.
+//!
+//! Threads that stay idle for [`KEEP_ALIVE`] exit, so the pool shrinks
+//! back down once a burst of blocking work is over. Both the idle timeout
+//! and a number of threads that never exit can be changed through the
+//! [`ThreadPool`](crate::ThreadPool). This is the only pool that shrinks:
+//! the regular workers are fixed in number and never time out.
+
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::Job;
+
+/// The most threads the pool will run at once.
+pub(crate) const MAX_THREADS: usize = 512;
+
+/// How long an idle thread waits for a job before exiting, by default.
+pub(crate) const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+pub(crate) struct BlockingPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    /// The threads currently running, whether busy or idle.
+    threads: Vec<thread::JoinHandle<()>>,
+    /// How many threads haven't decided to exit yet. Unlike `threads`,
+    /// this doesn't count threads on their way out.
+    live: usize,
+    /// How many of the running threads are waiting for a job.
+    idle: usize,
+    /// How few threads idle ones stop exiting at.
+    min_threads: usize,
+    /// How long a thread waits for a job before exiting.
+    keep_alive: Duration,
+    /// The number used to name the next thread.
+    next_id: usize,
+    closed: bool,
+}
+
+impl BlockingPool {
+    pub(crate) fn new() -> BlockingPool {
+        BlockingPool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    jobs: VecDeque::new(),
+                    threads: Vec::new(),
+                    live: 0,
+                    idle: 0,
+                    min_threads: 0,
+                    keep_alive: KEEP_ALIVE,
+                    next_id: 0,
+                    closed: false,
+                }),
+                available: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Queue a job, waking an idle thread for it or starting a new one.
+    ///
+    /// If a new thread is needed but can't be spawned, the job waits for
+    /// one of the running threads. If there are none, it waits until a
+    /// later job manages to start one.
+    pub(crate) fn execute(&self, job: Job) {
+        let mut state = self.shared.lock();
+        state.jobs.push_back(job);
+
+        // Each idle thread takes one of the queued jobs. A thread that was
+        // woken up but hasn't taken its job yet still counts as idle, so
+        // only jobs beyond that need a new thread.
+        if state.idle >= state.jobs.len() {
+            self.shared.available.notify_one();
+            return;
+        }
+
+        // Threads that timed out have already exited, so their handles
+        // can be let go of before counting the running threads.
+        state.threads.retain(|thread| !thread.is_finished());
+        if state.threads.len() >= MAX_THREADS {
+            return;
+        }
+
+        let id = state.next_id;
+        let shared = Arc::clone(&self.shared);
+        match thread::Builder::new()
+            .name(format!("blocking-{id}"))
+            .spawn(move || shared.run(id))
+        {
+            Ok(thread) => {
+                state.next_id += 1;
+                state.live += 1;
+                state.threads.push(thread);
+            }
+            Err(err) => eprintln!("Failed to spawn a blocking thread: {err}"),
+        }
+    }
+}
+
+impl BlockingPool {
+    /// Set how long a thread may wait for a job before exiting.
+    pub(crate) fn set_keep_alive(&self, keep_alive: Duration) {
+        self.shared.lock().keep_alive = keep_alive;
+        // Threads already waiting pick up the new timeout when they wake.
+        self.shared.available.notify_all();
+    }
+
+    /// Keep at least `min_threads` threads around once they have been
+    /// started, however long they stay idle.
+    pub(crate) fn set_min_threads(&self, min_threads: usize) {
+        self.shared.lock().min_threads = min_threads;
+        self.shared.available.notify_all();
+    }
+}
+
+impl Drop for BlockingPool {
+    /// Closing the pool lets the threads run the jobs still queued, and
+    /// then waits for every one of them to exit.
+    fn drop(&mut self) {
+        let threads = {
+            let mut state = self.shared.lock();
+            state.closed = true;
+            std::mem::take(&mut state.threads)
+        };
+        self.shared.available.notify_all();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Shared {
+    fn run(&self, id: usize) {
+        while let Some(job) = self.next_job() {
+            println!("Blocking thread {id} got a job; executing.");
+
+            // As on the regular workers, a panicking job is caught so the
+            // thread can go on to the next one.
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                println!("Blocking thread {id} recovered from a panicking job.");
+            }
+        }
+    }
+
+    /// Take the next job, or `None` once the thread should exit, either
+    /// because the pool was closed and drained or because no job came
+    /// along for the keep-alive time and there are more threads than the
+    /// minimum.
+    fn next_job(&self) -> Option<Job> {
+        let mut state = self.lock();
+        // The last time this thread had something to do, which the idle
+        // time is counted from across wakeups without a job.
+        let mut last_job = Instant::now();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                return Some(job);
+            }
+            if state.closed {
+                state.live -= 1;
+                return None;
+            }
+
+            state.idle += 1;
+            if state.live <= state.min_threads {
+                // Threads kept for the minimum don't time out. A new job,
+                // closing the pool or changing the settings wakes them, and
+                // they start counting their idle time again from there.
+                state = self
+                    .available
+                    .wait(state)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                last_job = Instant::now();
+            } else {
+                let idle_for = last_job.elapsed();
+                if idle_for >= state.keep_alive {
+                    state.idle -= 1;
+                    state.live -= 1;
+                    return None;
+                }
+                let timeout = state.keep_alive - idle_for;
+                state = self
+                    .available
+                    .wait_timeout(state, timeout)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .0;
+            }
+            state.idle -= 1;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Jobs never run while the lock is held, so a poisoned lock is
+        // still fine to use.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Barrier};
+
+    use super::*;
+
+    fn live(pool: &BlockingPool) -> usize {
+        pool.shared.lock().live
+    }
+
+    #[test]
+    fn shrinks_to_the_minimum_after_a_quiet_period() {
+        let pool = BlockingPool::new();
+        pool.set_min_threads(2);
+        pool.set_keep_alive(Duration::from_millis(50));
+
+        // Four jobs that wait for each other need four threads at once.
+        let barrier = Arc::new(Barrier::new(5));
+        for _ in 0..4 {
+            let barrier = Arc::clone(&barrier);
+            pool.execute(Box::new(move || {
+                barrier.wait();
+            }));
+        }
+        barrier.wait();
+        assert_eq!(live(&pool), 4);
+
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(live(&pool), 2);
+    }
+
+    #[test]
+    fn threads_kept_for_the_minimum_wait_without_a_keep_alive() {
+        let pool = BlockingPool::new();
+        pool.set_min_threads(1);
+        pool.set_keep_alive(Duration::ZERO);
+
+        let (sender, receiver) = mpsc::channel();
+        for job in 0..2 {
+            let sender = sender.clone();
+            pool.execute(Box::new(move || sender.send(job).unwrap()));
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(job));
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(live(&pool), 1);
+    }
+
+    #[test]
+    fn lowering_the_minimum_lets_idle_threads_exit() {
+        let pool = BlockingPool::new();
+        pool.set_min_threads(1);
+        pool.set_keep_alive(Duration::from_millis(20));
+        pool.execute(Box::new(|| {}));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(live(&pool), 1);
+
+        pool.set_min_threads(0);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(live(&pool), 0);
+    }
+}
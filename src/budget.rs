@@ -0,0 +1,108 @@
+//! A limit on the memory all buffered request bodies take up together,
+//! for [`ServerConfig::max_buffered_body_bytes`].
+//!
+//! Every body read into a request takes its bytes from a budget shared by
+//! all connections as they arrive, and gives them back once the request,
+//! and every copy of it, is gone. A body that doesn't fit is refused
+//! rather than waited for, since the client would hold on to its worker
+//! while waiting.
+//!
+//! [`ServerConfig::max_buffered_body_bytes`]: crate::server::ServerConfig::max_buffered_body_bytes
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The bytes that may be buffered for request bodies at once.
+#[derive(Debug)]
+pub(crate) struct BodyBudget {
+    limit: u64,
+    used: AtomicU64,
+}
+
+/// Bytes taken from a [`BodyBudget`], given back when this is dropped.
+#[derive(Debug)]
+pub(crate) struct BodyLease {
+    budget: Arc<BodyBudget>,
+    bytes: u64,
+}
+
+impl BodyBudget {
+    pub(crate) fn new(limit: u64) -> BodyBudget {
+        BodyBudget {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Take `bytes` from the budget, if there are that many left.
+    fn try_take(&self, bytes: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .is_ok()
+    }
+}
+
+impl BodyLease {
+    /// Start a lease of nothing yet on `budget`.
+    pub(crate) fn new(budget: Arc<BodyBudget>) -> BodyLease {
+        BodyLease { budget, bytes: 0 }
+    }
+
+    /// Take `bytes` more from the budget. Failing that, the error has the
+    /// kind `OutOfMemory`, which is answered with `503 Service
+    /// Unavailable`.
+    pub(crate) fn grow(&mut self, bytes: u64) -> io::Result<()> {
+        if !self.budget.try_take(bytes) {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "the memory for buffered request bodies is used up",
+            ));
+        }
+        self.bytes += bytes;
+        Ok(())
+    }
+}
+
+impl Drop for BodyLease {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leases_give_their_bytes_back_when_dropped() {
+        let budget = Arc::new(BodyBudget::new(100));
+        let mut first = BodyLease::new(Arc::clone(&budget));
+        first.grow(60).unwrap();
+        first.grow(40).unwrap();
+
+        let mut second = BodyLease::new(Arc::clone(&budget));
+        let err = second.grow(1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+
+        drop(first);
+        second.grow(100).unwrap();
+    }
+
+    #[test]
+    fn a_refused_growth_takes_nothing() {
+        let budget = Arc::new(BodyBudget::new(100));
+        let mut lease = BodyLease::new(Arc::clone(&budget));
+        lease.grow(60).unwrap();
+        assert!(lease.grow(60).is_err());
+        lease.grow(40).unwrap();
+        drop(lease);
+        assert_eq!(budget.used.load(Ordering::Acquire), 0);
+    }
+}
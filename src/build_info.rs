@@ -0,0 +1,94 @@
+//! What build of the server is running, for
+//! [`ServerConfig::version_path`](crate::server::ServerConfig::version_path).
+//!
+//! The version comes from the crate's manifest. The commit and build time
+//! are taken from the `RUST_SERVER_GIT_HASH` and `RUST_SERVER_BUILD_TIME`
+//! environment variables when the crate is compiled, if they are set, e.g.
+//!
+//! ```text
+//! RUST_SERVER_GIT_HASH=$(git rev-parse --short HEAD) \
+//! RUST_SERVER_BUILD_TIME=$(date -u +%Y-%m-%dT%H:%M:%SZ) \
+//! cargo build --release
+//! ```
+
+use crate::{request::Request, response::Response};
+
+/// The name of the crate.
+pub const NAME: &str = env!("CARGO_PKG_NAME");
+
+/// The version of the crate, e.g. `0.1.0`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The commit the crate was built from, if `RUST_SERVER_GIT_HASH` was set
+/// at build time.
+pub const GIT_HASH: Option<&str> = option_env!("RUST_SERVER_GIT_HASH");
+
+/// When the crate was built, if `RUST_SERVER_BUILD_TIME` was set at build
+/// time.
+pub const BUILD_TIME: Option<&str> = option_env!("RUST_SERVER_BUILD_TIME");
+
+/// Answer `request` with the build information, as JSON if the client
+/// prefers it and as plain text otherwise.
+///
+/// ```
+/// use rust_server::{build_info, Method, Request};
+///
+/// let mut request = Request::new(Method::Get, "/version");
+/// let response = build_info::respond(&request);
+/// let text = String::from_utf8(response.body().as_bytes().unwrap().to_vec()).unwrap();
+/// assert!(text.starts_with(&format!("rust-server {}\n", build_info::VERSION)));
+///
+/// request.set_header("Accept", "application/json");
+/// let response = build_info::respond(&request);
+/// assert_eq!(response.header("Content-Type"), Some("application/json"));
+/// ```
+///
+/// It can also be registered as an ordinary route:
+///
+/// ```
+/// use rust_server::{build_info, Router};
+///
+/// let router = Router::new().get("/version", |request, _| build_info::respond(request));
+/// ```
+pub fn respond(request: &Request) -> Response {
+    match request.prefers(&["text/plain", "application/json"]) {
+        Some("application/json") => {
+            let string = |value: Option<&str>| match value {
+                Some(value) => format!("\"{}\"", escape_json(value)),
+                None => "null".to_string(),
+            };
+            let json = format!(
+                "{{\"name\":{},\"version\":{},\"git_hash\":{},\"build_time\":{}}}",
+                string(Some(NAME)),
+                string(Some(VERSION)),
+                string(GIT_HASH),
+                string(BUILD_TIME),
+            );
+            Response::new(200)
+                .with_header("Content-Type", "application/json")
+                .with_body(json)
+        }
+        _ => Response::text(
+            200,
+            format!(
+                "{NAME} {VERSION}\ncommit: {}\nbuilt: {}\n",
+                GIT_HASH.unwrap_or("unknown"),
+                BUILD_TIME.unwrap_or("unknown"),
+            ),
+        ),
+    }
+}
+
+/// Escape `value` for use inside a JSON string.
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
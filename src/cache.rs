@@ -0,0 +1,394 @@
+//! Keeping responses in memory, so repeated `GET` requests don't run
+//! their handler again.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+use crate::{
+    request::{Method, Request},
+    response::{Body, Response},
+};
+
+/// A bounded in-process cache of responses, keyed by request target.
+///
+/// Handlers opt in by marking a response with
+/// [`Response::cache_for`]. Until that time is up, `GET` requests for the
+/// same path and query string are answered from the cache without running
+/// the handler again. A client sending `Cache-Control: no-cache` always
+/// gets a fresh response, which then replaces the cached one.
+///
+/// A response listing request headers in `Vary` is only reused for
+/// requests sending the same values for them, so e.g. each language of a
+/// page picked by `Accept-Language` is cached on its own. Responses with
+/// `Vary: *`, and those setting a cookie, are meant for a single client
+/// and never cached.
+///
+/// Only responses whose body is in memory can be cached; a file or a
+/// reader would be used up by the first response. When the cache is
+/// full, the entry that was used the longest time ago makes room.
+///
+/// Like [`Proxy`](crate::proxy::Proxy), a cache is meant to live in the
+/// router's state:
+///
+/// ```no_run
+/// use std::{sync::Arc, time::Duration};
+///
+/// use rust_server::{Response, ResponseCache, Router};
+///
+/// let router = Router::with_state(Arc::new(ResponseCache::new(100))).get(
+///     "/report",
+///     |request, cache| {
+///         cache.serve(request, |_| {
+///             Response::text(200, "expensive").cache_for(Duration::from_secs(60))
+///         })
+///     },
+/// );
+/// ```
+pub struct ResponseCache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// The cached responses for each target, one for each set of values
+    /// of the request headers they vary on.
+    entries: HashMap<String, Vec<Entry>>,
+    /// The targets of `entries` by when each of their responses was last
+    /// used, oldest first.
+    recency: BTreeMap<u64, String>,
+    /// Counts uses, to order the entries in `recency`.
+    next_use: u64,
+}
+
+struct Entry {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// The request headers named in the response's `Vary`, with the
+    /// values the request it was made for had.
+    vary: Vec<(String, Option<String>)>,
+    expires: Instant,
+    last_use: u64,
+}
+
+impl Entry {
+    /// Whether the response may answer `request`.
+    fn matches(&self, request: &Request) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request.header(name) == value.as_deref())
+    }
+}
+
+impl ResponseCache {
+    /// Create a cache holding at most `capacity` responses.
+    pub fn new(capacity: usize) -> ResponseCache {
+        ResponseCache {
+            capacity,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                next_use: 0,
+            }),
+        }
+    }
+
+    /// Answer `request` from the cache if possible, and with `handler`
+    /// otherwise.
+    ///
+    /// Requests other than `GET` always go to the handler, and their
+    /// responses are never cached.
+    pub fn serve<F>(&self, request: &Request, handler: F) -> Response
+    where
+        F: FnOnce(&Request) -> Response,
+    {
+        if request.method() != Method::Get {
+            return handler(request);
+        }
+
+        if !bypasses_cache(request) {
+            if let Some(response) = self.lookup(request) {
+                return response;
+            }
+        }
+
+        let mut response = handler(request);
+        if let Some(ttl) = response.cache_ttl() {
+            if is_shareable(&response) {
+                self.store(request, &mut response, Instant::now() + ttl);
+            }
+        }
+        response
+    }
+
+    /// The number of responses in the cache, including expired ones that
+    /// haven't been asked for since.
+    pub fn len(&self) -> usize {
+        self.lock().entries.values().map(Vec::len).sum()
+    }
+
+    /// Whether the cache holds no responses.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop every cached response.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.entries.clear();
+        state.recency.clear();
+    }
+
+    fn lookup(&self, request: &Request) -> Option<Response> {
+        let key = request.target();
+        let mut state = self.lock();
+        let state = &mut *state;
+
+        let variants = state.entries.get_mut(key)?;
+        let index = variants.iter().position(|entry| entry.matches(request))?;
+        let entry = &mut variants[index];
+        state.recency.remove(&entry.last_use);
+        if entry.expires <= Instant::now() {
+            variants.swap_remove(index);
+            if variants.is_empty() {
+                state.entries.remove(key);
+            }
+            return None;
+        }
+
+        entry.last_use = state.next_use;
+        state.recency.insert(state.next_use, key.to_string());
+        state.next_use += 1;
+
+        let mut response = Response::new(entry.status).with_body(entry.body.clone());
+        for (name, value) in &entry.headers {
+            response.add_header(name, value);
+        }
+        Some(response)
+    }
+
+    fn store(&self, request: &Request, response: &mut Response, expires: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        // The body is moved into the cache and copied back out, which
+        // saves copying it when it isn't in memory and can't be cached.
+        let body = match response.take_body() {
+            Body::Bytes(bytes) => bytes,
+            Body::Text(text) => text.into_bytes(),
+            Body::Static(bytes) => bytes.to_vec(),
+            other => {
+                response.set_body(other);
+                return;
+            }
+        };
+        response.set_body(body.clone());
+
+        let vary = response
+            .header("Vary")
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| (name.to_string(), request.header(name).map(str::to_string)))
+            .collect();
+
+        let key = request.target();
+        let mut state = self.lock();
+        let state = &mut *state;
+        // The new response replaces any cached one the request would have
+        // been answered with.
+        if let Some(variants) = state.entries.get_mut(key) {
+            variants.retain(|entry| {
+                let replaced = entry.matches(request);
+                if replaced {
+                    state.recency.remove(&entry.last_use);
+                }
+                !replaced
+            });
+        }
+        while state.recency.len() >= self.capacity {
+            let Some((last_use, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            if let Some(variants) = state.entries.get_mut(&oldest) {
+                variants.retain(|entry| entry.last_use != last_use);
+                if variants.is_empty() {
+                    state.entries.remove(&oldest);
+                }
+            }
+        }
+
+        let last_use = state.next_use;
+        state.next_use += 1;
+        state.recency.insert(last_use, key.to_string());
+        state
+            .entries
+            .entry(key.to_string())
+            .or_default()
+            .push(Entry {
+                status: response.status(),
+                headers: response.headers().to_vec(),
+                body,
+                vary,
+                expires,
+                last_use,
+            });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Whether the client asked for a response that doesn't come from a
+/// cache.
+fn bypasses_cache(request: &Request) -> bool {
+    request.header("Cache-Control").is_some_and(|value| {
+        value
+            .split(',')
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
+    })
+}
+
+/// Whether `response` may be given to other clients than the one it was
+/// made for.
+fn is_shareable(response: &Response) -> bool {
+    let varies_on_everything = response
+        .header("Vary")
+        .is_some_and(|vary| vary.split(',').any(|name| name.trim() == "*"));
+    !varies_on_everything && response.header("Set-Cookie").is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, thread, time::Duration};
+
+    use super::*;
+
+    fn get(target: &str) -> Request {
+        Request::new(Method::Get, target)
+    }
+
+    /// Serve `request`, returning the body and whether the handler ran.
+    fn serve(cache: &ResponseCache, request: &Request, response: Response) -> (String, bool) {
+        let ran = Cell::new(false);
+        let response = cache.serve(request, |_| {
+            ran.set(true);
+            response
+        });
+        let body = response.body().as_bytes().unwrap().to_vec();
+        (String::from_utf8(body).unwrap(), ran.get())
+    }
+
+    fn cacheable(body: &str, ttl: Duration) -> Response {
+        Response::text(200, body).cache_for(ttl)
+    }
+
+    #[test]
+    fn serves_from_the_cache_until_the_ttl_is_up() {
+        let cache = ResponseCache::new(10);
+        let ttl = Duration::from_millis(100);
+
+        assert_eq!(
+            serve(&cache, &get("/"), cacheable("first", ttl)),
+            ("first".into(), true)
+        );
+        assert_eq!(
+            serve(&cache, &get("/"), cacheable("second", ttl)),
+            ("first".into(), false)
+        );
+
+        thread::sleep(ttl);
+        assert_eq!(
+            serve(&cache, &get("/"), cacheable("third", ttl)),
+            ("third".into(), true)
+        );
+    }
+
+    #[test]
+    fn keys_on_the_target_and_skips_other_methods() {
+        let cache = ResponseCache::new(10);
+        let ttl = Duration::from_secs(60);
+        serve(&cache, &get("/a?x=1"), cacheable("a", ttl));
+
+        assert!(serve(&cache, &get("/a?x=2"), cacheable("b", ttl)).1);
+        assert!(!serve(&cache, &get("/a?x=1"), cacheable("c", ttl)).1);
+        let post = Request::new(Method::Post, "/a?x=1");
+        assert!(serve(&cache, &post, cacheable("d", ttl)).1);
+    }
+
+    #[test]
+    fn no_cache_fetches_a_fresh_response() {
+        let cache = ResponseCache::new(10);
+        let ttl = Duration::from_secs(60);
+        serve(&cache, &get("/"), cacheable("old", ttl));
+
+        let fresh = get("/").with_header("Cache-Control", "no-cache");
+        assert_eq!(
+            serve(&cache, &fresh, cacheable("new", ttl)),
+            ("new".into(), true)
+        );
+        assert_eq!(
+            serve(&cache, &get("/"), cacheable("newer", ttl)),
+            ("new".into(), false)
+        );
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_response() {
+        let cache = ResponseCache::new(2);
+        let ttl = Duration::from_secs(60);
+        serve(&cache, &get("/a"), cacheable("a", ttl));
+        serve(&cache, &get("/b"), cacheable("b", ttl));
+        serve(&cache, &get("/a"), cacheable("a", ttl));
+        serve(&cache, &get("/c"), cacheable("c", ttl));
+
+        assert_eq!(cache.len(), 2);
+        assert!(!serve(&cache, &get("/a"), cacheable("a", ttl)).1);
+        assert!(serve(&cache, &get("/b"), cacheable("b", ttl)).1);
+    }
+
+    #[test]
+    fn keeps_a_response_for_each_value_of_the_headers_it_varies_on() {
+        let cache = ResponseCache::new(10);
+        let ttl = Duration::from_secs(60);
+        let page = |body: &str| cacheable(body, ttl).with_header("Vary", "Accept-Language");
+        let english = get("/").with_header("Accept-Language", "en");
+        let french = get("/").with_header("Accept-Language", "fr");
+
+        assert_eq!(
+            serve(&cache, &english, page("hello")),
+            ("hello".into(), true)
+        );
+        assert_eq!(
+            serve(&cache, &french, page("bonjour")),
+            ("bonjour".into(), true)
+        );
+        assert_eq!(serve(&cache, &english, page("?")), ("hello".into(), false));
+        assert_eq!(serve(&cache, &french, page("?")), ("bonjour".into(), false));
+        assert!(serve(&cache, &get("/"), page("?")).1);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn never_stores_responses_for_a_single_client() {
+        let cache = ResponseCache::new(10);
+        let ttl = Duration::from_secs(60);
+        let mut cookie = cacheable("yours", ttl);
+        cookie
+            .set_cookie("session", "secret", &Default::default())
+            .unwrap();
+        let everything = cacheable("yours", ttl).with_header("Vary", "Accept, *");
+
+        serve(&cache, &get("/cookie"), cookie);
+        serve(&cache, &get("/everything"), everything);
+        assert!(cache.is_empty());
+    }
+}
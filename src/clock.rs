@@ -0,0 +1,151 @@
+//! Where the time comes from, for the parts of the crate that wait for
+//! it: the [pool's timer](crate::ThreadPool::clock), behind retries and
+//! intervals, and the server's [accept rate limit](crate::Server::clock).
+//!
+//! They use the [`SystemClock`] unless told otherwise. Tests can hand them
+//! a [`ManualClock`] instead, whose time only moves when the test moves it,
+//! so a job scheduled an hour from now runs as soon as the test says an
+//! hour has passed, without anyone waiting for it:
+//!
+//! ```
+//! use std::{sync::mpsc, time::Duration};
+//!
+//! use rust_server::{ManualClock, ThreadPool};
+//!
+//! let clock = ManualClock::new();
+//! let pool = ThreadPool::new(1).clock(clock.clone());
+//!
+//! let (sender, receiver) = mpsc::channel();
+//! pool.schedule_interval(Duration::from_secs(3600), move || sender.send(()).unwrap());
+//! assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+//!
+//! clock.advance(Duration::from_secs(3600));
+//! receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+//! ```
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A source of time.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// Block the calling thread until `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration);
+
+    /// Call `wake` every time the clock jumps forward, rather than its time
+    /// passing by itself.
+    ///
+    /// Waiting for a time on the clock by other means than
+    /// [`sleep`](Clock::sleep), like a condition variable with a timeout,
+    /// measures real time, and has to be woken up to notice the jump. The
+    /// default does nothing, which suits clocks that follow real time.
+    fn on_advance(&self, wake: Box<dyn Fn() + Send + Sync>) {
+        let _ = wake;
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration)
+    }
+
+    fn on_advance(&self, wake: Box<dyn Fn() + Send + Sync>) {
+        (**self).on_advance(wake)
+    }
+}
+
+/// The real time, as told by [`Instant::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// A clock that stands still until it is [advanced](ManualClock::advance),
+/// for tests.
+///
+/// It starts at the real time it was created. Clones share the same time,
+/// so a test keeps one to move the time of those it handed the others to.
+/// [`sleep`](Clock::sleep) doesn't block at all: it advances the clock by
+/// the time asked for and returns.
+#[derive(Clone)]
+pub struct ManualClock {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    now: Mutex<Instant>,
+    wakers: Mutex<Vec<Box<dyn Fn() + Send + Sync>>>,
+}
+
+impl ManualClock {
+    /// Create a clock standing at the current time.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            shared: Arc::new(Shared {
+                now: Mutex::new(Instant::now()),
+                wakers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Move the time forward by `duration`, running everything that was
+    /// waiting for a time up to then.
+    pub fn advance(&self, duration: Duration) {
+        *lock(&self.shared.now) += duration;
+        for wake in lock(&self.shared.wakers).iter() {
+            wake();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl fmt::Debug for ManualClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ManualClock")
+            .field("now", &*lock(&self.shared.now))
+            .finish()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *lock(&self.shared.now)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+
+    fn on_advance(&self, wake: Box<dyn Fn() + Send + Sync>) {
+        lock(&self.shared.wakers).push(wake);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,349 @@
+//! Conditional and range requests.
+//!
+//! [`evaluate`] looks at the validators of a finished `200 OK` response,
+//! its `ETag` and `Last-Modified` headers, and at the conditional headers
+//! of the request to decide whether the client gets the full response, a
+//! `304 Not Modified`, or only part of the body with `206 Partial Content`.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    time::SystemTime,
+};
+
+use crate::{
+    date,
+    request::{Method, Request},
+    response::{Body, Response},
+};
+
+/// Compute a strong `ETag` for a body, quotes included.
+///
+/// The tag is a 64-bit FNV-1a hash of the bytes. It only has to change
+/// whenever the body changes, it doesn't have to be hard to forge.
+pub fn etag(body: &[u8]) -> String {
+    let hash = body.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("\"{hash:016x}\"")
+}
+
+/// Apply the conditional headers of `request` to `response`.
+///
+/// Only successful `GET` and `HEAD` responses are touched:
+///
+/// * `If-None-Match` matching the response's `ETag` turns it into a
+///   `304 Not Modified` without a body.
+/// * `Range` asks for a single byte range of the body, which is answered
+///   with `206 Partial Content`, or `416 Range Not Satisfiable` when the
+///   range lies outside the body. Requests for several ranges at once get
+///   the full body, which the spec allows.
+/// * `If-Range` makes the `Range` header conditional. If its validator, an
+///   entity tag or a date, no longer matches the response, the client's
+///   copy is stale and it gets the full `200` response instead of a piece
+///   that would corrupt a resumed download.
+pub fn evaluate(request: &Request, mut response: Response) -> Response {
+    if !matches!(request.method(), Method::Get | Method::Head) || response.status() != 200 {
+        return response;
+    }
+
+    if let (Some(candidates), Some(etag)) =
+        (request.header("If-None-Match"), response.header("ETag"))
+    {
+        if none_match(candidates, etag) {
+            response.set_status(304);
+            response.set_body(Body::Empty);
+            return response;
+        }
+    }
+
+    // Open a file given by its path now, so the length the range is
+    // checked against is the length that will be sent.
+    if matches!(response.body(), Body::Path(_)) {
+        match response.take_body().open() {
+            Ok(body) => response.set_body(body),
+            Err(_) => return Response::text(500, "500 Internal Server Error"),
+        }
+    }
+
+    // Ranges need a length to count from, which a reader doesn't have. A
+    // `HEAD` response may have only the length of the body it stands for.
+    let declared_length = response.declared_length(request.method());
+    let Some(length) = declared_length.or(response.body().len()) else {
+        return response;
+    };
+
+    // Tell the client it may ask for ranges next time.
+    response.set_header("Accept-Ranges", "bytes");
+
+    let Some(range) = request.header("Range") else {
+        return response;
+    };
+
+    if let Some(validator) = request.header("If-Range") {
+        if !if_range_matches(validator, &response) {
+            return response;
+        }
+    }
+
+    match parse_range(range, length) {
+        ByteRange::Ignored => response,
+        ByteRange::Unsatisfiable => {
+            let mut response =
+                Response::new(416).with_header("Content-Range", &format!("bytes */{length}"));
+            response.set_header("Accept-Ranges", "bytes");
+            response
+        }
+        ByteRange::Satisfiable(start, end) => {
+            let partial = match response.take_body() {
+                Body::Bytes(bytes) => Body::Bytes(slice(bytes, start, end)),
+                Body::Text(text) => Body::Bytes(slice(text.into_bytes(), start, end)),
+                Body::Static(bytes) => Body::Static(&bytes[start as usize..=end as usize]),
+                // A file body starts at the file's current position, so
+                // skip ahead to the start of the range and stream from
+                // there.
+                Body::File(mut file, _) => {
+                    if file.seek(SeekFrom::Current(start as i64)).is_err() {
+                        return Response::new(500);
+                    }
+                    Body::File(file, end - start + 1)
+                }
+                // A reader can only skip ahead by reading.
+                Body::SizedReader(mut reader, _) => {
+                    match io::copy(&mut reader.by_ref().take(start), &mut io::sink()) {
+                        Ok(skipped) if skipped == start => {}
+                        _ => return Response::new(500),
+                    }
+                    Body::SizedReader(reader, end - start + 1)
+                }
+                Body::Empty if declared_length.is_some() => {
+                    let part = end - start + 1;
+                    response.set_header("Content-Length", &part.to_string());
+                    Body::Empty
+                }
+                // Any other empty body has no satisfiable range, a path
+                // was opened above and a reader returned early.
+                Body::Empty | Body::Path(_) | Body::Reader(_) => {
+                    unreachable!("no byte range for a body without bytes or length")
+                }
+            };
+            response.set_status(206);
+            response.set_body(partial);
+            response.set_header("Content-Range", &format!("bytes {start}-{end}/{length}"));
+            response
+        }
+    }
+}
+
+/// Keep the bytes from `start` to `end`, both included.
+fn slice(mut bytes: Vec<u8>, start: u64, end: u64) -> Vec<u8> {
+    bytes.truncate(end as usize + 1);
+    bytes.drain(..start as usize);
+    bytes
+}
+
+/// Check `If-None-Match` against an entity tag, using the weak comparison
+/// the spec asks for.
+fn none_match(candidates: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    candidates.trim() == "*"
+        || candidates
+            .split(',')
+            .any(|candidate| candidate.trim().trim_start_matches("W/") == etag)
+}
+
+/// Check the validator of an `If-Range` header against the response.
+fn if_range_matches(validator: &str, response: &Response) -> bool {
+    let validator = validator.trim();
+
+    // An entity tag must match strongly, so weak tags never match.
+    if validator.starts_with('"') || validator.starts_with("W/") {
+        return match response.header("ETag") {
+            Some(etag) => {
+                !etag.starts_with("W/") && !validator.starts_with("W/") && etag == validator
+            }
+            None => false,
+        };
+    }
+
+    // Otherwise it is a date, which must be exactly the last modification
+    // time of the response.
+    let modified: Option<SystemTime> = response
+        .header("Last-Modified")
+        .and_then(date::parse_http_date);
+    match (date::parse_http_date(validator), modified) {
+        (Some(since), Some(modified)) => since == modified,
+        _ => false,
+    }
+}
+
+/// The outcome of parsing a `Range` header against a body length.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// The header is malformed, uses another unit, or asks for more than
+    /// one range, so it is ignored and the full body is sent.
+    Ignored,
+    /// The range starts past the end of the body.
+    Unsatisfiable,
+    /// An inclusive range of byte offsets inside the body.
+    Satisfiable(u64, u64),
+}
+
+fn parse_range(header: &str, length: u64) -> ByteRange {
+    let Some((unit, spec)) = header.trim().split_once('=') else {
+        return ByteRange::Ignored;
+    };
+    if !unit.eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return ByteRange::Ignored;
+    }
+
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Ignored;
+    };
+    // Either bound may be left out, but whatever is there must be digits.
+    let bound = |value: &str| match value.trim() {
+        "" => Ok(None),
+        value if value.bytes().all(|b| b.is_ascii_digit()) => {
+            value.parse().map(Some).map_err(|_| ())
+        }
+        _ => Err(()),
+    };
+    let (Ok(first), Ok(last)) = (bound(first), bound(last)) else {
+        return ByteRange::Ignored;
+    };
+
+    match (first, last) {
+        // "bytes=500-999" or "bytes=500-".
+        (Some(first), last) => {
+            if last.is_some_and(|last| last < first) {
+                return ByteRange::Ignored;
+            }
+            if first >= length {
+                return ByteRange::Unsatisfiable;
+            }
+            let last = last.map_or(length - 1, |last: u64| last.min(length - 1));
+            ByteRange::Satisfiable(first, last)
+        }
+        // "bytes=-500", the last 500 bytes.
+        (None, Some(suffix)) => {
+            if suffix == 0 || length == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            ByteRange::Satisfiable(length.saturating_sub(suffix), length - 1)
+        }
+        (None, None) => ByteRange::Ignored,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const BODY: &str = "0123456789";
+    const MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn download() -> Response {
+        Response::text(200, BODY)
+            .with_header("ETag", &etag(BODY.as_bytes()))
+            .with_header("Last-Modified", MODIFIED)
+    }
+
+    fn get(headers: &[(&str, &str)]) -> Response {
+        let request = headers.iter().fold(
+            Request::new(Method::Get, "/file"),
+            |request, (name, value)| request.with_header(name, value),
+        );
+        evaluate(&request, download())
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body().as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serves_a_single_range() {
+        let response = get(&[("Range", "bytes=2-4")]);
+        assert_eq!(response.status(), 206);
+        assert_eq!(body(&response), "234");
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+
+        assert_eq!(body(&get(&[("Range", "bytes=7-")])), "789");
+        assert_eq!(body(&get(&[("Range", "bytes=-3")])), "789");
+        assert_eq!(body(&get(&[("Range", "bytes=8-100")])), "89");
+    }
+
+    #[test]
+    fn if_range_with_the_current_validator_gets_the_range() {
+        let tag = etag(BODY.as_bytes());
+        for validator in [tag.as_str(), MODIFIED] {
+            let response = get(&[("Range", "bytes=0-3"), ("If-Range", validator)]);
+            assert_eq!(response.status(), 206, "{validator}");
+            assert_eq!(body(&response), "0123");
+        }
+    }
+
+    #[test]
+    fn if_range_with_a_stale_validator_gets_everything() {
+        let earlier = date::format_http_date(
+            date::parse_http_date(MODIFIED).unwrap() - Duration::from_secs(1),
+        );
+        let weak = format!("W/{}", etag(BODY.as_bytes()));
+        for validator in ["\"stale\"", weak.as_str(), earlier.as_str(), "yesterday"] {
+            let response = get(&[("Range", "bytes=0-3"), ("If-Range", validator)]);
+            assert_eq!(response.status(), 200, "{validator}");
+            assert_eq!(body(&response), BODY);
+            assert_eq!(response.header("Content-Range"), None);
+        }
+    }
+
+    #[test]
+    fn if_none_match_gets_not_modified() {
+        let tag = etag(BODY.as_bytes());
+        for candidates in [tag.clone(), format!("\"other\", W/{tag}"), "*".to_string()] {
+            let response = get(&[("If-None-Match", &candidates)]);
+            assert_eq!(response.status(), 304, "{candidates}");
+            assert!(response.body().is_empty());
+        }
+        assert_eq!(get(&[("If-None-Match", "\"other\"")]).status(), 200);
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-0", 10), ByteRange::Satisfiable(0, 0));
+        assert_eq!(parse_range("BYTES=3-5", 10), ByteRange::Satisfiable(3, 5));
+        assert_eq!(parse_range("bytes=-20", 10), ByteRange::Satisfiable(0, 9));
+        assert_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0), ByteRange::Unsatisfiable);
+        for ignored in [
+            "bytes=5-3",
+            "bytes=0-1,3-4",
+            "items=0-1",
+            "bytes=-",
+            "bytes=+1-2",
+            "0-1",
+        ] {
+            assert_eq!(parse_range(ignored, 10), ByteRange::Ignored, "{ignored}");
+        }
+    }
+
+    #[test]
+    fn answers_unsatisfiable_ranges_with_416() {
+        let response = get(&[("Range", "bytes=20-30")]);
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+    }
+
+    #[test]
+    fn leaves_other_requests_alone() {
+        let request = Request::new(Method::Post, "/file").with_header("Range", "bytes=0-1");
+        assert_eq!(evaluate(&request, download()).status(), 200);
+
+        let request = Request::new(Method::Get, "/file").with_header("Range", "bytes=0-1");
+        let response = evaluate(&request, Response::text(404, "missing"));
+        assert_eq!(response.status(), 404);
+        assert_eq!(body(&response), "missing");
+    }
+}
@@ -0,0 +1,340 @@
+//! Reading a [`ServerConfig`] from the environment or a configuration
+//! file, so a server can be deployed without recompiling it.
+//!
+//! Both know the same settings:
+//!
+//! | Key                  | Environment variable             | Value                      |
+//! |----------------------|----------------------------------|----------------------------|
+//! | `addr`               | `RUST_SERVER_ADDR`               | address, e.g. `0.0.0.0:80` |
+//! | `pool_size`          | `RUST_SERVER_POOL_SIZE`          | number of workers          |
+//! | `keep_alive_timeout` | `RUST_SERVER_KEEP_ALIVE_TIMEOUT` | seconds                    |
+//! | `request_timeout`    | `RUST_SERVER_REQUEST_TIMEOUT`    | seconds                    |
+//! | `static_root`        | `RUST_SERVER_STATIC_ROOT`        | directory                  |
+//!
+//! Everything else keeps its default, and can still be changed in code
+//! afterwards.
+
+use std::{env, fmt, time::Duration};
+
+use crate::server::ServerConfig;
+
+/// The settings that can be read, in the order they are looked up.
+const KEYS: &[&str] = &[
+    "addr",
+    "pool_size",
+    "keep_alive_timeout",
+    "request_timeout",
+    "static_root",
+];
+
+/// The prefix of the environment variables read by
+/// [`ServerConfig::from_env`].
+const ENV_PREFIX: &str = "RUST_SERVER_";
+
+/// The reasons a configuration could not be read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// A configuration file isn't valid TOML.
+    Syntax { line: usize, reason: String },
+    /// A configuration file sets a key that isn't a known setting.
+    UnknownKey(String),
+    /// A setting, named by its key or environment variable, has a value of
+    /// the wrong kind.
+    Invalid { key: String, expected: &'static str },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Syntax { line, reason } => write!(f, "line {line}: {reason}"),
+            ConfigError::UnknownKey(key) => write!(f, "unknown setting {key:?}"),
+            ConfigError::Invalid { key, expected } => write!(f, "{key} should be {expected}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The value of a setting. Only TOML has values of a known kind.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "toml_config"), allow(dead_code))]
+enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    /// A TOML value no setting takes, like a table or an array.
+    Other,
+    /// Text from an environment variable, which says nothing about the
+    /// kind of value it is, so it is parsed as whatever the setting needs.
+    Raw(String),
+}
+
+impl Value {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(value) | Value::Raw(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_count(&self) -> Option<usize> {
+        let count = match self {
+            Value::Int(value) => usize::try_from(*value).ok()?,
+            Value::Raw(value) => value.trim().parse().ok()?,
+            _ => return None,
+        };
+        (count > 0).then_some(count)
+    }
+
+    fn as_seconds(&self) -> Option<Duration> {
+        let seconds = match self {
+            Value::Int(value) => *value as f64,
+            Value::Float(value) => *value,
+            Value::Raw(value) => value.trim().parse().ok()?,
+            _ => return None,
+        };
+        Duration::try_from_secs_f64(seconds).ok()
+    }
+}
+
+impl ServerConfig {
+    /// The default configuration, with the settings in the `RUST_SERVER_*`
+    /// environment variables applied, see [`ServerConfig::apply_env`].
+    pub fn from_env() -> Result<ServerConfig, ConfigError> {
+        ServerConfig::default().apply_env()
+    }
+
+    /// Apply the settings in the `RUST_SERVER_*` environment variables
+    /// listed in the [module documentation](crate::config), on top of
+    /// this configuration.
+    ///
+    /// Variables that aren't set, or aren't valid Unicode, leave their
+    /// setting alone. This is typically used after reading a file, so the
+    /// environment overrides it:
+    ///
+    /// ```no_run
+    /// # #[cfg(feature = "toml_config")]
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use rust_server::ServerConfig;
+    ///
+    /// let file = std::fs::read_to_string("server.toml")?;
+    /// let config = ServerConfig::from_toml_str(&file)?.apply_env()?;
+    /// # Ok(())
+    /// # }
+    /// # #[cfg(not(feature = "toml_config"))]
+    /// # fn main() {}
+    /// ```
+    pub fn apply_env(self) -> Result<ServerConfig, ConfigError> {
+        self.apply_vars(|name| env::var(name).ok())
+    }
+
+    fn apply_vars(
+        mut self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<ServerConfig, ConfigError> {
+        for key in KEYS {
+            let name = format!("{ENV_PREFIX}{}", key.to_ascii_uppercase());
+            if let Some(value) = var(&name) {
+                self = self.apply(key, &Value::Raw(value), &name)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Read a configuration from TOML, like
+    ///
+    /// ```toml
+    /// addr = "0.0.0.0:8080"
+    /// pool_size = 8
+    /// keep_alive_timeout = 2.5
+    /// static_root = "public"
+    /// ```
+    ///
+    /// with the keys listed in the [module documentation](crate::config).
+    /// Settings the text leaves out keep their default.
+    ///
+    /// The text is parsed with the `toml` crate, so any valid TOML is
+    /// read, but the settings are all at the top level.
+    #[cfg(feature = "toml_config")]
+    pub fn from_toml_str(text: &str) -> Result<ServerConfig, ConfigError> {
+        let table: toml::Table = toml::from_str(text).map_err(|err| ConfigError::Syntax {
+            line: err
+                .span()
+                .map_or(1, |span| text[..span.start].matches('\n').count() + 1),
+            reason: err.message().to_string(),
+        })?;
+
+        let mut config = ServerConfig::default();
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(value) => Value::Str(value),
+                toml::Value::Integer(value) => Value::Int(value),
+                toml::Value::Float(value) => Value::Float(value),
+                _ => Value::Other,
+            };
+            config = config.apply(&key, &value, &key)?;
+        }
+        Ok(config)
+    }
+
+    /// Apply one setting. `name` is how errors refer to it.
+    fn apply(self, key: &str, value: &Value, name: &str) -> Result<ServerConfig, ConfigError> {
+        let invalid = |expected| ConfigError::Invalid {
+            key: name.to_string(),
+            expected,
+        };
+
+        Ok(match key {
+            "addr" => self.addr(value.as_str().ok_or_else(|| invalid("a string"))?),
+            "pool_size" => self.pool_size(
+                value
+                    .as_count()
+                    .ok_or_else(|| invalid("a positive whole number"))?,
+            ),
+            "keep_alive_timeout" => self.keep_alive_timeout(
+                value
+                    .as_seconds()
+                    .ok_or_else(|| invalid("a number of seconds"))?,
+            ),
+            "request_timeout" => self.request_timeout(
+                value
+                    .as_seconds()
+                    .ok_or_else(|| invalid("a number of seconds"))?,
+            ),
+            "static_root" => self.static_root(value.as_str().ok_or_else(|| invalid("a string"))?),
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configurations have no equality, but their settings all show in
+    /// their debug output.
+    fn assert_same(config: ServerConfig, expected: ServerConfig) {
+        assert_eq!(format!("{config:?}"), format!("{expected:?}"));
+    }
+
+    fn vars<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn reads_the_environment() {
+        let config = ServerConfig::default()
+            .apply_vars(vars(&[
+                ("RUST_SERVER_ADDR", "0.0.0.0:80"),
+                ("RUST_SERVER_POOL_SIZE", " 16 "),
+                ("RUST_SERVER_REQUEST_TIMEOUT", "0.5"),
+            ]))
+            .unwrap();
+        assert_same(
+            config,
+            ServerConfig::default()
+                .addr("0.0.0.0:80")
+                .pool_size(16)
+                .request_timeout(Duration::from_millis(500)),
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_variables() {
+        let error = ServerConfig::default()
+            .apply_vars(vars(&[("RUST_SERVER_POOL_SIZE", "0")]))
+            .unwrap_err();
+        assert_eq!(
+            error,
+            ConfigError::Invalid {
+                key: "RUST_SERVER_POOL_SIZE".to_string(),
+                expected: "a positive whole number",
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "RUST_SERVER_POOL_SIZE should be a positive whole number"
+        );
+    }
+
+    #[cfg(feature = "toml_config")]
+    mod toml_file {
+        use super::*;
+
+        const FILE: &str = r#"
+            # Where to listen.
+            addr = "0.0.0.0:8080"
+            pool_size = 8
+            keep_alive_timeout = 2.5
+            request_timeout = 30
+            static_root = 'public'
+        "#;
+
+        #[test]
+        fn reads_a_file() {
+            assert_same(
+                ServerConfig::from_toml_str(FILE).unwrap(),
+                ServerConfig::default()
+                    .addr("0.0.0.0:8080")
+                    .pool_size(8)
+                    .keep_alive_timeout(Duration::from_millis(2500))
+                    .request_timeout(Duration::from_secs(30))
+                    .static_root("public"),
+            );
+        }
+
+        #[test]
+        fn the_environment_overrides_the_file() {
+            let config = ServerConfig::from_toml_str(FILE)
+                .unwrap()
+                .apply_vars(vars(&[
+                    ("RUST_SERVER_POOL_SIZE", "2"),
+                    ("RUST_SERVER_STATIC_ROOT", "/srv/www"),
+                ]))
+                .unwrap();
+            assert_same(
+                config,
+                ServerConfig::default()
+                    .addr("0.0.0.0:8080")
+                    .pool_size(2)
+                    .keep_alive_timeout(Duration::from_millis(2500))
+                    .request_timeout(Duration::from_secs(30))
+                    .static_root("/srv/www"),
+            );
+        }
+
+        #[test]
+        fn rejects_invalid_files() {
+            assert_eq!(
+                ServerConfig::from_toml_str("pool_size = 4\nworkers = 4").unwrap_err(),
+                ConfigError::UnknownKey("workers".to_string())
+            );
+            assert_eq!(
+                ServerConfig::from_toml_str("pool_size = \"4\"").unwrap_err(),
+                ConfigError::Invalid {
+                    key: "pool_size".to_string(),
+                    expected: "a positive whole number",
+                }
+            );
+            assert_eq!(
+                ServerConfig::from_toml_str("addr = [\"0.0.0.0:80\"]").unwrap_err(),
+                ConfigError::Invalid {
+                    key: "addr".to_string(),
+                    expected: "a string",
+                }
+            );
+            assert!(matches!(
+                ServerConfig::from_toml_str("pool_size = 4\npool_size = 5"),
+                Err(ConfigError::Syntax { line: 2, .. })
+            ));
+            assert!(matches!(
+                ServerConfig::from_toml_str("\n\naddr = "),
+                Err(ConfigError::Syntax { line: 3, .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Reading the `Cookie` request header and writing `Set-Cookie` headers.
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+/// The `SameSite` attribute of a cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept `SameSite=None` on cookies that are also
+    /// marked `Secure`.
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// The optional attributes of a `Set-Cookie` header.
+///
+/// The default is a session cookie with no attributes at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CookieAttributes {
+    /// Hide the cookie from JavaScript.
+    pub http_only: bool,
+    /// Only send the cookie over HTTPS.
+    pub secure: bool,
+    /// How long the client should keep the cookie. A zero duration asks
+    /// the client to delete it right away.
+    pub max_age: Option<Duration>,
+    /// The path prefix the cookie is sent for.
+    pub path: Option<String>,
+    /// Whether the cookie is sent on cross-site requests.
+    pub same_site: Option<SameSite>,
+}
+
+/// The ways a cookie can be rejected by
+/// [`Response::set_cookie`](crate::response::Response::set_cookie).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CookieError {
+    /// The name is empty or contains characters that aren't allowed in a
+    /// token.
+    InvalidName,
+    /// The value contains control characters or other characters that
+    /// aren't allowed in a cookie value.
+    InvalidValue,
+    /// The `Path` attribute contains control characters or a semicolon.
+    InvalidPath,
+}
+
+impl fmt::Display for CookieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CookieError::InvalidName => f.write_str("invalid cookie name"),
+            CookieError::InvalidValue => f.write_str("invalid cookie value"),
+            CookieError::InvalidPath => f.write_str("invalid cookie path"),
+        }
+    }
+}
+
+impl std::error::Error for CookieError {}
+
+/// Parse the value of one or more `Cookie` headers into a map.
+///
+/// Pairs without an `=` are skipped, and if a name shows up twice the
+/// first value is kept, since clients send the most specific cookie first.
+pub(crate) fn parse_cookies<'a>(headers: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+
+    for header in headers {
+        for pair in header.split(';') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+
+            // Values may be wrapped in double quotes, which aren't part of
+            // the value itself.
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+
+            cookies
+                .entry(name.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+
+    cookies
+}
+
+/// Build the value of a `Set-Cookie` header.
+pub(crate) fn set_cookie_header(
+    name: &str,
+    value: &str,
+    attrs: &CookieAttributes,
+) -> Result<String, CookieError> {
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(CookieError::InvalidName);
+    }
+    if !value.bytes().all(is_cookie_octet) {
+        return Err(CookieError::InvalidValue);
+    }
+
+    let mut header = format!("{name}={value}");
+
+    if let Some(path) = &attrs.path {
+        if path.bytes().any(|b| b.is_ascii_control() || b == b';') {
+            return Err(CookieError::InvalidPath);
+        }
+        header.push_str(&format!("; Path={path}"));
+    }
+    if let Some(max_age) = attrs.max_age {
+        header.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+    }
+    if let Some(same_site) = attrs.same_site {
+        header.push_str(&format!("; SameSite={}", same_site.as_str()));
+    }
+    if attrs.secure {
+        header.push_str("; Secure");
+    }
+    if attrs.http_only {
+        header.push_str("; HttpOnly");
+    }
+
+    Ok(header)
+}
+
+/// Whether `b` may appear in an HTTP token, such as a cookie name.
+pub(crate) fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Whether `b` may appear in a cookie value, per the `cookie-octet` rule
+/// of RFC 6265.
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        request::{Method, Request},
+        response::Response,
+    };
+
+    #[test]
+    fn parses_several_cookies() {
+        let request = Request::new(Method::Get, "/")
+            .with_header("Cookie", "session=abc123; theme=\"dark\"; broken; =x")
+            .with_header("Cookie", "lang=en;session=other");
+        let cookies = request.cookies();
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["session"], "abc123");
+        assert_eq!(cookies["theme"], "dark");
+        assert_eq!(cookies["lang"], "en");
+        assert!(Request::new(Method::Get, "/").cookies().is_empty());
+    }
+
+    #[test]
+    fn writes_cookies_with_attributes() {
+        let attrs = CookieAttributes {
+            http_only: true,
+            secure: true,
+            max_age: Some(Duration::from_secs(3600)),
+            path: Some("/app".to_string()),
+            same_site: Some(SameSite::Lax),
+        };
+        let mut response = Response::text(200, "hi");
+        response.set_cookie("session", "abc123", &attrs).unwrap();
+        response
+            .set_cookie("theme", "dark", &CookieAttributes::default())
+            .unwrap();
+
+        let cookies: Vec<&str> = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| name == "Set-Cookie")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(
+            cookies,
+            [
+                "session=abc123; Path=/app; Max-Age=3600; SameSite=Lax; Secure; HttpOnly",
+                "theme=dark",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_cookies() {
+        let attrs = CookieAttributes::default();
+        assert_eq!(
+            set_cookie_header("", "x", &attrs),
+            Err(CookieError::InvalidName)
+        );
+        assert_eq!(
+            set_cookie_header("a b", "x", &attrs),
+            Err(CookieError::InvalidName)
+        );
+        for value in [
+            "a\r\nSet-Cookie: evil=1",
+            "a;b",
+            "a b",
+            "a\u{7f}",
+            "caf\u{e9}",
+        ] {
+            assert_eq!(
+                set_cookie_header("name", value, &attrs),
+                Err(CookieError::InvalidValue),
+                "{value:?}"
+            );
+        }
+
+        let attrs = CookieAttributes {
+            path: Some("/; Domain=evil.example".to_string()),
+            ..CookieAttributes::default()
+        };
+        assert_eq!(
+            set_cookie_header("name", "x", &attrs),
+            Err(CookieError::InvalidPath)
+        );
+
+        let mut response = Response::text(200, "hi");
+        assert!(response.set_cookie("name", "a\nb", &attrs).is_err());
+        assert_eq!(response.header("Set-Cookie"), None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Formatting and parsing of HTTP dates.
+//!
+//! HTTP uses the IMF-fixdate format for every date it sends, for example
+//! `Sun, 06 Nov 1994 08:49:37 GMT`. Dates are always in GMT and have a
+//! resolution of one second.
+
+use std::{
+    cell::RefCell,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format `time` as an IMF-fixdate, dropping anything below a second.
+///
+/// Times before 1970 are clamped to the Unix epoch.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // The epoch was a Thursday.
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+    )
+}
+
+thread_local! {
+    /// The second last formatted by [`with_current_http_date`] on this
+    /// thread, and its IMF-fixdate.
+    static CURRENT: RefCell<(u64, String)> = const { RefCell::new((u64::MAX, String::new())) };
+}
+
+/// Call `f` with the current time as an IMF-fixdate.
+///
+/// The date only changes once a second, so each thread formats it once
+/// per second at most and hands out the same string until the next one.
+/// Keeping it per thread means the workers never wait on each other for
+/// it.
+pub(crate) fn with_current_http_date<T>(f: impl FnOnce(&str) -> T) -> T {
+    with_http_date_at(SystemTime::now(), f)
+}
+
+/// [`with_current_http_date`] for when it is `now`.
+fn with_http_date_at<T>(now: SystemTime, f: impl FnOnce(&str) -> T) -> T {
+    let secs = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        if current.0 != secs {
+            *current = (secs, format_http_date(now));
+        }
+        f(&current.1)
+    })
+}
+
+/// A `Retry-After` value asking to retry after `delay`, as a number of
+/// seconds.
+///
+/// Parts of a second are rounded up, so a client doesn't come back too
+/// early.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use rust_server::date::retry_after;
+///
+/// assert_eq!(retry_after(Duration::from_secs(120)), "120");
+/// assert_eq!(retry_after(Duration::from_millis(1500)), "2");
+/// assert_eq!(retry_after(Duration::ZERO), "0");
+/// ```
+pub fn retry_after(delay: Duration) -> String {
+    let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+    secs.to_string()
+}
+
+/// A `Retry-After` value asking to retry at `time`, as an IMF-fixdate in
+/// GMT like every HTTP date.
+///
+/// ```
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// use rust_server::date::retry_after_at;
+///
+/// let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+/// assert_eq!(retry_after_at(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+/// ```
+pub fn retry_after_at(time: SystemTime) -> String {
+    format_http_date(time)
+}
+
+/// Format `time` the way the Common Log Format wants it, e.g.
+/// `06/Nov/1994:08:49:37 +0000`. The time is always given in UTC.
+///
+/// Times before 1970 are clamped to the Unix epoch.
+pub fn format_clf_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+
+    let secs_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+    )
+}
+
+/// Parse an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+///
+/// The obsolete RFC 850 and asctime formats aren't supported, since no
+/// client in use today sends them.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let mut parts = value.trim().split(' ');
+
+    // The day name is redundant, so it only has to look like one.
+    let day_name = parts.next()?.strip_suffix(',')?;
+    if !DAYS.contains(&day_name) {
+        return None;
+    }
+
+    let day: u32 = parse_digits(parts.next()?, 2)?;
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month_name)? as u32 + 1;
+    let year: i64 = parse_digits(parts.next()?, 4)?.into();
+
+    let mut clock = parts.next()?.split(':');
+    let hour: u64 = parse_digits(clock.next()?, 2)?.into();
+    let minute: u64 = parse_digits(clock.next()?, 2)?.into();
+    let second: u64 = parse_digits(clock.next()?, 2)?.into();
+
+    if parts.next()? != "GMT" || parts.next().is_some() || clock.next().is_some() {
+        return None;
+    }
+    if day == 0 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Parse a number with exactly `width` digits.
+fn parse_digits(value: &str, width: usize) -> Option<u32> {
+    if value.len() != width || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Convert a number of days since 1970-01-01 into a `(year, month, day)`
+/// date, using Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The inverse of [`civil_from_days`].
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn formats_and_parses_imf_fixdates() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(format_http_date(at(784_111_777, 900)), date);
+        assert_eq!(parse_http_date(date), Some(at(784_111_777, 0)));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 31 Nov 1994 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn the_current_date_is_formatted_once_a_second() {
+        let first = with_http_date_at(at(784_111_777, 0), str::to_string);
+        assert_eq!(first, "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        // Within the same second the cached string is handed out as is,
+        // without being formatted again.
+        CURRENT.with(|current| current.borrow_mut().1 = "cached".to_string());
+        let same_second = with_http_date_at(at(784_111_777, 999), str::to_string);
+        assert_eq!(same_second, "cached");
+
+        let next_second = with_http_date_at(at(784_111_778, 0), str::to_string);
+        assert_eq!(next_second, "Sun, 06 Nov 1994 08:49:38 GMT");
+    }
+
+    #[test]
+    fn the_current_date_is_the_time_now() {
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let date = with_current_http_date(parse_http_date).unwrap();
+        assert!(date >= before && date <= SystemTime::now());
+    }
+}
@@ -0,0 +1,120 @@
+//! Errors a handler can answer with instead of a response.
+
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    error::Error,
+    fmt,
+};
+
+use crate::response::{reason_phrase, Response};
+
+/// A failed request, with the status to answer it with and the error
+/// behind it.
+///
+/// Any error converts into a `HandlerError` with status `500`, so a
+/// handler returning `Result<Response, HandlerError>` can collect its
+/// failures with `?`, and the router turns the error into a response:
+///
+/// ```no_run
+/// use std::fs;
+///
+/// use rust_server::{HandlerError, Request, Response, Router};
+///
+/// fn report(_request: &Request) -> Result<Response, HandlerError> {
+///     let text = fs::read_to_string("report.txt")?;
+///     Ok(Response::text(200, text))
+/// }
+///
+/// let router = Router::new().get("/report", |request, _| report(request));
+/// ```
+///
+/// The response only says what the status says, e.g. `500 Internal Server
+/// Error`. With [`ServerConfig::verbose_errors`] on, the server adds the
+/// error, its sources and, when `RUST_BACKTRACE` is set, a backtrace of
+/// where the `HandlerError` was made. Either way the server logs them.
+///
+/// [`ServerConfig::verbose_errors`]: crate::server::ServerConfig::verbose_errors
+pub struct HandlerError {
+    status: u16,
+    error: Box<dyn Error + Send + Sync>,
+    backtrace: Backtrace,
+}
+
+impl HandlerError {
+    /// Create an error answered with `status`, from an error or a message.
+    pub fn new(status: u16, error: impl Into<Box<dyn Error + Send + Sync>>) -> HandlerError {
+        HandlerError {
+            status,
+            error: error.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    /// Answer with `status` instead, e.g. `404` for a record that doesn't
+    /// exist.
+    pub fn with_status(mut self, status: u16) -> HandlerError {
+        self.status = status;
+        self
+    }
+
+    /// The status the request is answered with.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// The error behind the failure.
+    pub fn error(&self) -> &(dyn Error + Send + Sync + 'static) {
+        &*self.error
+    }
+
+    /// The error and its sources, one per line, followed by the
+    /// backtrace if one was captured.
+    pub(crate) fn detail(&self) -> String {
+        let mut detail = self.error.to_string();
+        let mut source = self.error.source();
+        while let Some(error) = source {
+            detail.push_str(&format!("\ncaused by: {error}"));
+            source = error.source();
+        }
+        if self.backtrace.status() == BacktraceStatus::Captured {
+            detail.push_str(&format!("\n\n{}", self.backtrace));
+        }
+        detail
+    }
+}
+
+impl<E: Error + Send + Sync + 'static> From<E> for HandlerError {
+    fn from(error: E) -> HandlerError {
+        HandlerError::new(500, error)
+    }
+}
+
+impl From<HandlerError> for Response {
+    fn from(error: HandlerError) -> Response {
+        let status = error.status;
+        let mut response = Response::text(status, format!("{status} {}", reason_phrase(status)));
+        response.set_error(error);
+        response
+    }
+}
+
+impl fmt::Debug for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandlerError")
+            .field("status", &self.status)
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.status,
+            reason_phrase(self.status),
+            self.error
+        )
+    }
+}
@@ -0,0 +1,29 @@
+//! Custom pages for error responses, registered with
+//! [`Server::error_page`](crate::server::Server::error_page).
+
+use std::collections::HashMap;
+
+use crate::{request::Request, response::Response};
+
+/// A handler building the page for an error status.
+pub(crate) type ErrorPage = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// The registered error pages, keyed by status.
+pub(crate) type ErrorPages = HashMap<u16, ErrorPage>;
+
+/// Replace `response` with the page `page` builds for `request`.
+///
+/// The page keeps the status of the response it replaces, and the headers
+/// it doesn't set itself, like the `Allow` of a `405` or the `Retry-After`
+/// of a `503`. Headers describing the old body are dropped with it.
+pub(crate) fn render(page: &ErrorPage, request: &Request, response: &Response) -> Response {
+    let mut rendered = page(request);
+    rendered.set_status(response.status());
+    for (name, value) in response.headers() {
+        let describes_body = name.to_ascii_lowercase().starts_with("content-");
+        if !describes_body && rendered.header(name).is_none() {
+            rendered.add_header(name, value);
+        }
+    }
+    rendered
+}
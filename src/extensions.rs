@@ -0,0 +1,204 @@
+//! A map of values keyed by their type, carried along with a request.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+};
+
+/// Values attached to a [`Request`](crate::request::Request), at most one
+/// of each type.
+///
+/// This is how a wrapper hands what it found out to the handler it wraps,
+/// e.g. the user an authentication wrapper logged in, without either side
+/// having to know about the other's types. Keying by type means values of
+/// private types can't be read or clobbered by anyone else.
+///
+/// Requests can be cloned, so the values have to be `Clone` too.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn AnyClone>>,
+}
+
+impl Extensions {
+    /// Create an empty map.
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Insert a value, returning the value of the same type it replaces.
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.into_any().downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// The value of type `T`, if there is one.
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any().downcast_ref())
+    }
+
+    /// The value of type `T` for changing in place, if there is one.
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any_mut().downcast_mut())
+    }
+
+    /// Remove the value of type `T` and return it, if there is one.
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.into_any().downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// The number of values in the map.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether the map has no values.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl Clone for Extensions {
+    fn clone(&self) -> Extensions {
+        Extensions {
+            map: self
+                .map
+                .iter()
+                .map(|(&id, value)| (id, (**value).clone_box()))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for Extensions {
+    /// The values are type-erased, so only how many there are is shown.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A value that can be downcast and cloned behind a trait object.
+trait AnyClone: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn AnyClone>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Clone + Send + Sync + 'static> AnyClone for T {
+    fn clone_box(&self) -> Box<dyn AnyClone> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        request::{Method, Request},
+        response::Response,
+        router::{Router, Service},
+        serve_connection,
+    };
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct User(String);
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct RequestId(u64);
+
+    #[test]
+    fn holds_one_value_of_each_type() {
+        let mut extensions = Extensions::new();
+        assert!(extensions.is_empty());
+        assert_eq!(extensions.insert(User("ferris".into())), None);
+        assert_eq!(extensions.insert(RequestId(7)), None);
+        assert_eq!(extensions.len(), 2);
+
+        assert_eq!(extensions.insert(RequestId(8)), Some(RequestId(7)));
+        assert_eq!(extensions.get::<RequestId>(), Some(&RequestId(8)));
+        assert_eq!(extensions.get::<String>(), None);
+
+        extensions.get_mut::<User>().unwrap().0.push('2');
+        let copy = extensions.clone();
+        assert_eq!(extensions.remove::<User>(), Some(User("ferris2".into())));
+        assert_eq!(extensions.remove::<User>(), None);
+        assert_eq!(copy.get::<User>(), Some(&User("ferris2".into())));
+        assert_eq!(format!("{copy:?}"), "Extensions { len: 2, .. }");
+    }
+
+    /// A layer around a service that logs in whoever sends a `Token`.
+    struct Authenticate<S>(S);
+
+    impl<S: Service> Service for Authenticate<S> {
+        fn handle(&self, request: &mut Request) -> Response {
+            if let Some(token) = request.header("Token") {
+                let user = User(token.to_string());
+                request.extensions_mut().insert(user);
+            }
+            self.0.handle(request)
+        }
+    }
+
+    fn whoami(request: &Request, _: &()) -> Response {
+        match request.extensions().get::<User>() {
+            Some(User(name)) => Response::text(200, format!("hello {name}")),
+            None => Response::text(200, "hello stranger"),
+        }
+    }
+
+    #[test]
+    fn handlers_read_what_a_layer_inserted() {
+        let service = Authenticate(Router::new().get("/me", whoami));
+        let input = "GET /me HTTP/1.1\r\nToken: ferris\r\n\r\n\
+                     GET /me HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &service);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\r\n\r\nhello ferrisHTTP/1.1 "));
+        // Every request starts out without extensions.
+        assert!(output.ends_with("\r\n\r\nhello stranger"));
+    }
+
+    #[test]
+    fn handlers_read_what_a_hook_inserted() {
+        let router = Router::new()
+            .before(|request, _| {
+                request.extensions_mut().insert(User("hooked".into()));
+                None
+            })
+            .get("/me", whoami)
+            // Handlers with a timeout get a copy of the request.
+            .route_with_timeout(Method::Get, "/slow", Duration::from_secs(5), whoami);
+
+        let response = router.handle(&mut Request::new(Method::Get, "/me"));
+        assert_eq!(response.body().as_bytes(), Some(&b"hello hooked"[..]));
+        let response = router.handle(&mut Request::new(Method::Get, "/slow"));
+        assert_eq!(response.body().as_bytes(), Some(&b"hello hooked"[..]));
+    }
+}
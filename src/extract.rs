@@ -0,0 +1,413 @@
+//! Reading the query string and the route parameters of a request into a
+//! type of your own, for [`Request::query_as`] and [`Request::params_as`].
+//!
+//! A type says how it is read by implementing [`FromParams`], looking up
+//! each field with [`Params::get`]. Fields of an `Option` type may be left
+//! out, the others are required:
+//!
+//! ```
+//! use rust_server::{ExtractError, FromParams, Method, Params, Request};
+//!
+//! #[derive(Debug, PartialEq)]
+//! struct Search {
+//!     q: String,
+//!     page: Option<u32>,
+//! }
+//!
+//! impl FromParams for Search {
+//!     fn from_params(params: &Params) -> Result<Search, ExtractError> {
+//!         Ok(Search {
+//!             q: params.get("q")?,
+//!             page: params.get("page")?,
+//!         })
+//!     }
+//! }
+//!
+//! let request = Request::new(Method::Get, "/search?q=rust+server&page=2");
+//! let search: Search = request.query_as().unwrap();
+//! assert_eq!(search, Search { q: "rust server".to_string(), page: Some(2) });
+//!
+//! let request = Request::new(Method::Get, "/search?q=caf%C3%A9");
+//! let search: Search = request.query_as().unwrap();
+//! assert_eq!(search, Search { q: "café".to_string(), page: None });
+//!
+//! let request = Request::new(Method::Get, "/search?page=2");
+//! let error = request.query_as::<Search>().unwrap_err();
+//! assert_eq!(error, ExtractError::Missing("q".to_string()));
+//!
+//! let request = Request::new(Method::Get, "/search?q=rust&page=two");
+//! let error = request.query_as::<Search>().unwrap_err();
+//! assert_eq!(error.to_string(), "page should be a non-negative integer, not \"two\"");
+//! ```
+//!
+//! An [`ExtractError`] answers with `400 Bad Request` and what was wrong,
+//! so handlers can read their input with `?`:
+//!
+//! ```
+//! # use rust_server::{ExtractError, FromParams, Params};
+//! # struct Search { q: String, page: Option<u32> }
+//! # impl FromParams for Search {
+//! #     fn from_params(params: &Params) -> Result<Search, ExtractError> {
+//! #         Ok(Search { q: params.get("q")?, page: params.get("page")? })
+//! #     }
+//! # }
+//! use rust_server::{serve_connection, Router};
+//!
+//! let router = Router::new().get("/search", |request, _| {
+//!     let search: Search = request.query_as()?;
+//!     Ok::<_, ExtractError>(format!("{} on page {}", search.q, search.page.unwrap_or(1)))
+//! });
+//!
+//! let input = "GET /search?q=rust HTTP/1.1\r\n\r\n\
+//!              GET /search HTTP/1.1\r\nConnection: close\r\n\r\n";
+//! let mut output = Vec::new();
+//! serve_connection(input.as_bytes(), &mut output, &router);
+//!
+//! let output = String::from_utf8(output).unwrap();
+//! assert!(output.contains("\r\n\r\nrust on page 1"));
+//! assert!(output.contains("HTTP/1.1 400 Bad Request"));
+//! assert!(output.ends_with("\r\n\r\nmissing parameter q"));
+//! ```
+//!
+//! With the `serde` feature, any type serde can deserialize implements
+//! [`FromParams`], read with `serde_urlencoded` like an HTML form:
+//!
+//! ```
+//! # #[cfg(feature = "serde")]
+//! # {
+//! use rust_server::{Method, Request};
+//! use serde::Deserialize;
+//!
+//! #[derive(Debug, PartialEq, Deserialize)]
+//! struct Search {
+//!     q: String,
+//!     page: Option<u32>,
+//! }
+//!
+//! let request = Request::new(Method::Get, "/search?q=rust+server&page=2");
+//! let search: Search = request.query_as().unwrap();
+//! assert_eq!(search, Search { q: "rust server".to_string(), page: Some(2) });
+//!
+//! let request = Request::new(Method::Get, "/search?page=2");
+//! let error = request.query_as::<Search>().unwrap_err();
+//! assert_eq!(error.to_string(), "missing field `q`");
+//! # }
+//! ```
+
+use std::fmt;
+
+use crate::{
+    response::{IntoResponse, Response},
+    uri,
+};
+
+/// A type that can be read from the query string or the route parameters
+/// of a request.
+pub trait FromParams: Sized {
+    /// Read `Self` from `params`.
+    fn from_params(params: &Params) -> Result<Self, ExtractError>;
+}
+
+/// A type a single parameter can be read as.
+///
+/// It is implemented for `String`, `bool`, `char`, the numbers, and an
+/// `Option` of any of them for parameters that may be left out.
+pub trait FromValue: Sized {
+    /// What a valid value looks like, for error messages, e.g. `"an
+    /// integer"`.
+    const EXPECTED: &'static str;
+
+    /// Parse `value`, or return `None` if it isn't valid.
+    fn from_value(value: &str) -> Option<Self>;
+
+    /// The value of a parameter that isn't there at all, or `None` if it
+    /// is required.
+    fn missing() -> Option<Self> {
+        None
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    const EXPECTED: &'static str = T::EXPECTED;
+
+    fn from_value(value: &str) -> Option<Option<T>> {
+        T::from_value(value).map(Some)
+    }
+
+    fn missing() -> Option<Option<T>> {
+        Some(None)
+    }
+}
+
+impl FromValue for String {
+    const EXPECTED: &'static str = "text";
+
+    fn from_value(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+}
+
+macro_rules! from_str_values {
+    ($expected:literal: $($ty:ty),*) => {
+        $(
+            impl FromValue for $ty {
+                const EXPECTED: &'static str = $expected;
+
+                fn from_value(value: &str) -> Option<$ty> {
+                    value.parse().ok()
+                }
+            }
+        )*
+    };
+}
+
+from_str_values!("true or false": bool);
+from_str_values!("a single character": char);
+from_str_values!("an integer": i8, i16, i32, i64, i128, isize);
+from_str_values!("a non-negative integer": u8, u16, u32, u64, u128, usize);
+from_str_values!("a number": f32, f64);
+
+/// The reasons a request's parameters could not be read into a type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtractError {
+    /// A required parameter isn't there.
+    Missing(String),
+    /// A parameter has a value that isn't of the expected kind.
+    Invalid {
+        name: String,
+        value: String,
+        expected: &'static str,
+    },
+    /// A type read through serde, with the `serde` feature, rejected the
+    /// parameters, for the reason given.
+    Deserialize(String),
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::Missing(name) => write!(f, "missing parameter {name}"),
+            ExtractError::Invalid {
+                name,
+                value,
+                expected,
+            } => write!(f, "{name} should be {expected}, not {value:?}"),
+            ExtractError::Deserialize(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+/// A `400 Bad Request` saying what was wrong.
+impl IntoResponse for ExtractError {
+    fn into_response(self) -> Response {
+        Response::text(400, self.to_string())
+    }
+}
+
+/// Read the parameters like an HTML form. The pairs are already decoded,
+/// so they are encoded again for `serde_urlencoded` to read.
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> FromParams for T {
+    fn from_params(params: &Params) -> Result<T, ExtractError> {
+        let form = serde_urlencoded::to_string(&params.pairs)
+            .map_err(|err| ExtractError::Deserialize(err.to_string()))?;
+        serde_urlencoded::from_str(&form).map_err(|err| ExtractError::Deserialize(err.to_string()))
+    }
+}
+
+/// Named values, like the pairs of a query string.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    /// Decode a query string like `q=rust+server&page=2`.
+    ///
+    /// Both names and values are percent-decoded, with `+` standing for a
+    /// space. A pair without `=` has an empty value.
+    pub fn from_query(query: &str) -> Params {
+        let pairs = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode(name), decode(value))
+            })
+            .collect();
+        Params { pairs }
+    }
+
+    /// The value of the first parameter called `name`.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The value of the parameter `name`, read as a `T`.
+    pub fn get<T: FromValue>(&self, name: &str) -> Result<T, ExtractError> {
+        match self.value(name) {
+            Some(value) => T::from_value(value).ok_or_else(|| ExtractError::Invalid {
+                name: name.to_string(),
+                value: value.to_string(),
+                expected: T::EXPECTED,
+            }),
+            None => T::missing().ok_or_else(|| ExtractError::Missing(name.to_string())),
+        }
+    }
+
+    /// All the parameters, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl From<Vec<(String, String)>> for Params {
+    fn from(pairs: Vec<(String, String)>) -> Params {
+        Params { pairs }
+    }
+}
+
+/// Percent-decode a part of a query string. Escapes that aren't valid are
+/// kept as they are, and bytes that aren't UTF-8 are replaced.
+fn decode(part: &str) -> String {
+    // A `+` stands for a space, but an escaped one is a plus.
+    String::from_utf8_lossy(&uri::percent_decode(&part.replace('+', " "))).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Method, Request};
+
+    #[derive(Debug, PartialEq)]
+    struct Search {
+        q: String,
+        page: Option<u32>,
+        exact: bool,
+    }
+
+    impl FromParams for Search {
+        fn from_params(params: &Params) -> Result<Search, ExtractError> {
+            Ok(Search {
+                q: params.get("q")?,
+                page: params.get("page")?,
+                exact: params.get::<Option<bool>>("exact")?.unwrap_or(false),
+            })
+        }
+    }
+
+    fn query<T: FromParams>(target: &str) -> Result<T, ExtractError> {
+        Request::new(Method::Get, target).query_as()
+    }
+
+    #[test]
+    fn reads_required_and_optional_fields() {
+        assert_eq!(
+            query::<Search>("/?q=a%2Bb+c&exact=true"),
+            Ok(Search {
+                q: "a+b c".to_string(),
+                page: None,
+                exact: true,
+            })
+        );
+        assert_eq!(
+            query::<Search>("/?page=3&q=x&q=y").map(|search| (search.q, search.page)),
+            Ok(("x".to_string(), Some(3)))
+        );
+        assert_eq!(
+            query::<Search>("/"),
+            Err(ExtractError::Missing("q".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_values_of_the_wrong_kind() {
+        assert_eq!(
+            query::<Search>("/?q=x&page=-1"),
+            Err(ExtractError::Invalid {
+                name: "page".to_string(),
+                value: "-1".to_string(),
+                expected: "a non-negative integer",
+            })
+        );
+        let error = query::<Search>("/?q=x&exact=yes").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "exact should be true or false, not \"yes\""
+        );
+        assert_eq!(error.into_response().status(), 400);
+    }
+
+    #[test]
+    fn pairs_without_a_value_are_empty() {
+        let params = Params::from_query("a&b=&=c&&d=1=2");
+        let pairs: Vec<_> = params.iter().collect();
+        assert_eq!(pairs, [("a", ""), ("b", ""), ("", "c"), ("d", "1=2")]);
+    }
+
+    #[cfg(feature = "serde")]
+    mod with_serde {
+        use serde::Deserialize;
+
+        use super::*;
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Filter {
+            tag: String,
+            limit: Option<usize>,
+        }
+
+        #[test]
+        fn deserializes_the_query() {
+            assert_eq!(
+                query::<Filter>("/?tag=caf%C3%A9+au+lait&limit=10"),
+                Ok(Filter {
+                    tag: "café au lait".to_string(),
+                    limit: Some(10),
+                })
+            );
+            assert_eq!(
+                query::<Filter>("/?tag=a%26b%3Dc"),
+                Ok(Filter {
+                    tag: "a&b=c".to_string(),
+                    limit: None,
+                })
+            );
+        }
+
+        #[test]
+        fn reports_missing_and_mistyped_fields() {
+            assert_eq!(
+                query::<Filter>("/?limit=10"),
+                Err(ExtractError::Deserialize("missing field `tag`".to_string()))
+            );
+            assert!(matches!(
+                query::<Filter>("/?tag=x&limit=ten"),
+                Err(ExtractError::Deserialize(_))
+            ));
+        }
+
+        #[test]
+        fn deserializes_route_parameters() {
+            let mut request = Request::new(Method::Get, "/tags/rust");
+            request.set_params(vec![
+                ("tag".to_string(), "rust".to_string()),
+                ("limit".to_string(), "5".to_string()),
+            ]);
+            assert_eq!(
+                request.params_as::<Filter>(),
+                Ok(Filter {
+                    tag: "rust".to_string(),
+                    limit: Some(5),
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Finding the client behind a reverse proxy, from the `X-Forwarded-For`
+//! and `X-Forwarded-Proto` headers the proxy adds.
+//!
+//! Anyone can send those headers, so they only count when the connection
+//! comes from a proxy the server was told to trust. Every proxy on the way
+//! appends the address it got the request from to `X-Forwarded-For`, so
+//! the list is read from the right, skipping the trusted proxies, and the
+//! first address that isn't one is the client. Entries further left were
+//! added by someone the server doesn't know, and may be made up.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::request::Request;
+
+/// Whose `X-Forwarded-*` headers to believe.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum ProxyTrust {
+    /// Nobody's: the client is whoever is at the other end of the socket.
+    #[default]
+    None,
+    /// Any peer's, for servers only reachable through their proxies.
+    Any,
+    /// Only those of peers at these addresses.
+    Only(Vec<IpAddr>),
+}
+
+impl ProxyTrust {
+    fn trusts(&self, ip: Option<IpAddr>) -> bool {
+        match self {
+            ProxyTrust::None => false,
+            ProxyTrust::Any => true,
+            ProxyTrust::Only(proxies) => ip.is_some_and(|ip| proxies.contains(&ip)),
+        }
+    }
+}
+
+/// Record on `request` that it came from `peer`, and the client and
+/// scheme it was made with, as far as `trust` allows telling.
+pub(crate) fn resolve(request: &mut Request, peer: Option<SocketAddr>, trust: &ProxyTrust) {
+    let mut client = peer.map(|peer| peer.ip());
+    let mut scheme = "http".to_string();
+
+    if trust.trusts(client) {
+        if let Some(forwarded) = request.header("X-Forwarded-For") {
+            let hops: Vec<Option<IpAddr>> = forwarded
+                .split(',')
+                .map(|hop| hop.trim().parse().ok())
+                .collect();
+            let found = match trust {
+                // A chain made only of trusted proxies started at the first
+                // of them.
+                ProxyTrust::Only(_) => hops
+                    .iter()
+                    .rev()
+                    .find(|hop| !trust.trusts(**hop))
+                    .or(hops.first()),
+                // Without a list of proxies there's no telling them apart,
+                // so only the one the server talks to is believed, and the
+                // client is the address it got the request from.
+                _ => hops.last(),
+            };
+            client = found.copied().flatten().or(client);
+        }
+        if let Some(proto) = request.header("X-Forwarded-Proto") {
+            let proto = proto.split(',').next().unwrap_or("").trim();
+            if proto.eq_ignore_ascii_case("http") || proto.eq_ignore_ascii_case("https") {
+                scheme = proto.to_ascii_lowercase();
+            }
+        }
+    }
+
+    request.set_client(peer, client, scheme);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+
+    fn client(forwarded_for: &str, peer: &str, trust: &ProxyTrust) -> Option<IpAddr> {
+        let mut request =
+            Request::new(Method::Get, "/").with_header("X-Forwarded-For", forwarded_for);
+        resolve(&mut request, Some(peer.parse().unwrap()), trust);
+        request.client_ip()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn any_proxy_believes_only_the_last_hop() {
+        let any = ProxyTrust::Any;
+        let forged = "198.51.100.1, 203.0.113.7";
+        assert_eq!(client(forged, "10.0.0.1:80", &any), ip("203.0.113.7"));
+        assert_eq!(
+            client("203.0.113.7", "10.0.0.1:80", &any),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn listed_proxies_are_skipped_from_the_right() {
+        let only = ProxyTrust::Only(vec![
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+        ]);
+        let chain = "198.51.100.1, 203.0.113.7, 10.0.0.2";
+        assert_eq!(client(chain, "10.0.0.1:80", &only), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn a_chain_of_trusted_proxies_falls_back_to_the_first() {
+        let only = ProxyTrust::Only(vec![
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+        ]);
+        assert_eq!(
+            client("10.0.0.2, 10.0.0.1", "10.0.0.1:80", &only),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let only = ProxyTrust::Only(vec!["10.0.0.1".parse().unwrap()]);
+        assert_eq!(client("203.0.113.7", "10.0.0.9:80", &only), ip("10.0.0.9"));
+        assert_eq!(
+            client("203.0.113.7", "10.0.0.9:80", &ProxyTrust::None),
+            ip("10.0.0.9")
+        );
+    }
+}
@@ -0,0 +1,1042 @@
+//! Compressing response bodies with gzip as they are sent, and
+//! decompressing request bodies as they are read.
+//!
+//! [`GzipEncoder`] compresses whatever a reader produces a chunk at a
+//! time, so a response is compressed while it is being written, and only
+//! one chunk of it is ever held in memory along with the window of
+//! recent bytes matches are looked for in. [`Response::gzip`] puts one in
+//! front of a response's body when the client accepts gzip.
+//!
+//! The encoder is a plain deflate implementation: LZ77 matching over a
+//! 32 KiB window with hash chains, and the fixed Huffman codes of the
+//! deflate format. It compresses text to a fraction of its size, though
+//! less tightly than zlib, which also builds codes fitted to the data.
+//!
+//! The other way round, [`GzipDecoder`] decompresses request bodies sent
+//! with `Content-Encoding: gzip` or `deflate` as handlers read them; see
+//! [`ServerConfig::max_decompressed_body_bytes`](crate::ServerConfig::max_decompressed_body_bytes).
+
+use std::{
+    fs::File,
+    io::{self, Cursor, Read},
+    mem,
+};
+
+use crate::{
+    negotiate,
+    request::Request,
+    response::{Body, Response},
+};
+
+/// How far back matches may be found, the most deflate allows.
+const WINDOW: usize = 32 * 1024;
+
+/// How much input is compressed into each deflate block.
+const CHUNK: usize = 64 * 1024;
+
+const HASH_BITS: u32 = 15;
+
+/// How many earlier positions are tried for each match. More finds
+/// longer matches, but costs time on repetitive input.
+const MAX_CHAIN: usize = 64;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Bodies known to be shorter than this aren't worth compressing.
+const MIN_SIZE: u64 = 1024;
+
+/// The smallest length each length code stands for, and the number of
+/// extra bits following the code.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// The same for distances.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// The symbol ending a deflate block.
+const END_OF_BLOCK: u16 = 256;
+
+/// A reader producing the gzip compressed form of what `R` produces.
+///
+/// ```
+/// use std::io::Read;
+///
+/// use rust_server::gzip::GzipEncoder;
+///
+/// let text = "all work and no play ".repeat(1000);
+/// let mut compressed = Vec::new();
+/// GzipEncoder::new(text.as_bytes())
+///     .read_to_end(&mut compressed)
+///     .unwrap();
+/// assert!(compressed.len() < text.len() / 10);
+/// ```
+pub struct GzipEncoder<R> {
+    inner: R,
+    /// The last [`WINDOW`] bytes compressed so far, followed by the chunk
+    /// being compressed.
+    data: Vec<u8>,
+    /// The position in the whole input of `data[0]`.
+    base: usize,
+    /// For every hash of three bytes, the position after the last one
+    /// seen with that hash, or zero.
+    head: Vec<usize>,
+    /// For every position in the window, the value `head` had before it
+    /// was inserted, chaining positions with the same hash.
+    prev: Vec<usize>,
+    bits: BitWriter,
+    /// How much of `bits.out` has been read.
+    pos: usize,
+    crc: u32,
+    /// The length of the input, modulo 2^32 as gzip stores it.
+    size: u32,
+    done: bool,
+}
+
+impl<R: Read> GzipEncoder<R> {
+    /// Compress what `inner` produces.
+    pub fn new(inner: R) -> GzipEncoder<R> {
+        let mut bits = BitWriter::default();
+        // The magic number, deflate, no flags, no modification time, no
+        // extra flags, and an unknown operating system.
+        bits.out
+            .extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+
+        GzipEncoder {
+            inner,
+            data: Vec::new(),
+            base: 0,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW],
+            bits,
+            pos: 0,
+            crc: !0,
+            size: 0,
+            done: false,
+        }
+    }
+
+    /// Compress the next chunk of input into `bits`, or end the stream if
+    /// there is none.
+    fn compress_next(&mut self) -> io::Result<()> {
+        let start = self.data.len();
+        self.data.resize(start + CHUNK, 0);
+        let mut end = start;
+        while end < self.data.len() {
+            match self.inner.read(&mut self.data[end..]) {
+                Ok(0) => break,
+                Ok(read) => end += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.data.truncate(start);
+                    return Err(err);
+                }
+            }
+        }
+        self.data.truncate(end);
+
+        if end == start {
+            // A last, empty block, then the checksum and the length.
+            self.bits.write(1, 1);
+            self.bits.write(1, 2);
+            self.bits.symbol(END_OF_BLOCK);
+            self.bits.align();
+            let crc = !self.crc;
+            self.bits.out.extend_from_slice(&crc.to_le_bytes());
+            self.bits.out.extend_from_slice(&self.size.to_le_bytes());
+            self.done = true;
+            return Ok(());
+        }
+
+        self.crc = crc32(self.crc, &self.data[start..end]);
+        self.size = self.size.wrapping_add((end - start) as u32);
+
+        // A block that isn't the last one, with the fixed codes.
+        let before = self.bits.mark();
+        self.bits.write(0, 1);
+        self.bits.write(1, 2);
+        let mut i = start;
+        while i < end {
+            match self.longest_match(i, end) {
+                Some((length, distance)) => {
+                    self.bits.length(length);
+                    self.bits.distance(distance);
+                    for position in i..i + length {
+                        self.insert(position, end);
+                    }
+                    i += length;
+                }
+                None => {
+                    self.bits.symbol(u16::from(self.data[i]));
+                    self.insert(i, end);
+                    i += 1;
+                }
+            }
+        }
+        self.bits.symbol(END_OF_BLOCK);
+
+        // Data that doesn't compress, like images or random bytes, comes
+        // out larger with the fixed codes. It is stored as it is instead.
+        if self.bits.out.len() - before.0 > end - start {
+            self.bits.reset(before);
+            for block in self.data[start..end].chunks(usize::from(u16::MAX)) {
+                self.bits.stored(block);
+            }
+        }
+
+        // Only the window is needed for the next chunk.
+        if self.data.len() > WINDOW {
+            let dropped = self.data.len() - WINDOW;
+            self.data.drain(..dropped);
+            self.base += dropped;
+        }
+        Ok(())
+    }
+
+    fn hash(&self, i: usize) -> usize {
+        let bytes = &self.data[i..i + MIN_MATCH];
+        let hash =
+            (usize::from(bytes[0]) << 10) ^ (usize::from(bytes[1]) << 5) ^ usize::from(bytes[2]);
+        hash & ((1 << HASH_BITS) - 1)
+    }
+
+    /// Remember position `i` for later matches.
+    fn insert(&mut self, i: usize, end: usize) {
+        if i + MIN_MATCH > end {
+            return;
+        }
+        let hash = self.hash(i);
+        let position = self.base + i;
+        self.prev[position % WINDOW] = self.head[hash];
+        self.head[hash] = position + 1;
+    }
+
+    /// The longest earlier match for the bytes at `i`, as its length and
+    /// distance, if there is one of at least [`MIN_MATCH`] bytes.
+    fn longest_match(&self, i: usize, end: usize) -> Option<(usize, usize)> {
+        if i + MIN_MATCH > end {
+            return None;
+        }
+        let max_length = MAX_MATCH.min(end - i);
+        let position = self.base + i;
+
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(i)];
+        for _ in 0..MAX_CHAIN {
+            // Positions are stored plus one, so zero means there are no
+            // more, and anything before the window is gone.
+            let Some(earlier) = candidate.checked_sub(1) else {
+                break;
+            };
+            if earlier < self.base || position - earlier > WINDOW {
+                break;
+            }
+
+            let j = earlier - self.base;
+            let length = self.data[i..i + max_length]
+                .iter()
+                .zip(&self.data[j..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length >= MIN_MATCH && best.is_none_or(|(best, _)| length > best) {
+                best = Some((length, i - j));
+                if length == max_length {
+                    break;
+                }
+            }
+
+            // A slot that has been reused since points forward, which
+            // ends the chain.
+            let next = self.prev[earlier % WINDOW];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        best
+    }
+}
+
+impl<R: Read> Read for GzipEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.bits.out.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.bits.out.clear();
+            self.pos = 0;
+            self.compress_next()?;
+        }
+
+        let out = &self.bits.out[self.pos..];
+        let read = out.len().min(buf.len());
+        buf[..read].copy_from_slice(&out[..read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+/// Packs codes into bytes, starting at the least significant bit as
+/// deflate does.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    pending: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    /// Write the low `count` bits of `value`.
+    fn write(&mut self, value: u32, count: u32) {
+        self.pending |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.pending as u8);
+            self.pending >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Write a Huffman code. Those are packed starting at their most
+    /// significant bit.
+    fn code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    /// Write a literal byte, or the end of a block, with its fixed code.
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let index = LENGTH_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= length)
+            .expect("a match is at least three bytes long");
+        self.symbol(257 + index as u16);
+        let extra = length - usize::from(LENGTH_BASE[index]);
+        self.write(extra as u32, u32::from(LENGTH_EXTRA[index]));
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let index = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= distance)
+            .expect("a distance is at least one");
+        self.code(index as u32, 5);
+        let extra = distance - usize::from(DISTANCE_BASE[index]);
+        self.write(extra as u32, u32::from(DISTANCE_EXTRA[index]));
+    }
+
+    /// Write a block holding `bytes` as they are, which mustn't be more
+    /// than `u16::MAX` of them.
+    fn stored(&mut self, bytes: &[u8]) {
+        self.write(0, 1);
+        self.write(0, 2);
+        self.align();
+        let len = bytes.len() as u16;
+        self.out.extend_from_slice(&len.to_le_bytes());
+        self.out.extend_from_slice(&(!len).to_le_bytes());
+        self.out.extend_from_slice(bytes);
+    }
+
+    /// Where the writer is, to go back to with [`BitWriter::reset`].
+    fn mark(&self) -> (usize, u64, u32) {
+        (self.out.len(), self.pending, self.count)
+    }
+
+    /// Undo everything written since `mark`.
+    fn reset(&mut self, (len, pending, count): (usize, u64, u32)) {
+        self.out.truncate(len);
+        self.pending = pending;
+        self.count = count;
+    }
+
+    /// Pad the last byte with zeros.
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+}
+
+/// A reader producing what the gzip or zlib compressed data `R` produces
+/// decompresses to, for request bodies sent with `Content-Encoding: gzip`
+/// or `deflate`.
+///
+/// Compressed data can expand to far more than it takes up, so the output
+/// can be limited. Reading past the limit fails with
+/// [`FileTooLarge`](io::ErrorKind::FileTooLarge), and corrupt data with
+/// [`InvalidData`](io::ErrorKind::InvalidData).
+///
+/// ```
+/// use std::io::{self, Read};
+///
+/// use rust_server::gzip::{GzipDecoder, GzipEncoder};
+///
+/// let text = "all work and no play ".repeat(1000);
+/// let mut compressed = Vec::new();
+/// GzipEncoder::new(text.as_bytes())
+///     .read_to_end(&mut compressed)
+///     .unwrap();
+///
+/// let mut decompressed = String::new();
+/// GzipDecoder::new(&compressed[..])
+///     .read_to_string(&mut decompressed)
+///     .unwrap();
+/// assert_eq!(decompressed, text);
+///
+/// let err = GzipDecoder::new(&compressed[..])
+///     .limit(1000)
+///     .read_to_end(&mut Vec::new())
+///     .unwrap_err();
+/// assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+/// ```
+pub struct GzipDecoder<R> {
+    bits: BitReader<R>,
+    wrapper: Wrapper,
+    state: State,
+    /// Whether the block being decoded is the last one.
+    last: bool,
+    /// The last [`WINDOW`] bytes read, followed by those not read yet.
+    out: Vec<u8>,
+    /// How much of `out` has been read.
+    pos: usize,
+    /// The number of bytes decompressed so far, and the most allowed.
+    size: u64,
+    limit: u64,
+    /// The running checksum of the output, CRC-32 kept inverted for gzip
+    /// and Adler-32 for zlib.
+    check: u32,
+}
+
+/// The format around the deflate data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wrapper {
+    Gzip,
+    Zlib,
+}
+
+enum State {
+    Header,
+    /// Between blocks.
+    Block,
+    /// Inside a stored block, with this many bytes left.
+    Stored(usize),
+    /// Inside a compressed block, with its codes.
+    Huffman(Box<Codes>),
+    Trailer,
+    Done,
+    /// Decoding failed, and can't go on.
+    Failed,
+}
+
+impl<R: Read> GzipDecoder<R> {
+    /// Decompress the gzip data `inner` produces.
+    pub fn new(inner: R) -> GzipDecoder<R> {
+        GzipDecoder::wrapped(inner, Wrapper::Gzip)
+    }
+
+    /// Decompress the zlib data `inner` produces, which is what HTTP calls
+    /// `deflate`.
+    pub fn zlib(inner: R) -> GzipDecoder<R> {
+        GzipDecoder::wrapped(inner, Wrapper::Zlib)
+    }
+
+    fn wrapped(inner: R, wrapper: Wrapper) -> GzipDecoder<R> {
+        GzipDecoder {
+            bits: BitReader::new(inner),
+            wrapper,
+            state: State::Header,
+            last: false,
+            out: Vec::new(),
+            pos: 0,
+            size: 0,
+            limit: u64::MAX,
+            check: match wrapper {
+                Wrapper::Gzip => !0,
+                Wrapper::Zlib => 1,
+            },
+        }
+    }
+
+    /// Fail once the output grows past `bytes`. There's no limit by
+    /// default.
+    pub fn limit(mut self, bytes: u64) -> GzipDecoder<R> {
+        self.limit = bytes;
+        self
+    }
+
+    /// Decode up to a chunk of output into `out`, or the next part of the
+    /// stream around it.
+    fn decode_next(&mut self) -> io::Result<()> {
+        let start = self.out.len();
+        self.state = match mem::replace(&mut self.state, State::Failed) {
+            State::Header => {
+                self.read_header()?;
+                State::Block
+            }
+            State::Block if self.last => State::Trailer,
+            State::Block => {
+                self.last = self.bits.bits(1)? == 1;
+                match self.bits.bits(2)? {
+                    0 => {
+                        self.bits.align();
+                        let len = self.bits.bits(16)?;
+                        if self.bits.bits(16)? != !len & 0xffff {
+                            return Err(invalid("stored block length mismatch"));
+                        }
+                        State::Stored(len as usize)
+                    }
+                    1 => State::Huffman(Box::new(Codes::fixed())),
+                    2 => State::Huffman(Box::new(Codes::read(&mut self.bits)?)),
+                    _ => return Err(invalid("invalid block type")),
+                }
+            }
+            State::Stored(left) => {
+                let len = left.min(CHUNK);
+                for _ in 0..len {
+                    let byte = self.bits.bits(8)? as u8;
+                    self.out.push(byte);
+                }
+                match left - len {
+                    0 => State::Block,
+                    left => State::Stored(left),
+                }
+            }
+            State::Huffman(codes) => {
+                if self.inflate(&codes)? {
+                    State::Block
+                } else {
+                    State::Huffman(codes)
+                }
+            }
+            State::Trailer => {
+                self.read_trailer()?;
+                State::Done
+            }
+            State::Done => State::Done,
+            State::Failed => return Err(invalid("corrupt compressed data")),
+        };
+
+        let decoded = &self.out[start..];
+        self.size += decoded.len() as u64;
+        if self.size > self.limit {
+            self.state = State::Failed;
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "compressed data expands past the limit",
+            ));
+        }
+        self.check = match self.wrapper {
+            Wrapper::Gzip => crc32(self.check, decoded),
+            Wrapper::Zlib => adler32(self.check, decoded),
+        };
+        Ok(())
+    }
+
+    /// Decode symbols with `codes` until a chunk of output is ready, or
+    /// the block ends, in which case this returns `true`.
+    fn inflate(&mut self, codes: &Codes) -> io::Result<bool> {
+        let end = self.out.len() + CHUNK;
+        while self.out.len() < end {
+            let symbol = codes.literals.decode(&mut self.bits)?;
+            if symbol < END_OF_BLOCK {
+                self.out.push(symbol as u8);
+                continue;
+            }
+            if symbol == END_OF_BLOCK {
+                return Ok(true);
+            }
+
+            let code = usize::from(symbol - 257);
+            if code >= LENGTH_BASE.len() {
+                return Err(invalid("invalid length code"));
+            }
+            let length = usize::from(LENGTH_BASE[code])
+                + self.bits.bits(u32::from(LENGTH_EXTRA[code]))? as usize;
+            let code = usize::from(codes.distances.decode(&mut self.bits)?);
+            if code >= DISTANCE_BASE.len() {
+                return Err(invalid("invalid distance code"));
+            }
+            let distance = usize::from(DISTANCE_BASE[code])
+                + self.bits.bits(u32::from(DISTANCE_EXTRA[code]))? as usize;
+            if distance > self.out.len() {
+                return Err(invalid("distance too far back"));
+            }
+            // The copy may overlap what it produces, repeating it.
+            let from = self.out.len() - distance;
+            for i in 0..length {
+                let byte = self.out[from + i];
+                self.out.push(byte);
+            }
+        }
+        Ok(false)
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        match self.wrapper {
+            Wrapper::Gzip => {
+                let mut header = [0; 10];
+                for byte in &mut header {
+                    *byte = self.bits.bits(8)? as u8;
+                }
+                let [0x1f, 0x8b, 8, flags, ..] = header else {
+                    return Err(invalid("not gzip data"));
+                };
+                if flags & 0xe0 != 0 {
+                    return Err(invalid("reserved gzip flags set"));
+                }
+                // Skip the extra field, the file name, the comment and the
+                // header checksum, whichever are there.
+                if flags & 4 != 0 {
+                    let len = self.bits.bits(16)?;
+                    for _ in 0..len {
+                        self.bits.bits(8)?;
+                    }
+                }
+                for flag in [8, 16] {
+                    if flags & flag != 0 {
+                        while self.bits.bits(8)? != 0 {}
+                    }
+                }
+                if flags & 2 != 0 {
+                    self.bits.bits(16)?;
+                }
+            }
+            Wrapper::Zlib => {
+                let method = self.bits.bits(8)?;
+                let flags = self.bits.bits(8)?;
+                if method & 0x0f != 8 || (method << 8 | flags) % 31 != 0 {
+                    return Err(invalid("not zlib data"));
+                }
+                if flags & 0x20 != 0 {
+                    return Err(invalid("zlib preset dictionaries aren't supported"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_trailer(&mut self) -> io::Result<()> {
+        self.bits.align();
+        match self.wrapper {
+            Wrapper::Gzip => {
+                let crc = self.bits.bits(16)? | self.bits.bits(16)? << 16;
+                let size = self.bits.bits(16)? | self.bits.bits(16)? << 16;
+                if crc != !self.check || size != self.size as u32 {
+                    return Err(invalid("gzip checksum mismatch"));
+                }
+            }
+            Wrapper::Zlib => {
+                let mut adler = 0;
+                for _ in 0..4 {
+                    adler = adler << 8 | self.bits.bits(8)?;
+                }
+                if adler != self.check {
+                    return Err(invalid("zlib checksum mismatch"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for GzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            if let State::Done = self.state {
+                return Ok(0);
+            }
+            // Everything has been read, so only the window is still
+            // needed.
+            if self.out.len() > 2 * WINDOW {
+                self.out.drain(..self.out.len() - WINDOW);
+                self.pos = self.out.len();
+            }
+            self.decode_next()?;
+        }
+
+        let out = &self.out[self.pos..];
+        let read = out.len().min(buf.len());
+        buf[..read].copy_from_slice(&out[..read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+/// The literal and length code, and the distance code, of a block.
+struct Codes {
+    literals: Huffman,
+    distances: Huffman,
+}
+
+impl Codes {
+    /// The codes of blocks compressed with the fixed codes.
+    fn fixed() -> Codes {
+        let mut lengths = [0; 288];
+        lengths[..144].fill(8);
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        lengths[280..].fill(8);
+        Codes {
+            literals: Huffman::new(&lengths),
+            distances: Huffman::new(&[5; 30]),
+        }
+    }
+
+    /// Read the codes a block compressed with its own codes starts with.
+    fn read<R: Read>(bits: &mut BitReader<R>) -> io::Result<Codes> {
+        /// The order the lengths of the code length code are sent in.
+        const ORDER: [usize; 19] = [
+            16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+        ];
+
+        let literals = bits.bits(5)? as usize + 257;
+        let distances = bits.bits(5)? as usize + 1;
+        let code_lengths = bits.bits(4)? as usize + 4;
+        if literals > 286 || distances > 30 {
+            return Err(invalid("too many codes"));
+        }
+
+        let mut lengths = [0; 19];
+        for &i in &ORDER[..code_lengths] {
+            lengths[i] = bits.bits(3)? as u8;
+        }
+        let code = Huffman::checked(&lengths)?;
+
+        // The lengths of both codes, run-length encoded together.
+        let mut lengths = Vec::with_capacity(literals + distances);
+        while lengths.len() < literals + distances {
+            let (length, repeat) = match code.decode(bits)? {
+                symbol @ 0..=15 => (symbol as u8, 1),
+                16 => match lengths.last() {
+                    Some(&previous) => (previous, 3 + bits.bits(2)?),
+                    None => return Err(invalid("repeated length without a previous one")),
+                },
+                17 => (0, 3 + bits.bits(3)?),
+                _ => (0, 11 + bits.bits(7)?),
+            };
+            if lengths.len() + repeat as usize > literals + distances {
+                return Err(invalid("too many code lengths"));
+            }
+            lengths.extend((0..repeat).map(|_| length));
+        }
+        if lengths[usize::from(END_OF_BLOCK)] == 0 {
+            return Err(invalid("no end of block code"));
+        }
+
+        Ok(Codes {
+            literals: Huffman::checked(&lengths[..literals])?,
+            distances: Huffman::checked(&lengths[literals..])?,
+        })
+    }
+}
+
+/// A canonical Huffman code, decoded a bit at a time.
+struct Huffman {
+    /// The number of codes of each length.
+    counts: [u16; 16],
+    /// The symbols, ordered by their codes.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// The code with the given length for each symbol, zero for symbols
+    /// that don't occur.
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; 16];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                let offset = &mut offsets[usize::from(length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    /// Like [`Huffman::new`], but for lengths read from the data, which
+    /// may ask for more codes than there are.
+    fn checked(lengths: &[u8]) -> io::Result<Huffman> {
+        let code = Huffman::new(lengths);
+        let mut left = 1i32;
+        for &count in &code.counts[1..] {
+            left = left * 2 - i32::from(count);
+            if left < 0 {
+                return Err(invalid("oversubscribed code"));
+            }
+        }
+        Ok(code)
+    }
+
+    fn decode<R: Read>(&self, bits: &mut BitReader<R>) -> io::Result<u16> {
+        // The codes of each length follow those of the length before, so
+        // `first` is the first code of the current length, and `index`
+        // where its symbols start.
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid code"))
+    }
+}
+
+/// Reads codes from bytes, starting at the least significant bit as
+/// deflate does.
+struct BitReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    /// The unread bytes of `buf`.
+    pos: usize,
+    len: usize,
+    pending: u64,
+    count: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn new(inner: R) -> BitReader<R> {
+        BitReader {
+            inner,
+            buf: vec![0; 8 * 1024],
+            pos: 0,
+            len: 0,
+            pending: 0,
+            count: 0,
+        }
+    }
+
+    /// Read `count` bits, at most 32.
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        while self.count < count {
+            let byte = self.byte()?;
+            self.pending |= u64::from(byte) << self.count;
+            self.count += 8;
+        }
+        let value = (self.pending & ((1 << count) - 1)) as u32;
+        self.pending >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Skip to the start of the next byte.
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.pending >>= partial;
+        self.count -= partial;
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        while self.pos == self.len {
+            match self.inner.read(&mut self.buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "compressed data ends early",
+                    ))
+                }
+                Ok(read) => {
+                    self.pos = 0;
+                    self.len = read;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+}
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Continue the Adler-32 checksum `adler` over `bytes`.
+fn adler32(adler: u32, bytes: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    // The sums can't overflow within this many bytes.
+    for chunk in bytes.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
+
+/// The CRC-32 lookup table, one entry per byte value.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            k += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+};
+
+/// Continue the CRC-32 `crc`, kept inverted, over `bytes`.
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc = CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+impl Response {
+    /// Compress the body with gzip as it is sent, if `request` accepts
+    /// it and compressing is likely worth it.
+    ///
+    /// That is the case for bodies whose `Content-Type` is text, JSON,
+    /// JavaScript, XML or SVG, that aren't known to be shorter than a
+    /// kilobyte, and that aren't encoded already. Partial content and
+    /// responses without a body are left alone.
+    ///
+    /// The compressed body is streamed with chunked transfer encoding, so
+    /// even a large file is never held in memory. The response gets
+    /// `Content-Encoding: gzip`, and a strong `ETag` becomes weak, since
+    /// the bytes sent change with the encoding. Any response that could
+    /// have been compressed gets `Vary: Accept-Encoding`, whether it was
+    /// or not.
+    ///
+    /// The client accepts gzip if its `Accept-Encoding` gives it a
+    /// quality above zero, by name or through `*`, that `identity` doesn't
+    /// beat. A tie goes to gzip.
+    ///
+    /// ```
+    /// use rust_server::{Method, Request, Response};
+    ///
+    /// let compressed = |accept_encoding: &str| {
+    ///     let mut request = Request::new(Method::Get, "/");
+    ///     request.set_header("Accept-Encoding", accept_encoding);
+    ///     let response = Response::text(200, "a".repeat(2048)).gzip(&request);
+    ///     response.header("Content-Encoding") == Some("gzip")
+    /// };
+    ///
+    /// assert!(compressed("gzip"));
+    /// assert!(compressed("br, gzip;q=0.5"));
+    /// assert!(compressed("*"));
+    /// assert!(compressed("gzip;q=0.5, identity;q=0.5"));
+    /// assert!(!compressed("gzip;q=0.5, identity;q=0.8"));
+    /// assert!(!compressed("gzip;q=0"));
+    /// assert!(!compressed("*;q=1, gzip;q=0"));
+    /// assert!(!compressed("br"));
+    /// ```
+    pub fn gzip(mut self, request: &Request) -> Response {
+        let compressible = self.header("Content-Type").is_some_and(is_compressible);
+        let worth_it = self.body().len().is_none_or(|len| len >= MIN_SIZE);
+        if !compressible
+            || !worth_it
+            || self.header("Content-Encoding").is_some()
+            || matches!(self.status(), 100..=199 | 204 | 206 | 304)
+        {
+            return self;
+        }
+
+        // Whether the response is compressed depends on the request's
+        // `Accept-Encoding` from here on, and caches need to know that
+        // even when it isn't.
+        self.add_vary("Accept-Encoding");
+        let accept_encoding = request.header("Accept-Encoding");
+        if negotiate::encoding(accept_encoding, &["gzip", "identity"]) != Some("gzip") {
+            return self;
+        }
+
+        let reader: Box<dyn Read + Send> = match self.take_body() {
+            Body::Empty => return self,
+            Body::Bytes(bytes) => Box::new(Cursor::new(bytes)),
+            Body::Text(text) => Box::new(Cursor::new(text.into_bytes())),
+            Body::Static(bytes) => Box::new(bytes),
+            Body::File(file, len) => Box::new(file.take(len)),
+            Body::SizedReader(reader, len) => Box::new(reader.take(len)),
+            Body::Reader(reader) => reader,
+            Body::Path(path) => match File::open(&path) {
+                Ok(file) => Box::new(file),
+                Err(_) => {
+                    // Leave the error to writing the response, which
+                    // reports it.
+                    self.set_body(Body::Path(path));
+                    return self;
+                }
+            },
+        };
+        self.set_body(Body::Reader(Box::new(GzipEncoder::new(reader))));
+
+        self.set_header("Content-Encoding", "gzip");
+        self.remove_header("Accept-Ranges");
+        if let Some(etag) = self.header("ETag").filter(|etag| !etag.starts_with("W/")) {
+            let weak = format!("W/{etag}");
+            self.set_header("ETag", &weak);
+        }
+        self
+    }
+}
+
+/// Whether bodies of `content_type` shrink when compressed. Images other
+/// than SVG, audio, video and archives are compressed already.
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
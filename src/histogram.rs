@@ -0,0 +1,80 @@
+//! How long jobs take, for
+//! [`ThreadPool::latency_histogram`](crate::ThreadPool::latency_histogram).
+//!
+//! Every worker adds the jobs it runs to the same counters. Each bucket is
+//! an atomic of its own, so recording a job never waits on another worker.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The upper bounds of the buckets, each excluded from its bucket. Jobs
+/// taking at least the last bound go to one more bucket.
+const BOUNDS: [Duration; 4] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// The number of buckets.
+const BUCKETS: usize = BOUNDS.len() + 1;
+
+/// How many jobs took how long to run, in the buckets under 1ms, under
+/// 10ms, under 100ms, under 1s, and 1s or more.
+///
+/// This is a copy of the counts at the time it was taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// The buckets as pairs of the time the jobs in them took less than,
+    /// or `None` for the last one, and the number of those jobs.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        BOUNDS
+            .iter()
+            .map(|&bound| Some(bound))
+            .chain([None])
+            .zip(self.counts)
+    }
+
+    /// The counts of the buckets, fastest first.
+    pub fn counts(&self) -> [u64; BUCKETS] {
+        self.counts
+    }
+
+    /// The number of jobs counted.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// The counters behind a [`LatencyHistogram`], shared by the workers.
+#[derive(Debug, Default)]
+pub(crate) struct Latencies {
+    counts: [AtomicU64; BUCKETS],
+}
+
+impl Latencies {
+    /// Count a job that took `elapsed`.
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let bucket = BOUNDS
+            .iter()
+            .position(|&bound| elapsed < bound)
+            .unwrap_or(BOUNDS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts so far.
+    pub(crate) fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            counts: self
+                .counts
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+        }
+    }
+}
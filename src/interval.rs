@@ -0,0 +1,138 @@
+//! Running a job over and over, for
+//! [`ThreadPool::schedule_interval`](crate::ThreadPool::schedule_interval).
+//!
+//! Like retries, the waits between runs happen on the pool's timer, and
+//! only the runs themselves go through the queue to a worker. The next
+//! wait starts once a run has finished, so runs never overlap, however
+//! long they take. A run that panics doesn't stop the job either.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{queue::Queue, timer::TimerHandle, Job};
+
+/// A way to stop a job started with
+/// [`ThreadPool::schedule_interval`](crate::ThreadPool::schedule_interval).
+///
+/// Dropping the handle leaves the job running.
+#[derive(Debug, Clone)]
+pub struct IntervalHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl IntervalHandle {
+    /// Stop the job. A run that has already started still finishes, but
+    /// no new one starts.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether [`cancel`](IntervalHandle::cancel) was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A job run every `period`, along with everything needed to schedule it.
+pub(crate) struct Interval<F> {
+    pub(crate) task: F,
+    pub(crate) period: Duration,
+    pub(crate) cancelled: Arc<AtomicBool>,
+    pub(crate) queue: Arc<Queue<Job>>,
+    pub(crate) timer: TimerHandle,
+}
+
+impl<F: FnMut() + Send + 'static> Interval<F> {
+    /// Start the job, returning the handle that cancels it.
+    pub(crate) fn start(self) -> IntervalHandle {
+        let handle = IntervalHandle {
+            cancelled: Arc::clone(&self.cancelled),
+        };
+        self.schedule();
+        handle
+    }
+
+    /// Queue the next run once a period has passed.
+    fn schedule(self) {
+        let timer = self.timer.clone();
+        timer.schedule(
+            self.period,
+            Box::new(move || {
+                if self.cancelled.load(Ordering::SeqCst) {
+                    return;
+                }
+                // Once the pool has been shut down, the job is dropped and
+                // stops for good.
+                let queue = Arc::clone(&self.queue);
+                let _ = queue.push(Box::new(move || self.run()));
+            }),
+        );
+    }
+
+    fn run(mut self) {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return;
+        }
+        // A panicking run would otherwise unwind through the worker and
+        // drop the interval with it, so it is caught here and the task
+        // runs again next period.
+        if panic::catch_unwind(AssertUnwindSafe(&mut self.task)).is_err() {
+            println!("Recovered from a panicking interval job; it runs again next period.");
+        }
+        self.schedule();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicUsize, thread};
+
+    use crate::ThreadPool;
+
+    use super::*;
+
+    #[test]
+    fn fires_every_period_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let handle = pool.schedule_interval(Duration::from_millis(20), {
+            let runs = Arc::clone(&runs);
+            move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        thread::sleep(Duration::from_millis(310));
+        handle.cancel();
+        let fired = runs.load(Ordering::SeqCst);
+        assert!((5..=15).contains(&fired), "fired {fired} times");
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(runs.load(Ordering::SeqCst) <= fired + 1);
+        assert!(handle.is_cancelled());
+    }
+
+    #[test]
+    fn keeps_running_after_a_panicking_run() {
+        let pool = ThreadPool::new(1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let handle = pool.schedule_interval(Duration::from_millis(10), {
+            let runs = Arc::clone(&runs);
+            move || {
+                if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("the first run fails");
+                }
+            }
+        });
+
+        thread::sleep(Duration::from_millis(200));
+        handle.cancel();
+        assert!(runs.load(Ordering::SeqCst) >= 3);
+    }
+}
@@ -0,0 +1,77 @@
+//! Jobs whose result is wanted back, for
+//! [`ThreadPool::submit`](crate::ThreadPool::submit).
+
+use std::{fmt, sync::mpsc};
+
+use crate::{error::HandlerError, response::IntoResponse, ExecuteError, Response};
+
+/// The result of a job started with
+/// [`ThreadPool::submit`](crate::ThreadPool::submit), to be waited for with
+/// [`JobHandle::join`].
+///
+/// A handler can return the handle of a job computing its response. The
+/// connection's thread then waits for the job and sends what it returns,
+/// or `500 Internal Server Error` if it panicked.
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    result: Result<mpsc::Receiver<T>, ExecuteError>,
+}
+
+/// The reasons a [`JobHandle`] has no result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobError {
+    /// The pool refused the job, so it never ran.
+    Refused(ExecuteError),
+    /// The job panicked, or was dropped without running.
+    Panicked,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Refused(err) => write!(f, "the job was refused: {err}"),
+            JobError::Panicked => f.write_str("the job panicked before returning"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+impl<T> JobHandle<T> {
+    /// The end a job sends its result into, and the handle it reaches.
+    pub(crate) fn new() -> (mpsc::SyncSender<T>, JobHandle<T>) {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        (
+            sender,
+            JobHandle {
+                result: Ok(receiver),
+            },
+        )
+    }
+
+    /// A handle for a job the pool refused.
+    pub(crate) fn refused(err: ExecuteError) -> JobHandle<T> {
+        JobHandle { result: Err(err) }
+    }
+
+    /// Wait for the job to finish and return its result.
+    pub fn join(self) -> Result<T, JobError> {
+        self.result
+            .map_err(JobError::Refused)?
+            .recv()
+            .map_err(|_| JobError::Panicked)
+    }
+}
+
+/// The response the job returns. A job the pool refused is answered with
+/// `503 Service Unavailable`, and one that panicked with `500 Internal
+/// Server Error`.
+impl<T: IntoResponse> IntoResponse for JobHandle<T> {
+    fn into_response(self) -> Response {
+        match self.join() {
+            Ok(value) => value.into_response(),
+            Err(err @ JobError::Refused(_)) => HandlerError::new(503, err).into_response(),
+            Err(err) => HandlerError::new(500, err).into_response(),
+        }
+    }
+}
@@ -1,15 +1,201 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    cell::{Cell, RefCell},
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+pub mod access_log;
+#[cfg(feature = "affinity")]
+mod affinity;
+pub mod auth;
+mod blocking;
+mod budget;
+pub mod build_info;
+pub mod cache;
+pub mod clock;
+pub mod conditional;
+pub mod config;
+pub mod cookie;
+pub mod date;
+pub mod error;
+mod error_page;
+pub mod extensions;
+pub mod extract;
+mod forwarded;
+#[cfg(feature = "gzip")]
+pub mod gzip;
+mod histogram;
+mod interval;
+mod job;
+pub mod metrics;
+mod negotiate;
+mod pause;
+pub mod provider;
+pub mod proxy;
+mod queue;
+pub mod radix;
+pub mod request;
+pub mod response;
+mod retry;
+pub mod router;
+pub mod server;
+pub mod shutdown;
+#[cfg(feature = "socket_options")]
+mod socket;
+pub mod static_files;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+mod timer;
+pub mod upgrade;
+pub mod uri;
+pub mod vhost;
+
+pub use access_log::LogFormat;
+pub use auth::BasicAuth;
+pub use cache::ResponseCache;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use config::ConfigError;
+pub use cookie::{CookieAttributes, SameSite};
+pub use error::HandlerError;
+pub use extensions::Extensions;
+pub use extract::{ExtractError, FromParams, FromValue, Params};
+pub use histogram::LatencyHistogram;
+pub use interval::IntervalHandle;
+pub use job::{JobError, JobHandle};
+pub use metrics::{ConnectionStats, TrafficStats};
+pub use provider::{DiskFiles, EmbeddedFiles, FileProvider, MemoryFiles};
+pub use proxy::Proxy;
+pub use radix::RadixRouter;
+pub use request::{Limits, Method, Request, Version};
+pub use response::{Body, IntoResponse, Response};
+pub use router::{Router, Service};
+pub use server::{serve_connection, AcceptStrategy, Server, ServerConfig, ServerError};
+pub use shutdown::ShutdownHandle;
+pub use static_files::StaticFiles;
+pub use upgrade::Upgraded;
+pub use uri::{InvalidPath, Uri};
+pub use vhost::VirtualHosts;
+
+use blocking::BlockingPool;
+use histogram::Latencies;
+use interval::Interval;
+use pause::Pause;
+use queue::{Pop, PushError, Queue};
+use retry::Retry;
+use timer::Timer;
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    queue: Arc<Queue<Job>>,
+    blocking: BlockingPool,
+    /// Whether the pool was shut down on purpose, by
+    /// [`ThreadPool::shutdown`] or by being dropped. The workers read it
+    /// to tell that apart from their queue closing for any other reason.
+    shut_down: Arc<AtomicBool>,
+    /// The thread retries wait on, started by the first job that needs it.
+    timer: OnceLock<Timer>,
+    /// The clock the timer waits on.
+    clock: Arc<dyn Clock>,
+    /// How many jobs may wait in the queue, if there is a limit.
+    capacity: Option<usize>,
+    /// What [`ThreadPool::execute`] does with a job past `capacity`.
+    overflow: Overflow,
+    /// How long the jobs the workers ran took.
+    latencies: Arc<Latencies>,
+    /// Whether the workers are held back from running jobs.
+    pause: Arc<Pause>,
+    /// How long an idle worker parks before looking around, in
+    /// nanoseconds. See [`ThreadPool::worker_keep_alive`].
+    idle_check: Arc<AtomicU64>,
+    /// What to run once the workers have exited, in order. The hooks
+    /// needn't be `Sync`, so they are kept behind a mutex for the pool to
+    /// be.
+    shutdown_hooks: Mutex<Vec<Job>>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// How long an idle worker waits for a job before checking whether the
+/// pool was shut down, by default. Shutting down also wakes the workers
+/// right away; this only bounds how long one can miss that.
+const IDLE_CHECK: Duration = Duration::from_millis(250);
+
+/// The reasons a [`ThreadPool`] could not be created.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// A pool needs at least one thread.
+    ZeroSize,
+    /// The operating system refused to start a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => f.write_str("a thread pool needs at least one thread"),
+            PoolCreationError::Spawn(err) => write!(f, "failed to spawn a worker thread: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize => None,
+            PoolCreationError::Spawn(err) => Some(err),
+        }
+    }
+}
+
+/// What a [`ThreadPool`] with a [queue capacity](ThreadPool::queue_capacity)
+/// does with a job that finds the queue full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Refuse the job with [`ExecuteError::Full`]. This is the default.
+    #[default]
+    Reject,
+    /// Wait until a worker takes a job off the queue. Jobs submitted by
+    /// the pool's own workers can then wait forever, once every worker
+    /// is waiting.
+    Block,
+    /// Run the job right away on the thread that submitted it, as a last
+    /// resort that keeps everything running, only more slowly. A panic in
+    /// the job then unwinds into the caller.
+    RunInline,
+}
+
+/// The reasons a job could not be handed to a [`ThreadPool`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// The pool was shut down, and takes no more jobs.
+    ShutDown,
+    /// The workers stopped taking jobs without the pool being shut down.
+    Disconnected,
+    /// The queue was full, and the pool's [`Overflow`] is
+    /// [`Overflow::Reject`].
+    Full,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::ShutDown => f.write_str("the thread pool has been shut down"),
+            ExecuteError::Disconnected => {
+                f.write_str("the thread pool's workers disconnected unexpectedly")
+            }
+            ExecuteError::Full => f.write_str("the thread pool's queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
@@ -17,22 +203,57 @@ impl ThreadPool {
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will panic if the size is zero, or if a worker
+    /// thread can't be spawned. Use [`ThreadPool::build`] to handle those
+    /// cases instead.
     pub fn new(size: usize) -> ThreadPool {
-        // Make sure the size is greater than 0, otherwise the pool
-        // would contain no threads.
-        assert!(size > 0);
-
-        // Create a channel to communicate with the threads in the pool.
-        // The channel is created with `mpsc::channel()`, which returns a
-        // tuple containing a sender and a receiver. We'll use the sender to
-        // send jobs to the threads in the pool.
-        let (sender, receiver) = mpsc::channel();
+        match ThreadPool::build(size) {
+            Ok(pool) => pool,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Create a new ThreadPool, returning an error instead of panicking.
+    ///
+    /// The size is the number of threads in the pool. It must be greater
+    /// than zero, otherwise the pool would contain no threads.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::spawn(size, &[])
+    }
+
+    /// Create a new ThreadPool whose workers are each pinned to a CPU
+    /// core, going round the cores the process may run on.
+    ///
+    /// Keeping a worker on the same core keeps its caches warm, which
+    /// helps CPU-bound jobs. Pinning is supported on Linux, Android,
+    /// Windows, macOS and FreeBSD; anywhere else, or if a worker can't be
+    /// pinned, a warning is printed and the workers run wherever the
+    /// operating system puts them.
+    #[cfg(feature = "affinity")]
+    pub fn build_pinned(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        let cores = affinity::cores();
+        if cores.is_empty() {
+            eprintln!("Pinning threads to cores isn't supported here; not pinning the workers");
+        }
+        ThreadPool::spawn(size, &cores)
+    }
+
+    /// Start `size` workers, pinning worker `n` to the `n`th of `cores`
+    /// modulo their number, unless there are none.
+    fn spawn(size: usize, cores: &[usize]) -> Result<ThreadPool, PoolCreationError> {
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
 
-        // We'll use the receiver to receive jobs from the sender. We need to
-        // wrap the receiver in an `Arc` and a `Mutex` so that it can be shared
-        // among multiple threads.
-        let receiver = Arc::new(Mutex::new(receiver));
+        // Create the queue the jobs wait in until a thread is free. All
+        // the threads take their jobs from it, so it's shared through an
+        // `Arc`. See the `queue` module for how idle threads wait for jobs
+        // without using any CPU.
+        let queue = Arc::new(Queue::new());
+        let shut_down = Arc::new(AtomicBool::new(false));
+        let latencies = Arc::new(Latencies::default());
+        let pause = Arc::new(Pause::default());
+        let idle_check = Arc::new(AtomicU64::new(IDLE_CHECK.as_nanos() as u64));
 
         // Create a vector to store the workers. We'll use the `with_capacity()`
         // function to pre-allocate space for the vector, so that we don't have
@@ -40,70 +261,559 @@ impl ThreadPool {
         let mut workers = Vec::with_capacity(size);
 
         // Create a worker for each thread in the pool. We'll use the `clone()`
-        // method to create a clone of the receiver for each worker, so that
-        // each worker has its own copy of the receiver.
+        // method to give each worker its own handle to the queue.
+        //
+        // If spawning fails, the queue is closed so that the workers
+        // created so far exit.
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            let core = (!cores.is_empty()).then(|| cores[id % cores.len()]);
+            let worker = Worker::new(
+                id,
+                Arc::clone(&queue),
+                Arc::clone(&shut_down),
+                Arc::clone(&latencies),
+                Arc::clone(&pause),
+                Arc::clone(&idle_check),
+                core,
+            );
+            match worker {
+                Ok(worker) => workers.push(worker),
+                Err(err) => {
+                    queue.close();
+                    return Err(PoolCreationError::Spawn(err));
+                }
+            }
         }
 
-        // Create a ThreadPool instance with the workers and the sender.
-        ThreadPool {
+        // Create a ThreadPool instance with the workers and the queue.
+        Ok(ThreadPool {
             workers,
-            sender: Some(sender),
-        }
+            queue,
+            blocking: BlockingPool::new(),
+            shut_down,
+            timer: OnceLock::new(),
+            clock: Arc::new(SystemClock),
+            capacity: None,
+            overflow: Overflow::Reject,
+            latencies,
+            pause,
+            idle_check,
+            shutdown_hooks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Let at most `capacity` jobs wait in the queue; by default there is
+    /// no limit. What happens to jobs past it is up to the pool's
+    /// [`overflow`](ThreadPool::overflow).
+    ///
+    /// Jobs queued again by [retries](ThreadPool::execute_with_retry) and
+    /// [intervals](ThreadPool::schedule_interval) count towards the limit
+    /// but are never held back by it.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPool {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Choose what [`execute`](ThreadPool::execute) does with a job that
+    /// finds the queue at its [capacity](ThreadPool::queue_capacity).
+    ///
+    /// ```
+    /// use rust_server::{Overflow, ThreadPool};
+    ///
+    /// // Under overload, the threads submitting jobs help run them.
+    /// let pool = ThreadPool::new(4)
+    ///     .queue_capacity(64)
+    ///     .overflow(Overflow::RunInline);
+    /// ```
+    pub fn overflow(mut self, overflow: Overflow) -> ThreadPool {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Set how long an idle worker stays parked before it wakes up on its
+    /// own to check whether the pool was shut down. It defaults to 250
+    /// milliseconds, and is at least a millisecond.
+    ///
+    /// Parked workers use no CPU, and a new job or shutting down the pool
+    /// wakes them right away whatever this is set to, so it only decides
+    /// how often an idle pool wakes up for nothing; see the `queue` module
+    /// for how workers wait. A longer keep-alive means fewer wakeups on a
+    /// quiet server.
+    pub fn worker_keep_alive(self, keep_alive: Duration) -> ThreadPool {
+        let keep_alive = keep_alive.max(Duration::from_millis(1));
+        let nanos = u64::try_from(keep_alive.as_nanos()).unwrap_or(u64::MAX);
+        self.idle_check.store(nanos, Ordering::Relaxed);
+        self
+    }
+
+    /// Time [retries](ThreadPool::execute_with_retry) and
+    /// [intervals](ThreadPool::schedule_interval) with `clock`. It
+    /// defaults to the [`SystemClock`].
+    ///
+    /// With a [`ManualClock`], their waits end when the clock is advanced
+    /// past them, see the [`clock`] module.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> ThreadPool {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Run `hook` when the pool is dropped, once all its workers have
+    /// finished their jobs and exited, to flush logs or close resources
+    /// the jobs used.
+    ///
+    /// Hooks run once, on the thread dropping the pool, in the order they
+    /// were added. One that panics doesn't keep the others from running.
+    ///
+    /// ```
+    /// use std::{
+    ///     sync::{Arc, Mutex},
+    ///     thread,
+    ///     time::Duration,
+    /// };
+    ///
+    /// use rust_server::ThreadPool;
+    ///
+    /// let events = Arc::new(Mutex::new(Vec::new()));
+    /// let log = |event: &'static str| {
+    ///     let events = Arc::clone(&events);
+    ///     move || events.lock().unwrap().push(event)
+    /// };
+    ///
+    /// let pool = ThreadPool::new(2)
+    ///     .on_shutdown(log("first hook"))
+    ///     .on_shutdown(|| panic!("a broken hook"))
+    ///     .on_shutdown(log("second hook"));
+    /// let job = log("job");
+    /// pool.execute(move || {
+    ///     thread::sleep(Duration::from_millis(100));
+    ///     job();
+    /// })
+    /// .unwrap();
+    ///
+    /// pool.shutdown();
+    /// assert!(events.lock().unwrap().is_empty());
+    ///
+    /// drop(pool);
+    /// assert_eq!(*events.lock().unwrap(), ["job", "first hook", "second hook"]);
+    /// ```
+    pub fn on_shutdown(mut self, hook: impl FnOnce() + Send + 'static) -> ThreadPool {
+        self.shutdown_hooks
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(Box::new(hook));
+        self
     }
 
     /// Execute a closure on a thread in the pool.
     ///
-    /// The `execute()` method takes a closure as an argument, and sends it
-    /// to one of the threads in the pool. The closure is executed on the
-    /// thread, and the result is discarded.
+    /// The `execute()` method takes a closure as an argument, and puts it
+    /// at the back of the pool's queue. The first thread to become free
+    /// takes it and runs it, and the result is discarded.
+    ///
+    /// The `execute()` method is safe to call from any thread, and never
+    /// blocks for longer than it takes to lock the queue, however many
+    /// jobs are already waiting.
+    ///
+    /// Once the pool has been [shut down](ThreadPool::shutdown), jobs are
+    /// refused with [`ExecuteError::ShutDown`] rather than queued for
+    /// workers that are gone.
+    ///
+    /// With a [queue capacity](ThreadPool::queue_capacity), a job that
+    /// finds the queue full is refused, waited with or run right away,
+    /// depending on the pool's [`Overflow`].
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Create a Box around the closure. This is necessary because jobs
+        // of different types have to fit in the same queue, so each one is
+        // stored as a trait object.
+        let job: Job = Box::new(f);
+
+        // Queue the job, which also wakes up a thread to run it if one is
+        // waiting. Without a capacity, the queue only refuses it once it
+        // is closed.
+        let pushed = match self.capacity {
+            Some(capacity) => {
+                let wait = self.overflow == Overflow::Block;
+                self.queue.push_bounded(job, capacity, wait)
+            }
+            None => self.queue.push(job).map_err(PushError::Closed),
+        };
+
+        match pushed {
+            Ok(()) => Ok(()),
+            Err(PushError::Full(job)) if self.overflow == Overflow::RunInline => {
+                job();
+                Ok(())
+            }
+            Err(PushError::Full(_)) => Err(ExecuteError::Full),
+            Err(PushError::Closed(_)) if self.is_shut_down() => Err(ExecuteError::ShutDown),
+            Err(PushError::Closed(_)) => Err(ExecuteError::Disconnected),
+        }
+    }
+
+    /// Execute a closure on a thread in the pool like
+    /// [`execute`](ThreadPool::execute), returning a handle to wait for
+    /// what it returns.
+    ///
+    /// A handler can use this to compute its response on another pool,
+    /// returning the handle in its place:
+    ///
+    /// ```no_run
+    /// use std::sync::Arc;
+    ///
+    /// use rust_server::{Response, Router, ThreadPool};
+    ///
+    /// let reports = Arc::new(ThreadPool::new(2));
+    /// let router = Router::with_state(reports).get("/report", |_, reports| {
+    ///     reports.submit(|| Response::text(200, "all good"))
+    /// });
+    /// ```
+    ///
+    /// The connection waits for the job on its own worker, so the job
+    /// must go to a pool other than the one serving connections. Otherwise
+    /// once every worker is waiting, none is left to run the jobs.
+    ///
+    /// If the pool refuses the job, the handle reports why when joined.
+    pub fn submit<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, handle) = JobHandle::new();
+        // If the job panics, the sender is dropped without sending
+        // anything, which the handle reports.
+        match self.execute(move || {
+            let _ = sender.send(f());
+        }) {
+            Ok(()) => handle,
+            Err(err) => JobHandle::refused(err),
+        }
+    }
+
+    /// Execute a job that may fail, running it again up to `attempts`
+    /// times in total until it succeeds. It always runs at least once.
+    ///
+    /// After a failed attempt, the job is queued again once a delay has
+    /// passed, which starts at `backoff` and doubles every time. Each delay
+    /// is then shortened to a random point between half and all of it, so
+    /// jobs that failed together don't all retry at once. Nothing holds on
+    /// to a worker while waiting.
+    ///
+    /// If the last attempt fails too, `on_failure` is called with its
+    /// error. Retries still waiting when the pool is shut down are dropped,
+    /// and `on_failure` isn't called for them.
+    ///
+    /// # Panics
+    ///
+    /// The first call panics if the thread that waits out the delays can't
+    /// be spawned.
+    pub fn execute_with_retry<F, G, E>(
+        &self,
+        attempts: u32,
+        backoff: Duration,
+        job: F,
+        on_failure: G,
+    ) -> Result<(), ExecuteError>
+    where
+        F: FnMut() -> Result<(), E> + Send + 'static,
+        G: FnOnce(E) + Send + 'static,
+    {
+        let timer = self.timer();
+        let retry = Retry {
+            job,
+            on_failure,
+            attempts: attempts.max(1),
+            attempt: 0,
+            backoff,
+            queue: Arc::clone(&self.queue),
+            timer: timer.handle().clone(),
+        };
+        self.execute(move || retry.run())
+    }
+
+    /// Run `task` on the pool every `interval`, e.g. to evict expired
+    /// cache entries or flush metrics, until the returned handle cancels
+    /// it.
+    ///
+    /// The first run comes one interval after the call. The wait for each
+    /// next run starts once the previous one has finished, so runs never
+    /// overlap and a slow run pushes the following ones back. Nothing
+    /// holds on to a worker while waiting.
+    ///
+    /// The task stops for good when the pool is shut down. A run that
+    /// panics is caught, and the task runs again an interval later.
+    ///
+    /// # Panics
+    ///
+    /// Panics like [`execute_with_retry`](ThreadPool::execute_with_retry)
+    /// if the timer thread can't be spawned.
+    pub fn schedule_interval<F>(&self, interval: Duration, task: F) -> IntervalHandle
+    where
+        F: FnMut() + Send + 'static,
+    {
+        Interval {
+            task,
+            period: interval,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            queue: Arc::clone(&self.queue),
+            timer: self.timer().handle().clone(),
+        }
+        .start()
+    }
+
+    /// The timer thread, started the first time it is needed.
+    fn timer(&self) -> &Timer {
+        self.timer
+            .get_or_init(|| match Timer::start(Arc::clone(&self.clock)) {
+                Ok(timer) => timer,
+                Err(err) => panic!("failed to spawn the timer thread: {err}"),
+            })
+    }
+
+    /// Execute a closure like [`execute`](ThreadPool::execute), under a
+    /// name saying what it does, e.g. `render-page`.
+    ///
+    /// While the job runs, [`current_job_name`] returns the name on the
+    /// worker's thread, so a panic hook installed with
+    /// [`std::panic::set_hook`] can tell which job panicked. The worker's
+    /// own message about recovering from the panic names the job too.
+    pub fn execute_named<F>(&self, name: impl Into<String>, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let name = name.into();
+        self.execute(move || {
+            let _named = NamedJob::start(name);
+            f();
+        })
+    }
+
+    /// Stop taking new jobs. The workers finish the jobs already queued
+    /// and then exit; dropping the pool waits for them. A
+    /// [paused](ThreadPool::pause) pool is resumed for that.
+    ///
+    /// So by the time a pool has been dropped, every job it accepted has
+    /// run to the end, whether it was running or still queued:
+    ///
+    /// ```
+    /// use std::{
+    ///     sync::{
+    ///         atomic::{AtomicUsize, Ordering},
+    ///         Arc,
+    ///     },
+    ///     thread,
+    ///     time::Duration,
+    /// };
+    ///
+    /// use rust_server::ThreadPool;
+    ///
+    /// let finished = Arc::new(AtomicUsize::new(0));
+    /// let pool = ThreadPool::new(2);
+    /// for _ in 0..6 {
+    ///     let finished = Arc::clone(&finished);
+    ///     pool.execute(move || {
+    ///         thread::sleep(Duration::from_millis(100));
+    ///         finished.fetch_add(1, Ordering::SeqCst);
+    ///     })
+    ///     .unwrap();
+    /// }
+    ///
+    /// drop(pool);
+    /// assert_eq!(finished.load(Ordering::SeqCst), 6);
+    /// ```
+    pub fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+        self.queue.close();
+        self.pause.resume();
+    }
+
+    /// Shut the pool down like [`shutdown`](ThreadPool::shutdown), then
+    /// wait up to `timeout` for the workers to finish the jobs already
+    /// queued and exit. Returns whether they all did in time.
+    ///
+    /// Workers still busy after the timeout keep going, and dropping the
+    /// pool waits for them.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> bool {
+        self.shutdown();
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let exited = self.workers.iter().all(|worker| {
+                worker
+                    .thread
+                    .as_ref()
+                    .is_none_or(|thread| thread.is_finished())
+            });
+            if exited {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Whether the pool was shut down, either by [`ThreadPool::shutdown`]
+    /// or because it is being dropped.
+    ///
+    /// This is `false` if the workers stopped for any other reason, in
+    /// which case [`ThreadPool::execute`] fails with
+    /// [`ExecuteError::Disconnected`].
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Execute a closure that blocks, such as one waiting on I/O, on a
+    /// thread set aside for blocking work.
     ///
-    /// The `execute()` method is safe to call from any thread, as it uses a
-    /// channel to communicate with the threads in the pool. The channel is
-    /// created with `mpsc::channel()`, which creates a sender and a receiver.
-    /// The sender is used to send the job to the threads in the pool, and the
-    /// receiver is used to receive the result of the job.
+    /// The pool's workers are fixed in number, so a handful of jobs that
+    /// sleep or wait would leave none free for anything else. Jobs passed
+    /// to `execute_blocking()` run on a separate set of threads instead,
+    /// which grows as blocking jobs come in and shrinks again once they
+    /// stop, so jobs passed to [`execute`](ThreadPool::execute) are never
+    /// stuck behind them.
     ///
-    /// The `execute()` method will panic if the sender is not available,
-    /// which means that the pool has been shut down. This is a bug, as the
-    /// pool should not be shut down until all the jobs have been completed.
-    pub fn execute<F>(&self, f: F)
+    /// Blocking threads are only started when no idle one is around, and
+    /// there are never more than a few hundred of them; past that, jobs
+    /// wait for one to become free.
+    pub fn execute_blocking<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        // Create a Box around the closure. This is necessary because the
-        // closure is a trait object, and trait objects can't be sent over a
-        // channel.
-        let job = Box::new(f);
+        self.blocking.execute(Box::new(f));
+    }
+
+    /// Set how long a blocking thread may sit idle before it exits. It
+    /// defaults to ten seconds.
+    ///
+    /// Threads started during a burst of blocking jobs stay around for this
+    /// long in case more come, and then the blocking pool shrinks back,
+    /// down to its [minimum size](ThreadPool::set_blocking_min_threads).
+    ///
+    /// Only the blocking pool shrinks. The pool's workers are the fixed
+    /// number it was created with, and they don't exit however long they
+    /// stay idle.
+    pub fn set_blocking_idle_timeout(&self, timeout: Duration) {
+        self.blocking.set_keep_alive(timeout);
+    }
 
-        // Get a reference to the sender. The sender is stored in the
-        // `ThreadPool` instance, and it's used to send jobs to the threads in
-        // the pool.
-        let sender = self.sender.as_ref().unwrap();
+    /// Keep at least `min_threads` blocking threads alive however long they
+    /// stay idle. It defaults to zero.
+    ///
+    /// Blocking threads are still only started when jobs need them; this
+    /// only stops the pool from shrinking below `min_threads` afterwards.
+    pub fn set_blocking_min_threads(&self, min_threads: usize) {
+        self.blocking.set_min_threads(min_threads);
+    }
 
-        // Send the job to the threads in the pool. The `send()` method takes a
-        // `Job` as an argument, and sends it to one of the threads in the pool.
-        // The `send()` method will block until the job is sent, so it's safe to
-        // call from any thread.
-        sender.send(job).unwrap();
+    /// Hold the workers back from running jobs, e.g. for a maintenance
+    /// window, until [`resume`](ThreadPool::resume) is called.
+    ///
+    /// Jobs already running finish, but no worker starts another one.
+    /// Jobs keep being accepted meanwhile, and wait in the queue in the
+    /// order they came, counting towards its
+    /// [capacity](ThreadPool::queue_capacity). Pausing a paused pool does
+    /// nothing.
+    ///
+    /// ```
+    /// use std::{sync::mpsc, time::Duration};
+    ///
+    /// use rust_server::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// pool.pause();
+    ///
+    /// let (sender, receiver) = mpsc::channel();
+    /// for job in 0..4 {
+    ///     let sender = sender.clone();
+    ///     pool.execute(move || sender.send(job).unwrap()).unwrap();
+    /// }
+    /// assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    ///
+    /// pool.resume();
+    /// let mut done: Vec<i32> = receiver.iter().take(4).collect();
+    /// done.sort();
+    /// assert_eq!(done, [0, 1, 2, 3]);
+    /// ```
+    pub fn pause(&self) {
+        self.pause.pause();
+    }
+
+    /// Let the workers run jobs again after [`pause`](ThreadPool::pause),
+    /// starting with the jobs that were queued meanwhile.
+    pub fn resume(&self) {
+        self.pause.resume();
+    }
+
+    /// Whether the pool is [paused](ThreadPool::pause).
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    /// The number of workers.
+    pub(crate) fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// How many panicking jobs each worker has survived, as pairs of
+    /// worker id and count.
+    ///
+    /// A worker whose count keeps growing while the others stay put is
+    /// probably being handed the same bad job over and over.
+    pub fn panic_counts(&self) -> Vec<(usize, u64)> {
+        self.workers
+            .iter()
+            .map(|worker| (worker.id, worker.panics.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// How long the jobs run by the workers took, from when a worker took
+    /// them off the queue until they returned or panicked.
+    ///
+    /// Jobs run by [`execute_blocking`](ThreadPool::execute_blocking), or
+    /// run inline by [`Overflow::RunInline`], aren't counted.
+    ///
+    /// ```
+    /// use std::{thread, time::Duration};
+    ///
+    /// use rust_server::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// pool.execute(|| {}).unwrap();
+    /// pool.execute(|| thread::sleep(Duration::from_millis(20))).unwrap();
+    /// pool.execute(|| thread::sleep(Duration::from_millis(200))).unwrap();
+    ///
+    /// // Once the workers are done, every job has been counted.
+    /// assert!(pool.shutdown_timeout(Duration::from_secs(5)));
+    /// assert_eq!(pool.latency_histogram().counts(), [1, 0, 1, 1, 0]);
+    /// ```
+    pub fn latency_histogram(&self) -> LatencyHistogram {
+        self.latencies.snapshot()
     }
 }
 
 impl Drop for ThreadPool {
     /// When the `ThreadPool` is dropped, we need to shut down all the threads
-    /// in the pool. This is done by taking the sender, which will cause all
-    /// the threads in the pool to exit when they try to receive a job.
+    /// in the pool. This is done by shutting the pool down, which closes the
+    /// queue and will cause all the threads in the pool to exit once no jobs
+    /// are left in it.
     ///
     /// We then iterate over the workers in the pool, and for each one, we
     /// print a message saying that we're shutting down the worker. We then
     /// take the thread from the worker, and call `join()` on it. This will
     /// block until the thread has finished, and then we can drop the thread.
+    /// Once every worker has been joined, the shutdown hooks run in the order
+    /// they were added.
     ///
     /// Note that we don't need to explicitly drop the workers, as they will
-    /// be dropped when the `ThreadPool` is dropped.
+    /// be dropped when the `ThreadPool` is dropped. The same goes for the
+    /// blocking threads, which are shut down and joined when the blocking
+    /// pool is dropped right after.
     fn drop(&mut self) {
-        drop(self.sender.take());
+        self.shutdown();
 
         for worker in &mut self.workers {
             println!("Shutting down worker {}", worker.id);
@@ -112,47 +822,468 @@ impl Drop for ThreadPool {
                 thread.join().unwrap();
             }
         }
+
+        let hooks = self
+            .shutdown_hooks
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for hook in hooks.drain(..) {
+            if panic::catch_unwind(AssertUnwindSafe(hook)).is_err() {
+                println!("Recovered from a panicking shutdown hook.");
+            }
+        }
     }
 }
 
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
+    /// The number of jobs that panicked on this worker. The worker thread
+    /// increments it and the pool reads it, so it is shared through an
+    /// `Arc`.
+    panics: Arc<AtomicU64>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(
+        id: usize,
+        queue: Arc<Queue<Job>>,
+        shut_down: Arc<AtomicBool>,
+        latencies: Arc<Latencies>,
+        pause: Arc<Pause>,
+        idle_check: Arc<AtomicU64>,
+        core: Option<usize>,
+    ) -> io::Result<Worker> {
+        let panics = Arc::new(AtomicU64::new(0));
+        let worker_panics = Arc::clone(&panics);
+
         // Spawn a new thread for the worker. The `move` keyword ensures that
-        // the closure takes ownership of the `id` and `receiver` variables.
-        let thread = thread::spawn(move || loop {
-            // Attempt to receive a job from the channel. The `lock().unwrap()`
-            // call locks the `Mutex` and panics if the lock is poisoned. The
-            // `recv()` method returns a `Result` which is `Ok(job)` if a job
-            // was received or `Err(_)` if the channel is disconnected.
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
+        // the closure takes ownership of the `id` and `queue` variables.
+        let thread = thread::Builder::new().spawn(move || {
+            if let Some(core) = core {
+                pin_to_core(id, core);
+            }
+            Worker::run(
+                id,
+                &queue,
+                &shut_down,
+                &worker_panics,
+                &latencies,
+                &pause,
+                &idle_check,
+            );
+        })?;
+
+        // Return a new `Worker` instance with the given `id` and the spawned
+        // thread wrapped in `Some`.
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+            panics,
+        })
+    }
+
+    fn run(
+        id: usize,
+        queue: &Queue<Job>,
+        shut_down: &AtomicBool,
+        worker_panics: &AtomicU64,
+        latencies: &Latencies,
+        pause: &Pause,
+        idle_check: &AtomicU64,
+    ) {
+        // The number of jobs this worker has taken, which numbers the next
+        // one for `current_job_id`.
+        let mut taken = 0;
+        loop {
+            // While the pool is paused, jobs stay in the queue.
+            pause.wait();
+
+            // Wait for a job from the queue. `pop()` returns `Pop::Job` when
+            // a job was taken, `Pop::TimedOut` when none came for a while,
+            // or `Pop::Closed` once the queue has been closed and every job
+            // in it has been run.
+            let idle_check = Duration::from_nanos(idle_check.load(Ordering::Relaxed));
+            match queue.pop(idle_check) {
                 // If a job was received, print a message and execute the job.
-                Ok(job) => {
+                Pop::Job(job) => {
+                    // The pool may have been paused while we were waiting
+                    // for the job.
+                    pause.wait();
                     println!("Worker {id} got a job; executing.");
+                    let job_id = JobId {
+                        worker: id,
+                        sequence: taken,
+                    };
+                    taken += 1;
+                    JOB_ID.with(|current| current.set(Some(job_id)));
+                    let started = Instant::now();
 
-                    // Call the job, which is a closure.
-                    job();
+                    // Call the job, which is a closure. A panicking job
+                    // would otherwise take the whole thread down with it,
+                    // leaving the pool one worker short for good, so the
+                    // panic is caught and counted instead. The job is dropped
+                    // while unwinding and the worker shares nothing with it,
+                    // so `AssertUnwindSafe` is fine.
+                    let result = panic::catch_unwind(AssertUnwindSafe(job));
+                    latencies.record(started.elapsed());
+                    // A named job that panicked left its name behind for
+                    // the message below.
+                    let name = JOB_NAME.with(|current| current.borrow_mut().take());
+                    if result.is_err() {
+                        worker_panics.fetch_add(1, Ordering::Relaxed);
+                        match name {
+                            Some(name) => {
+                                println!("Worker {id} recovered from a panic while running {name}.")
+                            }
+                            None => println!("Worker {id} recovered from a panicking job."),
+                        }
+                    }
+                    JOB_ID.with(|current| current.set(None));
+                }
+                // Nothing to do for a while. The queue is empty, so once the
+                // pool has been shut down there is nothing left to finish,
+                // even if the wakeup from closing the queue got lost.
+                Pop::TimedOut => {
+                    if shut_down.load(Ordering::SeqCst) {
+                        println!("Worker {id} found the pool shut down; shutting down.");
+                        break;
+                    }
                 }
-                // If the channel is disconnected, print a message and break
-                // the loop to terminate the thread.
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
+                // If the queue is closed, print a message and break the loop
+                // to terminate the thread. The message says whether that was
+                // because the pool was shut down or for some other reason.
+                Pop::Closed => {
+                    if shut_down.load(Ordering::SeqCst) {
+                        println!("Worker {id} disconnected; shutting down.");
+                    } else {
+                        println!("Worker {id} disconnected unexpectedly; shutting down.");
+                    }
                     break;
                 }
             }
+        }
+    }
+}
+
+thread_local! {
+    /// The name of the job running on this thread, if it was given one.
+    static JOB_NAME: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    /// The id of the job running on this thread, if it is a worker's.
+    static JOB_ID: Cell<Option<JobId>> = const { Cell::new(None) };
+}
+
+/// Gives the job running on this thread its name, until it is dropped.
+struct NamedJob {
+    /// The name of the job that ran this one inline, if any.
+    previous: Option<String>,
+}
+
+impl NamedJob {
+    fn start(name: String) -> NamedJob {
+        NamedJob {
+            previous: JOB_NAME.with(|current| current.replace(Some(name))),
+        }
+    }
+}
+
+impl Drop for NamedJob {
+    fn drop(&mut self) {
+        // A job panicking on a worker keeps its name until the worker has
+        // reported the panic and clears it. Anywhere else, like on a thread
+        // that ran the job inline, nothing would clear it later.
+        if thread::panicking() && current_job_id().is_some() {
+            return;
+        }
+        let previous = self.previous.take();
+        JOB_NAME.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// The name of the job running on the current thread, if it was started
+/// with [`ThreadPool::execute_named`].
+///
+/// This is meant for panic hooks, which run on the thread that panicked
+/// before the worker catches the panic.
+pub fn current_job_name() -> Option<String> {
+    JOB_NAME.with(|current| current.borrow().clone())
+}
+
+/// An id telling apart the jobs run by a [`ThreadPool`], e.g. to correlate
+/// the log lines of a background job. See [`current_job_id`].
+///
+/// It is made of the id of the worker that ran the job and the number of
+/// jobs that worker had run before it, and displays as both, like `2-17`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JobId {
+    worker: usize,
+    sequence: u64,
+}
+
+impl JobId {
+    /// The id of the worker that ran the job.
+    pub fn worker(&self) -> usize {
+        self.worker
+    }
+
+    /// The number of jobs the worker ran before this one.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.worker, self.sequence)
+    }
+}
+
+/// The id of the job running on the current thread, if it is one of a
+/// [`ThreadPool`]'s workers.
+///
+/// No two jobs of a pool get the same id, and the ids of the jobs a worker
+/// runs go up in the order it runs them. Workers of different pools are
+/// numbered the same way, so their ids can repeat. Jobs that don't run on
+/// a worker, like those given to
+/// [`execute_blocking`](ThreadPool::execute_blocking) or run inline by
+/// [`Overflow::RunInline`], have no id.
+///
+/// ```
+/// use std::collections::HashSet;
+///
+/// use rust_server::{current_job_id, ThreadPool};
+///
+/// let pool = ThreadPool::new(4);
+/// let handles: Vec<_> = (0..100).map(|_| pool.submit(current_job_id)).collect();
+/// let ids: Vec<_> = handles
+///     .into_iter()
+///     .map(|handle| handle.join().unwrap().unwrap())
+///     .collect();
+///
+/// // Unique across the workers...
+/// assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+/// // ...and going up within each, as jobs are taken in the order they
+/// // were submitted.
+/// for worker in 0..4 {
+///     let sequences: Vec<_> = ids
+///         .iter()
+///         .filter(|id| id.worker() == worker)
+///         .map(|id| id.sequence())
+///         .collect();
+///     assert!(sequences.windows(2).all(|pair| pair[0] < pair[1]));
+/// }
+///
+/// assert_eq!(current_job_id(), None);
+/// ```
+pub fn current_job_id() -> Option<JobId> {
+    JOB_ID.with(Cell::get)
+}
+
+/// Pin the calling worker's thread to `core`, warning if that fails.
+#[cfg(feature = "affinity")]
+fn pin_to_core(id: usize, core: usize) {
+    if !affinity::pin_current_thread(core) {
+        eprintln!("Failed to pin worker {id} to core {core}; leaving it unpinned");
+    }
+}
+
+/// Without the `affinity` feature no pool is ever given cores to pin to.
+#[cfg(not(feature = "affinity"))]
+fn pin_to_core(_id: usize, _core: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Barrier, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    fn counts_panics_per_worker() {
+        let pool = ThreadPool::new(3);
+        assert_eq!(pool.panic_counts(), [(0, 0), (1, 0), (2, 0)]);
+
+        for _ in 0..4 {
+            pool.execute(|| panic!("a bad job")).unwrap();
+        }
+        // The workers survive to run more jobs.
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(42).unwrap()).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(42));
+
+        let total = || -> u64 { pool.panic_counts().iter().map(|(_, count)| count).sum() };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while total() < 4 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let ids: Vec<usize> = pool.panic_counts().iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert_eq!(total(), 4);
+    }
+
+    #[test]
+    fn blocking_jobs_do_not_starve_regular_ones() {
+        const BLOCKING: usize = 32;
+
+        let pool = ThreadPool::new(2);
+        let started = Arc::new(Barrier::new(BLOCKING + 1));
+        let release = Arc::new(Barrier::new(BLOCKING + 1));
+        for _ in 0..BLOCKING {
+            let started = Arc::clone(&started);
+            let release = Arc::clone(&release);
+            pool.execute_blocking(move || {
+                started.wait();
+                release.wait();
+            });
+        }
+        // Every blocking job is running at once, on threads of its own.
+        started.wait();
+
+        let submitted = Instant::now();
+        let (sender, receiver) = mpsc::channel();
+        for job in 0..10 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(job * 2).unwrap()).unwrap();
+        }
+        let mut results: Vec<i32> = (0..10)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        results.sort_unstable();
+        assert_eq!(results, (0..10).map(|job| job * 2).collect::<Vec<_>>());
+        assert!(submitted.elapsed() < Duration::from_secs(1));
+
+        release.wait();
+    }
+
+    #[test]
+    fn parked_workers_take_every_job_of_a_burst() {
+        // With a short keep-alive the idle workers park and wake up many
+        // times before the burst comes.
+        let pool = Arc::new(ThreadPool::new(16).worker_keep_alive(Duration::from_millis(1)));
+        thread::sleep(Duration::from_millis(100));
+
+        let (sender, receiver) = mpsc::channel();
+        let submitters: Vec<_> = (0..4)
+            .map(|submitter| {
+                let pool = Arc::clone(&pool);
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for job in 0..250 {
+                        let sender = sender.clone();
+                        pool.execute(move || sender.send(submitter * 250 + job).unwrap())
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for submitter in submitters {
+            submitter.join().unwrap();
+        }
+
+        let mut done: Vec<i32> = (0..1000)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        done.sort();
+        assert_eq!(done, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn tells_a_shutdown_from_a_disconnect() {
+        let pool = ThreadPool::new(2);
+        assert!(!pool.is_shut_down());
+        pool.execute(|| {}).unwrap();
+
+        pool.shutdown();
+        assert!(pool.is_shut_down());
+        let err = pool.execute(|| {}).unwrap_err();
+        assert_eq!(err, ExecuteError::ShutDown);
+        assert_eq!(err.to_string(), "the thread pool has been shut down");
+
+        // Workers losing their queue without a shutdown is another matter.
+        let pool = ThreadPool::new(2);
+        pool.queue.close();
+        assert!(!pool.is_shut_down());
+        let err = pool.execute(|| {}).unwrap_err();
+        assert_eq!(err, ExecuteError::Disconnected);
+        assert_eq!(
+            err.to_string(),
+            "the thread pool's workers disconnected unexpectedly"
+        );
+    }
+
+    #[test]
+    fn the_panic_hook_sees_the_job_name() {
+        // The hook is shared by every test, so it only reports the panics
+        // of this test's jobs and leaves the rest to the hook before it,
+        // which is put back at the end.
+        let (sender, names) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let previous = Arc::new(panic::take_hook());
+        panic::set_hook({
+            let previous = Arc::clone(&previous);
+            Box::new(move |info| match current_job_name() {
+                Some(name) if name.starts_with("named-job-test-") => {
+                    sender.lock().unwrap().send(name).unwrap();
+                }
+                _ => previous(info),
+            })
         });
 
-        // Return a new `Worker` instance with the given `id` and the spawned
-        // thread wrapped in `Some`.
-        Worker {
-            id,
-            thread: Some(thread),
+        let pool = ThreadPool::new(1);
+        pool.execute_named("named-job-test-render", || panic!("render failed"))
+            .unwrap();
+        assert_eq!(
+            names.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "named-job-test-render"
+        );
+
+        // Names don't outlive their jobs, whether they panicked or not.
+        let name_on_worker = || {
+            let (sender, name) = mpsc::channel();
+            pool.execute(move || sender.send(current_job_name()).unwrap())
+                .unwrap();
+            name.recv_timeout(Duration::from_secs(5)).unwrap()
+        };
+        assert_eq!(name_on_worker(), None);
+        let (sender, seen) = mpsc::channel();
+        pool.execute_named("named-job-test-ok", move || {
+            sender.send(current_job_name()).unwrap();
+        })
+        .unwrap();
+        assert_eq!(
+            seen.recv_timeout(Duration::from_secs(5))
+                .unwrap()
+                .as_deref(),
+            Some("named-job-test-ok")
+        );
+        assert_eq!(name_on_worker(), None);
+        assert_eq!(current_job_name(), None);
+
+        drop(panic::take_hook());
+        match Arc::try_unwrap(previous) {
+            Ok(previous) => panic::set_hook(previous),
+            Err(_) => unreachable!("the test's hook was dropped"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn jobs_run_inline_leave_no_name_behind() {
+        let pool = ThreadPool::new(1)
+            .queue_capacity(0)
+            .overflow(Overflow::RunInline);
+        pool.execute_named("inline", || {
+            assert_eq!(current_job_name().as_deref(), Some("inline"));
+        })
+        .unwrap();
+        assert_eq!(current_job_name(), None);
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.execute_named("inline-panic", || panic!("inline job failed"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(current_job_name(), None);
+    }
+}
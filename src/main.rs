@@ -1,103 +1,51 @@
-use rust_server::ThreadPool;
-use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
-    thread,
-    time::Duration,
-};
+use rust_server::{Response, Router, Server, ServerConfig, StaticFiles};
+use std::{process, sync::Arc, thread, time::Duration};
 
 fn main() {
-    // Create a TcpListener instance, which listens for incoming
-    // connections. The argument is the address to listen on.
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-
-    // Create a ThreadPool instance with four threads. It's a
-    // good idea to create this early in `main()` so that any
-    // configuration (like logging) is set up before the pool
-    // is used.
-    let pool = ThreadPool::new(4);
-
-    // Get an iterator over incoming connections
-    for stream in listener.incoming() {
-        // `stream` is a `Result<TcpStream>` because it may not
-        // be possible to create a `TcpStream` from the incoming
-        // connection.
-        let stream = stream.unwrap();
-
-        // Submit a job to the thread pool. The closure passed to
-        // `execute()` is the code that will be run by one of the
-        // threads in the pool. The `move` keyword ensures that the
-        // closure takes ownership of `stream` and thus that the
-        // `handle_connection()` function will get a `TcpStream`
-        // argument.
-        pool.execute(|| {
-            handle_connection(stream);
-        });
-    }
-
-    println!("Shutting down.");
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    // Take the stream and wrap it in a BufReader.
-    //
-    // `BufReader` is a type from the standard library that wraps a
-    // `Read` object and provides buffering. It's useful for the
-    // `lines()` method, which returns an iterator over the lines
-    // of text in the stream.
-    let buf_reader = BufReader::new(&stream);
-
-    // Get the first line from the stream. This is the request line.
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
-
-    // Check the request line and return the appropriate response.
-    //
-    // The first arm of the `match` checks if the request line is
-    // "GET / HTTP/1.1" and if so, returns a tuple containing the
-    // status line and the filename that should be read.
-    //
-    // The second arm of the `match` checks if the request line is
-    // "GET /sleep HTTP/1.1" and if so, it sleeps for five seconds
-    // and then returns the same tuple as the first arm.
-    //
-    // The third arm of the `match` is the default arm and is
-    // executed if the request line is neither of the above. It
-    // returns a tuple containing a 404 status line and the
-    // filename "404.html".
-    let (status_line, filename) = match &request_line[..] {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "index.html"),
-        "GET /sleep HTTP/1.1" => {
-            thread::sleep(Duration::from_secs(5));
-            ("HTTP/1.1 200 OK", "index.html")
+    // Listen on port 7878 and serve connections with a pool of four
+    // threads, unless the RUST_SERVER_* environment variables say
+    // otherwise.
+    let config = match ServerConfig::new("127.0.0.1:7878").pool_size(4).apply_env() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid configuration: {err}");
+            process::exit(1);
         }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
     };
 
-    // Read the contents of the file with the given filename.
-    let contents = fs::read_to_string(filename).unwrap();
+    // The pages are served from the configured directory, the current one
+    // by default. The handlers get to them through the router's shared
+    // state.
+    let files = Arc::new(StaticFiles::new(config.static_root_dir()));
 
-    // Get the length of the contents.
-    let length = contents.len();
-
-    // Create the response string.
-    //
-    // The first line of the response is the status line, which
-    // contains the HTTP protocol, the status code, and a
-    // description of the status code.
-    //
-    // The second line of the response contains the length of the
-    // response body, which is the contents of the file.
+    // Register the routes. "/" serves the homepage, "/sleep" serves
+    // it too but only after sleeping for five seconds, and anything
+    // else gets the 404 page.
     //
-    // The third line of the response is a blank line, which
-    // indicates that the response headers are finished.
-    //
-    // The fourth line of the response is the response body, which
-    // is the contents of the file.
-    let response = format!(
-        "{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}"
-    );
+    // A "/sleep" request holds on to its worker for the whole five
+    // seconds, so four of them at once leave no worker for anyone else.
+    let router = Router::with_state(files)
+        .get("/", |request, files| files.serve(request, "index.html"))
+        .get("/sleep", |request, files| {
+            thread::sleep(Duration::from_secs(5));
+            files.serve(request, "index.html")
+        })
+        .fallback(|_, files| match files.open("404.html") {
+            Ok(mut response) => {
+                response.set_status(404);
+                response
+            }
+            Err(_) => Response::text(404, "404 Not Found"),
+        });
 
-    // Write the response to the stream.
-    stream.write_all(response.as_bytes()).unwrap();
+    // Binding fails if the port is already in use, and running fails
+    // if the listening socket breaks. Either way there is nothing left
+    // to do but report the error.
+    let result = Server::bind(config, router).and_then(|server| server.run());
+    if let Err(err) = result {
+        eprintln!("{err}");
+        process::exit(1);
+    }
+
+    println!("Shutting down.");
 }
@@ -0,0 +1,305 @@
+//! Counting the bytes that go through a [`Server`](crate::server::Server)'s
+//! connections.
+//!
+//! Every connection is wrapped in a [`CountingStream`] that adds up what
+//! is read from and written to the socket, headers, bodies and framing
+//! included, over all the requests the connection carries. When the
+//! connection closes, its totals are added to the server's
+//! [`TrafficStats`] and handed to the
+//! [`on_connection_closed`](crate::server::Server::on_connection_closed)
+//! hook, if there is one.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use crate::server::Connection;
+
+/// What went through one connection, from accepting it to closing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    peer: Option<SocketAddr>,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl ConnectionStats {
+    /// The address of the client, if it was known.
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// The bytes received from the client.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// The bytes sent to the client.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+}
+
+/// A hook called with the stats of every closed connection.
+pub(crate) type ConnectionHook = Box<dyn Fn(&ConnectionStats) + Send + Sync>;
+
+/// The traffic of all the connections a server has closed so far.
+///
+/// Get one from [`Server::traffic`](crate::server::Server::traffic)
+/// before calling `run`. Clones share the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficStats {
+    totals: Arc<Totals>,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    connections: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl TrafficStats {
+    /// The number of connections closed.
+    pub fn connections(&self) -> u64 {
+        self.totals.connections.load(Ordering::Relaxed)
+    }
+
+    /// The bytes received over all closed connections.
+    pub fn bytes_read(&self) -> u64 {
+        self.totals.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// The bytes sent over all closed connections.
+    pub fn bytes_written(&self) -> u64 {
+        self.totals.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Add a closed connection to the totals.
+    pub(crate) fn record(&self, connection: &ConnectionStats) {
+        let totals = &self.totals;
+        totals.connections.fetch_add(1, Ordering::Relaxed);
+        totals
+            .bytes_read
+            .fetch_add(connection.bytes_read, Ordering::Relaxed);
+        totals
+            .bytes_written
+            .fetch_add(connection.bytes_written, Ordering::Relaxed);
+    }
+}
+
+/// The number of connections a server has accepted and not closed yet,
+/// whether a worker is serving them or they are still queued.
+#[derive(Debug, Default)]
+pub(crate) struct LoadGauge {
+    open: AtomicUsize,
+}
+
+impl LoadGauge {
+    /// Count a newly accepted connection until the returned guard is
+    /// dropped.
+    pub(crate) fn open(self: &Arc<LoadGauge>) -> OpenConnection {
+        self.open.fetch_add(1, Ordering::Relaxed);
+        OpenConnection {
+            gauge: Arc::clone(self),
+        }
+    }
+
+    /// The number of open connections.
+    pub(crate) fn get(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+}
+
+/// A connection counted by a [`LoadGauge`].
+pub(crate) struct OpenConnection {
+    gauge: Arc<LoadGauge>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.gauge.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The connections a server may keep alive at once.
+#[derive(Debug)]
+pub(crate) struct KeepAliveSlots {
+    limit: usize,
+    taken: AtomicUsize,
+}
+
+impl KeepAliveSlots {
+    pub(crate) fn new(limit: usize) -> KeepAliveSlots {
+        KeepAliveSlots {
+            limit,
+            taken: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take a slot for a connection until the returned guard is dropped,
+    /// unless they are all taken.
+    pub(crate) fn take(&self) -> Option<KeepAliveSlot<'_>> {
+        self.taken
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |taken| {
+                (taken < self.limit).then_some(taken + 1)
+            })
+            .ok()
+            .map(|_| KeepAliveSlot { slots: self })
+    }
+}
+
+/// A connection that may be kept alive, counted by [`KeepAliveSlots`].
+pub(crate) struct KeepAliveSlot<'a> {
+    slots: &'a KeepAliveSlots,
+}
+
+impl Drop for KeepAliveSlot<'_> {
+    fn drop(&mut self) {
+        self.slots.taken.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The connections open from each client address, up to a limit per
+/// address.
+#[derive(Debug)]
+pub(crate) struct PeerLimit {
+    limit: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PeerLimit {
+    pub(crate) fn new(limit: usize) -> PeerLimit {
+        PeerLimit {
+            limit,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a connection from `ip` until the returned guard is dropped,
+    /// unless `ip` already has as many open as it may.
+    pub(crate) fn open(self: &Arc<PeerLimit>, ip: IpAddr) -> Option<PeerConnection> {
+        let mut open = self.lock();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(PeerConnection {
+            counted: Some((Arc::clone(self), ip)),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.open
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A connection counted by a [`PeerLimit`], or one that isn't counted
+/// because there is no limit.
+pub(crate) struct PeerConnection {
+    counted: Option<(Arc<PeerLimit>, IpAddr)>,
+}
+
+impl PeerConnection {
+    pub(crate) fn uncounted() -> PeerConnection {
+        PeerConnection { counted: None }
+    }
+}
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        let Some((limit, ip)) = &self.counted else {
+            return;
+        };
+        // Forget addresses without open connections, so the map only
+        // grows with the clients connected right now.
+        let mut open = limit.lock();
+        if let Some(count) = open.get_mut(ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(ip);
+            }
+        }
+    }
+}
+
+/// The byte counts of a [`CountingStream`], shared so they can still be
+/// read once the stream has been handed to an upgrade handler.
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounts {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl ByteCounts {
+    /// The totals so far, for the connection with the client at `peer`.
+    pub(crate) fn stats(&self, peer: Option<SocketAddr>) -> ConnectionStats {
+        ConnectionStats {
+            peer,
+            bytes_read: self.read.load(Ordering::Relaxed),
+            bytes_written: self.written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A connection that counts the bytes read from and written to it.
+pub(crate) struct CountingStream<T> {
+    inner: T,
+    counts: Arc<ByteCounts>,
+}
+
+impl<T> CountingStream<T> {
+    pub(crate) fn new(inner: T, counts: Arc<ByteCounts>) -> CountingStream<T> {
+        CountingStream { inner, counts }
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Read> Read for CountingStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.counts.read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for CountingStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.counts
+            .written
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Connection> Connection for CountingStream<T> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.inner.shutdown_write()
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
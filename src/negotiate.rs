@@ -0,0 +1,243 @@
+//! Content negotiation for the `Accept` family of headers.
+//!
+//! These headers are comma-separated lists where every entry may carry a
+//! quality value, e.g. `text/html, application/json;q=0.9, */*;q=0.1`.
+//! Quality values are kept as integers in thousandths (`q=0.9` is `900`)
+//! so that comparing them never runs into floating point surprises.
+
+/// The quality of an entry without a `q` parameter.
+const DEFAULT_QUALITY: u16 = 1000;
+
+/// One entry of a quality-valued header list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QualityItem<'a> {
+    /// The entry itself, e.g. `text/html` or `gzip`, without parameters.
+    pub(crate) value: &'a str,
+    /// The quality in thousandths, between 0 and 1000.
+    pub(crate) quality: u16,
+}
+
+/// Split a header like `Accept` into its entries.
+///
+/// Entries that can't be understood, such as ones with an invalid q-value,
+/// are left out instead of failing the whole header.
+pub(crate) fn quality_list(header: &str) -> Vec<QualityItem<'_>> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let value = params.next()?.trim();
+            if value.is_empty() {
+                return None;
+            }
+
+            // Only the `q` parameter matters for negotiation, any other
+            // parameter is accepted and ignored.
+            let mut quality = DEFAULT_QUALITY;
+            for param in params {
+                let (name, param_value) = param.split_once('=')?;
+                if name.trim().eq_ignore_ascii_case("q") {
+                    quality = parse_quality(param_value.trim())?;
+                }
+            }
+
+            Some(QualityItem { value, quality })
+        })
+        .collect()
+}
+
+/// Parse a q-value such as `1`, `0.5` or `0.125` into thousandths.
+fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    // The grammar allows at most three digits after the dot, and the whole
+    // part must be a single 0 or 1.
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut thousandths = 0;
+    for (i, digit) in fraction.bytes().enumerate() {
+        thousandths += u16::from(digit - b'0') * [100, 10, 1][i];
+    }
+
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Choose the entry of `available` that best matches an `Accept` header.
+///
+/// See [`Request::prefers`](crate::request::Request::prefers) for the
+/// rules.
+pub(crate) fn media_type<'a>(accept: Option<&str>, available: &[&'a str]) -> Option<&'a str> {
+    // Keep only the entries that look like "type/subtype". A range like
+    // "*/html" is meaningless, so it is dropped as well.
+    let ranges: Vec<_> = accept
+        .map(quality_list)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|item| {
+            let (kind, subtype) = item.value.split_once('/')?;
+            if kind.is_empty() || subtype.is_empty() || (kind == "*" && subtype != "*") {
+                return None;
+            }
+            Some((kind, subtype, item.quality))
+        })
+        .collect();
+
+    // A missing header, or one with nothing usable in it, means the client
+    // will take anything.
+    if ranges.is_empty() {
+        return available.first().copied();
+    }
+
+    let mut best: Option<(&'a str, u16)> = None;
+    for &candidate in available {
+        let Some((kind, subtype)) = candidate.split_once('/') else {
+            continue;
+        };
+
+        // The most specific matching range decides the quality, so
+        // "text/html;q=0" beats "text/*" which in turn beats "*/*".
+        let quality = ranges
+            .iter()
+            .filter_map(|&(range_kind, range_subtype, quality)| {
+                let specificity = if range_kind == "*" {
+                    0
+                } else if !range_kind.eq_ignore_ascii_case(kind) {
+                    return None;
+                } else if range_subtype == "*" {
+                    1
+                } else if range_subtype.eq_ignore_ascii_case(subtype) {
+                    2
+                } else {
+                    return None;
+                };
+                Some((specificity, quality))
+            })
+            .max_by_key(|&(specificity, _)| specificity)
+            .map_or(0, |(_, quality)| quality);
+
+        // Only a strictly better quality replaces the current choice, so
+        // earlier candidates win ties.
+        if quality > 0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((candidate, quality));
+        }
+    }
+
+    best.map(|(candidate, _)| candidate)
+}
+
+/// Choose the content coding from `available`, e.g. `br` or `gzip`, that
+/// an `Accept-Encoding` header likes best.
+///
+/// A coding listed by name takes its own quality, anything else the
+/// quality of `*`. As with media types, ties go to whichever coding comes
+/// first in `available`. Without the header this returns `None`, since
+/// plenty of clients that leave it out can't decode anything.
+pub(crate) fn encoding<'a>(
+    accept_encoding: Option<&str>,
+    available: &[&'a str],
+) -> Option<&'a str> {
+    let items = accept_encoding.map(quality_list).unwrap_or_default();
+
+    let mut best: Option<(&'a str, u16)> = None;
+    for &candidate in available {
+        let quality = items
+            .iter()
+            .find(|item| item.value.eq_ignore_ascii_case(candidate))
+            .or_else(|| items.iter().find(|item| item.value == "*"))
+            .map_or(0, |item| item.quality);
+
+        if quality > 0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((candidate, quality));
+        }
+    }
+
+    best.map(|(candidate, _)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: &[&str] = &["application/json", "text/html"];
+
+    #[test]
+    fn parses_quality_values() {
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0.125"), Some(125));
+        assert_eq!(parse_quality("0"), Some(0));
+        for invalid in ["1.5", "0.1234", "2", "-0.5", "0.x", "", ".5"] {
+            assert_eq!(parse_quality(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn skips_entries_it_cannot_read() {
+        let items = quality_list("text/html;level=1, ,application/json;q=2, */*;q=0.1");
+        assert_eq!(
+            items,
+            [
+                QualityItem {
+                    value: "text/html",
+                    quality: 1000
+                },
+                QualityItem {
+                    value: "*/*",
+                    quality: 100
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_by_quality() {
+        let accept = Some("text/html;q=0.8, application/json;q=0.9");
+        assert_eq!(media_type(accept, FORMATS), Some("application/json"));
+        let accept = Some("text/html, application/json;q=0.9");
+        assert_eq!(media_type(accept, FORMATS), Some("text/html"));
+        // Ties go to the first of the available types.
+        let accept = Some("text/html, application/json");
+        assert_eq!(media_type(accept, FORMATS), Some("application/json"));
+    }
+
+    #[test]
+    fn the_most_specific_range_decides() {
+        let accept = Some("text/*;q=0.5, text/html;q=0, */*;q=0.1");
+        assert_eq!(
+            media_type(accept, &["text/html", "text/plain"]),
+            Some("text/plain")
+        );
+        assert_eq!(media_type(accept, &["text/html"]), None);
+        assert_eq!(media_type(accept, &["image/png"]), Some("image/png"));
+    }
+
+    #[test]
+    fn wildcards_and_missing_headers_accept_anything() {
+        assert_eq!(media_type(Some("*/*"), FORMATS), Some("application/json"));
+        assert_eq!(media_type(None, FORMATS), Some("application/json"));
+        // Nothing usable in the header counts as no header.
+        assert_eq!(
+            media_type(Some("garbage, */html"), FORMATS),
+            Some("application/json")
+        );
+        assert_eq!(media_type(Some("image/png"), FORMATS), None);
+        assert_eq!(media_type(None, &[]), None);
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        let available = ["br", "gzip"];
+        assert_eq!(encoding(Some("gzip, br;q=0.5"), &available), Some("gzip"));
+        assert_eq!(encoding(Some("gzip, *"), &available), Some("br"));
+        assert_eq!(encoding(Some("*, br;q=0"), &available), Some("gzip"));
+        assert_eq!(encoding(Some("identity"), &available), None);
+        assert_eq!(encoding(None, &available), None);
+    }
+}
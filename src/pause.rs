@@ -0,0 +1,60 @@
+//! Holding the workers back, for
+//! [`ThreadPool::pause`](crate::ThreadPool::pause).
+//!
+//! A worker checks the flag before taking a job, and again before running
+//! one it took, since it may have been waiting for a job when the pool was
+//! paused. A paused worker sleeps on a condition variable until the pool
+//! is resumed. Checking the flag takes no lock, so a pool that is never
+//! paused pays next to nothing for it.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Condvar, Mutex, MutexGuard,
+};
+
+/// Whether the workers of a pool may run jobs.
+#[derive(Debug, Default)]
+pub(crate) struct Pause {
+    paused: AtomicBool,
+    lock: Mutex<()>,
+    resumed: Condvar,
+}
+
+impl Pause {
+    pub(crate) fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Let the workers run jobs again, waking those that are waiting.
+    pub(crate) fn resume(&self) {
+        // Holding the lock makes sure a worker is either waiting already,
+        // or hasn't checked the flag yet.
+        let _lock = self.lock();
+        self.paused.store(false, Ordering::SeqCst);
+        self.resumed.notify_all();
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Block until the workers may run jobs.
+    pub(crate) fn wait(&self) {
+        if !self.is_paused() {
+            return;
+        }
+        let mut lock = self.lock();
+        while self.is_paused() {
+            lock = self
+                .resumed
+                .wait(lock)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,271 @@
+//! Where [`StaticFiles`](crate::static_files::StaticFiles) gets its files.
+//!
+//! Static serving only ever needs to open a file and look at its size and
+//! modification time, so that is all a [`FileProvider`] has to do. Paths
+//! handed to a provider are relative and have already been checked for
+//! `..` and the like, so a provider never has to worry about requests
+//! escaping its root.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::response::Body;
+
+/// What a [`FileProvider`] knows about one of its files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// The size of the file in bytes.
+    pub len: u64,
+    /// When the file last changed, if the provider knows.
+    pub modified: Option<SystemTime>,
+    /// Whether this is a regular file rather than a directory or
+    /// something else that can't be served.
+    pub is_file: bool,
+}
+
+/// A source of files to serve.
+///
+/// Missing files must be reported as [`io::ErrorKind::NotFound`] so they
+/// can be answered with `404 Not Found`.
+pub trait FileProvider: Send + Sync {
+    /// Look up the size and modification time of the file at `path`.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Open the file at `path` as a response body.
+    fn open(&self, path: &Path) -> io::Result<Body>;
+}
+
+/// Files read from a directory on disk.
+///
+/// File bodies are streamed from disk, not read into memory first.
+#[derive(Debug, Clone)]
+pub struct DiskFiles {
+    root: PathBuf,
+}
+
+impl DiskFiles {
+    /// Provide the files below `root`.
+    pub fn new(root: impl Into<PathBuf>) -> DiskFiles {
+        DiskFiles { root: root.into() }
+    }
+}
+
+impl FileProvider for DiskFiles {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = self.root.join(path).metadata()?;
+        Ok(Metadata {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            is_file: metadata.is_file(),
+        })
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Body> {
+        // Take the length from the open file rather than trusting an
+        // earlier call to `metadata`, in case the file changed in between.
+        let file = File::open(self.root.join(path))?;
+        let len = file.metadata()?.len();
+        Ok(Body::File(file, len))
+    }
+}
+
+/// Files kept in memory.
+///
+/// This is useful for tests, which don't have to touch the disk, and for
+/// content generated when the program starts.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFiles {
+    files: HashMap<PathBuf, (Vec<u8>, SystemTime)>,
+}
+
+impl MemoryFiles {
+    /// Create a provider without any files.
+    pub fn new() -> MemoryFiles {
+        MemoryFiles::default()
+    }
+
+    /// Add a file at `path`, e.g. `css/site.css`, replacing any file that
+    /// was there. Its modification time is the moment it was added.
+    pub fn with_file(mut self, path: &str, contents: impl Into<Vec<u8>>) -> MemoryFiles {
+        self.insert(path, contents);
+        self
+    }
+
+    /// Add a file at `path`, replacing any file that was there.
+    pub fn insert(&mut self, path: &str, contents: impl Into<Vec<u8>>) {
+        let path = path.trim_start_matches('/');
+        self.files
+            .insert(PathBuf::from(path), (contents.into(), SystemTime::now()));
+    }
+
+    fn get(&self, path: &Path) -> io::Result<&(Vec<u8>, SystemTime)> {
+        self.files
+            .get(path)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+impl FileProvider for MemoryFiles {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let (contents, modified) = self.get(path)?;
+        Ok(Metadata {
+            len: contents.len() as u64,
+            modified: Some(*modified),
+            is_file: true,
+        })
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Body> {
+        let (contents, _) = self.get(path)?;
+        Ok(Body::Bytes(contents.clone()))
+    }
+}
+
+/// Files compiled into the binary, optionally backed by another provider
+/// for the files that weren't embedded.
+///
+/// Build the list of files with [`embed_files!`](crate::embed_files), which
+/// reads them at compile time, e.g.
+/// `EmbeddedFiles::new(embed_files!["index.html"]).fallback(DiskFiles::new("public"))`.
+///
+/// Embedded files are served straight from the binary without being
+/// copied. Their modification time is unknown, so their `ETag` is a hash
+/// of their contents instead.
+pub struct EmbeddedFiles {
+    files: HashMap<PathBuf, &'static [u8]>,
+    fallback: Option<Box<dyn FileProvider>>,
+}
+
+impl EmbeddedFiles {
+    /// Provide `files`, given as pairs of path and contents.
+    pub fn new(files: &[(&str, &'static [u8])]) -> EmbeddedFiles {
+        EmbeddedFiles {
+            files: files
+                .iter()
+                .map(|&(path, contents)| (PathBuf::from(path.trim_start_matches('/')), contents))
+                .collect(),
+            fallback: None,
+        }
+    }
+
+    /// Look for the files that weren't embedded in `provider`, e.g. a
+    /// [`DiskFiles`] for content that changes without a rebuild.
+    pub fn fallback(mut self, provider: impl FileProvider + 'static) -> EmbeddedFiles {
+        self.fallback = Some(Box::new(provider));
+        self
+    }
+
+    fn fallback_or_not_found(&self) -> io::Result<&dyn FileProvider> {
+        self.fallback
+            .as_deref()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+impl FileProvider for EmbeddedFiles {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.files.get(path) {
+            Some(contents) => Ok(Metadata {
+                len: contents.len() as u64,
+                modified: None,
+                is_file: true,
+            }),
+            None => self.fallback_or_not_found()?.metadata(path),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Body> {
+        match self.files.get(path) {
+            Some(contents) => Ok(Body::Static(contents)),
+            None => self.fallback_or_not_found()?.open(path),
+        }
+    }
+}
+
+/// Embed files into the binary, for [`EmbeddedFiles::new`].
+///
+/// Paths are relative to the directory of the crate's `Cargo.toml` and are
+/// also the paths the files are served under.
+#[macro_export]
+macro_rules! embed_files {
+    ($($path:literal),* $(,)?) => {
+        &[$((
+            $path,
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $path)) as &'static [u8],
+        )),*]
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        request::{Method, Request},
+        response::Response,
+        static_files::StaticFiles,
+    };
+
+    fn get(files: &StaticFiles, path: &str) -> Response {
+        files.serve(&Request::new(Method::Get, "/"), path)
+    }
+
+    #[test]
+    fn serves_embedded_files() {
+        let files = StaticFiles::with_provider(EmbeddedFiles::new(crate::embed_files![
+            "index.html",
+            "404.html"
+        ]));
+
+        let response = get(&files, "index.html");
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.header("Last-Modified"), None);
+        assert!(response.header("ETag").is_some());
+        assert!(matches!(response.body(), Body::Static(_)));
+        assert_eq!(
+            response.body().as_bytes(),
+            Some(&include_bytes!("../index.html")[..])
+        );
+
+        let other = get(&files, "/404.html");
+        assert_eq!(other.status(), 200);
+        assert_ne!(other.header("ETag"), response.header("ETag"));
+    }
+
+    #[test]
+    fn falls_back_for_files_that_were_not_embedded() {
+        let files = StaticFiles::with_provider(
+            EmbeddedFiles::new(&[("app.js", b"embedded")]).fallback(
+                MemoryFiles::new()
+                    .with_file("app.js", "from memory")
+                    .with_file("data.json", "{}"),
+            ),
+        );
+        assert_eq!(
+            get(&files, "app.js").body().as_bytes(),
+            Some(&b"embedded"[..])
+        );
+
+        let response = get(&files, "data.json");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        assert_eq!(response.body().as_bytes(), Some(&b"{}"[..]));
+
+        assert_eq!(get(&files, "missing.js").status(), 404);
+    }
+
+    #[test]
+    fn files_that_were_not_embedded_are_not_found_without_a_fallback() {
+        let files = StaticFiles::with_provider(EmbeddedFiles::new(&[("app.js", b"embedded")]));
+        assert_eq!(get(&files, "app.js").status(), 200);
+        assert_eq!(get(&files, "index.html").status(), 404);
+    }
+}
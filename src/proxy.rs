@@ -0,0 +1,628 @@
+//! Forwarding requests to another server, so this one can sit in front of
+//! it as a reverse proxy.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use crate::{
+    request::{Method, Request},
+    response::{Body, Response},
+};
+
+/// Headers that describe a single connection rather than the message, so
+/// they are dropped in both directions instead of being forwarded.
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// How many idle connections are kept for each upstream address. More
+/// than that are closed once their response has been read.
+const MAX_IDLE_PER_UPSTREAM: usize = 8;
+
+/// How long to wait for an upstream to accept a request or answer it.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest status or header line accepted from an upstream, in bytes.
+const MAX_LINE: u64 = 8 * 1024;
+
+/// The most header lines accepted from an upstream in one response.
+const MAX_HEADERS: usize = 100;
+
+/// Forwards requests to upstream servers and relays their responses.
+///
+/// The method, target, headers and body of a request are sent on as they
+/// are, except for the hop-by-hop headers like `Connection`, which only
+/// concern the connection they arrived on. The `Host` header is kept, so
+/// the upstream sees the name the client asked for. The upstream's
+/// response is streamed back without being read into memory first.
+///
+/// Connections to upstreams are kept open and reused for later requests
+/// to the same address, once the response they carried has been read in
+/// full. A request that fails on a reused connection before any answer
+/// came back, because the upstream closed it while it was idle, is sent
+/// again on a fresh connection, as long as its method is idempotent.
+/// Upstreams that can't be reached or send something that isn't an HTTP
+/// response are answered with `502 Bad Gateway`.
+///
+/// A `Proxy` is meant to be shared, e.g. as the router's state, so that
+/// all requests draw on the same connections:
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use rust_server::{Proxy, Router};
+///
+/// // Everything the router doesn't handle itself goes upstream.
+/// let router = Router::with_state(Arc::new(Proxy::new()))
+///     .fallback(|request, proxy| proxy.forward(request, "127.0.0.1:9000"));
+/// ```
+#[derive(Default)]
+pub struct Proxy {
+    pool: Arc<Pool>,
+}
+
+impl Proxy {
+    /// Create a proxy with no open connections.
+    pub fn new() -> Proxy {
+        Proxy::default()
+    }
+
+    /// Forward `request` to the server at `upstream`, e.g.
+    /// `"127.0.0.1:9000"`, and answer with its response.
+    pub fn forward(&self, request: &Request, upstream: &str) -> Response {
+        match self.try_forward(request, upstream) {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("Failed to forward request to {upstream}: {err}");
+                Response::text(502, "502 Bad Gateway")
+            }
+        }
+    }
+
+    fn try_forward(&self, request: &Request, upstream: &str) -> io::Result<Response> {
+        let head = request_head(request, upstream);
+
+        let (mut conn, reused) = match self.pool.take(upstream) {
+            Some(conn) => (conn, true),
+            None => (connect(upstream)?, false),
+        };
+        let (status, headers) = match exchange(&mut conn, &head, request.body()) {
+            Ok(answer) => answer,
+            // Nothing came back, so the request can't have been half
+            // answered, and an idempotent one is safe to send again.
+            Err(_) if reused && is_idempotent(request.method()) => {
+                conn = connect(upstream)?;
+                exchange(&mut conn, &head, request.body())?
+            }
+            Err(err) => return Err(err),
+        };
+
+        let listed = connection_options(&headers);
+        let mut response = Response::new(status);
+        for (name, value) in &headers {
+            if !is_hop_by_hop(name, &listed) && !name.eq_ignore_ascii_case("Content-Length") {
+                response.add_header(name, value);
+            }
+        }
+
+        let framing = if request.method() == Method::Head || matches!(status, 100..=199 | 204 | 304)
+        {
+            Framing::Length(0)
+        } else if headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"))
+        {
+            // Chunked has to be the last coding, and is the only one
+            // that can be undone here.
+            if !header(&headers, "Transfer-Encoding")
+                .is_some_and(|value| value.trim().eq_ignore_ascii_case("chunked"))
+            {
+                return Err(invalid_data("unsupported transfer coding"));
+            }
+            Framing::Chunked(0)
+        } else if let Some(length) = header(&headers, "Content-Length") {
+            Framing::Length(
+                length
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid_data("invalid Content-Length"))?,
+            )
+        } else {
+            Framing::Close
+        };
+
+        // After a `101 Switching Protocols` the connection no longer
+        // speaks HTTP, so it can't be used again.
+        let keep_alive = status != 101 && !listed.iter().any(|option| option == "close");
+        let mut body = UpstreamBody {
+            conn: Some(conn),
+            framing,
+            keep_alive,
+            pool: Arc::clone(&self.pool),
+            upstream: upstream.to_string(),
+        };
+        let body = match body.framing {
+            Framing::Length(0) => {
+                body.finish();
+                Body::Empty
+            }
+            Framing::Length(length) => Body::SizedReader(Box::new(body), length),
+            Framing::Chunked(_) | Framing::Close => Body::Reader(Box::new(body)),
+        };
+        response.set_body(body);
+        Ok(response)
+    }
+}
+
+/// Idle connections to upstreams, by address.
+#[derive(Default)]
+struct Pool {
+    idle: Mutex<HashMap<String, Vec<BufReader<TcpStream>>>>,
+}
+
+impl Pool {
+    fn take(&self, upstream: &str) -> Option<BufReader<TcpStream>> {
+        self.lock().get_mut(upstream)?.pop()
+    }
+
+    fn put(&self, upstream: String, conn: BufReader<TcpStream>) {
+        let mut idle = self.lock();
+        let conns = idle.entry(upstream).or_default();
+        if conns.len() < MAX_IDLE_PER_UPSTREAM {
+            conns.push(conn);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<BufReader<TcpStream>>>> {
+        self.idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// How the end of an upstream response's body is found.
+#[derive(Debug, Clone, Copy)]
+enum Framing {
+    /// After this many more bytes.
+    Length(u64),
+    /// After the last chunk, with this many bytes left of the current one.
+    Chunked(u64),
+    /// When the upstream closes the connection.
+    Close,
+}
+
+/// The body of an upstream response, read straight off the connection.
+///
+/// Once it has been read to the end, the connection goes back to the pool
+/// for the next request. A body that is dropped early takes the
+/// connection with it, since the rest of the body is still on it.
+struct UpstreamBody {
+    conn: Option<BufReader<TcpStream>>,
+    framing: Framing,
+    keep_alive: bool,
+    pool: Arc<Pool>,
+    upstream: String,
+}
+
+impl UpstreamBody {
+    fn finish(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            if self.keep_alive && !matches!(self.framing, Framing::Close) {
+                self.pool.put(std::mem::take(&mut self.upstream), conn);
+            }
+        }
+    }
+}
+
+impl Read for UpstreamBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(conn) = self.conn.as_mut() else {
+            return Ok(0);
+        };
+
+        let read = match &mut self.framing {
+            Framing::Length(remaining) => {
+                let read = read_some(conn, buf, *remaining)?;
+                *remaining -= read as u64;
+                if *remaining == 0 {
+                    self.finish();
+                }
+                read
+            }
+            Framing::Chunked(remaining) => {
+                if *remaining == 0 {
+                    *remaining = read_chunk_size(conn)?;
+                    if *remaining == 0 {
+                        // Trailers end with an empty line, like headers.
+                        while !read_line(conn)?.is_empty() {}
+                        self.finish();
+                        return Ok(0);
+                    }
+                }
+                let read = read_some(conn, buf, *remaining)?;
+                *remaining -= read as u64;
+                if *remaining == 0 && !read_line(conn)?.is_empty() {
+                    return Err(invalid_data("chunk longer than its size"));
+                }
+                read
+            }
+            Framing::Close => {
+                let read = conn.read(buf)?;
+                if read == 0 {
+                    self.conn = None;
+                }
+                read
+            }
+        };
+        Ok(read)
+    }
+}
+
+/// Read at most `limit` bytes into `buf`, failing if the connection ends
+/// before anything could be read.
+fn read_some(conn: &mut BufReader<TcpStream>, buf: &mut [u8], limit: u64) -> io::Result<usize> {
+    let max = limit.min(buf.len() as u64) as usize;
+    let read = conn.read(&mut buf[..max])?;
+    if read == 0 && max > 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(read)
+}
+
+fn read_chunk_size(conn: &mut BufReader<TcpStream>) -> io::Result<u64> {
+    let line = read_line(conn)?;
+    // Chunk extensions after a `;` carry nothing we need.
+    let size = line.split(';').next().unwrap_or_default().trim();
+    u64::from_str_radix(size, 16).map_err(|_| invalid_data("invalid chunk size"))
+}
+
+fn connect(upstream: &str) -> io::Result<BufReader<TcpStream>> {
+    let stream = TcpStream::connect(upstream)?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+    Ok(BufReader::new(stream))
+}
+
+/// Send a request and read the head of the response to it, skipping any
+/// interim `1xx` responses.
+fn exchange(
+    conn: &mut BufReader<TcpStream>,
+    head: &str,
+    body: &[u8],
+) -> io::Result<(u16, Vec<(String, String)>)> {
+    let stream = conn.get_mut();
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    loop {
+        let (status, headers) = read_response_head(conn)?;
+        if !(100..=199).contains(&status) || status == 101 {
+            return Ok((status, headers));
+        }
+    }
+}
+
+fn read_response_head(conn: &mut BufReader<TcpStream>) -> io::Result<(u16, Vec<(String, String)>)> {
+    let status_line = read_line(conn)?;
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts
+        .next()
+        .and_then(|status| status.parse().ok())
+        .filter(|status| (100..=999).contains(status));
+    let Some(status) = status.filter(|_| version.starts_with("HTTP/1.")) else {
+        return Err(invalid_data("malformed status line"));
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(conn)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid_data("too many headers"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data("malformed header line"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    // HTTP/1.0 connections close after every response unless asked not to.
+    if version == "HTTP/1.0"
+        && !connection_options(&headers)
+            .iter()
+            .any(|option| option == "keep-alive")
+    {
+        headers.push(("Connection".to_string(), "close".to_string()));
+    }
+    Ok((status, headers))
+}
+
+/// Read one line without its line ending, failing if the connection ends
+/// first or the line is longer than [`MAX_LINE`].
+fn read_line(conn: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    conn.by_ref().take(MAX_LINE + 2).read_line(&mut line)?;
+    if line.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with('\n') {
+        return Err(invalid_data("line too long"));
+    }
+    line.truncate(line.trim_end_matches(['\r', '\n']).len());
+    Ok(line)
+}
+
+/// The request line and headers to send upstream for `request`.
+fn request_head(request: &Request, upstream: &str) -> String {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\n",
+        request.method().as_str(),
+        request.target()
+    );
+
+    let listed = connection_options(request.headers());
+    for (name, value) in request.headers() {
+        if is_hop_by_hop(name, &listed) || name.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if request.header("Host").is_none() {
+        head.push_str(&format!("Host: {upstream}\r\n"));
+    }
+    if !request.body().is_empty()
+        || matches!(request.method(), Method::Post | Method::Put | Method::Patch)
+    {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body().len()));
+    }
+
+    head.push_str("\r\n");
+    head
+}
+
+/// Remove the headers that only concern the connection a message travels
+/// on from `headers`: the standard hop-by-hop headers like `Connection`
+/// and `Transfer-Encoding`, and any others the `Connection` header names.
+///
+/// A proxy has to do this before passing a message on, which [`Proxy`]
+/// does in both directions.
+///
+/// ```
+/// use rust_server::proxy::strip_hop_by_hop;
+///
+/// let mut headers = vec![
+///     ("Connection".to_string(), "keep-alive, X-Custom".to_string()),
+///     ("X-Custom".to_string(), "for this hop".to_string()),
+///     ("Keep-Alive".to_string(), "timeout=5".to_string()),
+///     ("Content-Type".to_string(), "text/plain".to_string()),
+///     ("X-Other".to_string(), "for everyone".to_string()),
+/// ];
+/// strip_hop_by_hop(&mut headers);
+///
+/// let names: Vec<&str> = headers.iter().map(|(name, _)| name.as_str()).collect();
+/// assert_eq!(names, ["Content-Type", "X-Other"]);
+/// ```
+pub fn strip_hop_by_hop(headers: &mut Vec<(String, String)>) {
+    let listed = connection_options(headers);
+    headers.retain(|(name, _)| !is_hop_by_hop(name, &listed));
+}
+
+/// The options listed in the `Connection` headers, in lowercase. Besides
+/// `close` and `keep-alive`, these name more headers that only concern the
+/// connection.
+pub(crate) fn connection_options(headers: &[(String, String)]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+fn is_hop_by_hop(name: &str, listed: &[String]) -> bool {
+    HOP_BY_HOP.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+        || listed
+            .iter()
+            .any(|option| option.eq_ignore_ascii_case(name))
+}
+
+fn is_idempotent(method: Method) -> bool {
+    !matches!(method, Method::Post | Method::Patch | Method::Connect)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .rev()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn invalid_data(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, TcpListener},
+        thread,
+    };
+
+    use super::*;
+    use crate::{router::Router, server::Server, shutdown::ShutdownHandle, ServerConfig};
+
+    /// A server running on its own thread until the guard is dropped.
+    struct Running {
+        addr: SocketAddr,
+        shutdown: ShutdownHandle,
+        thread: Option<thread::JoinHandle<Result<(), crate::ServerError>>>,
+    }
+
+    impl Running {
+        fn start(router: Router<impl Send + Sync + 'static>) -> Running {
+            let server = Server::bind(ServerConfig::new("127.0.0.1:0"), router).unwrap();
+            let addr = server.local_addr().unwrap();
+            let shutdown = server.shutdown_handle();
+            let thread = Some(thread::spawn(move || server.run()));
+            Running {
+                addr,
+                shutdown,
+                thread,
+            }
+        }
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.shutdown.shutdown();
+            self.thread.take().unwrap().join().unwrap().unwrap();
+        }
+    }
+
+    /// The upstream: it says what it was sent.
+    fn upstream() -> Running {
+        Running::start(Router::new().fallback(|request, _| {
+            let mut seen: Vec<String> = request
+                .headers()
+                .iter()
+                .map(|(name, value)| format!("{}={value}", name.to_ascii_lowercase()))
+                .collect();
+            seen.sort();
+            let body = format!(
+                "{} {} [{}] {}",
+                request.method(),
+                request.target(),
+                seen.join(" "),
+                String::from_utf8_lossy(request.body())
+            );
+            Response::text(201, body)
+                .with_header("X-Upstream", "yes")
+                .with_header("Connection", "X-Private")
+                .with_header("X-Private", "for the proxy only")
+                .with_header("Keep-Alive", "timeout=5")
+        }))
+    }
+
+    fn forward(proxy: &Proxy, request: &str, upstream: SocketAddr) -> String {
+        let request = Request::parse(&mut request.as_bytes()).unwrap();
+        let mut output = Vec::new();
+        proxy
+            .forward(&request, &upstream.to_string())
+            .write_to(&mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn relays_requests_and_responses() {
+        let upstream = upstream();
+        let proxy = Proxy::new();
+
+        let response = forward(
+            &proxy,
+            "GET /some/path?x=1 HTTP/1.1\r\nHost: example.com\r\nX-Test: 1\r\n\
+             Connection: keep-alive, X-Hop\r\nX-Hop: drop me\r\n\r\n",
+            upstream.addr,
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 201 Created\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("\r\nX-Upstream: yes\r\n"));
+        assert!(!response.contains("X-Private") && !response.contains("Keep-Alive"));
+        assert!(
+            response.ends_with("GET /some/path?x=1 [host=example.com x-test=1] "),
+            "{response}"
+        );
+
+        let response = forward(
+            &proxy,
+            "POST /submit HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+            upstream.addr,
+        );
+        let host = format!("host={}", upstream.addr);
+        assert!(
+            response.ends_with(&format!("POST /submit [content-length=5 {host}] hello")),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn reuses_connections_to_the_upstream() {
+        let upstream = upstream();
+        let proxy = Proxy::new();
+        for _ in 0..5 {
+            let response = forward(&proxy, "GET / HTTP/1.1\r\n\r\n", upstream.addr);
+            assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        }
+        // One connection did all the work and is back in the pool.
+        let idle = proxy.pool.lock();
+        assert_eq!(idle[&upstream.addr.to_string()].len(), 1);
+    }
+
+    #[test]
+    fn proxies_for_a_server_in_front() {
+        let upstream = upstream();
+        let addr = upstream.addr.to_string();
+        let front = Running::start(
+            Router::with_state(Arc::new(Proxy::new()))
+                .get("/local", |_, _| Response::text(200, "served locally"))
+                .fallback(move |request, proxy| proxy.forward(request, &addr)),
+        );
+
+        let mut stream = TcpStream::connect(front.addr).unwrap();
+        stream
+            .write_all(b"GET /remote HTTP/1.1\r\nHost: front\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        assert!(
+            response.ends_with("GET /remote [host=front] "),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn answers_502_for_a_bad_upstream() {
+        let proxy = Proxy::new();
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = closed.local_addr().unwrap();
+        drop(closed);
+        let response = forward(&proxy, "GET / HTTP/1.1\r\n\r\n", addr);
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+
+        let garbage = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = garbage.local_addr().unwrap();
+        let talking = thread::spawn(move || {
+            let (mut stream, _) = garbage.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").unwrap();
+        });
+        let response = forward(&proxy, "GET / HTTP/1.1\r\n\r\n", addr);
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        talking.join().unwrap();
+    }
+}
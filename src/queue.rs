@@ -0,0 +1,273 @@
+//! The job queue shared by the workers of a [`ThreadPool`](crate::ThreadPool).
+//!
+//! The queue is a `VecDeque` behind a `Mutex`, with a `Condvar` idle
+//! workers wait on.
+//!
+//! # Waking up workers
+//!
+//! A worker that finds the queue empty parks on the condition variable,
+//! which releases the lock and puts the thread to sleep in the kernel, so
+//! an idle pool uses next to no CPU. The wait has a timeout, after which
+//! the worker gets to look around, e.g. at whether the pool was shut down,
+//! before parking again. Pushing a job wakes exactly one parked
+//! worker with `notify_one`, rather than every worker rushing for a
+//! single job. Closing the queue wakes them all, since they all have to
+//! exit.
+//!
+//! No job can be missed: checking for jobs and going to sleep happen
+//! under the same lock that pushing takes, so a job is either seen by the
+//! check or its notification arrives after the worker is parked. Workers
+//! check the queue again after every wakeup, which also takes care of
+//! spurious wakeups and of jobs another worker took first.
+//!
+//! # Bounded pushes
+//!
+//! The queue itself has no limit, but a push can be given one. A push that
+//! waits for room parks on a second condition variable, which every pop
+//! wakes one waiter on.
+
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// A queue of jobs of type `T`.
+pub(crate) struct Queue<T> {
+    state: Mutex<State<T>>,
+    available: Condvar,
+    /// Signalled when a job is taken, for pushes waiting for room.
+    space: Condvar,
+}
+
+/// What a worker waiting for a job got.
+pub(crate) enum Pop<T> {
+    Job(T),
+    /// The queue stayed empty for the whole wait.
+    TimedOut,
+    /// The queue is closed and empty, so the worker should exit.
+    Closed,
+}
+
+/// Why a bounded push didn't queue its job, handing the job back.
+pub(crate) enum PushError<T> {
+    Closed(T),
+    Full(T),
+}
+
+struct State<T> {
+    jobs: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> Queue<T> {
+    pub(crate) fn new() -> Queue<T> {
+        Queue {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                closed: false,
+            }),
+            available: Condvar::new(),
+            space: Condvar::new(),
+        }
+    }
+
+    /// Add a job to the back of the queue and wake up a worker for it.
+    ///
+    /// Once the queue is closed no worker may be left to run the job, so
+    /// it is handed back instead.
+    pub(crate) fn push(&self, job: T) -> Result<(), T> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(job);
+        }
+        state.jobs.push_back(job);
+        drop(state);
+        self.available.notify_one();
+        Ok(())
+    }
+
+    /// Add a job like [`Queue::push`], unless `capacity` jobs are already
+    /// waiting. Then the job is handed back, or with `wait` set, the push
+    /// waits until a worker takes a job.
+    pub(crate) fn push_bounded(
+        &self,
+        job: T,
+        capacity: usize,
+        wait: bool,
+    ) -> Result<(), PushError<T>> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(PushError::Closed(job));
+            }
+            if state.jobs.len() < capacity {
+                break;
+            }
+            if !wait {
+                return Err(PushError::Full(job));
+            }
+            state = self
+                .space
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        state.jobs.push_back(job);
+        drop(state);
+        self.available.notify_one();
+        Ok(())
+    }
+
+    /// Take the job at the front of the queue, waiting up to `timeout`
+    /// for one if the queue is empty.
+    ///
+    /// Once the queue is closed, the jobs still in it are handed out, and
+    /// then [`Pop::Closed`] tells the worker to exit.
+    pub(crate) fn pop(&self, timeout: Duration) -> Pop<T> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                drop(state);
+                self.space.notify_one();
+                return Pop::Job(job);
+            }
+            if state.closed {
+                return Pop::Closed;
+            }
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Pop::TimedOut;
+            };
+            state = self
+                .available
+                .wait_timeout(state, left)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    /// Close the queue, waking every waiting worker so it can exit, and
+    /// every waiting push so it can give up.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.available.notify_all();
+        self.space.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        // Jobs never run while the lock is held, so nothing can panic with
+        // it and leave the state half updated. A poisoned lock is still
+        // fine to use.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn parked_workers_get_every_job_of_a_burst() {
+        const WORKERS: usize = 8;
+        const JOBS: usize = 10_000;
+
+        let queue = Arc::new(Queue::new());
+        let parked = Arc::new(Barrier::new(WORKERS + 1));
+        let workers: Vec<_> = (0..WORKERS)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let parked = Arc::clone(&parked);
+                thread::spawn(move || {
+                    parked.wait();
+                    let mut taken = Vec::new();
+                    loop {
+                        match queue.pop(Duration::from_millis(50)) {
+                            Pop::Job(job) => taken.push(job),
+                            Pop::TimedOut => {}
+                            Pop::Closed => return taken,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // Let every worker find the queue empty and park.
+        parked.wait();
+        thread::sleep(Duration::from_millis(20));
+
+        for job in 0..JOBS {
+            queue.push(job).unwrap();
+        }
+        queue.close();
+
+        let mut taken: Vec<usize> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        taken.sort_unstable();
+        assert_eq!(taken, (0..JOBS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn an_empty_queue_times_out() {
+        let queue = Queue::<()>::new();
+        let started = Instant::now();
+        assert!(matches!(
+            queue.pop(Duration::from_millis(30)),
+            Pop::TimedOut
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn a_closed_queue_hands_out_its_jobs_first() {
+        let queue = Queue::new();
+        queue.push(1).unwrap();
+        queue.push(2).unwrap();
+        queue.close();
+
+        assert!(matches!(queue.push(3), Err(3)));
+        assert!(matches!(queue.pop(Duration::ZERO), Pop::Job(1)));
+        assert!(matches!(queue.pop(Duration::ZERO), Pop::Job(2)));
+        assert!(matches!(queue.pop(Duration::from_secs(5)), Pop::Closed));
+    }
+
+    #[test]
+    fn bounded_pushes_wait_for_room() {
+        let queue = Arc::new(Queue::new());
+        assert!(queue.push_bounded(1, 1, false).is_ok());
+        assert!(matches!(
+            queue.push_bounded(2, 1, false),
+            Err(PushError::Full(2))
+        ));
+
+        let pusher = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push_bounded(2, 1, true).is_ok())
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!pusher.is_finished());
+        assert!(matches!(queue.pop(Duration::ZERO), Pop::Job(1)));
+        assert!(pusher.join().unwrap());
+        assert!(matches!(queue.pop(Duration::ZERO), Pop::Job(2)));
+
+        // Closing gives up on a waiting push.
+        queue.push(3).unwrap();
+        let pusher = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || {
+                matches!(queue.push_bounded(4, 1, true), Err(PushError::Closed(4)))
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        queue.close();
+        assert!(pusher.join().unwrap());
+    }
+}
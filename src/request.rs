@@ -0,0 +1,1157 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, Read},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    budget::{BodyBudget, BodyLease},
+    cookie,
+    extensions::Extensions,
+    extract::{ExtractError, FromParams, Params},
+    negotiate,
+    uri::Uri,
+};
+
+/// The request methods understood by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Trace,
+    Connect,
+}
+
+impl Method {
+    /// Parse a method token as it appears on the request line.
+    ///
+    /// Method names are case-sensitive, so `get` is not the same as `GET`.
+    pub fn parse(token: &str) -> Option<Method> {
+        match token {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "PATCH" => Some(Method::Patch),
+            "OPTIONS" => Some(Method::Options),
+            "TRACE" => Some(Method::Trace),
+            "CONNECT" => Some(Method::Connect),
+            _ => None,
+        }
+    }
+
+    /// The method name as it is written on the wire.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+        }
+    }
+}
+
+impl Method {
+    /// Every method, in declaration order.
+    pub(crate) const ALL: [Method; 9] = [
+        Method::Get,
+        Method::Head,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Patch,
+        Method::Options,
+        Method::Trace,
+        Method::Connect,
+    ];
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The HTTP version a request was made with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    /// The version as it is written on the wire, e.g. `HTTP/1.1`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+/// The reasons a request could not be read from a stream.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The underlying stream failed.
+    Io(io::Error),
+    /// The bytes on the stream are not a valid HTTP/1.x request.
+    Malformed(&'static str),
+    /// The request line names a method the server does not know.
+    UnknownMethod(String),
+    /// The request line is longer than [`Limits::max_request_line`].
+    UriTooLong,
+    /// A header line is longer than [`Limits::max_header_line`]. The rest
+    /// of it is still on the stream, so this is never recoverable.
+    HeaderLineTooLong,
+    /// The headers take up more than [`Limits::max_header_bytes`]
+    /// together, or there are more than [`Limits::max_headers`] of them.
+    /// Like a header line that is too long, this is never recoverable.
+    TooManyHeaders,
+    /// The body is sent with a transfer coding other than `identity`,
+    /// which the server can't decode.
+    UnsupportedTransferCoding(String),
+}
+
+impl ParseError {
+    /// The status code of the response that should be sent for this error.
+    pub fn status(&self) -> u16 {
+        match self {
+            // A body that didn't fit into the memory set aside for them.
+            ParseError::Io(err) if err.kind() == io::ErrorKind::OutOfMemory => 503,
+            ParseError::Io(_) | ParseError::Malformed(_) => 400,
+            ParseError::UnknownMethod(_) | ParseError::UnsupportedTransferCoding(_) => 501,
+            ParseError::UriTooLong => 414,
+            ParseError::HeaderLineTooLong | ParseError::TooManyHeaders => 431,
+        }
+    }
+
+    /// Whether the connection can go on to the next request after this
+    /// error has been answered.
+    ///
+    /// That's only the case when the whole request was read despite the
+    /// error, as with an unknown method. Otherwise the rest of the bad
+    /// request is still on the stream, and there's no telling where the
+    /// next one starts, so the connection has to be closed.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, ParseError::UnknownMethod(_))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "i/o error while reading request: {err}"),
+            ParseError::Malformed(reason) => write!(f, "malformed request: {reason}"),
+            ParseError::UnknownMethod(method) => write!(f, "unknown method {method:?}"),
+            ParseError::UriTooLong => f.write_str("request line too long"),
+            ParseError::HeaderLineTooLong => f.write_str("header line too long"),
+            ParseError::TooManyHeaders => f.write_str("too many header fields"),
+            ParseError::UnsupportedTransferCoding(coding) => {
+                write!(f, "unsupported transfer coding {coding:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        ParseError::Io(err)
+    }
+}
+
+/// Upper bounds on what a client may send, so that a single request can't
+/// make the server buffer unbounded amounts of data.
+///
+/// A request past a limit is answered with an error status, and its
+/// connection is always closed afterwards. Reading stops as soon as the
+/// limit is hit, so the rest of the oversized line is still on the
+/// connection, and there's no telling where the next request would start:
+///
+/// ```
+/// use rust_server::{serve_connection, Router};
+///
+/// let long = "a".repeat(10_000);
+/// let requests = format!(
+///     "GET / HTTP/1.1\r\nX-Long: {long}\r\n\r\nGET / HTTP/1.1\r\n\r\n"
+/// );
+/// let mut output = Vec::new();
+/// serve_connection(requests.as_bytes(), &mut output, &Router::new());
+///
+/// let output = String::from_utf8(output).unwrap();
+/// assert!(output.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+/// assert!(output.contains("Connection: close\r\n"));
+/// // The request after it is never read.
+/// assert_eq!(output.matches("HTTP/1.1").count(), 1);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// The longest request line accepted, in bytes, not counting the line
+    /// ending. Longer request lines are answered with `414 URI Too Long`.
+    pub max_request_line: usize,
+    /// The longest single header line accepted, in bytes, not counting
+    /// the line ending. Longer header lines are answered with `431 Request
+    /// Header Fields Too Large`, which always closes the connection.
+    pub max_header_line: usize,
+    /// The most bytes all header lines together may take up, not counting
+    /// their line endings. Past it, the request is answered with `431
+    /// Request Header Fields Too Large` too, without reading further.
+    pub max_header_bytes: usize,
+    /// The most header lines a request may have. Past it, the request is
+    /// answered with `431 Request Header Fields Too Large` as well.
+    pub max_headers: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_header_line: 8 * 1024,
+            max_header_bytes: 64 * 1024,
+            max_headers: 100,
+        }
+    }
+}
+
+/// A parsed HTTP request.
+#[derive(Debug, Clone)]
+pub struct Request {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    params: Vec<(String, String)>,
+    deadline: Option<Instant>,
+    peer: Option<SocketAddr>,
+    client: Option<IpAddr>,
+    scheme: String,
+    extensions: Extensions,
+    /// Where the memory for a body read into the request comes from, if
+    /// it is limited.
+    body_budget: Option<Arc<BodyBudget>>,
+    /// The memory the body holds, shared by every copy of the request.
+    body_lease: Option<Arc<BodyLease>>,
+    /// Whether [`Request::prefers`] was asked, so the response depends on
+    /// `Accept`. Shared by every copy, so handlers running on a copy count
+    /// too.
+    negotiated: Arc<AtomicBool>,
+}
+
+impl Request {
+    /// Build a request by hand, without reading it from a stream.
+    ///
+    /// The request uses HTTP/1.1 and starts with no headers and an
+    /// empty body.
+    pub fn new(method: Method, target: &str) -> Request {
+        Request {
+            method,
+            uri: Uri::parse(target),
+            version: Version::Http11,
+            headers: Vec::new(),
+            body: Vec::new(),
+            params: Vec::new(),
+            deadline: None,
+            peer: None,
+            client: None,
+            scheme: "http".to_string(),
+            extensions: Extensions::new(),
+            body_budget: None,
+            body_lease: None,
+            negotiated: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Add a header to a hand-built request.
+    pub fn with_header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Replace the body of a hand-built request.
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    /// Give the request a deadline, like the one the server sets from
+    /// [`ServerConfig::request_timeout`](crate::server::ServerConfig::request_timeout).
+    pub fn with_deadline(mut self, deadline: Instant) -> Request {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Read one request from `reader`, using the default [`Limits`].
+    ///
+    /// This reads the request line, every header line up to the blank
+    /// line that ends the header block, and then as many body bytes as
+    /// the `Content-Length` header announces.
+    ///
+    /// Nothing assumes that a read returns a whole line. Lines are put
+    /// together from as many reads as it takes, so a request that arrives
+    /// split over many TCP segments, even one byte at a time, parses the
+    /// same as one that arrives in a single piece. Bytes after the end of
+    /// the request stay in `reader` for the next one.
+    ///
+    /// `Transfer-Encoding: identity` means no coding at all. Any other
+    /// transfer coding is an error answered with `501 Not Implemented`,
+    /// and so is a `Transfer-Encoding` header along with a
+    /// `Content-Length`, with `400 Bad Request`:
+    ///
+    /// ```
+    /// use rust_server::Request;
+    ///
+    /// let identity = "GET / HTTP/1.1\r\nTransfer-Encoding: identity\r\n\r\n";
+    /// assert!(Request::parse(&mut identity.as_bytes()).unwrap().body().is_empty());
+    ///
+    /// let gzip = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+    /// let err = Request::parse(&mut gzip.as_bytes()).unwrap_err();
+    /// assert_eq!(err.status(), 501);
+    ///
+    /// let both = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+    ///             Content-Length: 5\r\n\r\nhello";
+    /// let err = Request::parse(&mut both.as_bytes()).unwrap_err();
+    /// assert_eq!(err.status(), 400);
+    /// ```
+    ///
+    /// A path with a NUL byte, raw or percent-encoded as `%00`, or with an
+    /// encoded slash, `%2F`, is rejected with `400 Bad Request` as well,
+    /// since it is only ever sent to trick file name checks:
+    ///
+    /// ```
+    /// use rust_server::Request;
+    ///
+    /// let nul = "GET /secret.txt%00.png HTTP/1.1\r\n\r\n";
+    /// let err = Request::parse(&mut nul.as_bytes()).unwrap_err();
+    /// assert_eq!(err.status(), 400);
+    ///
+    /// let slash = "GET /static/..%2F..%2Fetc%2Fpasswd HTTP/1.1\r\n\r\n";
+    /// let err = Request::parse(&mut slash.as_bytes()).unwrap_err();
+    /// assert_eq!(err.status(), 400);
+    /// ```
+    ///
+    /// A `Content-Length` sent more than once has to be the same every
+    /// time, or the request is answered with `400 Bad Request` too:
+    ///
+    /// ```
+    /// use rust_server::Request;
+    ///
+    /// let single = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+    /// assert_eq!(Request::parse(&mut single.as_bytes()).unwrap().body(), b"hello");
+    ///
+    /// let same = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello";
+    /// assert_eq!(Request::parse(&mut same.as_bytes()).unwrap().body(), b"hello");
+    ///
+    /// let differing = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 3\r\n\r\nhello";
+    /// let err = Request::parse(&mut differing.as_bytes()).unwrap_err();
+    /// assert_eq!(err.status(), 400);
+    /// ```
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Request::parse_with_limits(reader, &Limits::default())
+    }
+
+    /// Read one request from `reader`, enforcing `limits` while reading.
+    ///
+    /// The limits are checked as the bytes arrive rather than once a whole
+    /// line has been buffered, so a client can't make the server hold on to
+    /// more than a limit's worth of data.
+    pub fn parse_with_limits<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<Request, ParseError> {
+        let (mut request, length) = Request::parse_head_with_limits(reader, limits)?;
+        request.read_body(&mut BodyReader::new(reader, length))?;
+        Ok(request)
+    }
+
+    /// Read the request line and headers from `reader`, leaving the body
+    /// on it, and return the request along with the length of its body.
+    ///
+    /// The body of a request with an unknown method is skipped, so the
+    /// error leaves `reader` at the start of the next request like it
+    /// does for [`Request::parse_with_limits`].
+    pub(crate) fn parse_head_with_limits<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<(Request, u64), ParseError> {
+        // The request line looks like "GET /index.html HTTP/1.1".
+        let request_line = read_line(reader, limits.max_request_line, || ParseError::UriTooLong)?;
+        let mut parts = request_line.split(' ');
+        let (method, target, version) =
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(method), Some(target), Some(version), None) => (method, target, version),
+                _ => return Err(ParseError::Malformed("bad request line")),
+            };
+
+        // An unknown method is only reported once the whole request has
+        // been read, so the connection can carry on with the next one.
+        let method = Method::parse(method).ok_or_else(|| method.to_string());
+
+        if target.is_empty() {
+            return Err(ParseError::Malformed("empty request target"));
+        }
+
+        // A NUL byte ends a path for the C functions behind most file
+        // systems, so `/secret.txt%00.png` could open another file than
+        // the one every check on the path saw. An encoded slash would
+        // make a single segment look like several once decoded.
+        let uri =
+            Uri::try_parse(target).map_err(|invalid| ParseError::Malformed(invalid.reason()))?;
+
+        let version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            _ => return Err(ParseError::Malformed("unsupported http version")),
+        };
+
+        // Every following line is a "Name: value" header until we reach
+        // the empty line separating the headers from the body.
+        let mut headers = Vec::new();
+        let mut header_bytes = 0;
+        loop {
+            // A line is cut off at whichever limit it hits first, the one
+            // on its own length or what is left of the one on all of them.
+            let left = limits.max_header_bytes.saturating_sub(header_bytes);
+            let line = if left < limits.max_header_line {
+                read_line(reader, left, || ParseError::TooManyHeaders)?
+            } else {
+                read_line(reader, limits.max_header_line, || {
+                    ParseError::HeaderLineTooLong
+                })?
+            };
+            if line.is_empty() {
+                break;
+            }
+            header_bytes += line.len();
+            if headers.len() == limits.max_headers {
+                return Err(ParseError::TooManyHeaders);
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(ParseError::Malformed("header line without a colon"))?;
+
+            // Whitespace between the header name and the colon is not
+            // allowed, since it is a well known way to confuse proxies.
+            if name.is_empty() || name.ends_with([' ', '\t']) {
+                return Err(ParseError::Malformed("bad header name"));
+            }
+
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+
+        // A transfer coding would have to be decoded to find where the
+        // body ends, which only works for `identity`, i.e. no coding at
+        // all. A request with both a coding and a length is a well known
+        // way to smuggle a second request past a proxy that believes the
+        // other one, so it isn't accepted either way.
+        let content_length = content_length(&headers)?;
+        let mut codings = headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"))
+            .peekable();
+        if codings.peek().is_some() && content_length.is_some() {
+            return Err(ParseError::Malformed(
+                "both transfer-encoding and content-length",
+            ));
+        }
+        if let Some(coding) = codings
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .find(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+        {
+            return Err(ParseError::UnsupportedTransferCoding(coding.to_string()));
+        }
+
+        // The body follows, if the client announced one.
+        let length = content_length.unwrap_or(0);
+
+        let method = match method {
+            Ok(method) => method,
+            Err(method) => {
+                let skipped = io::copy(&mut reader.take(length), &mut io::sink())?;
+                if skipped < length {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                return Err(ParseError::UnknownMethod(method));
+            }
+        };
+
+        let request = Request {
+            method,
+            uri,
+            version,
+            headers,
+            body: Vec::new(),
+            params: Vec::new(),
+            deadline: None,
+            peer: None,
+            client: None,
+            scheme: "http".to_string(),
+            extensions: Extensions::new(),
+            body_budget: None,
+            body_lease: None,
+            negotiated: Arc::new(AtomicBool::new(false)),
+        };
+        Ok((request, length))
+    }
+
+    /// Read the whole body from `body` into the request.
+    ///
+    /// With a [budget](Request::set_body_budget), the bytes are taken from
+    /// it as they arrive, and reading fails with `OutOfMemory` once it is
+    /// used up.
+    pub(crate) fn read_body(&mut self, body: &mut dyn Read) -> io::Result<()> {
+        let mut lease = self
+            .body_budget
+            .as_ref()
+            .map(|budget| BodyLease::new(Arc::clone(budget)));
+
+        let mut buf = Vec::new();
+        let mut chunk = [0; 8 * 1024];
+        loop {
+            let read = match body.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if let Some(lease) = &mut lease {
+                lease.grow(read as u64)?;
+            }
+            buf.extend_from_slice(&chunk[..read]);
+        }
+
+        self.body = buf;
+        self.body_lease = lease.map(Arc::new);
+        Ok(())
+    }
+
+    /// Limit the memory the body may take when it is read into the
+    /// request, along with every other body read from `budget`.
+    pub(crate) fn set_body_budget(&mut self, budget: Option<Arc<BodyBudget>>) {
+        self.body_budget = budget;
+    }
+
+    /// The request method.
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// The raw request target, including any query string.
+    pub fn target(&self) -> &str {
+        self.uri.as_str()
+    }
+
+    /// Replace the request target, e.g. to rewrite a legacy path in a
+    /// [`Router::before`](crate::router::Router::before) hook. The new
+    /// target is normalized like one read from a client.
+    pub fn set_target(&mut self, target: &str) {
+        self.uri = Uri::parse(target);
+    }
+
+    /// The request target, split into its parts.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// The normalized path part of the request target, without the query
+    /// string. See [`Uri`] for how it is normalized.
+    pub fn path(&self) -> &str {
+        self.uri.path()
+    }
+
+    /// The query string of the request target, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.uri.query()
+    }
+
+    /// The HTTP version the request was made with.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The value of the first header called `name`.
+    ///
+    /// Header names are compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set the header `name` to `value`, replacing any headers of that
+    /// name.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remove every header called `name`.
+    #[cfg_attr(not(feature = "gzip"), allow(dead_code))]
+    pub(crate) fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+    }
+
+    /// Whether the client sent `Expect: 100-continue`, asking to be told to
+    /// go ahead before it sends the body.
+    ///
+    /// The server says so the first time the body is read, so a handler
+    /// that turns the request down without looking at the body, say for
+    /// failing authentication or announcing too large a `Content-Length`,
+    /// never receives it. The connection is closed after such a response,
+    /// since the client may still send the body afterwards.
+    pub fn expects_continue(&self) -> bool {
+        self.version == Version::Http11 && self.has_token("Expect", "100-continue")
+    }
+
+    /// Whether the comma separated header `name` contains `token`, like
+    /// `close` in `Connection: close`. Tokens are compared
+    /// case-insensitively.
+    pub(crate) fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    }
+
+    /// All the headers in the order the client sent them.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The request body.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The value the router captured for the `:name` segment of the
+    /// matched route, e.g. `id` for a route registered as `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Read the query string into a `T`, see [`extract`](crate::extract).
+    /// A request without a query string reads like one with an empty
+    /// query string.
+    ///
+    /// With the `serde` feature, `T` can be any type that implements
+    /// `serde::de::DeserializeOwned`.
+    pub fn query_as<T: FromParams>(&self) -> Result<T, ExtractError> {
+        T::from_params(&Params::from_query(self.query().unwrap_or("")))
+    }
+
+    /// Read the parameters the router captured into a `T`, see
+    /// [`extract`](crate::extract).
+    ///
+    /// ```
+    /// use rust_server::{serve_connection, ExtractError, FromParams, Params, Router};
+    ///
+    /// struct Post {
+    ///     user: String,
+    ///     id: u64,
+    /// }
+    ///
+    /// impl FromParams for Post {
+    ///     fn from_params(params: &Params) -> Result<Post, ExtractError> {
+    ///         Ok(Post {
+    ///             user: params.get("user")?,
+    ///             id: params.get("id")?,
+    ///         })
+    ///     }
+    /// }
+    ///
+    /// let router = Router::new().get("/users/:user/posts/:id", |request, _| {
+    ///     let post: Post = request.params_as()?;
+    ///     Ok::<_, ExtractError>(format!("post {} by {}", post.id, post.user))
+    /// });
+    ///
+    /// let input = "GET /users/ferris/posts/7 HTTP/1.1\r\n\r\n\
+    ///              GET /users/ferris/posts/latest HTTP/1.1\r\nConnection: close\r\n\r\n";
+    /// let mut output = Vec::new();
+    /// serve_connection(input.as_bytes(), &mut output, &router);
+    ///
+    /// let output = String::from_utf8(output).unwrap();
+    /// assert!(output.contains("\r\n\r\npost 7 by ferris"));
+    /// assert!(output.ends_with("id should be a non-negative integer, not \"latest\""));
+    /// ```
+    pub fn params_as<T: FromParams>(&self) -> Result<T, ExtractError> {
+        T::from_params(&Params::from(self.params.clone()))
+    }
+
+    /// The moment by which the response should be ready, if the server
+    /// was given a request timeout.
+    ///
+    /// Nothing enforces the deadline. Handlers doing slow work can check
+    /// it and give up early, typically with `503 Service Unavailable`,
+    /// rather than keep a worker busy with an answer nobody waits for.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// How much time is left until the [deadline](Request::deadline), or
+    /// `None` without a deadline. Once the deadline has passed this is
+    /// zero.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Set the deadline of a request read from a connection.
+    pub(crate) fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// The address at the other end of the connection the request came
+    /// on, if the server knows it. Behind a reverse proxy that's the
+    /// proxy; see [`Request::client_ip`] for the client.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// The address of the client that made the request.
+    ///
+    /// That's the [peer](Request::peer_addr), unless the server
+    /// [trusts](crate::server::ServerConfig::trust_proxy) it as a proxy
+    /// and it says who it is forwarding for in `X-Forwarded-For`.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client
+    }
+
+    /// The scheme the client made the request with, `http` or `https`.
+    ///
+    /// The server itself only speaks plain HTTP, so this is `http` unless
+    /// a [trusted](crate::server::ServerConfig::trust_proxy) proxy says
+    /// otherwise in `X-Forwarded-Proto`.
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// Set where a request read from a connection came from.
+    pub(crate) fn set_client(
+        &mut self,
+        peer: Option<SocketAddr>,
+        client: Option<IpAddr>,
+        scheme: String,
+    ) {
+        self.peer = peer;
+        self.client = client;
+        self.scheme = scheme;
+    }
+
+    /// Replace the method, for method overrides.
+    pub(crate) fn set_method(&mut self, method: Method) {
+        self.method = method;
+    }
+
+    /// Replace the captured route parameters.
+    pub(crate) fn set_params(&mut self, params: Vec<(String, String)>) {
+        self.params = params;
+    }
+
+    /// The cookies sent in the `Cookie` header, by name.
+    pub fn cookies(&self) -> HashMap<String, String> {
+        cookie::parse_cookies(
+            self.headers
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("Cookie"))
+                .map(|(_, value)| value.as_str()),
+        )
+    }
+
+    /// The values attached to the request, e.g. by a wrapper for the
+    /// handler it wraps.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// The values attached to the request, for adding to them.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Pick the media type from `available` the client likes best.
+    ///
+    /// The `Accept` header is parsed together with its q-values, and each
+    /// of the `available` types is scored by the most specific media range
+    /// that matches it. The type with the highest score wins; ties go to
+    /// whichever type comes first in `available`, so list the type you
+    /// would rather serve first.
+    ///
+    /// A request without an `Accept` header accepts anything, so the first
+    /// available type is returned. Malformed entries in the header are
+    /// skipped. `None` means the client accepts none of the types, which
+    /// usually calls for a `406 Not Acceptable` response.
+    ///
+    /// The server adds `Vary: Accept` to the response of a request this
+    /// was called for, so caches keep the variants apart.
+    pub fn prefers<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        self.negotiated.store(true, Ordering::Relaxed);
+        negotiate::media_type(self.header("Accept"), available)
+    }
+
+    /// Whether [`Request::prefers`] was called for this request or a copy
+    /// of it.
+    pub(crate) fn negotiated(&self) -> bool {
+        self.negotiated.load(Ordering::Relaxed)
+    }
+}
+
+/// The body of a request, read straight from the connection.
+///
+/// It ends after the length the request announced. A connection that ends
+/// before that is an `UnexpectedEof` error, rather than the end of the
+/// body, so a cut-off upload can't pass for a complete one.
+pub(crate) struct BodyReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> BodyReader<R> {
+    pub(crate) fn new(inner: R, length: u64) -> BodyReader<R> {
+        BodyReader {
+            inner,
+            remaining: length,
+        }
+    }
+
+    /// The number of body bytes that haven't been read yet.
+    pub(crate) fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// The reader the body is read from.
+    pub(crate) fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = self.remaining.min(buf.len() as u64) as usize;
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "connection closed with {} body bytes missing",
+                    self.remaining
+                ),
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// The body length the `Content-Length` headers announce, if any.
+///
+/// A length may be repeated, in several headers or as a list in one, as
+/// long as it is the same every time. Lengths that differ are rejected,
+/// since a proxy in front of the server may pick another one than the
+/// server, and take part of the body for the next request or the other
+/// way round.
+fn content_length(headers: &[(String, String)]) -> Result<Option<u64>, ParseError> {
+    let mut length = None;
+    for value in headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .flat_map(|(_, value)| value.split(','))
+    {
+        let value: u64 = value
+            .trim()
+            .parse()
+            .map_err(|_| ParseError::Malformed("bad content-length"))?;
+        if length.is_some_and(|length| length != value) {
+            return Err(ParseError::Malformed("conflicting content-lengths"));
+        }
+        length = Some(value);
+    }
+    Ok(length)
+}
+
+/// Read a single CRLF (or bare LF) terminated line, without the line ending.
+///
+/// If the line turns out to be longer than `limit` bytes, reading stops
+/// right away and the error built by `too_long` is returned.
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+    too_long: impl Fn() -> ParseError,
+) -> Result<String, ParseError> {
+    let mut line = Vec::new();
+
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        // If the stream ended before a newline, the request was cut short.
+        if available.is_empty() {
+            return Err(ParseError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        match available.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                line.extend_from_slice(&available[..end]);
+                reader.consume(end + 1);
+                break;
+            }
+            None => {
+                line.extend_from_slice(available);
+                let consumed = available.len();
+                reader.consume(consumed);
+            }
+        }
+
+        // The last byte might be the carriage return of the line ending,
+        // so allow one byte more than the limit until the newline arrives.
+        if line.len() > limit.saturating_add(1) {
+            return Err(too_long());
+        }
+    }
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    if line.len() > limit {
+        return Err(too_long());
+    }
+
+    String::from_utf8(line).map_err(|_| ParseError::Malformed("line is not valid utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+
+    use super::*;
+    use crate::{response::Response, router::Router, serve_connection};
+
+    fn limits(max_header_line: usize, max_header_bytes: usize, max_headers: usize) -> Limits {
+        Limits {
+            max_header_line,
+            max_header_bytes,
+            max_headers,
+            ..Limits::default()
+        }
+    }
+
+    fn parse(input: impl Read, limits: &Limits) -> Result<Request, ParseError> {
+        Request::parse_with_limits(&mut BufReader::new(input), limits)
+    }
+
+    #[test]
+    fn rejects_a_long_request_line_while_reading_it() {
+        let endless = b"GET /".chain(io::repeat(b'a'));
+        let err = parse(endless, &Limits::default()).unwrap_err();
+        assert!(matches!(err, ParseError::UriTooLong));
+        assert_eq!(err.status(), 414);
+        assert!(!err.is_recoverable());
+    }
+
+    #[test]
+    fn accepts_request_lines_up_to_the_limit() {
+        let limits = Limits {
+            max_request_line: 100,
+            ..Limits::default()
+        };
+        // "GET " and " HTTP/1.1" take up 13 bytes of the line.
+        let target = format!("/{}", "a".repeat(86));
+        let input = format!("GET {target} HTTP/1.1\r\n\r\n");
+        assert_eq!(parse(input.as_bytes(), &limits).unwrap().path(), target);
+
+        let input = format!("GET {target}a HTTP/1.1\r\n\r\n");
+        let err = parse(input.as_bytes(), &limits).unwrap_err();
+        assert!(matches!(err, ParseError::UriTooLong));
+    }
+
+    #[test]
+    fn rejects_a_long_header_line_while_reading_it() {
+        // The header line never ends, so the limit has to be hit before
+        // the newline arrives.
+        let endless = b"GET / HTTP/1.1\r\nX-Long: ".chain(io::repeat(b'a'));
+        let err = parse(endless, &Limits::default()).unwrap_err();
+        assert!(matches!(err, ParseError::HeaderLineTooLong));
+        assert_eq!(err.status(), 431);
+        assert!(!err.is_recoverable());
+    }
+
+    #[test]
+    fn accepts_header_lines_up_to_the_limit() {
+        let input = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(92));
+        let request = parse(input.as_bytes(), &limits(100, 1000, 10)).unwrap();
+        assert_eq!(request.header("X-Long").unwrap().len(), 92);
+
+        let input = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(93));
+        let err = parse(input.as_bytes(), &limits(100, 1000, 10)).unwrap_err();
+        assert!(matches!(err, ParseError::HeaderLineTooLong));
+    }
+
+    #[test]
+    fn caps_the_bytes_of_all_headers_together() {
+        let header = format!("X-Filler: {}\r\n", "a".repeat(90));
+        let input = format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(10));
+        assert!(parse(input.as_bytes(), &limits(1000, 1000, 100)).is_ok());
+
+        let input = format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(11));
+        let err = parse(input.as_bytes(), &limits(1000, 1000, 100)).unwrap_err();
+        assert!(matches!(err, ParseError::TooManyHeaders));
+        assert_eq!(err.status(), 431);
+
+        // Endless headers are cut off once their total is reached.
+        let endless =
+            b"GET / HTTP/1.1\r\n".chain(io::repeat(b'a').take(1 << 20).chain(&b"\r\n"[..]));
+        let err = parse(endless, &limits(1 << 21, 1000, 100)).unwrap_err();
+        assert!(matches!(err, ParseError::TooManyHeaders));
+    }
+
+    #[test]
+    fn caps_the_number_of_headers() {
+        let input = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(10));
+        assert_eq!(
+            parse(input.as_bytes(), &limits(100, 1000, 10))
+                .unwrap()
+                .headers()
+                .len(),
+            10
+        );
+
+        let input = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(11));
+        let err = parse(input.as_bytes(), &limits(100, 1000, 10)).unwrap_err();
+        assert!(matches!(err, ParseError::TooManyHeaders));
+    }
+
+    #[test]
+    fn closes_a_keep_alive_connection_after_431() {
+        let router = Router::new().get("/", |_, _| "hello");
+        for headers in [
+            format!("X-Long: {}\r\n", "a".repeat(10_000)),
+            "X-A: b\r\n".repeat(101),
+        ] {
+            let input = format!("GET / HTTP/1.1\r\n{headers}\r\nGET / HTTP/1.1\r\n\r\n");
+            let mut output = Vec::new();
+            serve_connection(input.as_bytes(), &mut output, &router);
+
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+            assert!(output.contains("Connection: close\r\n"));
+            assert!(!output.contains("hello"));
+        }
+    }
+
+    #[test]
+    fn answers_a_long_request_line_with_414() {
+        let router = Router::new().get("/", |_, _| Response::text(200, "hello"));
+        let input = format!(
+            "GET /{} HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+            "a".repeat(10_000)
+        );
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &router);
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(!output.contains("hello"));
+    }
+
+    #[test]
+    fn time_remaining_counts_down_to_zero() {
+        assert_eq!(Request::new(Method::Get, "/").time_remaining(), None);
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let request = Request::new(Method::Get, "/").with_deadline(deadline);
+        assert_eq!(request.deadline(), Some(deadline));
+        let first = request.time_remaining().unwrap();
+        assert!(first <= Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(20));
+        let second = request.time_remaining().unwrap();
+        assert!(second < first && second <= Duration::from_millis(80));
+
+        let request = Request::new(Method::Get, "/").with_deadline(Instant::now());
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(request.time_remaining(), Some(Duration::ZERO));
+    }
+
+    /// A reader that hands out at most one byte per read, like a client
+    /// sending every byte in its own TCP segment.
+    struct OneByte<R>(R);
+
+    impl<R: Read> Read for OneByte<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn parses_requests_that_arrive_one_byte_at_a_time() {
+        let input = "POST /submit?x=1 HTTP/1.1\r\n\
+                     Host: example.com\r\n\
+                     Content-Type: text/plain\r\n\
+                     Content-Length: 11\r\n\r\n\
+                     hello world\
+                     GET /next HTTP/1.1\r\n\r\n";
+        let mut reader = BufReader::with_capacity(1, OneByte(input.as_bytes()));
+
+        let request = Request::parse(&mut reader).unwrap();
+        assert_eq!(request.method(), Method::Post);
+        assert_eq!(request.path(), "/submit");
+        assert_eq!(request.query(), Some("x=1"));
+        assert_eq!(request.header("host"), Some("example.com"));
+        assert_eq!(request.header("Content-Type"), Some("text/plain"));
+        assert_eq!(request.body(), b"hello world");
+
+        // The next request is left in the reader, whole.
+        let request = Request::parse(&mut reader).unwrap();
+        assert_eq!(request.path(), "/next");
+    }
+
+    #[test]
+    fn serves_requests_that_arrive_one_byte_at_a_time() {
+        let router = Router::new()
+            .get("/", |_, _| Response::text(200, "index"))
+            .post("/echo", |request, _| {
+                Response::new(200).with_body(request.body().to_vec())
+            });
+        let input = "GET / HTTP/1.1\r\n\r\n\
+                     POST /echo HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody";
+        let mut output = Vec::new();
+        serve_connection(OneByte(input.as_bytes()), &mut output, &router);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(output.contains("\r\n\r\nindexHTTP/1.1 "));
+        assert!(output.ends_with("\r\n\r\nbody"));
+    }
+}
@@ -0,0 +1,884 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    cookie::{self, CookieAttributes, CookieError},
+    error::HandlerError,
+    request::{Method, Version},
+};
+
+/// The body of a [`Response`].
+pub enum Body {
+    /// No body at all.
+    Empty,
+    /// Bytes held in memory.
+    Bytes(Vec<u8>),
+    /// Text held in memory, sent as UTF-8.
+    Text(String),
+    /// Bytes that live as long as the program, like files embedded into
+    /// the binary. Serving them doesn't copy them.
+    Static(&'static [u8]),
+    /// The next `len` bytes of an open file.
+    ///
+    /// `len` is usually the size the file's metadata gives when it is
+    /// opened, and exactly that many bytes are sent, however the file
+    /// changes meanwhile.
+    ///
+    /// The file is copied to the client in small chunks when the response
+    /// is written, so serving a file takes the same amount of memory
+    /// however large it is.
+    File(File, u64),
+    /// The whole file at a path, opened when the response is written.
+    Path(PathBuf),
+    /// Everything a reader produces until it ends. The length isn't known
+    /// up front, so the body is sent with chunked transfer encoding.
+    Reader(Box<dyn Read + Send>),
+    /// The next `len` bytes a reader produces, like a response relayed
+    /// from another server. They are streamed with a `Content-Length`,
+    /// the same way as a [`Body::File`].
+    SizedReader(Box<dyn Read + Send>, u64),
+}
+
+impl Body {
+    /// The number of bytes in the body, or `None` if that isn't known
+    /// before the body is sent.
+    ///
+    /// For a [`Body::Path`] this looks up the size of the file, and is
+    /// `None` if it can't.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Text(text) => Some(text.len() as u64),
+            Body::Static(bytes) => Some(bytes.len() as u64),
+            Body::File(_, len) | Body::SizedReader(_, len) => Some(*len),
+            Body::Path(path) => fs::metadata(path).ok().map(|metadata| metadata.len()),
+            Body::Reader(_) => None,
+        }
+    }
+
+    /// Whether the body is known to have no bytes at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// The bytes of a body held in memory, or `None` for a streamed body.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Empty => Some(&[]),
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Text(text) => Some(text.as_bytes()),
+            Body::Static(bytes) => Some(bytes),
+            Body::File(..) | Body::Path(_) | Body::Reader(_) | Body::SizedReader(..) => None,
+        }
+    }
+
+    /// Open a [`Body::Path`] as a [`Body::File`], so its length is fixed
+    /// for as long as it is being sent. Other bodies are returned as they
+    /// are.
+    pub fn open(self) -> io::Result<Body> {
+        match self {
+            Body::Path(path) => {
+                let file = File::open(path)?;
+                let len = file.metadata()?.len();
+                Ok(Body::File(file, len))
+            }
+            body => Ok(body),
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => f.write_str("Empty"),
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::Text(text) => f.debug_tuple("Text").field(text).finish(),
+            Body::Static(bytes) => f.debug_tuple("Static").field(bytes).finish(),
+            Body::File(file, len) => f.debug_tuple("File").field(file).field(len).finish(),
+            Body::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            Body::Reader(_) => f.write_str("Reader(..)"),
+            Body::SizedReader(_, len) => f
+                .debug_tuple("SizedReader")
+                .field(&"..")
+                .field(len)
+                .finish(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Text(text)
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Text(text.to_string())
+    }
+}
+
+/// An HTTP response that can be written to a stream.
+#[derive(Debug)]
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+    cache_ttl: Option<Duration>,
+    error: Option<Box<HandlerError>>,
+}
+
+impl Response {
+    /// Create a response with the given status code, no headers and an
+    /// empty body.
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+            cache_ttl: None,
+            error: None,
+        }
+    }
+
+    /// Create a response with an HTML body.
+    pub fn html(status: u16, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body.into())
+    }
+
+    /// Create a response with a plain text body.
+    pub fn text(status: u16, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body.into())
+    }
+
+    /// Add a header, keeping any headers with the same name.
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.add_header(name, value);
+        self
+    }
+
+    /// Replace the body.
+    pub fn with_body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// The status code.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Change the status code.
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    /// The value of the first header called `name`, compared
+    /// case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All the headers in the order they were added.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Add a header, keeping any headers with the same name.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Set a header, replacing every header with the same name.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.add_header(name, value);
+    }
+
+    /// Remove every header called `name`.
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+    }
+
+    /// Add `field` to the `Vary` header, which tells caches the response
+    /// depends on that request header, e.g. `Accept-Encoding` for a
+    /// compressed response.
+    ///
+    /// Fields already listed aren't added again, and neither is anything
+    /// once the header is `*`.
+    pub fn add_vary(&mut self, field: &str) {
+        let Some(vary) = self.header("Vary") else {
+            self.add_header("Vary", field);
+            return;
+        };
+        let listed = vary
+            .split(',')
+            .map(str::trim)
+            .any(|listed| listed == "*" || listed.eq_ignore_ascii_case(field));
+        if !listed {
+            let vary = format!("{vary}, {field}");
+            self.set_header("Vary", &vary);
+        }
+    }
+
+    /// The response body.
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    /// Take the body out of the response, leaving an empty one behind.
+    pub fn take_body(&mut self) -> Body {
+        std::mem::replace(&mut self.body, Body::Empty)
+    }
+
+    /// Replace the body.
+    pub fn set_body(&mut self, body: impl Into<Body>) {
+        self.body = body.into();
+    }
+
+    /// Mark the response as one a
+    /// [`ResponseCache`](crate::cache::ResponseCache) may keep for `ttl`
+    /// and answer later requests for the same target with.
+    ///
+    /// This only concerns the server's own cache, no `Cache-Control`
+    /// header is added for clients.
+    pub fn cache_for(mut self, ttl: Duration) -> Response {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// How long the response may be cached, if it was marked with
+    /// [`Response::cache_for`].
+    pub fn cache_ttl(&self) -> Option<Duration> {
+        self.cache_ttl
+    }
+
+    /// The error this response was made from, if it was converted from a
+    /// [`HandlerError`].
+    pub fn error(&self) -> Option<&HandlerError> {
+        self.error.as_deref()
+    }
+
+    pub(crate) fn set_error(&mut self, error: HandlerError) {
+        self.error = Some(Box::new(error));
+    }
+
+    pub(crate) fn take_error(&mut self) -> Option<Box<HandlerError>> {
+        self.error.take()
+    }
+
+    /// Add a `Set-Cookie` header asking the client to store a cookie.
+    ///
+    /// The name must be a valid HTTP token and the value may only contain
+    /// the characters allowed by RFC 6265, which in particular rules out
+    /// control characters, whitespace, quotes, commas, semicolons and
+    /// backslashes. Anything else is rejected rather than escaped, so a
+    /// cookie can never be used to smuggle extra headers into a response.
+    pub fn set_cookie(
+        &mut self,
+        name: &str,
+        value: &str,
+        attrs: &CookieAttributes,
+    ) -> Result<(), CookieError> {
+        let header = cookie::set_cookie_header(name, value, attrs)?;
+        self.add_header("Set-Cookie", &header);
+        Ok(())
+    }
+
+    /// Write the status line, the headers and the body to `writer`.
+    ///
+    /// A file body is streamed with `io::copy`, which goes through a small
+    /// fixed-size buffer, or no buffer at all where the platform can copy
+    /// from a file to a socket directly. Exactly the announced number of
+    /// bytes is sent, even if the file changes meanwhile: anything it
+    /// grows by is left out, and anything it shrinks by is padded with
+    /// zeros. A [`Body::SizedReader`] that ends early fails with
+    /// `UnexpectedEof` instead, since the connection can't be used for
+    /// another response after that. A [`Body::Path`] that can't be opened
+    /// fails before anything is written.
+    ///
+    /// A `Content-Length` header is always computed from the body, so any
+    /// `Content-Length` or `Transfer-Encoding` set by hand is ignored. A
+    /// [`Body::Reader`] has no length and is sent in chunks instead.
+    /// Responses that can't have a body, `1xx`, `204 No Content` and `304
+    /// Not Modified`, get neither header and no body at all.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        self.write_for(writer, Method::Get, Version::Http11, 0)
+            .map(|_| ())
+    }
+
+    /// Write the response for a request made with `method` and `version`.
+    ///
+    /// The response to a `HEAD` request gets the headers a `GET` would,
+    /// `Content-Length` included, but its body is never read or sent. One
+    /// that has no body at all may say how long it would be with a
+    /// `Content-Length` of its own instead, which is then sent as is.
+    ///
+    /// HTTP/1.0 clients don't understand chunked transfer encoding, so a
+    /// body of unknown length is sent to them as is, and the end of the
+    /// connection marks its end. The caller has to close the connection
+    /// afterwards.
+    ///
+    /// A body held in memory that is no longer than `single_write_limit`
+    /// goes out in the same write as the headers.
+    ///
+    /// Returns the number of bytes written after the headers, exactly as
+    /// they went out, so chunk framing is included.
+    pub(crate) fn write_for<W: Write>(
+        self,
+        writer: &mut W,
+        method: Method,
+        version: Version,
+        single_write_limit: u64,
+    ) -> io::Result<u64> {
+        let has_body = !matches!(self.status, 100..=199 | 204 | 304);
+        let declared_length = self.declared_length(method);
+        let mut head = self.head();
+        let body = if has_body {
+            self.body.open()?
+        } else {
+            Body::Empty
+        };
+        let length = declared_length.or(body.len());
+        let chunked = has_body && length.is_none() && version == Version::Http11;
+
+        if has_body {
+            match length {
+                Some(length) => head.push_str(&format!("Content-Length: {length}\r\n")),
+                None if chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+                None => {}
+            }
+        }
+
+        // The blank line after the headers tells the client that the body
+        // comes next.
+        head.push_str("\r\n");
+
+        if method != Method::Head && length.is_some_and(|length| length <= single_write_limit) {
+            if let Some(bytes) = body.as_bytes() {
+                let mut buf = head.into_bytes();
+                buf.extend_from_slice(bytes);
+                writer.write_all(&buf)?;
+                writer.flush()?;
+                return Ok(bytes.len() as u64);
+            }
+        }
+
+        writer.write_all(head.as_bytes())?;
+        if method == Method::Head {
+            writer.flush()?;
+            return Ok(0);
+        }
+
+        // Count the body bytes on their way to the writer rather than
+        // adding up lengths, so whatever encoding the body is sent with is
+        // accounted for.
+        let mut writer = CountingWriter::new(writer);
+        let writer = &mut writer;
+        match body {
+            Body::Empty | Body::Path(_) => {}
+            Body::Bytes(bytes) => writer.write_all(&bytes)?,
+            Body::Text(text) => writer.write_all(text.as_bytes())?,
+            Body::Static(bytes) => writer.write_all(bytes)?,
+            Body::File(file, len) => {
+                // A file that grows while it is sent is cut off at the
+                // announced length by `take`. One that shrinks is made up
+                // for with zeros, so the client still gets the bytes it
+                // was promised, and the next response starts where it
+                // expects.
+                let copied = io::copy(&mut file.take(len), writer)?;
+                if copied < len {
+                    eprintln!("File ended after {copied} of {len} bytes; padding the response");
+                    io::copy(&mut io::repeat(0).take(len - copied), writer)?;
+                }
+            }
+            Body::SizedReader(reader, len) => {
+                let copied = io::copy(&mut reader.take(len), writer)?;
+                if copied < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("body ended after {copied} of {len} bytes"),
+                    ));
+                }
+            }
+            Body::Reader(mut reader) if chunked => write_chunked(&mut reader, writer)?,
+            Body::Reader(mut reader) => {
+                io::copy(&mut reader, writer)?;
+            }
+        }
+        writer.flush()?;
+        Ok(writer.count())
+    }
+
+    /// The `Content-Length` set by hand on a response to a `HEAD` request
+    /// that has no body, standing for the length of the body a `GET` would
+    /// get.
+    pub(crate) fn declared_length(&self, method: Method) -> Option<u64> {
+        if method != Method::Head || !matches!(self.body, Body::Empty) {
+            return None;
+        }
+        self.header("Content-Length")?.trim().parse().ok()
+    }
+
+    /// Write only the status line and the headers, leaving the body out
+    /// and without any `Content-Length` or `Transfer-Encoding`.
+    ///
+    /// This is for responses after which the connection stops speaking
+    /// HTTP, like the answer to a `CONNECT` request.
+    pub(crate) fn write_head<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = self.head();
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+        writer.flush()
+    }
+
+    /// The status line and the headers, except the ones describing how the
+    /// body is framed, which are worked out from the body itself.
+    fn head(&self) -> String {
+        // The status line contains the HTTP protocol, the status code
+        // and a description of the status code.
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+
+        for (name, value) in &self.headers {
+            if name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                continue;
+            }
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head
+    }
+}
+
+/// Anything a handler can answer with.
+///
+/// Handlers registered with a [`Router`](crate::router::Router) may
+/// return any type implementing this: a [`Response`], text as a `&str` or
+/// `String`, bytes as a `Vec<u8>`, or any of these paired with a status
+/// like `(404, "no such user")`. It also includes a `Result` whose error
+/// implements it too, like [`HandlerError`], so handlers can use `?`:
+///
+/// ```no_run
+/// use std::fs;
+///
+/// use rust_server::{HandlerError, Response, Router};
+///
+/// let router = Router::new().get("/report", |_, _| -> Result<Response, HandlerError> {
+///     let text = fs::read_to_string("report.txt")?;
+///     Ok(Response::text(200, text))
+/// });
+/// ```
+///
+/// Implementing it for an error type of your own decides the status each
+/// error is answered with.
+pub trait IntoResponse {
+    /// Turn `self` into the response sent to the client.
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+/// A `200 OK` with a plain text body.
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        Response::text(200, self)
+    }
+}
+
+/// A `200 OK` with a plain text body.
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::text(200, self)
+    }
+}
+
+/// A `200 OK` with a binary body, sent as `application/octet-stream`.
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "application/octet-stream")
+            .with_body(self)
+    }
+}
+
+/// The response for the second element with the status replaced, e.g.
+/// `(201, "created")`.
+impl<T: IntoResponse> IntoResponse for (u16, T) {
+    fn into_response(self) -> Response {
+        let (status, value) = self;
+        let mut response = value.into_response();
+        response.set_status(status);
+        response
+    }
+}
+
+impl IntoResponse for HandlerError {
+    fn into_response(self) -> Response {
+        Response::from(self)
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+/// A writer that passes everything on to another one, keeping count of
+/// the bytes written.
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub(crate) fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner, count: 0 }
+    }
+
+    /// The number of bytes written so far.
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The size of the chunks a [`Body::Reader`] is sent in, at most.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Copy everything `reader` produces to `writer` with chunked transfer
+/// encoding: every chunk is preceded by its length in hex, and an empty
+/// chunk marks the end.
+fn write_chunked<W: Write>(reader: &mut dyn Read, writer: &mut W) -> io::Result<()> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        write!(writer, "{read:x}\r\n")?;
+        writer.write_all(&buf[..read])?;
+        writer.write_all(b"\r\n")?;
+    }
+    writer.write_all(b"0\r\n\r\n")
+}
+
+/// The standard description of a status code, as sent on the status line.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        507 => "Insufficient Storage",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path in the temporary directory, removed again on drop.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> TempFile {
+            let path = std::env::temp_dir().join(format!(
+                "rust-server-response-{}-{name}",
+                std::process::id()
+            ));
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn written(body: Body) -> String {
+        let mut output = Vec::new();
+        Response::new(200)
+            .with_body(body)
+            .write_to(&mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn writes_bodies_of_known_length() {
+        assert_eq!(
+            written(Body::Empty),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        );
+        for body in [
+            Body::Bytes(b"hello".to_vec()),
+            Body::Text("hello".to_string()),
+            Body::Static(b"hello"),
+            Body::SizedReader(Box::new(&b"hello, world"[..]), 5),
+        ] {
+            assert_eq!(
+                written(body),
+                "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"
+            );
+        }
+    }
+
+    #[test]
+    fn writes_files() {
+        let file = TempFile::new("file", b"from disk");
+        assert_eq!(
+            written(Body::Path(file.0.clone())),
+            "HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nfrom disk"
+        );
+        assert_eq!(
+            written(Body::File(File::open(&file.0).unwrap(), 4)),
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nfrom"
+        );
+    }
+
+    #[test]
+    fn counts_the_body_bytes_that_went_out() {
+        let send = |response: Response| {
+            let mut output = Vec::new();
+            let sent = response
+                .write_for(&mut output, Method::Get, Version::Http11, 0)
+                .unwrap();
+            let end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            (output.split_off(end), sent)
+        };
+
+        let (body, sent) = send(Response::text(200, "hello"));
+        assert_eq!(body, b"hello");
+        assert_eq!(sent, 5);
+
+        // Chunk framing counts too.
+        let (body, sent) =
+            send(Response::new(200).with_body(Body::Reader(Box::new(&b"hello"[..]))));
+        assert_eq!(body, b"5\r\nhello\r\n0\r\n\r\n");
+        assert_eq!(sent, body.len() as u64);
+
+        assert_eq!(send(Response::new(204)).1, 0);
+    }
+
+    #[test]
+    fn a_missing_file_fails_before_anything_is_written() {
+        let mut output = Vec::new();
+        let missing = std::env::temp_dir().join("rust-server-response-missing");
+        let err = Response::new(200)
+            .with_body(Body::Path(missing))
+            .write_to(&mut output)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn writes_readers_in_chunks() {
+        assert_eq!(
+            written(Body::Reader(Box::new(&b"hello"[..]))),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+        );
+
+        let contents = vec![b'x'; CHUNK_SIZE + 100];
+        let output = written(Body::Reader(Box::new(io::Cursor::new(contents))));
+        let (_, chunks) = output.split_once("\r\n\r\n").unwrap();
+        let expected = format!(
+            "2000\r\n{}\r\n64\r\n{}\r\n0\r\n\r\n",
+            "x".repeat(CHUNK_SIZE),
+            "x".repeat(100)
+        );
+        assert_eq!(chunks, expected);
+    }
+
+    #[test]
+    fn http_1_0_clients_get_readers_until_the_connection_closes() {
+        let mut output = Vec::new();
+        let sent = Response::new(200)
+            .with_body(Body::Reader(Box::new(&b"hello"[..])))
+            .write_for(&mut output, Method::Get, Version::Http10, 0)
+            .unwrap();
+        assert_eq!(sent, 5);
+        assert_eq!(output, b"HTTP/1.1 200 OK\r\n\r\nhello");
+    }
+
+    #[test]
+    fn a_sized_reader_that_ends_early_fails() {
+        let mut output = Vec::new();
+        let err = Response::new(200)
+            .with_body(Body::SizedReader(Box::new(&b"hi"[..]), 5))
+            .write_to(&mut output)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn responses_without_a_body_send_no_length() {
+        for status in [101, 204, 304] {
+            let mut output = Vec::new();
+            Response::new(status)
+                .with_body("ignored")
+                .write_to(&mut output)
+                .unwrap();
+            let output = String::from_utf8(output).unwrap();
+            assert!(!output.contains("Content-Length"), "{output}");
+            assert!(output.ends_with("\r\n\r\n"), "{output}");
+        }
+    }
+
+    #[test]
+    fn the_length_is_computed_from_the_body() {
+        let mut output = Vec::new();
+        Response::new(200)
+            .with_header("Content-Length", "100")
+            .with_header("Transfer-Encoding", "chunked")
+            .with_body("hello")
+            .write_to(&mut output)
+            .unwrap();
+        assert_eq!(output, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+    }
+
+    /// A writer that runs `change` before the first bytes it is given, to
+    /// change a file while the response is being sent.
+    struct Changing<F: FnOnce()> {
+        change: Option<F>,
+        output: Vec<u8>,
+    }
+
+    impl<F: FnOnce()> Write for Changing<F> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(change) = self.change.take() {
+                change();
+            }
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn written_while(body: Body, change: impl FnOnce()) -> Vec<u8> {
+        let mut writer = Changing {
+            change: Some(change),
+            output: Vec::new(),
+        };
+        Response::new(200)
+            .with_body(body)
+            .write_to(&mut writer)
+            .unwrap();
+        writer.output
+    }
+
+    #[test]
+    fn a_file_that_grows_while_sent_is_cut_at_its_length() {
+        let file = TempFile::new("growing", b"hello");
+        let body = Body::Path(file.0.clone()).open().unwrap();
+        let output = written_while(body, || {
+            let mut appending = fs::OpenOptions::new().append(true).open(&file.0).unwrap();
+            appending.write_all(b", world").unwrap();
+        });
+        assert_eq!(output, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+    }
+
+    #[test]
+    fn a_file_that_shrinks_while_sent_is_padded_with_zeros() {
+        let file = TempFile::new("shrinking", b"hello");
+        let body = Body::Path(file.0.clone()).open().unwrap();
+        let output = written_while(body, || {
+            let truncating = fs::OpenOptions::new().write(true).open(&file.0).unwrap();
+            truncating.set_len(2).unwrap();
+        });
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhe\0\0\0"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Running a job again after it fails, for
+//! [`ThreadPool::execute_with_retry`](crate::ThreadPool::execute_with_retry).
+//!
+//! Every failed attempt but the last schedules the next one on the pool's
+//! timer, so no worker sits idle while waiting. The wait doubles with each
+//! attempt, and is then cut down to a random point between half and all
+//! of it. Without that jitter, jobs that failed together, say because a
+//! downstream service went away, would all retry together too and hit it
+//! again at the same moment.
+
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{queue::Queue, timer::TimerHandle, Job};
+
+/// A job being retried, along with everything needed to retry it again.
+pub(crate) struct Retry<F, G> {
+    pub(crate) job: F,
+    pub(crate) on_failure: G,
+    /// How many times the job may run in total.
+    pub(crate) attempts: u32,
+    /// How many times it has run so far.
+    pub(crate) attempt: u32,
+    pub(crate) backoff: Duration,
+    pub(crate) queue: Arc<Queue<Job>>,
+    pub(crate) timer: TimerHandle,
+}
+
+impl<F, G, E> Retry<F, G>
+where
+    F: FnMut() -> Result<(), E> + Send + 'static,
+    G: FnOnce(E) + Send + 'static,
+{
+    /// Make one attempt, and schedule the next one if it fails and there
+    /// are attempts left.
+    pub(crate) fn run(mut self) {
+        let err = match (self.job)() {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+
+        self.attempt += 1;
+        if self.attempt >= self.attempts {
+            (self.on_failure)(err);
+            return;
+        }
+
+        let delay = jittered(backoff(self.backoff, self.attempt));
+        let timer = self.timer.clone();
+        let queue = Arc::clone(&self.queue);
+        timer.schedule(
+            delay,
+            Box::new(move || {
+                // The pool may have been shut down while we were waiting,
+                // in which case the job is dropped.
+                if queue.push(Box::new(move || self.run())).is_err() {
+                    println!("Dropping a job retry, the pool has been shut down.");
+                }
+            }),
+        );
+    }
+}
+
+/// The wait before the attempt after the `attempt`th one: `base`, doubled
+/// for every attempt after the first.
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    let factor = 1_u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+    base.saturating_mul(factor)
+}
+
+/// A random duration between half of `delay` and all of it.
+fn jittered(delay: Duration) -> Duration {
+    let half = delay / 2;
+    let nanos = u64::try_from(half.as_nanos()).unwrap_or(u64::MAX);
+    half + Duration::from_nanos(random() % nanos.saturating_add(1))
+}
+
+/// A random number, good enough to spread out retries but nothing else.
+///
+/// Every thread starts from a seed taken from the standard library's
+/// randomly keyed hasher, and moves on with xorshift.
+fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver};
+
+    use super::*;
+    use crate::{ManualClock, ThreadPool};
+
+    #[test]
+    fn backs_off_exponentially() {
+        let base = Duration::from_millis(100);
+        assert_eq!(backoff(base, 1), base);
+        assert_eq!(backoff(base, 2), base * 2);
+        assert_eq!(backoff(base, 3), base * 4);
+        assert_eq!(backoff(base, 40), base.saturating_mul(u32::MAX));
+    }
+
+    #[test]
+    fn jitters_between_half_and_all_of_the_delay() {
+        let delay = Duration::from_secs(8);
+        let waits: Vec<Duration> = (0..1000).map(|_| jittered(delay)).collect();
+        assert!(waits.iter().all(|&wait| delay / 2 <= wait && wait <= delay));
+        // They are spread out, not all the same.
+        assert!(waits.iter().any(|&wait| wait < delay * 3 / 4));
+        assert!(waits.iter().any(|&wait| wait > delay * 3 / 4));
+        assert_eq!(jittered(Duration::ZERO), Duration::ZERO);
+    }
+
+    /// Wait for the next attempt, moving the clock on until the timer
+    /// hands it to the pool.
+    fn next_attempt(clock: &ManualClock, attempts: &Receiver<u32>) -> Option<u32> {
+        for _ in 0..100 {
+            if let Ok(attempt) = attempts.recv_timeout(Duration::from_millis(10)) {
+                return Some(attempt);
+            }
+            clock.advance(Duration::from_secs(1));
+        }
+        None
+    }
+
+    #[test]
+    fn retries_until_the_job_succeeds() {
+        let clock = ManualClock::new();
+        let pool = ThreadPool::new(2).clock(clock.clone());
+        let (sender, attempts) = mpsc::channel();
+        let (failed, failures) = mpsc::channel::<String>();
+
+        let mut attempt = 0;
+        pool.execute_with_retry(
+            5,
+            Duration::from_secs(1),
+            move || {
+                attempt += 1;
+                sender.send(attempt).unwrap();
+                if attempt < 3 {
+                    Err(format!("attempt {attempt} failed"))
+                } else {
+                    Ok(())
+                }
+            },
+            move |err| failed.send(err).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(next_attempt(&clock, &attempts), Some(1));
+        assert_eq!(next_attempt(&clock, &attempts), Some(2));
+        assert_eq!(next_attempt(&clock, &attempts), Some(3));
+        // Nothing runs after the success.
+        assert_eq!(next_attempt(&clock, &attempts), None);
+        assert!(failures.try_recv().is_err());
+    }
+
+    #[test]
+    fn reports_the_last_failure() {
+        let clock = ManualClock::new();
+        let pool = ThreadPool::new(2).clock(clock.clone());
+        let (sender, attempts) = mpsc::channel();
+        let (failed, failures) = mpsc::channel();
+
+        let mut attempt = 0;
+        pool.execute_with_retry(
+            3,
+            Duration::from_secs(1),
+            move || {
+                attempt += 1;
+                sender.send(attempt).unwrap();
+                Err(attempt)
+            },
+            move |err| failed.send(err).unwrap(),
+        )
+        .unwrap();
+
+        for expected in 1..=3 {
+            assert_eq!(next_attempt(&clock, &attempts), Some(expected));
+        }
+        assert_eq!(failures.recv_timeout(Duration::from_secs(5)), Ok(3));
+        assert_eq!(next_attempt(&clock, &attempts), None);
+    }
+}
@@ -0,0 +1,1154 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    iter,
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    request::{Method, Request},
+    response::{IntoResponse, Response},
+};
+
+/// A request handler with access to the router's shared state.
+pub(crate) type Handler<S> = Box<dyn Fn(&Request, &S) -> Response + Send + Sync>;
+
+/// A request handler that reads the request body itself, as it arrives.
+pub(crate) type StreamingHandler<S> =
+    Box<dyn Fn(&Request, &mut dyn Read, &S) -> Response + Send + Sync>;
+
+/// A hook run on every request before routing, which may answer it
+/// instead of the router.
+pub(crate) type Hook<S> = Box<dyn Fn(&mut Request, &S) -> Option<Response> + Send + Sync>;
+
+/// A handler registered for a route.
+pub(crate) enum Endpoint<S> {
+    Buffered(Handler<S>),
+    Streaming(StreamingHandler<S>),
+}
+
+impl<S> Endpoint<S> {
+    /// Run the handler for a request whose body has been read already.
+    pub(crate) fn call(&self, request: &Request, state: &S) -> Response {
+        match self {
+            Endpoint::Buffered(handler) => handler(request, state),
+            Endpoint::Streaming(handler) => handler(request, &mut request.body(), state),
+        }
+    }
+}
+
+/// Anything that turns requests into responses.
+///
+/// [`Server`](crate::server::Server) runs a `Service` for every request it
+/// reads. [`Router`] and [`RadixRouter`](crate::radix::RadixRouter) both
+/// implement it.
+pub trait Service: Send + Sync + 'static {
+    /// Produce the response for `request`.
+    ///
+    /// The request is mutable so that routers can store the route
+    /// parameters they captured in it before calling a handler.
+    fn handle(&self, request: &mut Request) -> Response;
+
+    /// Produce the response for `request`, whose body hasn't been read
+    /// yet and can be read from `body`.
+    ///
+    /// The server calls this rather than [`Service::handle`]. By default
+    /// the body is read into the request and the request handled as
+    /// usual. [`Router`] hands it to handlers registered with
+    /// [`Router::route_streaming`] instead.
+    fn handle_streaming(&self, request: &mut Request, body: &mut dyn Read) -> Response {
+        match request.read_body(body) {
+            Ok(()) => self.handle(request),
+            Err(err) => body_error(&err),
+        }
+    }
+}
+
+/// Maps a method and a path to the handler that answers it.
+///
+/// Every handler receives the request and a reference to the state the
+/// router was created with. The state lives in an `Arc`, so it is shared
+/// by every worker thread and can hold things like configuration, counters
+/// or connection pools without resorting to globals.
+///
+/// Paths are matched without the query string. A segment starting with a
+/// colon, like `:id` in `/users/:id`, matches any non-empty segment and
+/// makes it available through [`Request::param`]. A last segment starting
+/// with an asterisk, like `*path` in `/static/*path`, matches the rest of
+/// the path, slashes included, so `/static/css/app.css` captures
+/// `css/app.css`. It needs at least one non-empty segment to match, so
+/// `/static/` doesn't.
+///
+/// When several routes match, the one with a literal segment where the
+/// others have a parameter, or a parameter where the others have a
+/// wildcard, wins, looking at the segments from left to right. Routes
+/// that are still tied are tried in the order they were registered.
+///
+/// Wildcards suit serving a directory, since what they capture is a
+/// relative path. [`StaticFiles::serve`](crate::static_files::StaticFiles::serve)
+/// keeps it inside the root, whatever the client put in it:
+///
+/// ```
+/// use rust_server::{serve_connection, MemoryFiles, Router, StaticFiles};
+///
+/// let files = StaticFiles::with_provider(
+///     MemoryFiles::new().with_file("css/app.css", "body {}"),
+/// );
+/// let router = Router::new().get("/static/*path", move |request, _| {
+///     assert_eq!(request.param("path"), Some("css/app.css"));
+///     files.serve(request, request.param("path").unwrap())
+/// });
+///
+/// let input = "GET /static/css/app.css HTTP/1.1\r\nConnection: close\r\n\r\n";
+/// let mut output = Vec::new();
+/// serve_connection(input.as_bytes(), &mut output, &router);
+///
+/// let output = String::from_utf8(output).unwrap();
+/// assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+/// assert!(output.ends_with("body {}"));
+/// ```
+///
+/// Exact paths are looked up in a hash map and parameter routes are
+/// checked one by one, which is plenty for most applications. See
+/// [`RadixRouter`](crate::radix::RadixRouter) for a router that stays fast
+/// with many parameter routes.
+pub struct Router<S = ()> {
+    exact: HashMap<String, MethodHandlers<S>>,
+    patterns: Vec<(Vec<Segment>, MethodHandlers<S>)>,
+    fallback: Handler<S>,
+    hooks: Vec<Hook<S>>,
+    state: Arc<S>,
+    method_override: bool,
+}
+
+impl Router<()> {
+    /// Create a router without any state.
+    pub fn new() -> Router<()> {
+        Router::with_state(Arc::new(()))
+    }
+}
+
+impl Default for Router<()> {
+    fn default() -> Router<()> {
+        Router::new()
+    }
+}
+
+impl<S: Send + Sync + 'static> Router<S> {
+    /// Create a router whose handlers all receive `state`.
+    ///
+    /// Keep a clone of the `Arc` to look at the state from outside the
+    /// handlers.
+    pub fn with_state(state: Arc<S>) -> Router<S> {
+        Router {
+            exact: HashMap::new(),
+            patterns: Vec::new(),
+            fallback: default_fallback(),
+            hooks: Vec::new(),
+            state,
+            method_override: false,
+        }
+    }
+
+    /// Let `POST` requests pretend to be `PUT`, `PATCH` or `DELETE`
+    /// requests, which HTML forms can't send.
+    ///
+    /// The method is taken from the `X-HTTP-Method-Override` header, or
+    /// else from a `_method` field of a URL-encoded form body, and replaces
+    /// the request's method before routing. Any other method in either
+    /// place gets `400 Bad Request`.
+    ///
+    /// This is off by default, since it lets clients reach handlers that a
+    /// proxy in front of the server may have been told to block.
+    pub fn method_override(mut self, enabled: bool) -> Router<S> {
+        self.method_override = enabled;
+        self
+    }
+
+    /// Register `handler` for requests with the given method and path.
+    ///
+    /// The handler may return anything that implements [`IntoResponse`],
+    /// such as a `Result<Response, HandlerError>`, whose error becomes a
+    /// `500` or whatever status the
+    /// [`HandlerError`](crate::error::HandlerError) asks for.
+    ///
+    /// Registering the same method and path twice replaces the earlier
+    /// handler.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path has more than [`MAX_PARAMS`] parameters, a
+    /// parameter without a name, or a wildcard that isn't the last
+    /// segment.
+    pub fn route<F, T>(self, method: Method, path: &str, handler: F) -> Router<S>
+    where
+        F: Fn(&Request, &S) -> T + Send + Sync + 'static,
+        T: IntoResponse,
+    {
+        self.insert(method, path, Endpoint::Buffered(boxed(handler)))
+    }
+
+    /// Register `handler` like [`Router::route`], but hand it the request
+    /// body as it arrives rather than once it has been read in full.
+    ///
+    /// The handler reads the body from its second argument, and the
+    /// request's own [`body`](Request::body) stays empty. This lets e.g. an
+    /// upload handler write a large file to disk without holding it in
+    /// memory. A client that hangs up halfway makes the reads fail with
+    /// `UnexpectedEof`.
+    ///
+    /// A handler may also answer before reading all of the body, or any of
+    /// it, say with `507 Insufficient Storage` when the disk is full, or
+    /// `401 Unauthorized` before accepting an upload at all. Its response
+    /// is sent as usual, but always with `Connection: close`, since the
+    /// rest of the body is still on the connection and the next request
+    /// can't be found after it. The server then stops reading, short of
+    /// briefly throwing away what the client sends while it notices, so
+    /// the rest of a large body isn't transferred for nothing:
+    ///
+    /// ```
+    /// use std::io::{self, Read};
+    ///
+    /// use rust_server::{serve_connection, Method, Response, Router};
+    ///
+    /// let router = Router::new().route_streaming(Method::Post, "/upload", |request, body, _| {
+    ///     if request.header("Authorization").is_none() {
+    ///         return Response::new(401);
+    ///     }
+    ///     io::copy(body, &mut io::sink()).unwrap();
+    ///     Response::new(201)
+    /// });
+    ///
+    /// // A gigabyte upload, followed by another request.
+    /// let head = "POST /upload HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n";
+    /// let input = head
+    ///     .as_bytes()
+    ///     .chain(io::repeat(b'x').take(1_000_000_000))
+    ///     .chain(&b"GET / HTTP/1.1\r\n\r\n"[..]);
+    /// let mut output = Vec::new();
+    /// serve_connection(input, &mut output, &router);
+    ///
+    /// let output = String::from_utf8(output).unwrap();
+    /// assert!(output.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    /// assert!(output.contains("Connection: close\r\n"));
+    /// assert_eq!(output.matches("HTTP/1.1").count(), 1);
+    /// ```
+    ///
+    /// With [`Router::method_override`] on, `POST` bodies are read in full
+    /// before routing anyway, since the method may be hidden in them.
+    pub fn route_streaming<F, T>(self, method: Method, path: &str, handler: F) -> Router<S>
+    where
+        F: Fn(&Request, &mut dyn Read, &S) -> T + Send + Sync + 'static,
+        T: IntoResponse,
+    {
+        let handler: StreamingHandler<S> =
+            Box::new(move |request, body, state| handler(request, body, state).into_response());
+        self.insert(method, path, Endpoint::Streaming(handler))
+    }
+
+    fn insert(mut self, method: Method, path: &str, handler: Endpoint<S>) -> Router<S> {
+        let pattern = parse_pattern(path);
+
+        if pattern
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+        {
+            self.exact
+                .entry(path.to_string())
+                .or_default()
+                .insert(method, handler);
+        } else if let Some((_, handlers)) = self.patterns.iter_mut().find(|(p, _)| *p == pattern) {
+            handlers.insert(method, handler);
+        } else {
+            let mut handlers = MethodHandlers::default();
+            handlers.insert(method, handler);
+            self.patterns.push((pattern, handlers));
+        }
+
+        self
+    }
+
+    /// Register `handler` for `GET` requests to `path`.
+    pub fn get<F, T>(self, path: &str, handler: F) -> Router<S>
+    where
+        F: Fn(&Request, &S) -> T + Send + Sync + 'static,
+        T: IntoResponse,
+    {
+        self.route(Method::Get, path, handler)
+    }
+
+    /// Register `handler` for `POST` requests to `path`.
+    pub fn post<F, T>(self, path: &str, handler: F) -> Router<S>
+    where
+        F: Fn(&Request, &S) -> T + Send + Sync + 'static,
+        T: IntoResponse,
+    {
+        self.route(Method::Post, path, handler)
+    }
+
+    /// Register `handler` like [`Router::route`], but answer `504 Gateway
+    /// Timeout` if it takes longer than `timeout`.
+    ///
+    /// The handler runs on a thread of its own, with a copy of the
+    /// request, while the worker waits for it. A handler that times out
+    /// can't be stopped, so it keeps running in the background and its
+    /// response is thrown away. Starting a thread for every request costs
+    /// a few microseconds, so only slow routes should have a timeout.
+    pub fn route_with_timeout<F, T>(
+        self,
+        method: Method,
+        path: &str,
+        timeout: Duration,
+        handler: F,
+    ) -> Router<S>
+    where
+        F: Fn(&Request, &S) -> T + Send + Sync + 'static,
+        T: IntoResponse,
+    {
+        let handler = with_timeout(timeout, Arc::clone(&self.state), handler);
+        self.route(method, path, handler)
+    }
+
+    /// Set the handler for requests whose path matches no route.
+    ///
+    /// By default those requests get a plain `404 Not Found`.
+    pub fn fallback<F, T>(mut self, handler: F) -> Router<S>
+    where
+        F: Fn(&Request, &S) -> T + Send + Sync + 'static,
+        T: IntoResponse,
+    {
+        self.fallback = boxed(handler);
+        self
+    }
+
+    /// Run `hook` on every request before it is routed.
+    ///
+    /// The hook may change the request, e.g. rewrite a legacy path with
+    /// [`Request::set_target`] so the route for the new one answers it. Or
+    /// it may answer the request itself by returning a response, which
+    /// skips routing altogether, e.g. to turn away clients a global guard
+    /// doesn't let in:
+    ///
+    /// ```
+    /// use rust_server::{Response, Router};
+    ///
+    /// let router = Router::new()
+    ///     .before(|request, _| {
+    ///         if request.path() == "/old" {
+    ///             request.set_target("/new");
+    ///         }
+    ///         None
+    ///     })
+    ///     .before(|request, _| {
+    ///         let banned = request.header("User-Agent") == Some("BadBot");
+    ///         banned.then(|| Response::text(403, "403 Forbidden"))
+    ///     })
+    ///     .get("/new", |_, _| "the new page");
+    /// ```
+    ///
+    /// Hooks run in the order they were registered, after any
+    /// [method override](Router::method_override), until one returns a
+    /// response. The body may not have been read yet, so hooks shouldn't
+    /// look at it.
+    pub fn before<F>(mut self, hook: F) -> Router<S>
+    where
+        F: Fn(&mut Request, &S) -> Option<Response> + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// The state shared by every handler.
+    pub fn state(&self) -> &Arc<S> {
+        &self.state
+    }
+
+    /// Every registered method and path, with parameter routes in the
+    /// form they were registered in, sorted by path and then method.
+    ///
+    /// This is meant for checking the configuration, say by logging the
+    /// routes at startup:
+    ///
+    /// ```
+    /// use rust_server::{Method, Router};
+    ///
+    /// let router = Router::new()
+    ///     .post("/users", |_, _| "created")
+    ///     .get("/users/:id", |_, _| "a user")
+    ///     .get("/", |_, _| "home")
+    ///     .get("/users", |_, _| "all users");
+    ///
+    /// assert_eq!(
+    ///     router.routes(),
+    ///     [
+    ///         (Method::Get, "/".to_string()),
+    ///         (Method::Get, "/users".to_string()),
+    ///         (Method::Post, "/users".to_string()),
+    ///         (Method::Get, "/users/:id".to_string()),
+    ///     ]
+    /// );
+    /// ```
+    ///
+    /// The fallback and the [`before`](Router::before) hooks aren't
+    /// routes, so they aren't listed.
+    pub fn routes(&self) -> Vec<(Method, String)> {
+        let exact = self
+            .exact
+            .iter()
+            .map(|(path, handlers)| (path.clone(), handlers));
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|(pattern, handlers)| (format_pattern(pattern), handlers));
+
+        let mut routes: Vec<(Method, String)> = exact
+            .chain(patterns)
+            .flat_map(|(path, handlers)| {
+                handlers.methods().map(move |method| (method, path.clone()))
+            })
+            .collect();
+        routes.sort_by(|(a_method, a_path), (b_method, b_path)| {
+            a_path
+                .cmp(b_path)
+                .then((*a_method as usize).cmp(&(*b_method as usize)))
+        });
+        routes
+    }
+
+    /// Run the handler registered for `request` and return its response.
+    ///
+    /// A path that is registered, but not for the request's method, gets
+    /// `405 Method Not Allowed` with an `Allow` header listing the methods
+    /// that would have worked. Any other unknown path goes to the fallback
+    /// handler.
+    pub fn handle(&self, request: &mut Request) -> Response {
+        if self.method_override {
+            if let Err(response) = apply_method_override(request) {
+                return response;
+            }
+        }
+        if let Some(response) = self.run_hooks(request) {
+            return response;
+        }
+        self.dispatch(request)
+    }
+
+    /// Run the [`before`](Router::before) hooks until one answers.
+    fn run_hooks(&self, request: &mut Request) -> Option<Response> {
+        self.hooks
+            .iter()
+            .find_map(|hook| hook(request, &self.state))
+    }
+
+    /// Route a request the hooks have seen.
+    fn dispatch(&self, request: &mut Request) -> Response {
+        match self.find(request) {
+            Ok(handler) => handler.call(request, &self.state),
+            Err(0) => (self.fallback)(request, &self.state),
+            Err(allowed) => method_not_allowed(allowed),
+        }
+    }
+
+    /// Like [`Router::handle`], but for a request whose body is still to
+    /// be read from `body`, which goes straight to a handler registered
+    /// with [`Router::route_streaming`].
+    pub fn handle_streaming(&self, request: &mut Request, body: &mut dyn Read) -> Response {
+        // An overridden method may come from the form in the body, and
+        // the route can't be found without it.
+        if self.method_override && request.method() == Method::Post {
+            return match request.read_body(body) {
+                Ok(()) => self.handle(request),
+                Err(err) => body_error(&err),
+            };
+        }
+
+        if let Some(response) = self.run_hooks(request) {
+            return response;
+        }
+        match self.find(request) {
+            Ok(Endpoint::Streaming(handler)) => return handler(request, body, &self.state),
+            // A client waiting to be told to send the body doesn't need
+            // to send it just to learn the method is wrong.
+            Err(allowed) if allowed != 0 && request.expects_continue() => {
+                return method_not_allowed(allowed);
+            }
+            _ => {}
+        }
+        match request.read_body(body) {
+            Ok(()) => self.dispatch(request),
+            Err(err) => body_error(&err),
+        }
+    }
+
+    /// Find the handler registered for `request`, and store the route
+    /// parameters it captured in the request.
+    ///
+    /// Without one, the error is the bit set of methods the path does
+    /// have handlers for, which is zero for an unknown path.
+    fn find(&self, request: &mut Request) -> Result<&Endpoint<S>, u16> {
+        let method = request.method();
+        let mut allowed = 0;
+
+        // Literal paths always beat parameter routes.
+        if let Some(handlers) = self.exact.get(request.path()) {
+            if let Some(handler) = handlers.get(method) {
+                request.set_params(Vec::new());
+                return Ok(handler);
+            }
+            allowed |= handlers.allowed();
+        }
+
+        // Find the most specific parameter route for the method. Only a
+        // strictly more specific route replaces the current best, so ties
+        // go to the route registered first.
+        let mut best: Option<(PatternMatch, &Endpoint<S>)> = None;
+        for (pattern, handlers) in &self.patterns {
+            let Some(found) = match_pattern(pattern, request.path()) else {
+                continue;
+            };
+            allowed |= handlers.allowed();
+
+            let Some(handler) = handlers.get(method) else {
+                continue;
+            };
+            if best
+                .as_ref()
+                .is_none_or(|(best, _)| found.specificity > best.specificity)
+            {
+                best = Some((found, handler));
+            }
+        }
+
+        match best {
+            Some((found, handler)) => {
+                request.set_params(found.params);
+                Ok(handler)
+            }
+            None => Err(allowed),
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static> Service for Router<S> {
+    fn handle(&self, request: &mut Request) -> Response {
+        Router::handle(self, request)
+    }
+
+    fn handle_streaming(&self, request: &mut Request, body: &mut dyn Read) -> Response {
+        Router::handle_streaming(self, request, body)
+    }
+}
+
+/// The most parameters a single route may have, counting a wildcard.
+pub const MAX_PARAMS: usize = 8;
+
+/// One segment of a registered route.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Segment {
+    Literal(String),
+    Param(String),
+    /// Matches the rest of the path.
+    Wildcard(String),
+}
+
+/// Split a route like `/users/:id` into its segments.
+pub(crate) fn parse_pattern(path: &str) -> Vec<Segment> {
+    let pattern: Vec<Segment> = segments(path)
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix('*') {
+                assert!(
+                    !name.is_empty(),
+                    "route {path:?} has a wildcard without a name"
+                );
+                return Segment::Wildcard(name.to_string());
+            }
+            match segment.strip_prefix(':') {
+                Some("") => panic!("route {path:?} has a parameter without a name"),
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(segment.to_string()),
+            }
+        })
+        .collect();
+
+    if let Some(position) = pattern
+        .iter()
+        .position(|segment| matches!(segment, Segment::Wildcard(_)))
+    {
+        assert!(
+            position == pattern.len() - 1,
+            "route {path:?} has a wildcard before its last segment"
+        );
+    }
+
+    let params = pattern
+        .iter()
+        .filter(|segment| matches!(segment, Segment::Param(_) | Segment::Wildcard(_)))
+        .count();
+    assert!(
+        params <= MAX_PARAMS,
+        "route {path:?} has {params} parameters, at most {MAX_PARAMS} are supported"
+    );
+
+    pattern
+}
+
+/// The route a pattern was parsed from, like `/users/:id`.
+fn format_pattern(pattern: &[Segment]) -> String {
+    pattern
+        .iter()
+        .map(|segment| match segment {
+            Segment::Literal(literal) => format!("/{literal}"),
+            Segment::Param(name) => format!("/:{name}"),
+            Segment::Wildcard(name) => format!("/*{name}"),
+        })
+        .collect()
+}
+
+/// The segments of a path, without the leading slash. `/` is a single
+/// empty segment.
+pub(crate) fn segments(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+/// A path matched against a parameter route.
+struct PatternMatch {
+    /// For every segment, whether it matched a literal, a parameter or a
+    /// wildcard, from most to least specific. Comparing these
+    /// lexicographically ranks the routes.
+    specificity: Vec<Specificity>,
+    params: Vec<(String, String)>,
+}
+
+/// What a segment of a path matched, ordered by how specific it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Specificity {
+    Wildcard,
+    Param,
+    Literal,
+}
+
+/// Match a path against a pattern.
+fn match_pattern(pattern: &[Segment], path: &str) -> Option<PatternMatch> {
+    let mut specificity = Vec::with_capacity(pattern.len());
+    let mut params = Vec::new();
+    let mut path_segments = segments(path);
+
+    for segment in pattern {
+        let value = path_segments.next()?;
+        match segment {
+            Segment::Literal(literal) if literal == value => {
+                specificity.push(Specificity::Literal);
+            }
+            Segment::Param(name) if !value.is_empty() => {
+                specificity.push(Specificity::Param);
+                params.push((name.clone(), value.to_string()));
+            }
+            Segment::Wildcard(name) => {
+                let rest: Vec<&str> = iter::once(value).chain(path_segments.by_ref()).collect();
+                let rest = rest.join("/");
+                if rest.is_empty() {
+                    return None;
+                }
+                specificity.push(Specificity::Wildcard);
+                params.push((name.clone(), rest));
+            }
+            _ => return None,
+        }
+    }
+
+    if path_segments.next().is_some() {
+        return None;
+    }
+    Some(PatternMatch {
+        specificity,
+        params,
+    })
+}
+
+/// The handlers registered for one path, one slot per method.
+pub(crate) struct MethodHandlers<S> {
+    handlers: [Option<Endpoint<S>>; Method::ALL.len()],
+}
+
+impl<S> Default for MethodHandlers<S> {
+    fn default() -> MethodHandlers<S> {
+        MethodHandlers {
+            handlers: Default::default(),
+        }
+    }
+}
+
+impl<S> MethodHandlers<S> {
+    pub(crate) fn insert(&mut self, method: Method, handler: Endpoint<S>) {
+        self.handlers[method as usize] = Some(handler);
+    }
+
+    pub(crate) fn get(&self, method: Method) -> Option<&Endpoint<S>> {
+        self.handlers[method as usize].as_ref()
+    }
+
+    /// The methods with a handler, in the order of [`Method::ALL`].
+    pub(crate) fn methods(&self) -> impl Iterator<Item = Method> + '_ {
+        Method::ALL
+            .into_iter()
+            .filter(|&method| self.get(method).is_some())
+    }
+
+    /// A bit set of the methods with a handler, indexed like
+    /// [`Method::ALL`].
+    pub(crate) fn allowed(&self) -> u16 {
+        self.handlers
+            .iter()
+            .enumerate()
+            .filter(|(_, handler)| handler.is_some())
+            .fold(0, |allowed, (i, _)| allowed | 1 << i)
+    }
+}
+
+/// Wrap `handler` so that it runs on a helper thread and gets `504 Gateway
+/// Timeout` when it doesn't answer within `timeout`.
+///
+/// The helper thread may outlive the request, so it can't borrow the state
+/// the router passes to the wrapper. It gets its own `Arc` of the router's
+/// state instead.
+pub(crate) fn with_timeout<S, F, T>(
+    timeout: Duration,
+    state: Arc<S>,
+    handler: F,
+) -> impl Fn(&Request, &S) -> Response + Send + Sync + 'static
+where
+    S: Send + Sync + 'static,
+    F: Fn(&Request, &S) -> T + Send + Sync + 'static,
+    T: IntoResponse,
+{
+    let handler = Arc::new(handler);
+
+    move |request, _| {
+        // A request deadline that comes sooner wins over the route's own
+        // timeout.
+        let timeout = request
+            .time_remaining()
+            .map_or(timeout, |remaining| remaining.min(timeout));
+
+        let (sender, receiver) = mpsc::channel();
+        let request = request.clone();
+        let state = Arc::clone(&state);
+        let handler = Arc::clone(&handler);
+
+        let spawned = thread::Builder::new().spawn(move || {
+            // The worker stops listening once the timeout has passed, so
+            // a late response has nowhere to go. That's fine.
+            let _ = sender.send(handler(&request, &state).into_response());
+        });
+        if let Err(err) = spawned {
+            eprintln!("Failed to spawn a thread for a handler with a timeout: {err}");
+            return Response::text(503, "503 Service Unavailable");
+        }
+
+        match receiver.recv_timeout(timeout) {
+            Ok(response) => response,
+            Err(mpsc::RecvTimeoutError::Timeout) => Response::text(504, "504 Gateway Timeout"),
+            // The handler panicked before sending its response.
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Response::text(500, "500 Internal Server Error")
+            }
+        }
+    }
+}
+
+/// Replace the method of a `POST` request with the one it asks for in an
+/// `X-HTTP-Method-Override` header or a `_method` form field.
+pub(crate) fn apply_method_override(request: &mut Request) -> Result<(), Response> {
+    if request.method() != Method::Post {
+        return Ok(());
+    }
+
+    let requested = match request.header("X-HTTP-Method-Override") {
+        Some(method) => Some(method.to_string()),
+        None => form_method(request),
+    };
+    let Some(requested) = requested else {
+        return Ok(());
+    };
+
+    // Only the methods forms are missing can be asked for. Turning a POST
+    // into a GET or a CONNECT would only confuse caches and proxies.
+    match Method::parse(&requested.to_ascii_uppercase()) {
+        Some(method @ (Method::Put | Method::Patch | Method::Delete)) => {
+            request.set_method(method);
+            Ok(())
+        }
+        _ => Err(Response::text(
+            400,
+            "400 Bad Request: invalid method override",
+        )),
+    }
+}
+
+/// The `_method` field of a URL-encoded form body.
+///
+/// Method names never need percent-encoding, so the value is taken as is.
+fn form_method(request: &Request) -> Option<String> {
+    let content_type = request.header("Content-Type")?;
+    let mime = content_type.split(';').next()?.trim();
+    if !mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        return None;
+    }
+
+    let body = std::str::from_utf8(request.body()).ok()?;
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == "_method")
+        .map(|(_, value)| value.to_string())
+}
+
+/// The response for a request whose body couldn't be read.
+///
+/// The server closes the connection after it, since what is left of the
+/// body is still on it.
+pub(crate) fn body_error(err: &io::Error) -> Response {
+    eprintln!("Failed to read a request body: {err}");
+    match err.kind() {
+        io::ErrorKind::OutOfMemory => return Response::text(503, "503 Service Unavailable"),
+        // A compressed body that expands to more than it may.
+        io::ErrorKind::FileTooLarge => return Response::text(413, "413 Content Too Large"),
+        _ => {}
+    }
+    Response::text(400, "400 Bad Request")
+}
+
+/// Box a handler, turning whatever it returns into a response.
+pub(crate) fn boxed<S, F, T>(handler: F) -> Handler<S>
+where
+    F: Fn(&Request, &S) -> T + Send + Sync + 'static,
+    T: IntoResponse,
+{
+    Box::new(move |request, state| handler(request, state).into_response())
+}
+
+/// The handler used until [`Router::fallback`] replaces it.
+pub(crate) fn default_fallback<S>() -> Handler<S> {
+    Box::new(|_, _| Response::text(404, "404 Not Found"))
+}
+
+/// The response for a path that exists, but not for the request's method.
+pub(crate) fn method_not_allowed(allowed: u16) -> Response {
+    let mut methods: Vec<&str> = Method::ALL
+        .iter()
+        .filter(|&&method| allowed & (1 << method as usize) != 0)
+        .map(|method| method.as_str())
+        .collect();
+
+    // Sort so the header reads the same however the routes were added.
+    methods.sort_unstable();
+    Response::text(405, "405 Method Not Allowed").with_header("Allow", &methods.join(", "))
+}
+
+/// Build a [`Router`] from a list of routes, one `METHOD "path" => handler`
+/// per entry:
+///
+/// ```
+/// use rust_server::{routes, Method, Request, Response, Router};
+///
+/// fn index(_: &Request, _: &()) -> Response {
+///     Response::text(200, "the homepage")
+/// }
+///
+/// fn submit(request: &Request, _: &()) -> Response {
+///     Response::text(201, format!("got {} bytes", request.body().len()))
+/// }
+///
+/// let router = routes! {
+///     GET "/" => index,
+///     POST "/submit" => submit,
+///     DELETE "/items/:id" => |_, _| Response::text(204, ""),
+/// };
+///
+/// // The same as registering the routes by hand.
+/// let manual = Router::new()
+///     .route(Method::Get, "/", index)
+///     .route(Method::Post, "/submit", submit)
+///     .route(Method::Delete, "/items/:id", |_, _| Response::text(204, ""));
+///
+/// for (method, target) in [
+///     (Method::Get, "/"),
+///     (Method::Post, "/submit"),
+///     (Method::Delete, "/items/7"),
+///     (Method::Get, "/submit"),
+///     (Method::Get, "/missing"),
+/// ] {
+///     let from_macro = router.handle(&mut Request::new(method, target));
+///     let by_hand = manual.handle(&mut Request::new(method, target));
+///     assert_eq!(from_macro.status(), by_hand.status());
+///     assert_eq!(from_macro.header("Allow"), by_hand.header("Allow"));
+/// }
+/// ```
+///
+/// Each entry expands to a [`Router::route`] call, so handlers are the
+/// same functions or closures `route` takes. The methods are written as
+/// on the request line, `GET` through `CONNECT`.
+///
+/// The routes are added to [`Router::new`], unless the list starts with
+/// another router and a semicolon, e.g. one with state or a fallback:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use rust_server::{routes, Router};
+///
+/// let router = routes! { Router::with_state(Arc::new(42));
+///     GET "/answer" => |_, answer: &i32| answer.to_string(),
+/// };
+/// ```
+#[macro_export]
+macro_rules! routes {
+    (@method GET) => { $crate::Method::Get };
+    (@method HEAD) => { $crate::Method::Head };
+    (@method POST) => { $crate::Method::Post };
+    (@method PUT) => { $crate::Method::Put };
+    (@method DELETE) => { $crate::Method::Delete };
+    (@method PATCH) => { $crate::Method::Patch };
+    (@method OPTIONS) => { $crate::Method::Options };
+    (@method TRACE) => { $crate::Method::Trace };
+    (@method CONNECT) => { $crate::Method::Connect };
+    ($router:expr; $($method:ident $path:literal => $handler:expr),* $(,)?) => {
+        $router$(.route($crate::routes!(@method $method), $path, $handler))*
+    };
+    ($($method:ident $path:literal => $handler:expr),* $(,)?) => {
+        $crate::routes!($crate::Router::new(); $($method $path => $handler),*)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::serve_connection;
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body().as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn handlers_share_the_state() {
+        let router = Router::with_state(Arc::new(AtomicUsize::new(0)))
+            .get("/count", |_, hits: &AtomicUsize| {
+                Response::text(200, (hits.fetch_add(1, Ordering::SeqCst) + 1).to_string())
+            })
+            .post("/reset", |_, hits: &AtomicUsize| {
+                hits.store(0, Ordering::SeqCst);
+                Response::text(200, "reset")
+            });
+        let hits = Arc::clone(router.state());
+
+        let input = "GET /count HTTP/1.1\r\n\r\n".repeat(3)
+            + "GET /count HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &router);
+        let output = String::from_utf8(output).unwrap();
+        let bodies: Vec<&str> = output
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| response.rsplit("\r\n").next().unwrap())
+            .collect();
+        assert_eq!(bodies, ["1", "2", "3", "4"]);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+
+        router.handle(&mut Request::new(Method::Post, "/reset"));
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn the_state_is_shared_between_threads() {
+        let router = Arc::new(Router::with_state(Arc::new(AtomicUsize::new(0))).get(
+            "/count",
+            |_, hits: &AtomicUsize| {
+                hits.fetch_add(1, Ordering::SeqCst);
+                Response::text(200, "counted")
+            },
+        ));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let router = Arc::clone(&router);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let response = router.handle(&mut Request::new(Method::Get, "/count"));
+                        assert_eq!(body(&response), "counted");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(router.state().load(Ordering::SeqCst), 400);
+    }
+
+    #[test]
+    fn the_fallback_and_hooks_see_the_state() {
+        let router = Router::with_state(Arc::new("secret".to_string()))
+            .before(|request, token: &String| {
+                (request.header("Token") != Some(token.as_str()))
+                    .then(|| Response::text(403, "403 Forbidden"))
+            })
+            .fallback(|request, token: &String| format!("{} is not {token}", request.path()));
+
+        let response = router.handle(&mut Request::new(Method::Get, "/missing"));
+        assert_eq!(response.status(), 403);
+
+        let mut request = Request::new(Method::Get, "/missing").with_header("Token", "secret");
+        assert_eq!(body(&router.handle(&mut request)), "/missing is not secret");
+    }
+
+    fn timed() -> Router<String> {
+        Router::with_state(Arc::new("state".to_string()))
+            .route_with_timeout(
+                Method::Get,
+                "/slow",
+                Duration::from_millis(50),
+                |_, _: &String| {
+                    thread::sleep(Duration::from_secs(1));
+                    Response::text(200, "too late")
+                },
+            )
+            .route_with_timeout(
+                Method::Get,
+                "/fast",
+                Duration::from_secs(5),
+                |request, state: &String| {
+                    Response::text(200, format!("{} from {state}", request.path()))
+                },
+            )
+    }
+
+    #[test]
+    fn slow_handlers_time_out() {
+        let router = timed();
+
+        let started = Instant::now();
+        let response = router.handle(&mut Request::new(Method::Get, "/slow"));
+        assert_eq!(response.status(), 504);
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let response = router.handle(&mut Request::new(Method::Get, "/fast"));
+        assert_eq!(response.status(), 200);
+        assert_eq!(body(&response), "/fast from state");
+    }
+
+    #[test]
+    fn a_sooner_request_deadline_wins_over_the_route_timeout() {
+        let router = Router::new().route_with_timeout(
+            Method::Get,
+            "/slow",
+            Duration::from_secs(5),
+            |_, _| {
+                thread::sleep(Duration::from_secs(1));
+                Response::text(200, "too late")
+            },
+        );
+
+        let started = Instant::now();
+        let mut request =
+            Request::new(Method::Get, "/slow").with_deadline(started + Duration::from_millis(50));
+        assert_eq!(router.handle(&mut request).status(), 504);
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn a_panicking_handler_with_a_timeout_is_an_error() {
+        let router = Router::new().route_with_timeout(
+            Method::Get,
+            "/panic",
+            Duration::from_secs(5),
+            |_, _| -> Response { panic!("handler failed") },
+        );
+        let response = router.handle(&mut Request::new(Method::Get, "/panic"));
+        assert_eq!(response.status(), 500);
+    }
+
+    fn resources(method_override: bool) -> Router {
+        Router::new()
+            .post("/items", |_, _| Response::text(200, "created"))
+            .route(Method::Delete, "/items", |_, _| {
+                Response::text(200, "deleted")
+            })
+            .route(Method::Put, "/items", |_, _| {
+                Response::text(200, "replaced")
+            })
+            .method_override(method_override)
+    }
+
+    fn form(fields: &str) -> Request {
+        Request::new(Method::Post, "/items")
+            .with_header("Content-Type", "application/x-www-form-urlencoded")
+            .with_body(fields)
+    }
+
+    #[test]
+    fn overrides_the_method_from_a_form_field() {
+        let router = resources(true);
+        assert_eq!(body(&router.handle(&mut form("_method=DELETE"))), "deleted");
+        assert_eq!(
+            body(&router.handle(&mut form("name=x&_method=put"))),
+            "replaced"
+        );
+        assert_eq!(body(&router.handle(&mut form("name=x"))), "created");
+
+        // Streamed bodies are read first to find the field.
+        let input = "POST /items HTTP/1.1\r\n\
+                     Content-Type: application/x-www-form-urlencoded\r\n\
+                     Content-Length: 14\r\n\
+                     Connection: close\r\n\r\n\
+                     _method=DELETE";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &router);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("\r\n\r\ndeleted"));
+    }
+
+    #[test]
+    fn overrides_the_method_from_a_header() {
+        let router = resources(true);
+        let mut request =
+            Request::new(Method::Post, "/items").with_header("X-HTTP-Method-Override", "DELETE");
+        assert_eq!(body(&router.handle(&mut request)), "deleted");
+
+        // The header wins over the form.
+        let mut request = form("_method=DELETE").with_header("X-HTTP-Method-Override", "PUT");
+        assert_eq!(body(&router.handle(&mut request)), "replaced");
+
+        // Only POST requests can be overridden.
+        let mut request =
+            Request::new(Method::Get, "/items").with_header("X-HTTP-Method-Override", "DELETE");
+        assert_eq!(router.handle(&mut request).status(), 405);
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        let router = resources(true);
+        for method in ["GET", "CONNECT", "FETCH", ""] {
+            let mut request =
+                Request::new(Method::Post, "/items").with_header("X-HTTP-Method-Override", method);
+            assert_eq!(router.handle(&mut request).status(), 400, "{method:?}");
+        }
+        assert_eq!(router.handle(&mut form("_method=GET")).status(), 400);
+    }
+
+    #[test]
+    fn method_override_is_off_by_default() {
+        let router = resources(false);
+        assert_eq!(body(&router.handle(&mut form("_method=DELETE"))), "created");
+        let mut request =
+            Request::new(Method::Post, "/items").with_header("X-HTTP-Method-Override", "DELETE");
+        assert_eq!(body(&router.handle(&mut request)), "created");
+    }
+}
@@ -0,0 +1,3377 @@
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    access_log::{self, LogFormat},
+    budget::BodyBudget,
+    build_info,
+    clock::{Clock, SystemClock},
+    date,
+    error_page::{self, ErrorPages},
+    forwarded::{self, ProxyTrust},
+    metrics::{
+        ByteCounts, ConnectionHook, ConnectionStats, CountingStream, KeepAliveSlot, KeepAliveSlots,
+        LoadGauge, PeerConnection, PeerLimit, TrafficStats,
+    },
+    proxy,
+    request::{BodyReader, Limits, Method, ParseError, Request, Version},
+    response::{reason_phrase, Body, IntoResponse, Response},
+    router::{Router, Service},
+    shutdown::{ShutdownHandle, ShutdownState},
+    static_files,
+    upgrade::{self, UpgradeHandler, UpgradeHandlers, Upgraded},
+    ExecuteError, Overflow, PoolCreationError, ThreadPool,
+};
+
+#[cfg(feature = "gzip")]
+use crate::gzip::GzipDecoder;
+#[cfg(feature = "socket_options")]
+use crate::socket::{self, ReuseOptions};
+
+/// The settings a [`Server`] is started with.
+///
+/// Every setting has a default, so only the ones that matter need to be
+/// changed, e.g. `ServerConfig::new("0.0.0.0:8080").pool_size(8)`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    addr: String,
+    pool_size: usize,
+    #[cfg(feature = "affinity")]
+    pin_to_cores: bool,
+    queue_capacity: Option<usize>,
+    overflow: Overflow,
+    accept_strategy: AcceptStrategy,
+    #[cfg(feature = "socket_options")]
+    reuse: ReuseOptions,
+    limits: Limits,
+    keep_alive_timeout: Duration,
+    request_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_accept_rate: Option<u32>,
+    max_keep_alive_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    proxy_trust: ProxyTrust,
+    keep_alive_high_water: Option<usize>,
+    max_buffered_body_bytes: Option<u64>,
+    #[cfg(feature = "gzip")]
+    max_decompressed_body_bytes: u64,
+    http10_buffer_limit: Option<u64>,
+    single_write_limit: u64,
+    lame_duck: Duration,
+    readiness_path: Option<String>,
+    version_path: Option<String>,
+    allow_trace: bool,
+    reject_get_body: bool,
+    log_format: LogFormat,
+    slow_request_threshold: Option<Duration>,
+    server_timing: bool,
+    verbose_errors: bool,
+    default_headers: DefaultHeaders,
+    static_root: PathBuf,
+}
+
+impl ServerConfig {
+    /// Create a configuration listening on `addr`, e.g. `127.0.0.1:7878`.
+    pub fn new(addr: &str) -> ServerConfig {
+        ServerConfig {
+            addr: addr.to_string(),
+            ..ServerConfig::default()
+        }
+    }
+
+    /// Set the address to listen on, e.g. `0.0.0.0:8080`.
+    pub fn addr(mut self, addr: &str) -> ServerConfig {
+        self.addr = addr.to_string();
+        self
+    }
+
+    /// Set the number of worker threads serving connections. It defaults
+    /// to four.
+    ///
+    /// This is also the number of connections served at once; see
+    /// [`Server`] for what happens to the others.
+    pub fn pool_size(mut self, pool_size: usize) -> ServerConfig {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Let at most `capacity` accepted connections wait for a worker; by
+    /// default there is no limit. What happens to connections past it is
+    /// up to [`ServerConfig::overflow`].
+    pub fn queue_capacity(mut self, capacity: usize) -> ServerConfig {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Choose what happens to a connection accepted while the
+    /// [queue](ServerConfig::queue_capacity) is full. By default it is
+    /// closed right away.
+    ///
+    /// With [`Overflow::RunInline`] the accepting thread serves it
+    /// itself, so under overload the server degrades to serving one
+    /// connection at a time on that thread rather than dropping any.
+    /// Nothing new is accepted meanwhile. [`Overflow::Block`] stops
+    /// accepting until a worker frees up instead.
+    pub fn overflow(mut self, overflow: Overflow) -> ServerConfig {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Choose how accepted connections get to the workers. By default one
+    /// thread accepts them and [dispatches](AcceptStrategy::Dispatch) them.
+    pub fn accept_strategy(mut self, strategy: AcceptStrategy) -> ServerConfig {
+        self.accept_strategy = strategy;
+        self
+    }
+
+    /// Choose whether each worker thread is pinned to a CPU core, going
+    /// round the available cores. This is off by default.
+    ///
+    /// See [`ThreadPool::build_pinned`] for when this helps, and what
+    /// happens where pinning isn't supported.
+    #[cfg(feature = "affinity")]
+    pub fn pin_to_cores(mut self, enabled: bool) -> ServerConfig {
+        self.pin_to_cores = enabled;
+        self
+    }
+
+    /// Choose whether the listening socket sets `SO_REUSEADDR`, which is
+    /// on by default.
+    ///
+    /// It lets a restarted server bind its port right away, even while
+    /// connections from the previous run linger in `TIME_WAIT`. Without the
+    /// `socket_options` feature the standard library's choice applies,
+    /// which is the same on Unix.
+    ///
+    /// Windows has no `TIME_WAIT` problem to solve, and its `SO_REUSEADDR`
+    /// would let another process take the port over, so this does nothing
+    /// there.
+    #[cfg(feature = "socket_options")]
+    pub fn reuse_address(mut self, enabled: bool) -> ServerConfig {
+        self.reuse.address = enabled;
+        self
+    }
+
+    /// Choose whether the listening socket sets `SO_REUSEPORT`, which is
+    /// off by default.
+    ///
+    /// Several server processes listening on the same port with this set
+    /// have the kernel spread incoming connections between them on Linux.
+    /// Other Unix systems allow sharing the port too, but may hand every
+    /// connection to the same process. On Windows, Solaris and illumos
+    /// binding fails.
+    #[cfg(feature = "socket_options")]
+    pub fn reuse_port(mut self, enabled: bool) -> ServerConfig {
+        self.reuse.port = enabled;
+        self
+    }
+
+    /// Set the limits enforced while reading requests.
+    pub fn limits(mut self, limits: Limits) -> ServerConfig {
+        self.limits = limits;
+        self
+    }
+
+    /// Set how long a connection may sit idle between two requests before
+    /// the server closes it. It defaults to five seconds.
+    ///
+    /// Every open connection holds on to a worker, so this also bounds
+    /// how long idle clients can keep workers away from everyone else.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> ServerConfig {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Give every request a [deadline](Request::deadline) `timeout` after
+    /// it has been read. There is none by default.
+    ///
+    /// Handlers can look at the deadline to give up on slow work. Routes
+    /// registered with a timeout, like
+    /// [`Router::route_with_timeout`](crate::router::Router::route_with_timeout),
+    /// answer `504 Gateway Timeout` when the deadline passes, even if their
+    /// own timeout is longer.
+    pub fn request_timeout(mut self, timeout: Duration) -> ServerConfig {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on a response that takes longer than `timeout` to write,
+    /// and close its connection. There is no limit by default.
+    ///
+    /// A client that reads its responses very slowly, or not at all, keeps
+    /// a worker writing to it for as long as it likes otherwise, which is
+    /// all a slow-read attack needs. The timeout covers the whole
+    /// response, so trickling it out a few bytes at a time doesn't help
+    /// either. It is separate from the
+    /// [keep-alive timeout](ServerConfig::keep_alive_timeout), which only
+    /// applies to reading.
+    pub fn write_timeout(mut self, timeout: Duration) -> ServerConfig {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Accept at most `per_second` new connections per second, across all
+    /// clients. There's no limit by default.
+    ///
+    /// Connections arriving faster than that wait in the operating
+    /// system's backlog until the server gets to them, so a flood of
+    /// connections can't pile up in the worker pool's queue. Short bursts
+    /// of up to `per_second` connections are let through at once.
+    pub fn max_accept_rate(mut self, per_second: u32) -> ServerConfig {
+        self.max_accept_rate = Some(per_second);
+        self
+    }
+
+    /// Keep at most `connections` connections alive at once. By default
+    /// that is one less than the [number of workers](ServerConfig::pool_size),
+    /// but at least one.
+    ///
+    /// An idle connection kept alive holds on to its worker until the
+    /// client sends its next request or the
+    /// [keep-alive timeout](ServerConfig::keep_alive_timeout) passes. Were
+    /// every worker held that way, new connections would wait in the queue
+    /// for nothing. Past the limit, connections are served one request,
+    /// answered with `Connection: close`, and closed. A connection that was
+    /// kept alive once stays kept alive until it closes, and then makes
+    /// room for another.
+    ///
+    /// Unlike the [high-water mark](ServerConfig::keep_alive_high_water),
+    /// this only counts connections kept alive, not those waiting for a
+    /// worker, and it is on by default. A limit of zero turns keep-alive
+    /// off altogether.
+    ///
+    /// ```
+    /// use std::{
+    ///     io::{Read, Write},
+    ///     net::TcpStream,
+    ///     thread,
+    /// };
+    ///
+    /// use rust_server::{Router, Server, ServerConfig};
+    ///
+    /// let config = ServerConfig::new("127.0.0.1:0").max_keep_alive_connections(1);
+    /// let server = Server::bind(config, Router::new().get("/", |_, _| "hi")).unwrap();
+    /// let addr = server.local_addr().unwrap();
+    /// let shutdown = server.shutdown_handle();
+    /// let running = thread::spawn(move || server.run());
+    ///
+    /// // Send a request and read the response, which ends with "hi".
+    /// let fetch = |stream: &mut TcpStream| {
+    ///     stream.write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+    ///     let mut response = Vec::new();
+    ///     while !response.ends_with(b"hi") {
+    ///         let mut buf = [0; 1024];
+    ///         let read = stream.read(&mut buf).unwrap();
+    ///         response.extend_from_slice(&buf[..read]);
+    ///     }
+    ///     String::from_utf8(response).unwrap()
+    /// };
+    ///
+    /// // The first connection takes the only slot, so the second is closed.
+    /// let mut first = TcpStream::connect(addr).unwrap();
+    /// assert!(fetch(&mut first).contains("Connection: keep-alive\r\n"));
+    /// let mut second = TcpStream::connect(addr).unwrap();
+    /// assert!(fetch(&mut second).contains("Connection: close\r\n"));
+    ///
+    /// // Until the first one closes and gives its slot back, which the
+    /// // server notices shortly.
+    /// drop(first);
+    /// while !fetch(&mut TcpStream::connect(addr).unwrap()).contains("keep-alive") {
+    ///     thread::sleep(std::time::Duration::from_millis(10));
+    /// }
+    ///
+    /// shutdown.shutdown();
+    /// running.join().unwrap().unwrap();
+    /// ```
+    pub fn max_keep_alive_connections(mut self, connections: usize) -> ServerConfig {
+        self.max_keep_alive_connections = Some(connections);
+        self
+    }
+
+    /// Allow at most `connections` open at once from the same IP address.
+    /// There's no limit by default.
+    ///
+    /// A client opening many connections and keeping them busy, like a
+    /// slowloris attack, could otherwise take up every worker. Past the
+    /// limit, new connections get `503 Service Unavailable` and are
+    /// closed right away, without reading anything from them.
+    ///
+    /// ```
+    /// use std::{
+    ///     io::{Read, Write},
+    ///     net::TcpStream,
+    ///     thread,
+    /// };
+    ///
+    /// use rust_server::{Router, Server, ServerConfig};
+    ///
+    /// let config = ServerConfig::new("127.0.0.1:0").max_connections_per_ip(2);
+    /// let server = Server::bind(config, Router::new().get("/", |_, _| "hi")).unwrap();
+    /// let addr = server.local_addr().unwrap();
+    /// let shutdown = server.shutdown_handle();
+    /// let running = thread::spawn(move || server.run());
+    ///
+    /// // Two connections are served, and stay open.
+    /// let mut open = Vec::new();
+    /// for _ in 0..2 {
+    ///     let mut stream = TcpStream::connect(addr).unwrap();
+    ///     stream.write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+    ///     let mut response = Vec::new();
+    ///     while !response.ends_with(b"hi") {
+    ///         let mut buf = [0; 1024];
+    ///         let read = stream.read(&mut buf).unwrap();
+    ///         response.extend_from_slice(&buf[..read]);
+    ///     }
+    ///     open.push(stream);
+    /// }
+    ///
+    /// // The third is turned away.
+    /// let mut response = String::new();
+    /// TcpStream::connect(addr).unwrap().read_to_string(&mut response).unwrap();
+    /// assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    ///
+    /// drop(open);
+    /// shutdown.shutdown();
+    /// running.join().unwrap().unwrap();
+    /// ```
+    pub fn max_connections_per_ip(mut self, connections: usize) -> ServerConfig {
+        self.max_connections_per_ip = Some(connections);
+        self
+    }
+
+    /// Believe the `X-Forwarded-For` and `X-Forwarded-Proto` headers of
+    /// any client, for a server only reachable through a reverse proxy.
+    /// They are ignored by default, since anyone can send them.
+    ///
+    /// The client and scheme they name are what [`Request::client_ip`] and
+    /// [`Request::scheme`] return, and the address the access log shows.
+    /// The client is the last address in `X-Forwarded-For`, the one the
+    /// proxy got the request from; anything before it was sent by the
+    /// client itself. If some clients can reach the server directly, list the proxies
+    /// with [`trusted_proxies`](ServerConfig::trusted_proxies) instead.
+    ///
+    /// ```
+    /// use std::{
+    ///     io::{Read, Write},
+    ///     net::TcpStream,
+    ///     thread,
+    /// };
+    ///
+    /// use rust_server::{Router, Server, ServerConfig};
+    ///
+    /// // Answer a request the proxy forwarded for 203.0.113.7 over HTTPS,
+    /// // which claims to come from 198.51.100.1 before that, with who the
+    /// // server thinks the client is.
+    /// let client = |config: ServerConfig| {
+    ///     let router = Router::new().get("/", |request, _| {
+    ///         format!("{} {}", request.client_ip().unwrap(), request.scheme())
+    ///     });
+    ///     let server = Server::bind(config, router).unwrap();
+    ///     let addr = server.local_addr().unwrap();
+    ///     let shutdown = server.shutdown_handle();
+    ///     let running = thread::spawn(move || server.run());
+    ///
+    ///     let mut stream = TcpStream::connect(addr).unwrap();
+    ///     stream
+    ///         .write_all(
+    ///             b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\
+    ///               X-Forwarded-For: 198.51.100.1, 203.0.113.7\r\n\
+    ///               X-Forwarded-Proto: https\r\n\r\n",
+    ///         )
+    ///         .unwrap();
+    ///     let mut response = String::new();
+    ///     stream.read_to_string(&mut response).unwrap();
+    ///
+    ///     shutdown.shutdown();
+    ///     running.join().unwrap().unwrap();
+    ///     response.split("\r\n\r\n").nth(1).unwrap().to_string()
+    /// };
+    ///
+    /// let trusting = ServerConfig::new("127.0.0.1:0").trust_proxy(true);
+    /// assert_eq!(client(trusting), "203.0.113.7 https");
+    /// assert_eq!(client(ServerConfig::new("127.0.0.1:0")), "127.0.0.1 http");
+    /// ```
+    pub fn trust_proxy(mut self, trust: bool) -> ServerConfig {
+        self.proxy_trust = if trust {
+            ProxyTrust::Any
+        } else {
+            ProxyTrust::None
+        };
+        self
+    }
+
+    /// Believe the `X-Forwarded-For` and `X-Forwarded-Proto` headers only
+    /// on connections from `proxies`, like
+    /// [`trust_proxy`](ServerConfig::trust_proxy) does for everyone.
+    ///
+    /// The client is the last address in `X-Forwarded-For` that isn't one
+    /// of the proxies, since whatever comes before it was added by someone
+    /// the server doesn't trust. If they all are, it is the first one.
+    pub fn trusted_proxies(mut self, proxies: impl IntoIterator<Item = IpAddr>) -> ServerConfig {
+        self.proxy_trust = ProxyTrust::Only(proxies.into_iter().collect());
+        self
+    }
+
+    /// Stop keeping connections alive while more than `connections` are
+    /// open. By default connections are kept alive however many there are.
+    ///
+    /// Open connections count whether a worker is serving them or they are
+    /// still waiting for one. Past the mark, every response says
+    /// `Connection: close` and its connection is closed after it, even if
+    /// the client wanted to keep it, so that workers aren't held by idle
+    /// connections while others wait. Once enough have closed, connections
+    /// are kept alive again.
+    pub fn keep_alive_high_water(mut self, connections: usize) -> ServerConfig {
+        self.keep_alive_high_water = Some(connections);
+        self
+    }
+
+    /// Let the bodies read into requests take up at most `bytes` of
+    /// memory together, across all connections. There's no limit by
+    /// default.
+    ///
+    /// The [`Limits`] bound a single request, but many requests with
+    /// moderate bodies at once can still add up to more memory than the
+    /// server has. Once the budget is used up, requests whose bodies
+    /// don't fit get `503 Service Unavailable` and their connection is
+    /// closed. Bodies handed to
+    /// [streaming handlers](crate::router::Router::route_streaming) aren't
+    /// buffered, so they don't count.
+    pub fn max_buffered_body_bytes(mut self, bytes: u64) -> ServerConfig {
+        self.max_buffered_body_bytes = Some(bytes);
+        self
+    }
+
+    /// Let request bodies sent with `Content-Encoding: gzip` or `deflate`
+    /// expand to at most `bytes` once decompressed. The default is 16 MiB.
+    ///
+    /// Such bodies reach handlers decompressed, without the
+    /// `Content-Encoding` and `Content-Length` headers, which describe the
+    /// compressed body. A few kilobytes of compressed data can expand to
+    /// gigabytes, so reading past the limit fails, and the request gets
+    /// `413 Content Too Large` unless its handler reads the body itself.
+    ///
+    /// ```
+    /// use std::io::Read;
+    ///
+    /// use rust_server::{gzip::GzipEncoder, serve_connection, Router};
+    ///
+    /// let router = Router::new().post("/", |request, _| format!("{} bytes", request.body().len()));
+    /// let upload = |body: &[u8]| {
+    ///     let mut compressed = Vec::new();
+    ///     GzipEncoder::new(body).read_to_end(&mut compressed).unwrap();
+    ///     let mut input = format!(
+    ///         "POST / HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+    ///         compressed.len()
+    ///     )
+    ///     .into_bytes();
+    ///     input.extend_from_slice(&compressed);
+    ///
+    ///     let mut output = Vec::new();
+    ///     serve_connection(&input[..], &mut output, &router);
+    ///     String::from_utf8(output).unwrap()
+    /// };
+    ///
+    /// assert!(upload(&[b'a'; 100_000]).ends_with("\r\n\r\n100000 bytes"));
+    ///
+    /// // 20 MiB of zeros compress to well under a megabyte.
+    /// let bomb = upload(&vec![0; 20 * 1024 * 1024]);
+    /// assert!(bomb.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    /// ```
+    ///
+    /// Bodies in other encodings get `415 Unsupported Media Type`, as do
+    /// all encoded bodies without the `gzip` feature:
+    ///
+    /// ```
+    /// use rust_server::{serve_connection, Router};
+    ///
+    /// let router = Router::new().post("/", |_, _| "uploaded");
+    /// let input = "POST / HTTP/1.1\r\nContent-Encoding: br\r\nContent-Length: 4\r\n\r\nbody";
+    /// let mut output = Vec::new();
+    /// serve_connection(input.as_bytes(), &mut output, &router);
+    ///
+    /// let output = String::from_utf8(output).unwrap();
+    /// assert!(output.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+    /// assert!(output.contains("Accept-Encoding: gzip, deflate\r\n"));
+    /// ```
+    #[cfg(feature = "gzip")]
+    pub fn max_decompressed_body_bytes(mut self, bytes: u64) -> ServerConfig {
+        self.max_decompressed_body_bytes = bytes;
+        self
+    }
+
+    /// Buffer response bodies of unknown length of up to `bytes` for
+    /// HTTP/1.0 clients asking for keep-alive, so they can be sent with a
+    /// `Content-Length` and the connection kept open. By default nothing
+    /// is buffered.
+    ///
+    /// HTTP/1.0 has no chunked transfer encoding, so otherwise a body
+    /// whose length isn't known, like a [`Body::Reader`](crate::Body::Reader),
+    /// is sent as it comes, and the connection closes after it to mark its
+    /// end:
+    ///
+    /// ```
+    /// use std::io;
+    ///
+    /// use rust_server::{serve_connection, Body, Response, Router};
+    ///
+    /// let router = Router::new().get("/", |_, _| {
+    ///     Response::new(200).with_body(Body::Reader(Box::new(io::Cursor::new("streamed"))))
+    /// });
+    /// let input = "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+    ///              GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+    /// let mut output = Vec::new();
+    /// serve_connection(input.as_bytes(), &mut output, &router);
+    ///
+    /// let output = String::from_utf8(output).unwrap();
+    /// assert!(output.contains("Connection: close\r\n"));
+    /// assert!(!output.contains("Content-Length"));
+    /// assert!(!output.contains("Transfer-Encoding"));
+    /// assert!(output.ends_with("\r\n\r\nstreamed"));
+    /// // The second request is never answered.
+    /// assert_eq!(output.matches("HTTP/1.1").count(), 1);
+    /// ```
+    ///
+    /// Longer bodies are still sent that way, after the part that was
+    /// buffered. HTTP/1.1 clients get chunks either way.
+    pub fn buffer_http10_bodies(mut self, bytes: u64) -> ServerConfig {
+        self.http10_buffer_limit = Some(bytes);
+        self
+    }
+
+    /// Send responses whose body is already in memory and no longer than
+    /// `bytes` with a single write, headers and all. The default is 8 KiB,
+    /// and 0 turns it off.
+    ///
+    /// Writing the headers and the body one after the other costs a
+    /// system call each, and may send them in separate packets. Larger
+    /// bodies, and those read from a file or a reader, are still written
+    /// as they come after the headers.
+    ///
+    /// ```
+    /// use std::io::{self, Write};
+    ///
+    /// use rust_server::{serve_connection, Router};
+    ///
+    /// /// Counts the calls to `write`.
+    /// struct Writes(usize);
+    ///
+    /// impl Write for Writes {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    ///         self.0 += 1;
+    ///         Ok(buf.len())
+    ///     }
+    ///
+    ///     fn flush(&mut self) -> io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let router = Router::new().get("/", |_, _| "Hello, world!");
+    /// let mut writes = Writes(0);
+    /// serve_connection(&b"GET / HTTP/1.1\r\n\r\n"[..], &mut writes, &router);
+    /// assert_eq!(writes.0, 1);
+    /// ```
+    pub fn single_write_limit(mut self, bytes: u64) -> ServerConfig {
+        self.single_write_limit = bytes;
+        self
+    }
+
+    /// Keep serving for `period` after
+    /// [`ShutdownHandle::shutdown`](crate::shutdown::ShutdownHandle::shutdown)
+    /// is called, while failing readiness checks, before actually
+    /// stopping. There's no lame duck period by default.
+    ///
+    /// This gives a load balancer polling the
+    /// [readiness path](ServerConfig::readiness_path) time to notice and
+    /// send new clients elsewhere before connections start being refused.
+    pub fn lame_duck(mut self, period: Duration) -> ServerConfig {
+        self.lame_duck = period;
+        self
+    }
+
+    /// Have the server answer `GET` requests to `path`, e.g. `/readyz`,
+    /// with `200 OK` while it is running and `503 Service Unavailable`
+    /// once it is shutting down. These requests never reach the router.
+    pub fn readiness_path(mut self, path: &str) -> ServerConfig {
+        self.readiness_path = Some(path.to_string());
+        self
+    }
+
+    /// Have the server answer `GET` requests to `path`, e.g. `/version`,
+    /// with the version of the crate and, if they were given at build
+    /// time, the commit and time it was built from. These requests never
+    /// reach the router. See [`build_info`](crate::build_info) for the
+    /// details.
+    pub fn version_path(mut self, path: &str) -> ServerConfig {
+        self.version_path = Some(path.to_string());
+        self
+    }
+
+    /// Let `TRACE` requests through to the router. They are answered with
+    /// `405 Method Not Allowed` by default.
+    ///
+    /// A `TRACE` handler echoes the request back, cookies and
+    /// authorization headers included, which a script on another site can
+    /// use to read credentials it isn't supposed to see ("cross-site
+    /// tracing"). Hardly anything needs `TRACE`, so it is safer off.
+    pub fn allow_trace(mut self, allow: bool) -> ServerConfig {
+        self.allow_trace = allow;
+        self
+    }
+
+    /// Answer `GET` and `HEAD` requests that come with a body with `400
+    /// Bad Request` and close the connection. This is off by default.
+    ///
+    /// Otherwise their body is read and thrown away before the request is
+    /// handled, so handlers never see it and the next request on the
+    /// connection starts where it should. A `Content-Length: 0` isn't a
+    /// body, and is fine either way:
+    ///
+    /// ```
+    /// use rust_server::{serve_connection, Router};
+    ///
+    /// let router = Router::new().get("/", |request, _| format!("{} bytes", request.body().len()));
+    /// let input = "GET / HTTP/1.1\r\nContent-Length: 0\r\n\r\n\
+    ///              GET / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+    ///              GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+    /// let mut output = Vec::new();
+    /// serve_connection(input.as_bytes(), &mut output, &router);
+    ///
+    /// let output = String::from_utf8(output).unwrap();
+    /// assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 3);
+    /// assert_eq!(output.matches("\r\n\r\n0 bytes").count(), 3);
+    /// ```
+    pub fn reject_get_body(mut self, reject: bool) -> ServerConfig {
+        self.reject_get_body = reject;
+        self
+    }
+
+    /// Set the format of the line logged for every request. It defaults to
+    /// [`LogFormat::Short`].
+    pub fn log_format(mut self, format: LogFormat) -> ServerConfig {
+        self.log_format = format;
+        self
+    }
+
+    /// Only log requests that take at least `threshold` to answer, from
+    /// the moment they have been read until their response has been
+    /// written. By default every request is logged.
+    ///
+    /// The others are left out of the log altogether, and a slow one is
+    /// logged as a warning with the time it took instead of the usual
+    /// line, e.g. `WARN slow request: GET /report 200 took 2.503s`.
+    pub fn slow_request_threshold(mut self, threshold: Duration) -> ServerConfig {
+        self.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Tell clients how long their requests took to answer, in a
+    /// `Server-Timing` header like `total;dur=12.3` that browsers show in
+    /// their developer tools. It's off by default.
+    ///
+    /// The duration is in milliseconds, from the moment the request has
+    /// been read until its response is about to be written, so it doesn't
+    /// include sending a streamed body. `Server-Timing` headers the
+    /// handler adds itself are kept.
+    ///
+    /// ```
+    /// use std::{
+    ///     io::{Read, Write},
+    ///     net::TcpStream,
+    ///     thread,
+    ///     time::Duration,
+    /// };
+    ///
+    /// use rust_server::{Router, Server, ServerConfig};
+    ///
+    /// let router = Router::new().get("/", |_, _| {
+    ///     thread::sleep(Duration::from_millis(20));
+    ///     "done"
+    /// });
+    /// let server = Server::bind(ServerConfig::new("127.0.0.1:0").server_timing(true), router).unwrap();
+    /// let addr = server.local_addr().unwrap();
+    /// let shutdown = server.shutdown_handle();
+    /// let running = thread::spawn(move || server.run());
+    ///
+    /// let mut stream = TcpStream::connect(addr).unwrap();
+    /// stream
+    ///     .write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+    ///     .unwrap();
+    /// let mut response = String::new();
+    /// stream.read_to_string(&mut response).unwrap();
+    ///
+    /// let timing = response
+    ///     .lines()
+    ///     .find_map(|line| line.strip_prefix("Server-Timing: total;dur="))
+    ///     .unwrap();
+    /// let millis: f64 = timing.parse().unwrap();
+    /// assert!((20.0..10_000.0).contains(&millis));
+    ///
+    /// shutdown.shutdown();
+    /// running.join().unwrap().unwrap();
+    /// ```
+    pub fn server_timing(mut self, enabled: bool) -> ServerConfig {
+        self.server_timing = enabled;
+        self
+    }
+
+    /// Choose whether responses made from a
+    /// [`HandlerError`](crate::error::HandlerError) show the error, its
+    /// sources and any backtrace in their body. Otherwise they only say
+    /// what their status says.
+    ///
+    /// This is on in debug builds and off in release builds by default.
+    /// The details can reveal a lot about the server, so they are for
+    /// local development only. They are logged either way.
+    pub fn verbose_errors(mut self, enabled: bool) -> ServerConfig {
+        self.verbose_errors = enabled;
+        self
+    }
+
+    /// Set the `Server` header sent with every response, or `None` to
+    /// leave it out. It defaults to `rust-server`.
+    pub fn server_header(mut self, value: Option<&str>) -> ServerConfig {
+        self.default_headers.server = value.map(str::to_string);
+        self
+    }
+
+    /// Choose whether every response gets a `Date` header with the time it
+    /// was sent. This is on by default, as HTTP/1.1 requires it of servers
+    /// with a clock.
+    pub fn date_header(mut self, enabled: bool) -> ServerConfig {
+        self.default_headers.date = enabled;
+        self
+    }
+
+    /// Add a header to every response that doesn't already have one with
+    /// the same name.
+    ///
+    /// Handlers override a default simply by setting the header
+    /// themselves.
+    pub fn default_header(mut self, name: &str, value: &str) -> ServerConfig {
+        self.default_headers
+            .extra
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Add a conservative set of security headers to every response:
+    /// `X-Content-Type-Options: nosniff`, `X-Frame-Options: DENY` and
+    /// `Referrer-Policy: no-referrer`.
+    pub fn security_headers(self) -> ServerConfig {
+        self.default_header("X-Content-Type-Options", "nosniff")
+            .default_header("X-Frame-Options", "DENY")
+            .default_header("Referrer-Policy", "no-referrer")
+    }
+
+    /// Set the directory static files are served from. It defaults to the
+    /// current directory.
+    ///
+    /// The server doesn't serve files by itself. This is for the
+    /// application to hand to [`StaticFiles`](crate::static_files::StaticFiles),
+    /// so the directory can be configured along with everything else,
+    /// e.g. through [`ServerConfig::from_env`].
+    pub fn static_root(mut self, root: impl Into<PathBuf>) -> ServerConfig {
+        self.static_root = root.into();
+        self
+    }
+
+    /// The directory static files are served from, see
+    /// [`ServerConfig::static_root`].
+    pub fn static_root_dir(&self) -> &Path {
+        &self.static_root
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            addr: "127.0.0.1:7878".to_string(),
+            pool_size: 4,
+            #[cfg(feature = "affinity")]
+            pin_to_cores: false,
+            queue_capacity: None,
+            overflow: Overflow::Reject,
+            accept_strategy: AcceptStrategy::Dispatch,
+            #[cfg(feature = "socket_options")]
+            reuse: ReuseOptions::default(),
+            limits: Limits::default(),
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: None,
+            write_timeout: None,
+            max_accept_rate: None,
+            max_keep_alive_connections: None,
+            max_connections_per_ip: None,
+            proxy_trust: ProxyTrust::None,
+            keep_alive_high_water: None,
+            max_buffered_body_bytes: None,
+            #[cfg(feature = "gzip")]
+            max_decompressed_body_bytes: 16 * 1024 * 1024,
+            http10_buffer_limit: None,
+            single_write_limit: 8 * 1024,
+            lame_duck: Duration::ZERO,
+            readiness_path: None,
+            version_path: None,
+            allow_trace: false,
+            reject_get_body: false,
+            log_format: LogFormat::default(),
+            slow_request_threshold: None,
+            server_timing: false,
+            verbose_errors: cfg!(debug_assertions),
+            default_headers: DefaultHeaders {
+                server: Some("rust-server".to_string()),
+                date: true,
+                extra: Vec::new(),
+            },
+            static_root: PathBuf::from("."),
+        }
+    }
+}
+
+/// How a [`Server`] hands the connections it accepts to its workers, see
+/// [`ServerConfig::accept_strategy`].
+///
+/// Both serve requests the same way:
+///
+/// ```
+/// use std::{
+///     io::{Read, Write},
+///     net::TcpStream,
+///     thread,
+/// };
+///
+/// use rust_server::{AcceptStrategy, Router, Server, ServerConfig};
+///
+/// for strategy in [AcceptStrategy::Dispatch, AcceptStrategy::Workers] {
+///     let config = ServerConfig::new("127.0.0.1:0").accept_strategy(strategy);
+///     let server = Server::bind(config, Router::new().get("/", |_, _| "hi")).unwrap();
+///     let addr = server.local_addr().unwrap();
+///     let shutdown = server.shutdown_handle();
+///     let running = thread::spawn(move || server.run());
+///
+///     for _ in 0..8 {
+///         let mut stream = TcpStream::connect(addr).unwrap();
+///         stream
+///             .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+///             .unwrap();
+///         let mut response = String::new();
+///         stream.read_to_string(&mut response).unwrap();
+///         assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+///         assert!(response.ends_with("\r\n\r\nhi"));
+///     }
+///
+///     shutdown.shutdown();
+///     running.join().unwrap().unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AcceptStrategy {
+    /// Accept every connection on the thread running
+    /// [`Server::run`], and queue it for the next free worker. This is
+    /// the default.
+    ///
+    /// The queue lets connections wait for a worker, within the
+    /// [queue capacity](ServerConfig::queue_capacity), while every worker
+    /// is busy.
+    #[default]
+    Dispatch,
+    /// Have every worker accept its own connections from the listening
+    /// socket, and serve each itself before accepting the next.
+    ///
+    /// This saves the trip through the queue, and connections are only
+    /// accepted when a worker is free to serve them. Otherwise they wait
+    /// in the operating system's backlog, so the queue capacity and
+    /// overflow don't apply. Every idle worker is woken up by a new
+    /// connection on some systems, only for all but one to go back to
+    /// waiting.
+    Workers,
+}
+
+/// The headers added to responses that don't set them.
+#[derive(Debug, Clone)]
+struct DefaultHeaders {
+    server: Option<String>,
+    date: bool,
+    extra: Vec<(String, String)>,
+}
+
+impl DefaultHeaders {
+    /// Add every default header that `response` doesn't have yet.
+    fn apply(&self, response: &mut Response) {
+        if let Some(server) = &self.server {
+            if response.header("Server").is_none() {
+                response.add_header("Server", server);
+            }
+        }
+
+        // The date is formatted at most once a second, and always gives
+        // the second the response was sent.
+        if self.date && response.header("Date").is_none() {
+            date::with_current_http_date(|date| response.add_header("Date", date));
+        }
+
+        for (name, value) in &self.extra {
+            if response.header(name).is_none() {
+                response.add_header(name, value);
+            }
+        }
+    }
+}
+
+/// The reasons a [`Server`] could not start or stopped serving.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The listening socket could not be bound, e.g. because the port is
+    /// already in use.
+    Bind(io::Error),
+    /// Accepting a connection failed in a way that won't go away by
+    /// retrying.
+    Accept(io::Error),
+    /// The worker pool could not be created.
+    Pool(PoolCreationError),
+    /// None of the workers could be handed an accept loop, with
+    /// [`AcceptStrategy::Workers`].
+    Workers(ExecuteError),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Bind(err) => write!(f, "failed to bind the listening socket: {err}"),
+            ServerError::Accept(err) => write!(f, "failed to accept a connection: {err}"),
+            ServerError::Pool(err) => write!(f, "failed to create the worker pool: {err}"),
+            ServerError::Workers(err) => {
+                write!(f, "failed to start accepting on the workers: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Bind(err) | ServerError::Accept(err) => Some(err),
+            ServerError::Pool(err) => Some(err),
+            ServerError::Workers(err) => Some(err),
+        }
+    }
+}
+
+/// An HTTP server: a listening socket, a pool of workers and a router.
+///
+/// Any [`Service`] can take the place of the router, such as a
+/// [`RadixRouter`](crate::radix::RadixRouter).
+///
+/// # Connections and workers
+///
+/// A connection is handed to a worker when it is accepted and keeps that
+/// worker until it closes, including while it sits idle between kept-alive
+/// requests. Requests on one connection are therefore answered one after
+/// the other, but a slow request never holds up another connection as
+/// long as a worker is free for it.
+///
+/// With a [pool size](ServerConfig::pool_size) of `n`, `n` open
+/// connections occupy every worker, whether they are waiting on slow
+/// handlers, like the five second `/sleep` route of the example binary,
+/// or simply idle. Connections accepted after that wait in the pool's
+/// queue until one of them closes, which an idle connection does after the
+/// [keep-alive timeout](ServerConfig::keep_alive_timeout) at the latest.
+/// So up to `n` slow requests can be in progress at once, and a client
+/// arriving while they are can wait as long as the slowest of them takes
+/// to finish. Size the pool for the number of slow requests expected
+/// at once, and keep the keep-alive timeout short.
+pub struct Server<R = Router> {
+    listener: TcpListener,
+    pool: ThreadPool,
+    router: R,
+    settings: Settings,
+}
+
+/// Everything a connection needs besides the router.
+struct Settings {
+    limits: Limits,
+    keep_alive_timeout: Duration,
+    request_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_accept_rate: Option<u32>,
+    accept_strategy: AcceptStrategy,
+    http10_buffer_limit: Option<u64>,
+    single_write_limit: u64,
+    clock: Arc<dyn Clock>,
+    keep_alive_slots: KeepAliveSlots,
+    peer_limit: Option<Arc<PeerLimit>>,
+    proxy_trust: ProxyTrust,
+    keep_alive_high_water: Option<usize>,
+    load: Arc<LoadGauge>,
+    body_budget: Option<Arc<BodyBudget>>,
+    #[cfg(feature = "gzip")]
+    max_decompressed_body_bytes: u64,
+    readiness_path: Option<String>,
+    version_path: Option<String>,
+    allow_trace: bool,
+    reject_get_body: bool,
+    log_format: LogFormat,
+    slow_request_threshold: Option<Duration>,
+    server_timing: bool,
+    verbose_errors: bool,
+    default_headers: DefaultHeaders,
+    upgrades: UpgradeHandlers,
+    tunnel: Option<UpgradeHandler>,
+    error_pages: ErrorPages,
+    traffic: TrafficStats,
+    on_connection_closed: Option<ConnectionHook>,
+    shutdown: Arc<ShutdownState>,
+}
+
+impl Settings {
+    fn new(config: ServerConfig, shutdown: ShutdownState) -> Settings {
+        Settings {
+            limits: config.limits,
+            keep_alive_timeout: config.keep_alive_timeout,
+            request_timeout: config.request_timeout,
+            write_timeout: config.write_timeout,
+            max_accept_rate: config.max_accept_rate,
+            accept_strategy: config.accept_strategy,
+            http10_buffer_limit: config.http10_buffer_limit,
+            single_write_limit: config.single_write_limit,
+            clock: Arc::new(SystemClock),
+            proxy_trust: config.proxy_trust,
+            peer_limit: config
+                .max_connections_per_ip
+                .map(|limit| Arc::new(PeerLimit::new(limit))),
+            keep_alive_slots: KeepAliveSlots::new(
+                config
+                    .max_keep_alive_connections
+                    .unwrap_or(config.pool_size.saturating_sub(1).max(1)),
+            ),
+            keep_alive_high_water: config.keep_alive_high_water,
+            load: Arc::new(LoadGauge::default()),
+            #[cfg(feature = "gzip")]
+            max_decompressed_body_bytes: config.max_decompressed_body_bytes,
+            body_budget: config
+                .max_buffered_body_bytes
+                .map(|bytes| Arc::new(BodyBudget::new(bytes))),
+            readiness_path: config.readiness_path,
+            version_path: config.version_path,
+            allow_trace: config.allow_trace,
+            reject_get_body: config.reject_get_body,
+            log_format: config.log_format,
+            slow_request_threshold: config.slow_request_threshold,
+            server_timing: config.server_timing,
+            verbose_errors: config.verbose_errors,
+            default_headers: config.default_headers,
+            upgrades: UpgradeHandlers::new(),
+            tunnel: None,
+            error_pages: ErrorPages::new(),
+            traffic: TrafficStats::default(),
+            on_connection_closed: None,
+            shutdown: Arc::new(shutdown),
+        }
+    }
+
+    /// Whether more connections are open than should be kept alive.
+    fn overloaded(&self) -> bool {
+        self.keep_alive_high_water
+            .is_some_and(|high_water| self.load.get() > high_water)
+    }
+}
+
+/// Everything a connection needs, shared by all the workers.
+struct Shared<R> {
+    router: R,
+    settings: Settings,
+}
+
+impl<R: Service> Server<R> {
+    /// Bind the listening socket and start the worker pool.
+    ///
+    /// Nothing is accepted until [`Server::run`] is called, but clients
+    /// can already connect, since the socket is listening.
+    pub fn bind(config: ServerConfig, router: R) -> Result<Server<R>, ServerError> {
+        let listener = bind_listener(&config).map_err(ServerError::Bind)?;
+        let pool = build_pool(&config).map_err(ServerError::Pool)?;
+        let addr = listener.local_addr().map_err(ServerError::Bind)?;
+        let shutdown = ShutdownState::new(Some(addr), config.lame_duck);
+
+        Ok(Server {
+            listener,
+            pool,
+            router,
+            settings: Settings::new(config, shutdown),
+        })
+    }
+
+    /// Hand connections asking to upgrade to `protocol` over to `handler`.
+    ///
+    /// When a request carries `Connection: upgrade` and lists `protocol`
+    /// in its `Upgrade` header, the server answers `101 Switching
+    /// Protocols` instead of routing the request, and calls `handler` with
+    /// the request and the raw connection. The connection is the handler's
+    /// from then on and is closed when the handler drops it. Protocol names
+    /// are compared case-insensitively.
+    pub fn upgrade<F>(mut self, protocol: &str, handler: F) -> Server<R>
+    where
+        F: Fn(&Request, Upgraded) + Send + Sync + 'static,
+    {
+        self.settings
+            .upgrades
+            .insert(protocol.to_ascii_lowercase(), Arc::new(handler));
+        self
+    }
+
+    /// Hand connections that send a `CONNECT` request over to `handler`,
+    /// to tunnel them to the host the request names.
+    ///
+    /// The server answers `200` and calls `handler` with the request and
+    /// the raw connection, which is the handler's from then on, just like
+    /// after an [upgrade](Server::upgrade). The target host and port are
+    /// the request's [`target`](Request::target).
+    ///
+    /// Without a tunneling handler, `CONNECT` requests are answered with
+    /// `405 Method Not Allowed` and never reach the router, since an open
+    /// tunnel lets clients reach any host the server can.
+    pub fn connect<F>(mut self, handler: F) -> Server<R>
+    where
+        F: Fn(&Request, Upgraded) + Send + Sync + 'static,
+    {
+        self.settings.tunnel = Some(Arc::new(handler));
+        self
+    }
+
+    /// Answer responses with `status` with the page `handler` builds for
+    /// the request instead, e.g. a branded `500` page.
+    ///
+    /// The page is sent with `status` whatever the handler returns, along
+    /// with the headers of the response it replaces that it doesn't set
+    /// itself, like the `Allow` of a `405`. It replaces the responses the
+    /// router returns as well as those the server answers routed requests
+    /// with itself. Requests that can't be parsed get the built-in page,
+    /// since there is no request to hand the handler. So do errors while
+    /// [`ServerConfig::verbose_errors`] is on, so their detail is shown.
+    ///
+    /// Registering a page for the same status twice replaces the earlier
+    /// one.
+    pub fn error_page<F, T>(mut self, status: u16, handler: F) -> Server<R>
+    where
+        F: Fn(&Request) -> T + Send + Sync + 'static,
+        T: IntoResponse,
+    {
+        self.settings.error_pages.insert(
+            status,
+            Box::new(move |request| handler(request).into_response()),
+        );
+        self
+    }
+
+    /// Answer responses with `status` with the file at `path`, like
+    /// [`Server::error_page`]. The file is read for every response, with
+    /// its `Content-Type` guessed from its extension, so it can be edited
+    /// while the server runs. If it can't be read, the response is a
+    /// plain text page saying what the status says.
+    pub fn error_page_file(self, status: u16, path: impl Into<PathBuf>) -> Server<R> {
+        let path = path.into();
+        self.error_page(status, move |_| match fs::read(&path) {
+            Ok(page) => Response::new(status)
+                .with_header("Content-Type", static_files::content_type(&path))
+                .with_body(page),
+            Err(err) => {
+                eprintln!("Failed to read the error page {path:?}: {err}");
+                Response::text(status, format!("{status} {}", reason_phrase(status)))
+            }
+        })
+    }
+
+    /// Call `hook` with the byte counts of every connection once it has
+    /// closed, e.g. for per-client bandwidth monitoring.
+    ///
+    /// The counts cover everything read from and written to the socket
+    /// over all the requests of the connection, including after an
+    /// [upgrade](Server::upgrade). The hook runs on the connection's
+    /// worker.
+    pub fn on_connection_closed<F>(mut self, hook: F) -> Server<R>
+    where
+        F: Fn(&ConnectionStats) + Send + Sync + 'static,
+    {
+        self.settings.on_connection_closed = Some(Box::new(hook));
+        self
+    }
+
+    /// Time the [accept rate limit](ServerConfig::max_accept_rate) with
+    /// `clock`. It defaults to the [`SystemClock`].
+    ///
+    /// With a [`ManualClock`](crate::clock::ManualClock), waiting for the
+    /// limit advances the clock instead of sleeping, so tests of a limited
+    /// server run at full speed.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Server<R> {
+        self.settings.clock = Arc::new(clock);
+        self
+    }
+
+    /// The byte counts of all the connections the server has closed,
+    /// which keep growing while it runs.
+    pub fn traffic(&self) -> TrafficStats {
+        self.settings.traffic.clone()
+    }
+
+    /// The address the server is listening on.
+    ///
+    /// This is useful after binding to port 0, which lets the operating
+    /// system pick a free port.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// A handle to shut the server down once it is running.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(Arc::clone(&self.settings.shutdown))
+    }
+
+    /// Accept connections and hand them to the worker pool until the
+    /// server is shut down.
+    ///
+    /// Errors that only affect the one connection being accepted, like a
+    /// client hanging up before the connection was accepted, are logged
+    /// and skipped. Running out of file descriptors or memory is logged
+    /// too, and accepting is retried after a pause that doubles with every
+    /// failure in a row, giving open connections time to finish. Any other
+    /// accept error stops the server and is returned.
+    ///
+    /// After a [shutdown](ShutdownHandle::shutdown), this waits for the
+    /// workers to finish the connections they are serving before
+    /// returning `Ok`.
+    pub fn run(self) -> Result<(), ServerError> {
+        let Server {
+            mut listener,
+            pool,
+            router,
+            settings,
+        } = self;
+
+        let shared = Arc::new(Shared { router, settings });
+
+        let rate = shared.settings.max_accept_rate.map(|per_second| {
+            let clock = Arc::clone(&shared.settings.clock);
+            Arc::new(Mutex::new(TokenBucket::new(per_second, clock)))
+        });
+        if shared.settings.accept_strategy == AcceptStrategy::Workers {
+            return accept_on_workers(&listener, &pool, &shared, rate);
+        }
+
+        let stopping = || shared.settings.shutdown.is_stopping();
+        accept_loop(&mut listener, stopping, rate.as_deref(), |mut stream| {
+            let Some(peer) = admit(&mut stream, &shared.settings) else {
+                return;
+            };
+            let shared = Arc::clone(&shared);
+            // The connection counts as open until the job is done with it,
+            // or dropped along with it.
+            let open = shared.settings.load.open();
+
+            // Submit a job to the thread pool. The closure passed to
+            // `execute()` is the code that will be run by one of the
+            // threads in the pool. The `move` keyword ensures that the
+            // closure takes ownership of `stream` and `shared`.
+            //
+            // The pool only refuses jobs once it has stopped, or when its
+            // queue is full, and then the connection is dropped, closing
+            // it.
+            if let Err(err) = pool.execute(move || {
+                handle_connection(stream, &shared);
+                drop(open);
+                drop(peer);
+            }) {
+                eprintln!("Dropping a connection: {err}");
+            }
+        })
+    }
+}
+
+/// Let every worker of `pool` accept connections from `listener` and serve
+/// them, for [`AcceptStrategy::Workers`], until the server is shut down.
+///
+/// The workers share the `rate` limit. A worker that can't accept any
+/// more stops the others too, and the first error is returned once they
+/// have all stopped. If no worker takes an accept loop at all, there is
+/// nobody to serve connections, which is an error as well.
+fn accept_on_workers<R: Service>(
+    listener: &TcpListener,
+    pool: &ThreadPool,
+    shared: &Arc<Shared<R>>,
+    rate: Option<Arc<Mutex<TokenBucket>>>,
+) -> Result<(), ServerError> {
+    let listeners = (0..pool.size())
+        .map(|_| listener.try_clone())
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(ServerError::Accept)?;
+
+    let (results, finished) = mpsc::channel();
+    let mut started = 0;
+    let mut refused = None;
+    for mut listener in listeners {
+        let shared = Arc::clone(shared);
+        let rate = rate.clone();
+        let results = results.clone();
+        let accepting = pool.execute(move || {
+            let stopping = || shared.settings.shutdown.is_stopping();
+            let result = accept_loop(&mut listener, stopping, rate.as_deref(), |mut stream| {
+                let Some(_peer) = admit(&mut stream, &shared.settings) else {
+                    return;
+                };
+                let _open = shared.settings.load.open();
+                handle_connection(stream, &shared);
+            });
+            // Only one worker gets the connection that wakes it up to
+            // stop, so it passes one on to the next. The first to fail
+            // starts that, since the others may be blocked accepting.
+            match result {
+                Ok(()) => shared.settings.shutdown.wake(),
+                Err(_) => shared.settings.shutdown.stop(),
+            }
+            let _ = results.send(result);
+        });
+        match accepting {
+            Ok(()) => started += 1,
+            Err(err) => {
+                eprintln!("Failed to start accepting on a worker: {err}");
+                refused = Some(err);
+            }
+        }
+    }
+    drop(results);
+    if started == 0 {
+        return Err(ServerError::Workers(
+            refused.unwrap_or(ExecuteError::ShutDown),
+        ));
+    }
+
+    // Wait for every worker, not only up to the first error.
+    let mut outcome = Ok(());
+    for result in finished {
+        if outcome.is_ok() {
+            outcome = result;
+        }
+    }
+    outcome
+}
+
+/// Bind the listening socket `config` asks for.
+fn bind_listener(config: &ServerConfig) -> io::Result<TcpListener> {
+    #[cfg(feature = "socket_options")]
+    return socket::bind(&config.addr, config.reuse);
+    #[cfg(not(feature = "socket_options"))]
+    TcpListener::bind(&config.addr)
+}
+
+/// Start the worker pool `config` asks for.
+fn build_pool(config: &ServerConfig) -> Result<ThreadPool, PoolCreationError> {
+    #[cfg(feature = "affinity")]
+    let pool = if config.pin_to_cores {
+        ThreadPool::build_pinned(config.pool_size)
+    } else {
+        ThreadPool::build(config.pool_size)
+    };
+    #[cfg(not(feature = "affinity"))]
+    let pool = ThreadPool::build(config.pool_size);
+
+    let mut pool = pool?.overflow(config.overflow);
+    if let Some(capacity) = config.queue_capacity {
+        pool = pool.queue_capacity(capacity);
+    }
+    Ok(pool)
+}
+
+/// Serve the requests a client sends on `reader`, writing the responses
+/// to `writer`, exactly like a server with the default
+/// [`ServerConfig`] would over a socket.
+///
+/// This runs the whole per-connection logic, keep-alive and pipelining
+/// included, and returns once the connection would have been closed: the
+/// reader ended, the client asked to close, or it sent something that
+/// isn't a valid request. Protocol upgrades and `CONNECT` tunnels are
+/// never accepted, since there is no connection to hand over.
+///
+/// It makes it possible to test a router end to end without sockets, e.g.
+/// by reading from a byte slice of pipelined requests and writing to a
+/// `Vec<u8>`.
+pub fn serve_connection<I: Read, O: Write, S: Service>(reader: I, writer: O, service: &S) {
+    let settings = Settings::new(
+        ServerConfig::default(),
+        ShutdownState::new(None, Duration::ZERO),
+    );
+    let mut stream = BufReader::new(Duplex { reader, writer });
+    serve_requests(&mut stream, None, service, &settings);
+}
+
+/// A reader and a writer joined into a single stream.
+struct Duplex<I, O> {
+    reader: I,
+    writer: O,
+}
+
+impl<I: Read, O> Read for Duplex<I, O> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<I, O: Write> Write for Duplex<I, O> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A stream requests are read from and responses written to.
+pub(crate) trait Connection: Read + Write {
+    /// Tell the client that nothing more will be written, while still
+    /// being able to read what it sends.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+
+    /// Make a write that can't go on for `timeout` fail, or let it wait
+    /// forever with `None`.
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Make a read that can't go on for `timeout` fail, or let it wait
+    /// forever with `None`.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<I: Read, O: Write> Connection for Duplex<I, O> {
+    /// There's no telling the writer that nothing more is coming, short
+    /// of dropping it, which happens when the connection is done anyway.
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// A writer can't be interrupted, so only the writes started after
+    /// the deadline fail.
+    fn set_write_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Nor can a reader, so reads wait for as long as they take.
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The reading end of a connection, which sends `100 Continue` before
+/// the first read if the client is waiting for it to send the body.
+struct Continue<'a, T> {
+    reader: &'a mut BufReader<T>,
+    pending: bool,
+    write_timeout: Option<Duration>,
+}
+
+impl<'a, T: Connection> Continue<'a, T> {
+    fn new(
+        reader: &'a mut BufReader<T>,
+        pending: bool,
+        write_timeout: Option<Duration>,
+    ) -> Continue<'a, T> {
+        Continue {
+            reader,
+            pending,
+            write_timeout,
+        }
+    }
+}
+
+impl<T: Connection> Read for Continue<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending {
+            let mut stream = Deadline::new(self.reader.get_mut(), self.write_timeout);
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            stream.flush()?;
+            self.pending = false;
+        }
+        self.reader.read(buf)
+    }
+}
+
+/// The writing end of a connection, failing with `TimedOut` once
+/// `deadline` has passed, for the [write timeout](ServerConfig::write_timeout).
+///
+/// Each write may only block until the deadline, so a client that stops
+/// reading can't hold it up for longer either.
+struct Deadline<'a, T> {
+    stream: &'a mut T,
+    deadline: Option<Instant>,
+}
+
+impl<'a, T: Connection> Deadline<'a, T> {
+    /// Write to `stream` for at most `timeout` from now, if there is one.
+    fn new(stream: &'a mut T, timeout: Option<Duration>) -> Deadline<'a, T> {
+        Deadline {
+            stream,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+}
+
+impl<T: Connection> Write for Deadline<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the write timeout passed",
+                ));
+            }
+            self.stream.set_write_timeout(Some(left))?;
+        }
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A source of incoming connections, usually a [`TcpListener`].
+pub(crate) trait Accept {
+    type Connection;
+
+    /// Wait for the next connection.
+    fn accept(&mut self) -> io::Result<Self::Connection>;
+}
+
+impl Accept for TcpListener {
+    type Connection = TcpStream;
+
+    fn accept(&mut self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _)| stream)
+    }
+}
+
+/// How long to wait before the first retry after running out of resources.
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+
+/// The longest wait between two retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Accept connections from `source` and pass each one to `on_connection`
+/// until a fatal error happens, or `stopping` says to stop.
+///
+/// `stopping` is checked whenever a connection arrives. The connection
+/// that finds the loop stopping is dropped without being served.
+///
+/// With a `rate` limit, every accept waits for a token from the bucket
+/// first. Several loops may share the bucket.
+pub(crate) fn accept_loop<A: Accept>(
+    source: &mut A,
+    stopping: impl Fn() -> bool,
+    rate: Option<&Mutex<TokenBucket>>,
+    mut on_connection: impl FnMut(A::Connection),
+) -> Result<(), ServerError> {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        if let Some(rate) = rate {
+            rate.lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .take();
+        }
+
+        match source.accept() {
+            Ok(_) if stopping() => return Ok(()),
+            Ok(connection) => {
+                backoff = MIN_BACKOFF;
+                on_connection(connection);
+            }
+            Err(err) => match AcceptError::classify(&err) {
+                AcceptError::Connection => {
+                    eprintln!("Skipping a connection that failed while being accepted: {err}");
+                }
+                AcceptError::Resources => {
+                    eprintln!("Out of resources while accepting ({err}); retrying in {backoff:?}");
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                AcceptError::Fatal => return Err(ServerError::Accept(err)),
+            },
+        }
+    }
+}
+
+/// A token bucket admitting a steady number of events per second, with
+/// bursts of up to a second's worth.
+///
+/// Rather than counting tokens, the bucket keeps the time it has saved up,
+/// and every token costs a second divided by the rate.
+pub(crate) struct TokenBucket {
+    cost: Duration,
+    saved: Duration,
+    last: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl TokenBucket {
+    /// A full bucket for `per_second` events per second, timed by `clock`.
+    /// A rate of zero is treated as one.
+    pub(crate) fn new(per_second: u32, clock: Arc<dyn Clock>) -> TokenBucket {
+        TokenBucket {
+            cost: Duration::from_secs(1) / per_second.max(1),
+            saved: Duration::from_secs(1),
+            last: clock.now(),
+            clock,
+        }
+    }
+
+    /// Take a token, sleeping until one is available if the bucket is
+    /// empty.
+    pub(crate) fn take(&mut self) {
+        let now = self.clock.now();
+        self.saved = (self.saved + (now - self.last)).min(Duration::from_secs(1));
+        self.last = now;
+
+        match self.saved.checked_sub(self.cost) {
+            Some(left) => self.saved = left,
+            None => {
+                self.clock.sleep(self.cost - self.saved);
+                self.saved = Duration::ZERO;
+                self.last = self.clock.now();
+            }
+        }
+    }
+}
+
+/// What an accept error means for the accept loop.
+#[derive(Debug, PartialEq, Eq)]
+enum AcceptError {
+    /// Only the connection being accepted is affected, so the next accept
+    /// can succeed right away.
+    Connection,
+    /// The process or the system ran out of file descriptors or memory.
+    /// Retrying immediately would fail again, but waiting for connections
+    /// to close frees them up.
+    Resources,
+    /// The listening socket itself is broken.
+    Fatal,
+}
+
+impl AcceptError {
+    fn classify(err: &io::Error) -> AcceptError {
+        // EMFILE and ENFILE share their numbers on every Unix we care
+        // about, as does ENOMEM. Windows reports WSAEMFILE.
+        #[cfg(unix)]
+        const RESOURCE_ERRORS: &[i32] = &[12, 23, 24];
+        #[cfg(windows)]
+        const RESOURCE_ERRORS: &[i32] = &[10024];
+        #[cfg(not(any(unix, windows)))]
+        const RESOURCE_ERRORS: &[i32] = &[];
+
+        if err
+            .raw_os_error()
+            .is_some_and(|code| RESOURCE_ERRORS.contains(&code))
+        {
+            return AcceptError::Resources;
+        }
+
+        match err.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock => AcceptError::Connection,
+            io::ErrorKind::OutOfMemory => AcceptError::Resources,
+            _ => AcceptError::Fatal,
+        }
+    }
+}
+
+/// Count `stream` against the connections its client may have open, or
+/// answer it with `503 Service Unavailable` if it has too many already.
+/// The refused connection is closed as it is dropped.
+fn admit(stream: &mut TcpStream, settings: &Settings) -> Option<PeerConnection> {
+    let Some(limit) = &settings.peer_limit else {
+        return Some(PeerConnection::uncounted());
+    };
+    let Ok(peer) = stream.peer_addr() else {
+        return Some(PeerConnection::uncounted());
+    };
+    if let Some(counted) = limit.open(peer.ip()) {
+        return Some(counted);
+    }
+    eprintln!("Refusing a connection from {}: too many open", peer.ip());
+    // A fresh connection has room in its send buffer for a response this
+    // short, so writing it doesn't hold up the accept loop.
+    let response = Response::text(503, "too many connections").with_header("Connection", "close");
+    let _ = response.write_to(stream);
+    None
+}
+
+fn handle_connection<R: Service>(stream: TcpStream, shared: &Shared<R>) {
+    let settings = &shared.settings;
+
+    // Give up on connections that stay quiet for too long, whether they
+    // are idle between requests or stuck halfway through one.
+    if let Err(err) = stream.set_read_timeout(Some(settings.keep_alive_timeout)) {
+        eprintln!("Failed to set the read timeout: {err}");
+        return;
+    }
+
+    // Take the stream and wrap it in a BufReader.
+    //
+    // `BufReader` is a type from the standard library that wraps a
+    // `Read` object and provides buffering. `Request::parse()` needs
+    // it to read the request line by line. Responses are written to the
+    // stream underneath, through `get_mut()`.
+    //
+    // The same reader is used for every request on the connection. A
+    // client pipelining its requests sends the next one before it has
+    // seen the previous response, so the buffer may already hold part of
+    // it, and a new reader would lose those bytes.
+    //
+    // Underneath it, the stream counts the bytes going through it.
+    let counts = Arc::new(ByteCounts::default());
+    let stream = CountingStream::new(stream, Arc::clone(&counts));
+    let mut buf_reader = BufReader::new(stream);
+
+    // A request to switch to a protocol we have a handler for takes the
+    // connection away from HTTP for good. Any bytes the client sent after
+    // the request are still in the reader, so they go to the handler too.
+    let peer = buf_reader.get_ref().get_ref().peer_addr().ok();
+    if let Some((request, handler)) =
+        serve_requests(&mut buf_reader, peer, &shared.router, settings)
+    {
+        // The handler speaks its own protocol, and sets its own timeouts.
+        if let Err(err) = buf_reader.get_mut().set_write_timeout(None) {
+            eprintln!("Failed to clear the write timeout: {err}");
+            return;
+        }
+        handler(&request, Upgraded::new(buf_reader));
+    } else {
+        drop(buf_reader);
+    }
+
+    let stats = counts.stats(peer);
+    settings.traffic.record(&stats);
+    if let Some(hook) = &settings.on_connection_closed {
+        hook(&stats);
+    }
+}
+
+/// Answer the requests on a connection until it should be closed, or
+/// until a request asks to upgrade to a protocol with a handler.
+///
+/// In the latter case the `101 Switching Protocols` response has already
+/// been sent, and the request and the handler to call are returned.
+fn serve_requests<T: Connection, R: Service>(
+    buf_reader: &mut BufReader<T>,
+    peer: Option<SocketAddr>,
+    router: &R,
+    settings: &Settings,
+) -> Option<(Request, UpgradeHandler)> {
+    // The slot that lets the connection be kept alive, once it has one.
+    // Past the limit, connections are closed after their first request.
+    let mut kept_alive: Option<KeepAliveSlot> = None;
+    loop {
+        // The client is allowed to close the connection between two
+        // requests, or to let it go idle until the timeout, and neither
+        // deserves an error response.
+        match buf_reader.fill_buf() {
+            Ok([]) | Err(_) => return None,
+            Ok(_) => {}
+        }
+
+        // Parse the request line and the headers. If the client sent
+        // something that isn't HTTP, or a request line that is too long,
+        // tell it so and give up on the connection, since there is no
+        // telling where the next request would start. Errors that leave
+        // the stream at the start of the next request, like an unknown
+        // method, are answered without closing it.
+        //
+        // The body is left on the stream for the router, which may hand it
+        // to a handler as it arrives. Requests answered before routing get
+        // their body read here, unless the client is waiting to be told to
+        // send it, since they're all turned down or need no body anyway.
+        let parsed = Request::parse_head_with_limits(buf_reader, &settings.limits).and_then(
+            |(mut request, length)| {
+                request.set_body_budget(settings.body_budget.clone());
+                // A body on a `GET` or `HEAD` means nothing, so it is
+                // turned down or thrown away before anyone sees the
+                // request. Either way it can't be taken for the next one.
+                if length > 0 && matches!(request.method(), Method::Get | Method::Head) {
+                    if settings.reject_get_body {
+                        return Err(ParseError::Malformed("body on a GET or HEAD request"));
+                    }
+                    let expecting = request.expects_continue();
+                    let stream = Continue::new(&mut *buf_reader, expecting, settings.write_timeout);
+                    io::copy(&mut BodyReader::new(stream, length), &mut io::sink())?;
+                    return Ok((request, 0));
+                }
+                if is_routed(&request, settings) || request.expects_continue() {
+                    return Ok((request, length));
+                }
+                request.read_body(&mut BodyReader::new(&mut *buf_reader, length))?;
+                Ok((request, 0))
+            },
+        );
+        let (mut request, length) = match parsed {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                let recoverable = err.is_recoverable();
+                let mut response = Response::new(err.status());
+                if recoverable {
+                    response.add_header("Connection", "keep-alive");
+                } else {
+                    response.add_header("Connection", "close");
+                }
+                settings.default_headers.apply(&mut response);
+                if let Err(err) = response.write_to(&mut Deadline::new(
+                    buf_reader.get_mut(),
+                    settings.write_timeout,
+                )) {
+                    log_write_error(&err);
+                    return None;
+                }
+                if recoverable {
+                    continue;
+                }
+                finish(buf_reader.get_mut());
+                return None;
+            }
+        };
+        let received = SystemTime::now();
+        let started = Instant::now();
+        request.set_deadline(
+            settings
+                .request_timeout
+                .map(|timeout| Instant::now() + timeout),
+        );
+        forwarded::resolve(&mut request, peer, &settings.proxy_trust);
+
+        if let Some((protocol, handler)) = upgrade::find_handler(&request, &settings.upgrades) {
+            let mut response = upgrade::switching_protocols(protocol);
+            settings.default_headers.apply(&mut response);
+            if let Err(err) = response.write_to(&mut Deadline::new(
+                buf_reader.get_mut(),
+                settings.write_timeout,
+            )) {
+                log_write_error(&err);
+                return None;
+            }
+            let handler = Arc::clone(handler);
+            return Some((request, handler));
+        }
+
+        // A successful answer to `CONNECT` has neither a body nor a length,
+        // since the connection becomes the tunnel right after it.
+        if let (Method::Connect, Some(handler)) = (request.method(), &settings.tunnel) {
+            let mut response = Response::new(200);
+            settings.default_headers.apply(&mut response);
+            if let Err(err) = response.write_head(&mut Deadline::new(
+                buf_reader.get_mut(),
+                settings.write_timeout,
+            )) {
+                log_write_error(&err);
+                return None;
+            }
+            return Some((request, Arc::clone(handler)));
+        }
+
+        // Let the router pick the handler for the request's method and path,
+        // fill in the default headers the handler didn't set, and write the
+        // response to the stream. Readiness checks are answered here,
+        // since only the server knows whether it is shutting down, and so
+        // are requests for the build information.
+        let mut unread = length;
+        // Whether the client is still waiting to be told to send its body.
+        let mut awaiting_body = request.expects_continue() && length > 0;
+        let mut response = if is_readiness_check(&request, settings) {
+            readiness_response(&settings.shutdown)
+        } else if is_version_check(&request, settings) {
+            build_info::respond(&request)
+        } else if is_refused_method(&request, settings) {
+            Response::text(405, "405 Method Not Allowed")
+        } else if let ContentCoding::Unsupported = content_coding(&request) {
+            Response::text(415, "415 Unsupported Media Type")
+                .with_header("Accept-Encoding", SUPPORTED_CODINGS)
+        } else {
+            let stream = Continue::new(&mut *buf_reader, awaiting_body, settings.write_timeout);
+            let mut body = BodyReader::new(stream, length);
+            let response = match content_coding(&request) {
+                #[cfg(feature = "gzip")]
+                coding @ (ContentCoding::Gzip | ContentCoding::Zlib) => {
+                    // The handler sees the body as it was before it was
+                    // compressed.
+                    request.remove_header("Content-Encoding");
+                    request.remove_header("Content-Length");
+                    let decoder = match coding {
+                        ContentCoding::Gzip => GzipDecoder::new(&mut body),
+                        _ => GzipDecoder::zlib(&mut body),
+                    };
+                    let mut decoder = decoder.limit(settings.max_decompressed_body_bytes);
+                    router.handle_streaming(&mut request, &mut decoder)
+                }
+                _ => router.handle_streaming(&mut request, &mut body),
+            };
+            unread = body.remaining();
+            awaiting_body = body.get_ref().pending;
+            response
+        };
+
+        // Caches have to keep apart the responses a handler picked by the
+        // request's `Accept` header.
+        if request.negotiated() {
+            response.add_vary("Accept");
+        }
+
+        let mut detailed = false;
+        if let Some(error) = response.take_error() {
+            let detail = error.detail();
+            eprintln!(
+                "Failed to handle {} {}: {detail}",
+                request.method().as_str(),
+                request.target()
+            );
+            if settings.verbose_errors {
+                let status = response.status();
+                response.set_body(format!("{status} {}\n\n{detail}", reason_phrase(status)));
+                detailed = true;
+            }
+        }
+        if let (false, Some(page)) = (detailed, settings.error_pages.get(&response.status())) {
+            response = error_page::render(page, &request, &response);
+        }
+
+        // Once the server is stopping, every connection closes after the
+        // request it is serving, so the workers can finish. So does a
+        // connection with part of a body the handler didn't read still on
+        // it, since the next request only starts after that, and any
+        // connection while too many are open.
+        if settings.shutdown.is_stopping() || unread > 0 || settings.overloaded() {
+            response.set_header("Connection", "close");
+        }
+        // An HTTP/1.0 client can't be sent chunks, so a body of unknown
+        // length ends with the connection, unless it is short enough to be
+        // buffered and sent with its length.
+        if let Some(limit) = settings.http10_buffer_limit {
+            if request.version() == Version::Http10
+                && request.method() != Method::Head
+                && request.has_token("Connection", "keep-alive")
+            {
+                if let Err(err) = buffer_body(&mut response, limit) {
+                    eprintln!("Failed to read a response body: {err}");
+                    return None;
+                }
+            }
+        }
+        let mut keep_alive = keep_alive(&request, &mut response);
+        if keep_alive && kept_alive.is_none() {
+            kept_alive = settings.keep_alive_slots.take();
+            if kept_alive.is_none() {
+                response.set_header("Connection", "close");
+                keep_alive = false;
+            }
+        }
+        if settings.server_timing {
+            let millis = started.elapsed().as_secs_f64() * 1000.0;
+            response.add_header("Server-Timing", &format!("total;dur={millis:.1}"));
+        }
+        settings.default_headers.apply(&mut response);
+        let status = response.status();
+        let sent = match response.write_for(
+            &mut Deadline::new(buf_reader.get_mut(), settings.write_timeout),
+            request.method(),
+            request.version(),
+            settings.single_write_limit,
+        ) {
+            Ok(sent) => sent,
+            Err(err) => {
+                log_write_error(&err);
+                return None;
+            }
+        };
+
+        // Log the request along with the body bytes that actually went
+        // out, which differ from the body's length once it is encoded.
+        let entry = access_log::Entry {
+            peer,
+            request: &request,
+            received,
+            elapsed: started.elapsed(),
+            status,
+            sent,
+        };
+        match settings.slow_request_threshold {
+            None => println!("{}", settings.log_format.format(&entry)),
+            Some(threshold) => {
+                if let Some(warning) = access_log::format_slow(&entry, threshold) {
+                    eprintln!("{warning}");
+                }
+            }
+        }
+
+        if !keep_alive {
+            finish(buf_reader.get_mut());
+            // A client that was never told to go ahead has no body on the
+            // way to wait out.
+            if unread > 0 && !awaiting_body {
+                linger(buf_reader);
+            }
+            return None;
+        }
+    }
+}
+
+/// Read a body of unknown length into memory if it is at most `limit`
+/// bytes, so it gets a length. A longer one stays a reader, with the
+/// bytes read put back in front of it.
+fn buffer_body(response: &mut Response, limit: u64) -> io::Result<()> {
+    let mut reader = match response.take_body() {
+        Body::Reader(reader) => reader,
+        body => {
+            response.set_body(body);
+            return Ok(());
+        }
+    };
+    let mut buffered = Vec::new();
+    reader
+        .by_ref()
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut buffered)?;
+    if buffered.len() as u64 <= limit {
+        response.set_body(buffered);
+    } else {
+        response.set_body(Body::Reader(Box::new(
+            io::Cursor::new(buffered).chain(reader),
+        )));
+    }
+    Ok(())
+}
+
+/// Report a response that couldn't be written.
+///
+/// This mostly happens when the client went away without reading the
+/// response, which is its own business and no reason to take the worker
+/// down. The connection can't be trusted after a partial write, though,
+/// so it is closed.
+fn log_write_error(err: &io::Error) {
+    // A socket write that times out fails with `WouldBlock` on Unix.
+    if matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    ) {
+        eprintln!("Timed out writing a response, closing the connection");
+    } else {
+        eprintln!("Failed to write a response, closing the connection: {err}");
+    }
+}
+
+/// Half-close a connection after its last response.
+///
+/// Shutting down the write side sends the client a FIN right away, so it
+/// knows the response is complete even if the socket itself lingers, e.g.
+/// while unread bytes from the client are still in flight. The client may
+/// already be gone, so failing to do so is fine.
+fn finish<T: Connection>(stream: &mut T) {
+    let _ = stream.flush();
+    let _ = stream.shutdown_write();
+}
+
+/// The longest a connection closed with part of a request body unread
+/// waits for the client to stop sending.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most bytes of an unread body thrown away while waiting.
+const LINGER_LIMIT: u64 = 256 * 1024;
+
+/// Throw away what the client still sends for a little while, once its
+/// connection has been half-closed with part of a request body unread.
+///
+/// Closing a socket with unread data in it makes the operating system
+/// reset the connection, and a client still sending its body may lose the
+/// response to that before reading it. Having received the response and
+/// the FIN, it should stop sending soon. One that doesn't is cut off
+/// after [`LINGER_TIMEOUT`] or [`LINGER_LIMIT`] bytes, so the body is
+/// never read in full.
+fn linger<T: Connection>(reader: &mut BufReader<T>) {
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut discarded = 0;
+    let mut buf = [0; 8192];
+    while discarded < LINGER_LIMIT {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || reader.get_mut().set_read_timeout(Some(left)).is_err() {
+            return;
+        }
+        match reader.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => discarded += read as u64,
+        }
+    }
+}
+
+/// Whether `request` goes to the router, rather than being answered by
+/// the server itself or handed to an upgrade or tunnel handler.
+fn is_routed(request: &Request, settings: &Settings) -> bool {
+    let tunneled = request.method() == Method::Connect && settings.tunnel.is_some();
+    upgrade::find_handler(request, &settings.upgrades).is_none()
+        && !tunneled
+        && !is_readiness_check(request, settings)
+        && !is_version_check(request, settings)
+        && !is_refused_method(request, settings)
+}
+
+/// Whether `request` is for the configured readiness path.
+fn is_readiness_check(request: &Request, settings: &Settings) -> bool {
+    request.method() == Method::Get
+        && settings
+            .readiness_path
+            .as_deref()
+            .is_some_and(|path| path == request.path())
+}
+
+/// Whether `request` is for the configured version path.
+fn is_version_check(request: &Request, settings: &Settings) -> bool {
+    request.method() == Method::Get
+        && settings
+            .version_path
+            .as_deref()
+            .is_some_and(|path| path == request.path())
+}
+
+/// The content codings of request bodies, as the server understands them.
+enum ContentCoding {
+    Identity,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// What HTTP calls `deflate`.
+    #[cfg(feature = "gzip")]
+    Zlib,
+    Unsupported,
+}
+
+/// The content codings the server can decode, for the `Accept-Encoding`
+/// of a `415 Unsupported Media Type` response.
+const SUPPORTED_CODINGS: &str = if cfg!(feature = "gzip") {
+    "gzip, deflate"
+} else {
+    "identity"
+};
+
+/// How the body of `request` is encoded, by its `Content-Encoding`.
+/// Bodies encoded more than once aren't supported.
+fn content_coding(request: &Request) -> ContentCoding {
+    let codings: Vec<&str> = request
+        .header("Content-Encoding")
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+        .collect();
+    let coding = match codings.as_slice() {
+        [] => return ContentCoding::Identity,
+        [coding] => coding.to_ascii_lowercase(),
+        _ => return ContentCoding::Unsupported,
+    };
+    match coding.as_str() {
+        #[cfg(feature = "gzip")]
+        "gzip" | "x-gzip" => ContentCoding::Gzip,
+        #[cfg(feature = "gzip")]
+        "deflate" => ContentCoding::Zlib,
+        _ => ContentCoding::Unsupported,
+    }
+}
+
+/// Whether `request` uses a method the server refuses before routing:
+/// `TRACE` unless it is [allowed](ServerConfig::allow_trace), and
+/// `CONNECT`, which only a [tunneling handler](Server::connect) accepts.
+fn is_refused_method(request: &Request, settings: &Settings) -> bool {
+    match request.method() {
+        Method::Trace => !settings.allow_trace,
+        Method::Connect => true,
+        _ => false,
+    }
+}
+
+/// The answer to a readiness check.
+fn readiness_response(shutdown: &ShutdownState) -> Response {
+    if shutdown.is_ready() {
+        Response::text(200, "ready")
+    } else {
+        Response::text(503, "shutting down")
+    }
+}
+
+/// Decide whether the connection stays open after `response`, and tell
+/// the client through the response's `Connection` header: `close` when
+/// the server is going to close it, and `keep-alive` otherwise.
+///
+/// HTTP/1.1 connections stay open unless either side says `close`, so
+/// there the `keep-alive` only spells out the default. HTTP/1.0
+/// connections only stay open when the client asked for it with
+/// `keep-alive`, and then the response has to confirm it.
+///
+/// Any other headers the handler named in `Connection` only concern the
+/// connection, and would lose that meaning once the header is replaced,
+/// so they are dropped.
+fn keep_alive(request: &Request, response: &mut Response) -> bool {
+    let options = proxy::connection_options(response.headers());
+    for option in &options {
+        if option != "close" && option != "keep-alive" {
+            response.remove_header(option);
+        }
+    }
+    if options.iter().any(|option| option == "close") {
+        response.set_header("Connection", "close");
+        return false;
+    }
+
+    // Without chunked encoding, an HTTP/1.0 client can only tell where a
+    // body of unknown length ends by the connection closing.
+    let keep_alive = match request.version() {
+        Version::Http11 => !request.has_token("Connection", "close"),
+        Version::Http10 => {
+            request.has_token("Connection", "keep-alive") && response.body().len().is_some()
+        }
+    };
+
+    if keep_alive {
+        response.set_header("Connection", "keep-alive");
+    } else {
+        response.set_header("Connection", "close");
+    }
+    keep_alive
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::VecDeque,
+        io::{Read, Write},
+        net::TcpStream,
+        sync::Barrier,
+        thread,
+        time::{Instant, SystemTime},
+    };
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    /// An accept source handing out the given results in order, and
+    /// failing for good once they run out.
+    struct Scripted(VecDeque<io::Result<u32>>);
+
+    impl Accept for Scripted {
+        type Connection = u32;
+
+        fn accept(&mut self) -> io::Result<u32> {
+            self.0
+                .pop_front()
+                .unwrap_or_else(|| Err(io::Error::other("listener closed")))
+        }
+    }
+
+    fn scripted(results: impl IntoIterator<Item = io::Result<u32>>) -> Scripted {
+        Scripted(results.into_iter().collect())
+    }
+
+    #[test]
+    fn binding_a_used_port_fails() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+
+        let err = Server::bind(ServerConfig::new(&addr), Router::new())
+            .err()
+            .unwrap();
+        assert!(matches!(&err, ServerError::Bind(err) if err.kind() == io::ErrorKind::AddrInUse));
+        assert!(err
+            .to_string()
+            .starts_with("failed to bind the listening socket: "));
+    }
+
+    #[test]
+    fn an_empty_pool_fails() {
+        let config = ServerConfig::new("127.0.0.1:0").pool_size(0);
+        let err = Server::bind(config, Router::new()).err().unwrap();
+        assert!(matches!(
+            err,
+            ServerError::Pool(PoolCreationError::ZeroSize)
+        ));
+    }
+
+    #[test]
+    fn skips_connections_that_fail_while_being_accepted() {
+        let mut source = scripted([
+            Ok(1),
+            Err(io::ErrorKind::ConnectionAborted.into()),
+            Err(io::ErrorKind::ConnectionReset.into()),
+            Ok(2),
+            Err(io::Error::from_raw_os_error(9)),
+            Ok(3),
+        ]);
+        let mut accepted = Vec::new();
+        let err = accept_loop(
+            &mut source,
+            || false,
+            None,
+            |connection| accepted.push(connection),
+        )
+        .unwrap_err();
+
+        // The bad file descriptor ends the loop before the last connection.
+        assert!(matches!(err, ServerError::Accept(err) if err.raw_os_error() == Some(9)));
+        assert_eq!(accepted, [1, 2]);
+    }
+
+    #[test]
+    fn backs_off_when_out_of_resources() {
+        let out_of_memory = || Err(io::ErrorKind::OutOfMemory.into());
+        let mut source = scripted([out_of_memory(), out_of_memory(), Ok(1), Ok(2)]);
+        let mut accepted = Vec::new();
+        let started = Instant::now();
+        let err = accept_loop(
+            &mut source,
+            || false,
+            None,
+            |connection| accepted.push(connection),
+        )
+        .unwrap_err();
+
+        assert!(matches!(err, ServerError::Accept(_)));
+        assert_eq!(accepted, [1, 2]);
+        // Once after the minimum wait, and once after twice that.
+        assert!(started.elapsed() >= MIN_BACKOFF * 3);
+    }
+
+    #[test]
+    fn classifies_accept_errors() {
+        for kind in [
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::Interrupted,
+            io::ErrorKind::WouldBlock,
+        ] {
+            assert_eq!(AcceptError::classify(&kind.into()), AcceptError::Connection);
+        }
+        assert_eq!(
+            AcceptError::classify(&io::ErrorKind::OutOfMemory.into()),
+            AcceptError::Resources
+        );
+        // EMFILE, ENFILE and ENOMEM.
+        #[cfg(unix)]
+        for code in [24, 23, 12] {
+            assert_eq!(
+                AcceptError::classify(&io::Error::from_raw_os_error(code)),
+                AcceptError::Resources
+            );
+        }
+        assert_eq!(
+            AcceptError::classify(&io::ErrorKind::InvalidInput.into()),
+            AcceptError::Fatal
+        );
+    }
+
+    #[test]
+    fn drops_the_connection_that_finds_it_stopping() {
+        let mut source = scripted([Ok(1), Ok(2), Ok(3)]);
+        let served = Cell::new(0);
+        let result = accept_loop(
+            &mut source,
+            || served.get() == 1,
+            None,
+            |_| served.set(served.get() + 1),
+        );
+        assert!(result.is_ok());
+        assert_eq!(served.get(), 1);
+        assert_eq!(source.0.len(), 1);
+    }
+
+    /// Send `request` to `addr` and read the response until the server
+    /// closes the connection.
+    fn exchange(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn adds_the_default_headers() {
+        let config = ServerConfig::new("127.0.0.1:0")
+            .server_header(Some("test-server/1.0"))
+            .security_headers()
+            .default_header("X-Frame-Options", "SAMEORIGIN");
+        let router = Router::new()
+            .get("/", |_, _| Response::text(200, "hi"))
+            .get("/framed", |_, _| {
+                Response::text(200, "framed")
+                    .with_header("X-Frame-Options", "ALLOWALL")
+                    .with_header("Server", "custom")
+            });
+        let server = Server::bind(config, router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let response = exchange(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let after = SystemTime::now();
+        assert!(response.contains("\r\nServer: test-server/1.0\r\n"));
+        assert!(response.contains("\r\nX-Content-Type-Options: nosniff\r\n"));
+        assert!(response.contains("\r\nReferrer-Policy: no-referrer\r\n"));
+        // The first default for a header wins.
+        assert!(response.contains("\r\nX-Frame-Options: DENY\r\n"));
+        assert!(!response.contains("SAMEORIGIN"));
+
+        let date = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Date: "))
+            .unwrap();
+        let sent = date::parse_http_date(date).unwrap();
+        assert!(before <= sent && sent <= after, "{date}");
+        assert_eq!(date::format_http_date(sent), date);
+
+        // Handlers override the defaults.
+        let response = exchange(addr, "GET /framed HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.contains("\r\nX-Frame-Options: ALLOWALL\r\n"));
+        assert!(response.contains("\r\nServer: custom\r\n"));
+        assert_eq!(response.matches("X-Frame-Options").count(), 1);
+        assert_eq!(response.matches("Server:").count(), 1);
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn leaves_out_disabled_default_headers() {
+        let mut response = Response::new(204);
+        ServerConfig::new("127.0.0.1:0")
+            .server_header(None)
+            .date_header(false)
+            .default_headers
+            .apply(&mut response);
+        assert!(response.headers().is_empty());
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let router = Router::new()
+            .get("/first", |_, _| {
+                // A slow first response must still come out first.
+                thread::sleep(Duration::from_millis(50));
+                Response::text(200, "first")
+            })
+            .get("/second", |_, _| Response::text(200, "second"))
+            .post("/echo", |request, _| {
+                Response::new(200).with_body(request.body().to_vec())
+            });
+        let server = Server::bind(ServerConfig::new("127.0.0.1:0"), router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        // Everything arrives in one write, so the body of the POST and the
+        // requests after it sit in the server's read buffer together.
+        let response = exchange(
+            addr,
+            "GET /first HTTP/1.1\r\n\r\n\
+             POST /echo HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world\
+             GET /second HTTP/1.1\r\n\r\n\
+             GET /first HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let bodies: Vec<&str> = response
+            .split("HTTP/1.1 200 OK\r\n")
+            .skip(1)
+            .map(|response| response.split_once("\r\n\r\n").unwrap().1)
+            .collect();
+        assert_eq!(bodies, ["first", "hello world", "second", "first"]);
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn fails_when_no_worker_takes_an_accept_loop() {
+        let config = ServerConfig::new("127.0.0.1:0")
+            .accept_strategy(AcceptStrategy::Workers)
+            .queue_capacity(0);
+        let server = Server::bind(config, Router::new()).unwrap();
+
+        let err = server.run().unwrap_err();
+        assert!(matches!(err, ServerError::Workers(ExecuteError::Full)));
+    }
+
+    #[test]
+    fn workers_serve_connections_at_the_same_time() {
+        let config = ServerConfig::new("127.0.0.1:0")
+            .pool_size(4)
+            .accept_strategy(AcceptStrategy::Workers);
+        let server = Server::bind(config, Router::new().get("/", |_, _| "hi")).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        // Connections kept open hold on to their workers, and the others
+        // still get served.
+        let idle: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nhi"));
+
+        drop(idle);
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn fails_readiness_checks_while_a_lame_duck() {
+        let lame_duck = Duration::from_millis(300);
+        let config = ServerConfig::new("127.0.0.1:0")
+            .lame_duck(lame_duck)
+            .readiness_path("/readyz");
+        let server = Server::bind(
+            config,
+            Router::new().get("/", |_, _| Response::text(200, "hi")),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let ready = "GET /readyz HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let index = "GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        assert!(exchange(addr, ready).starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(shutdown.is_ready());
+
+        let started = Instant::now();
+        shutdown.shutdown();
+        assert!(!shutdown.is_ready());
+        let response = exchange(addr, ready);
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("shutting down"));
+        let response = exchange(addr, index);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(started.elapsed() < lame_duck);
+
+        running.join().unwrap().unwrap();
+        assert!(started.elapsed() >= lame_duck);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    /// Serve `input` in memory and split what was written into responses.
+    fn serve_in_memory(input: &str, router: &Router) -> Vec<String> {
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, router);
+        String::from_utf8(output)
+            .unwrap()
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| response.to_string())
+            .collect()
+    }
+
+    fn echo_router() -> Router {
+        Router::new()
+            .get("/", |_, _| Response::text(200, "index"))
+            .post("/echo", |request, _| {
+                Response::new(200).with_body(request.body().to_vec())
+            })
+    }
+
+    #[test]
+    fn serves_a_whole_connection_in_memory() {
+        let input = "GET / HTTP/1.1\r\n\r\n\
+                     POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+                     POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde\
+                     GET /missing HTTP/1.1\r\n\r\n";
+        let responses = serve_in_memory(input, &echo_router());
+        assert_eq!(responses.len(), 4);
+        assert!(responses[0].starts_with("200 OK\r\n") && responses[0].ends_with("index"));
+        assert!(responses[1].ends_with("\r\n\r\nhello"));
+        assert!(responses[2].ends_with("\r\n\r\nabcde"));
+        assert!(responses[3].starts_with("404 Not Found\r\n"));
+        // The connection stayed open until the input ended.
+        assert!(responses
+            .iter()
+            .all(|response| !response.contains("Connection: close")));
+    }
+
+    #[test]
+    fn stops_serving_when_the_connection_would_close() {
+        let router = echo_router();
+
+        let input = "GET / HTTP/1.1\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let responses = serve_in_memory(input, &router);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].contains("\r\nConnection: close\r\n"));
+
+        // HTTP/1.0 connections close unless the client asks otherwise.
+        let input = "GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n";
+        assert_eq!(serve_in_memory(input, &router).len(), 1);
+        let input = "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n";
+        assert_eq!(serve_in_memory(input, &router).len(), 2);
+
+        // Nothing after a malformed request can be trusted.
+        let input = "NONSENSE\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let responses = serve_in_memory(input, &router);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].starts_with("400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn serving_in_memory_never_upgrades() {
+        let router = Router::new().get("/ws", |_, _| Response::text(200, "plain"));
+        let input = "GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n";
+        let responses = serve_in_memory(input, &router);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].ends_with("plain"));
+    }
+
+    #[test]
+    fn handlers_see_the_request_deadline() {
+        let timeout = Duration::from_millis(100);
+        let router = Router::new()
+            .get("/work", |request, _| {
+                // Work in small steps until done or out of time.
+                for _ in 0..100 {
+                    if request.time_remaining() == Some(Duration::ZERO) {
+                        return Response::text(503, "gave up");
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Response::text(200, "done")
+            })
+            .get("/deadline", |request, _| {
+                Response::text(200, format!("{:?}", request.time_remaining().is_some()))
+            });
+        let config = ServerConfig::new("127.0.0.1:0").request_timeout(timeout);
+        let server = Server::bind(config, router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let started = Instant::now();
+        let response = exchange(addr, "GET /work HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 "), "{response}");
+        assert!(response.ends_with("gave up"));
+        let elapsed = started.elapsed();
+        assert!(timeout <= elapsed && elapsed < Duration::from_millis(900));
+
+        let response = exchange(addr, "GET /deadline HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("true"));
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+
+        // Without a request timeout there is no deadline.
+        let router = Router::new().get("/deadline", |request: &Request, _: &()| {
+            Response::text(200, format!("{:?}", request.time_remaining().is_some()))
+        });
+        let responses = serve_in_memory("GET /deadline HTTP/1.1\r\n\r\n", &router);
+        assert!(responses[0].ends_with("false"));
+    }
+
+    #[test]
+    fn keeps_the_connection_after_a_recoverable_error() {
+        let input = "GET / HTTP/1.1\r\n\r\n\
+                     BREW /pot HTTP/1.1\r\n\r\n\
+                     GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let responses = serve_in_memory(input, &echo_router());
+        assert_eq!(responses.len(), 3);
+        assert!(responses[1].starts_with("501 Not Implemented\r\n"));
+        assert!(responses[1].contains("\r\nConnection: keep-alive\r\n"));
+        assert!(responses[2].ends_with("index"));
+    }
+
+    #[test]
+    fn closes_the_connection_after_a_framing_error() {
+        let router = echo_router();
+        for bad in [
+            "GET / HTTP/1.1\r\nno colon here\r\n\r\n",
+            "POST /echo HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd",
+            "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        ] {
+            let input = format!("GET / HTTP/1.1\r\n\r\n{bad}GET / HTTP/1.1\r\n\r\n");
+            let responses = serve_in_memory(&input, &router);
+            assert_eq!(responses.len(), 2, "{bad:?}");
+            assert!(responses[0].ends_with("index"));
+            assert!(!responses[1].starts_with("200 "), "{bad:?}");
+            assert!(responses[1].contains("\r\nConnection: close\r\n"));
+        }
+
+        // Over a socket, the server closes its end after the answer.
+        let server = Server::bind(ServerConfig::new("127.0.0.1:0"), router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let response = exchange(
+            addr,
+            "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nno colon here\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 2);
+        assert!(response.contains("HTTP/1.1 400 Bad Request\r\n"));
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    /// An in-memory connection that remembers being half-closed, and
+    /// refuses writes after that like a socket would.
+    struct HalfClosable {
+        input: &'static [u8],
+        output: Vec<u8>,
+        write_shut: bool,
+    }
+
+    impl Read for HalfClosable {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for HalfClosable {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.write_shut {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for HalfClosable {
+        fn shutdown_write(&mut self) -> io::Result<()> {
+            self.write_shut = true;
+            Ok(())
+        }
+
+        fn set_write_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serve_half_closable(input: &'static str) -> HalfClosable {
+        let settings = Settings::new(
+            ServerConfig::default(),
+            ShutdownState::new(None, Duration::ZERO),
+        );
+        let mut stream = BufReader::new(HalfClosable {
+            input: input.as_bytes(),
+            output: Vec::new(),
+            write_shut: false,
+        });
+        serve_requests(&mut stream, None, &echo_router(), &settings);
+        stream.into_inner()
+    }
+
+    #[test]
+    fn half_closes_after_the_last_response() {
+        let connection = serve_half_closable(
+            "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(connection.write_shut);
+        let output = String::from_utf8(connection.output).unwrap();
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(output.contains("\r\nConnection: close\r\n"));
+        assert!(output.ends_with("\r\n\r\nindex"));
+
+        // So does an HTTP/1.0 response, and the answer to a bad request.
+        assert!(serve_half_closable("GET / HTTP/1.0\r\n\r\n").write_shut);
+        assert!(serve_half_closable("NONSENSE\r\n\r\n").write_shut);
+    }
+
+    #[test]
+    fn the_client_sees_the_end_of_a_close_response() {
+        let server = Server::bind(ServerConfig::new("127.0.0.1:0"), echo_router()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("index"));
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    fn tracing_router() -> Router {
+        Router::new()
+            .route(Method::Trace, "/", |request, _| {
+                Response::text(
+                    200,
+                    format!(
+                        "{} {}",
+                        request.method(),
+                        request.header("Cookie").unwrap_or("")
+                    ),
+                )
+            })
+            .fallback(|request, _| {
+                Response::text(200, format!("fallback for {}", request.method()))
+            })
+    }
+
+    #[test]
+    fn refuses_trace_and_connect_by_default() {
+        let input = "TRACE / HTTP/1.1\r\nCookie: secret\r\n\r\n\
+                     CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n\
+                     GET /other HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let responses = serve_in_memory(input, &tracing_router());
+        assert_eq!(responses.len(), 3);
+        for response in &responses[..2] {
+            assert!(
+                response.starts_with("405 Method Not Allowed\r\n"),
+                "{response}"
+            );
+            assert!(!response.contains("secret") && !response.contains("fallback"));
+        }
+        assert!(responses[2].ends_with("fallback for GET"));
+    }
+
+    #[test]
+    fn lets_trace_and_connect_through_when_asked_to() {
+        let config = ServerConfig::new("127.0.0.1:0").allow_trace(true);
+        let server =
+            Server::bind(config, tracing_router())
+                .unwrap()
+                .connect(|request, mut tunnel| {
+                    tunnel
+                        .write_all(format!("tunnel to {}", request.target()).as_bytes())
+                        .unwrap();
+                });
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let response = exchange(
+            addr,
+            "TRACE / HTTP/1.1\r\nCookie: c=1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("TRACE c=1"));
+
+        let response = exchange(addr, "CONNECT example.com:443 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.contains("Content-Length") && !response.contains("Transfer-Encoding"));
+        assert!(response.ends_with("\r\n\r\ntunnel to example.com:443"));
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    /// Send `request` on a connection that stays open, and read the
+    /// response up to the body it should end with.
+    fn fetch(stream: &mut TcpStream, request: &str, body: &str) -> String {
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(body.as_bytes()) {
+            let mut buf = [0; 1024];
+            let read = stream.read(&mut buf).unwrap();
+            assert!(read > 0, "connection closed early");
+            response.extend_from_slice(&buf[..read]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn a_slow_request_leaves_the_other_workers_free() {
+        let slow = Duration::from_millis(600);
+        let router = Router::new()
+            .get("/", |_, _| Response::text(200, "index"))
+            .get("/sleep", move |_, _| {
+                thread::sleep(slow);
+                Response::text(200, "slept")
+            });
+        let server = Server::bind(ServerConfig::new("127.0.0.1:0").pool_size(2), router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let sleeping =
+            |stream: &mut TcpStream| fetch(stream, "GET /sleep HTTP/1.1\r\n\r\n", "slept");
+
+        // One slow request on a kept-alive connection ties up one worker.
+        let mut kept = TcpStream::connect(addr).unwrap();
+        let slept = thread::spawn(move || {
+            let response = sleeping(&mut kept);
+            (kept, response)
+        });
+        thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        let response = exchange(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("index"));
+        assert!(started.elapsed() < slow / 2, "{:?}", started.elapsed());
+        let (mut kept, response) = slept.join().unwrap();
+        assert!(response.contains("Connection: keep-alive\r\n"));
+
+        // As many slow requests as there are workers saturate the pool,
+        // and the next request waits for one of them to finish.
+        let other = thread::spawn(move || sleeping(&mut TcpStream::connect(addr).unwrap()));
+        let slept = thread::spawn(move || sleeping(&mut kept));
+        thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        let response = exchange(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("index"));
+        assert!(started.elapsed() >= slow / 2, "{:?}", started.elapsed());
+        other.join().unwrap();
+        slept.join().unwrap();
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn answers_a_client_that_closed_its_write_side() {
+        let server = Server::bind(ServerConfig::new("127.0.0.1:0"), echo_router()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+            .unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn survives_clients_that_leave_before_the_response() {
+        let big = "x".repeat(8 << 20);
+        let router = Router::new()
+            .get("/", |_, _| Response::text(200, "index"))
+            .get("/big", move |_, _| Response::text(200, big.clone()));
+        // A single worker, so the next request shows it survived.
+        let config = ServerConfig::new("127.0.0.1:0").pool_size(1);
+        let server = Server::bind(config, router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            drop(stream);
+        }
+        let response = exchange(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("index"));
+
+        // Writing to a client that is gone ends the connection quietly.
+        struct Gone;
+
+        impl Write for Gone {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let input = "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        serve_connection(input.as_bytes(), Gone, &echo_router());
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn the_token_bucket_lets_bursts_through_and_then_paces() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut bucket = TokenBucket::new(10, Arc::new(clock.clone()));
+
+        // A second's worth of tokens goes at once.
+        for _ in 0..10 {
+            bucket.take();
+        }
+        assert_eq!(clock.now(), start);
+
+        // Then every token waits its turn.
+        bucket.take();
+        assert_eq!(clock.now() - start, Duration::from_millis(100));
+        bucket.take();
+        assert_eq!(clock.now() - start, Duration::from_millis(200));
+
+        // A long quiet spell saves up no more than a second's worth.
+        clock.advance(Duration::from_secs(60));
+        let rested = clock.now();
+        for _ in 0..10 {
+            bucket.take();
+        }
+        assert_eq!(clock.now(), rested);
+        bucket.take();
+        assert_eq!(clock.now() - rested, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn throttles_accepts_beyond_the_rate() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let rate = Mutex::new(TokenBucket::new(2, Arc::new(clock.clone())));
+        let mut source = scripted((1..=6).map(Ok));
+        let mut accepted = Vec::new();
+        accept_loop(
+            &mut source,
+            || false,
+            Some(&rate),
+            |connection| {
+                accepted.push((connection, clock.now() - start));
+            },
+        )
+        .unwrap_err();
+
+        let secs = |millis| Duration::from_millis(millis);
+        assert_eq!(
+            accepted,
+            [
+                (1, secs(0)),
+                (2, secs(0)),
+                (3, secs(500)),
+                (4, secs(1000)),
+                (5, secs(1500)),
+                (6, secs(2000)),
+            ]
+        );
+
+        // A rate of zero still lets connections through, one a second.
+        let mut bucket = TokenBucket::new(0, Arc::new(clock.clone()));
+        let before = clock.now();
+        bucket.take();
+        bucket.take();
+        assert_eq!(clock.now() - before, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bodies_that_would_exceed_the_shared_budget_are_refused() {
+        // Each body fits on its own, but two at once don't.
+        let held = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+        let router = {
+            let (held, release) = (Arc::clone(&held), Arc::clone(&release));
+            Router::new().post("/hold", move |_, _| {
+                held.wait();
+                release.wait();
+                "held"
+            })
+        };
+        let config = ServerConfig::new("127.0.0.1:0")
+            .pool_size(2)
+            .max_buffered_body_bytes(1000);
+        let server = Server::bind(config, router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let body = "x".repeat(600);
+        let request = format!(
+            "POST /hold HTTP/1.1\r\nContent-Length: 600\r\nConnection: close\r\n\r\n{body}"
+        );
+        let holding = {
+            let request = request.clone();
+            thread::spawn(move || exchange(addr, &request))
+        };
+        held.wait();
+
+        let response = exchange(addr, &request);
+        assert!(response.starts_with("HTTP/1.1 503 "), "{response}");
+
+        // Once the first request is done with, its bytes are free again.
+        // The client sees the end of the response a moment before the
+        // server lets go of the request, so that may take a few tries.
+        release.wait();
+        assert!(holding.join().unwrap().ends_with("held"));
+        let releasing = {
+            let release = Arc::clone(&release);
+            thread::spawn(move || {
+                held.wait();
+                release.wait();
+            })
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let response = loop {
+            let response = exchange(addr, &request);
+            if !response.starts_with("HTTP/1.1 503 ") || Instant::now() > deadline {
+                break response;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(response.ends_with("held"), "{response}");
+        releasing.join().unwrap();
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn renders_the_error_pages_registered_for_a_status() {
+        let router = Router::new()
+            .get("/broken", |_, _| Response::text(500, "database down"))
+            .get("/private", |_, _| Response::text(403, "no"))
+            .post("/only-post", |_, _| "posted");
+        let page = std::env::temp_dir().join(format!(
+            "rust-server-error-page-{}.html",
+            std::process::id()
+        ));
+        fs::write(&page, "<h1>Forbidden</h1>").unwrap();
+        let server = Server::bind(ServerConfig::new("127.0.0.1:0"), router)
+            .unwrap()
+            .error_page(500, |request: &Request| {
+                format!("sorry, {} broke", request.path())
+            })
+            .error_page_file(403, &page);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        // The handler sees the request that failed, and its page keeps the
+        // status.
+        let response = exchange(addr, "GET /broken HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 "), "{response}");
+        assert!(
+            response.ends_with("\r\n\r\nsorry, /broken broke"),
+            "{response}"
+        );
+
+        let response = exchange(addr, "GET /private HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 "), "{response}");
+        assert!(response.contains("Content-Type: text/html"), "{response}");
+        assert!(response.ends_with("<h1>Forbidden</h1>"), "{response}");
+
+        // Statuses without a page of their own get the built-in one.
+        let response = exchange(addr, "GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 "), "{response}");
+        assert!(response.ends_with("404 Not Found"), "{response}");
+        let response = exchange(addr, "GET /only-post HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 "), "{response}");
+        assert!(response.contains("Allow: POST"), "{response}");
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+        fs::remove_file(&page).unwrap();
+    }
+
+    /// A connection whose client takes its responses a byte at a time,
+    /// slowly.
+    struct Trickling {
+        input: &'static [u8],
+        output: Vec<u8>,
+    }
+
+    impl Read for Trickling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Trickling {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(2));
+            self.output.write(&buf[..buf.len().min(1)])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Trickling {
+        fn shutdown_write(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn gives_up_on_a_client_that_reads_too_slowly() {
+        let settings = Settings::new(
+            ServerConfig::default().write_timeout(Duration::from_millis(100)),
+            ShutdownState::new(None, Duration::ZERO),
+        );
+        let router = Router::new().get("/big", |_, _| "x".repeat(10_000));
+        let mut stream = BufReader::new(Trickling {
+            input: b"GET /big HTTP/1.1\r\n\r\nGET /big HTTP/1.1\r\n\r\n",
+            output: Vec::new(),
+        });
+
+        // Writing the whole response would take twenty seconds, but the
+        // worker is free again once the timeout has passed.
+        let started = Instant::now();
+        let upgrade = serve_requests(&mut stream, None, &router, &settings);
+        assert!(upgrade.is_none());
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            started.elapsed()
+        );
+
+        // The connection is given up on partway through the first
+        // response, without answering the second request.
+        let output = String::from_utf8(stream.into_inner().output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 2"), "{output}");
+        assert!(output.len() < 1000, "{}", output.len());
+    }
+
+    #[test]
+    fn a_client_that_stops_reading_loses_its_connection() {
+        const BIG: usize = 64 * 1024 * 1024;
+        let router = Router::new()
+            .get("/", |_, _| "index")
+            .get("/big", |_, _| "x".repeat(BIG));
+        let config = ServerConfig::new("127.0.0.1:0")
+            .pool_size(1)
+            .write_timeout(Duration::from_millis(200));
+        let server = Server::bind(config, router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        // The response fills the socket's buffers long before it is all
+        // written, and the client doesn't read any of it for now.
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+
+        // The only worker gets free for other clients once the timeout
+        // has passed.
+        thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        let response = exchange(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with("index"));
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            started.elapsed()
+        );
+
+        // By then the stalled connection was closed partway through.
+        stalled
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut received = Vec::new();
+        let _ = stalled.read_to_end(&mut received);
+        assert!(received.len() < BIG, "{}", received.len());
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn stops_keeping_connections_alive_above_the_high_water_mark() {
+        let settings = Settings::new(
+            ServerConfig::default().keep_alive_high_water(2),
+            ShutdownState::new(None, Duration::ZERO),
+        );
+        let serve = || {
+            let input = "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+            let mut stream = BufReader::new(Duplex {
+                reader: input.as_bytes(),
+                writer: Vec::new(),
+            });
+            serve_requests(&mut stream, None, &echo_router(), &settings);
+            String::from_utf8(stream.into_inner().writer).unwrap()
+        };
+
+        // At the mark, HTTP/1.1 connections are kept alive as usual.
+        let open = [settings.load.open(), settings.load.open()];
+        let output = serve();
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(!output.contains("Connection: close"));
+
+        // Above it, the first response closes the connection.
+        let more = settings.load.open();
+        let output = serve();
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(output.contains("Connection: close\r\n"));
+
+        // And once connections have closed, they are kept alive again.
+        drop(more);
+        drop(open);
+        assert_eq!(serve().matches("HTTP/1.1 200 OK").count(), 2);
+    }
+
+    #[test]
+    fn turns_down_an_expected_body_without_reading_it() {
+        let router = Router::new()
+            .get("/upload", |_, _| "nothing to see")
+            .route_streaming(Method::Put, "/upload", |request, _body, _| {
+                // Turned down from its announced length alone.
+                match request.header("Content-Length") {
+                    Some("4") => Response::text(204, ""),
+                    _ => Response::text(413, "413 Content Too Large"),
+                }
+            });
+        for (head, status) in [
+            ("POST /upload HTTP/1.1\r\n", "405"),
+            ("PUT /upload HTTP/1.1\r\n", "413"),
+        ] {
+            let head = format!("{head}Expect: 100-continue\r\nContent-Length: 10\r\n\r\n");
+            let body = "0123456789GET /upload HTTP/1.1\r\n\r\n";
+            let mut input = head.as_bytes().chain(body.as_bytes());
+            let mut output = Vec::new();
+            serve_connection(&mut input, &mut output, &router);
+
+            let output = String::from_utf8(output).unwrap();
+            assert!(
+                output.starts_with(&format!("HTTP/1.1 {status} ")),
+                "{output}"
+            );
+            assert_eq!(output.matches("HTTP/1.1 ").count(), 1, "{output}");
+            assert!(output.contains("Connection: close\r\n"));
+            assert!(!output.contains("100 Continue"));
+            let (_, unread) = input.into_inner();
+            assert_eq!(unread, body.as_bytes());
+        }
+    }
+}
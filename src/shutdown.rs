@@ -0,0 +1,141 @@
+//! Stopping a running [`Server`](crate::server::Server).
+//!
+//! Shutting down goes through three phases. While running, everything is
+//! served normally. During the lame duck period that follows a call to
+//! [`ShutdownHandle::shutdown`], the server keeps serving but reports
+//! itself as not ready, so load balancers stop sending it new traffic.
+//! Finally the server stops accepting connections, lets the requests in
+//! flight finish, and [`Server::run`](crate::server::Server::run) returns.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+const RUNNING: u8 = 0;
+const LAME_DUCK: u8 = 1;
+const STOPPING: u8 = 2;
+
+/// The shutdown phase of a server, shared by the server, its workers and
+/// every [`ShutdownHandle`].
+#[derive(Debug)]
+pub(crate) struct ShutdownState {
+    phase: AtomicU8,
+    /// The address of the listening socket, if there is one to wake up.
+    addr: Option<SocketAddr>,
+    lame_duck: Duration,
+}
+
+impl ShutdownState {
+    pub(crate) fn new(addr: Option<SocketAddr>, lame_duck: Duration) -> ShutdownState {
+        ShutdownState {
+            phase: AtomicU8::new(RUNNING),
+            addr,
+            lame_duck,
+        }
+    }
+
+    /// Whether the server should still get new traffic.
+    pub(crate) fn is_ready(&self) -> bool {
+        self.phase.load(Ordering::SeqCst) == RUNNING
+    }
+
+    /// Whether the server has stopped accepting connections.
+    pub(crate) fn is_stopping(&self) -> bool {
+        self.phase.load(Ordering::SeqCst) == STOPPING
+    }
+
+    /// Stop accepting connections right away, skipping any lame duck
+    /// period.
+    pub(crate) fn stop(&self) {
+        self.phase.store(STOPPING, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Connect to the listening socket, so an accept loop blocked waiting
+    /// for a connection gets one.
+    ///
+    /// Accept loops only look at the phase once they get a connection.
+    /// When every worker runs one, each wakes the next as it stops.
+    pub(crate) fn wake(&self) {
+        let Some(addr) = self.addr else {
+            return;
+        };
+        let addr = match addr {
+            SocketAddr::V4(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::from((Ipv4Addr::LOCALHOST, addr.port()))
+            }
+            SocketAddr::V6(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::from((Ipv6Addr::LOCALHOST, addr.port()))
+            }
+            addr => addr,
+        };
+        if let Err(err) = TcpStream::connect(addr) {
+            eprintln!("Failed to wake up the accept loop: {err}");
+        }
+    }
+}
+
+/// Asks a running server to shut down, from any thread.
+///
+/// Get one from [`Server::shutdown_handle`](crate::server::Server::shutdown_handle)
+/// before calling `run`.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(state: Arc<ShutdownState>) -> ShutdownHandle {
+        ShutdownHandle { state }
+    }
+
+    /// Start shutting the server down, and return right away.
+    ///
+    /// The server first spends its lame duck period, if it has one,
+    /// serving as usual but answering readiness checks with `503 Service
+    /// Unavailable`. Then it stops accepting connections, finishes the
+    /// requests it is working on, and closes keep-alive connections after
+    /// their current request. Calling this more than once has no effect.
+    pub fn shutdown(&self) {
+        let started = self
+            .state
+            .phase
+            .compare_exchange(RUNNING, LAME_DUCK, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        if !started {
+            return;
+        }
+
+        if self.state.lame_duck.is_zero() {
+            self.state.stop();
+            return;
+        }
+
+        let state = Arc::clone(&self.state);
+        let spawned = thread::Builder::new()
+            .name("lame-duck".to_string())
+            .spawn(move || {
+                thread::sleep(state.lame_duck);
+                state.stop();
+            });
+
+        // Without a thread to wait on, skipping the lame duck period is
+        // better than never stopping at all.
+        if let Err(err) = spawned {
+            eprintln!("Failed to start the lame duck period, stopping now: {err}");
+            self.state.stop();
+        }
+    }
+
+    /// Whether the server still reports itself as ready, i.e. whether
+    /// shutting down hasn't started yet.
+    pub fn is_ready(&self) -> bool {
+        self.state.is_ready()
+    }
+}
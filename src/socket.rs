@@ -0,0 +1,130 @@
+//! Binding the listening socket with address reuse options, for
+//! [`ServerConfig::reuse_address`](crate::server::ServerConfig::reuse_address)
+//! and [`ServerConfig::reuse_port`](crate::server::ServerConfig::reuse_port).
+//!
+//! The standard library binds and listens in one go, with no chance to set
+//! options in between, so the socket is set up with `socket2` instead.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// How the listening socket treats addresses other sockets use or used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReuseOptions {
+    /// Set `SO_REUSEADDR`, so the server can bind while connections from
+    /// a previous run are still in `TIME_WAIT`.
+    pub(crate) address: bool,
+    /// Set `SO_REUSEPORT`, so several processes can listen on the same
+    /// port and the kernel spreads connections between them.
+    pub(crate) port: bool,
+}
+
+impl Default for ReuseOptions {
+    fn default() -> ReuseOptions {
+        ReuseOptions {
+            address: true,
+            port: false,
+        }
+    }
+}
+
+/// Bind a listening socket to the first address `addr` resolves to that
+/// works, like [`TcpListener::bind`].
+pub(crate) fn bind(addr: &str, options: ReuseOptions) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_one(addr, options) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+/// The backlog the standard library listens with.
+const BACKLOG: i32 = 128;
+
+fn bind_one(addr: SocketAddr, options: ReuseOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // On Windows, `SO_REUSEADDR` lets a socket take over a port another
+    // one is listening on, which is nothing like what it does elsewhere.
+    // A port there can be bound again right away anyway, so the option
+    // is left alone, like the standard library does.
+    #[cfg(unix)]
+    socket.set_reuse_address(options.address)?;
+
+    if options.port {
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT isn't supported on this platform",
+        ));
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    /// Bind `addr`, and have one connection closed by the server first,
+    /// which leaves the server's end in `TIME_WAIT`.
+    fn serve_one(addr: &str, options: ReuseOptions) -> io::Result<SocketAddr> {
+        let listener = bind(addr, options)?;
+        let addr = listener.local_addr()?;
+        let mut client = TcpStream::connect(addr)?;
+        let (mut server, _) = listener.accept()?;
+        server.write_all(b"bye")?;
+        drop(server);
+        let mut bye = Vec::new();
+        client.read_to_end(&mut bye)?;
+        assert_eq!(bye, b"bye");
+        Ok(addr)
+    }
+
+    #[test]
+    fn rebinds_a_port_right_after_closing_it() {
+        let options = ReuseOptions::default();
+        let addr = serve_one("127.0.0.1:0", options).unwrap();
+        let addr = addr.to_string();
+        serve_one(&addr, options).unwrap();
+        serve_one(&addr, options).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn shares_a_port_with_reuse_port() {
+        let options = ReuseOptions {
+            address: true,
+            port: true,
+        };
+        let first = bind("127.0.0.1:0", options).unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+        let second = bind(&addr, options).unwrap();
+        assert_eq!(second.local_addr().unwrap(), first.local_addr().unwrap());
+
+        // Without the option, the port is taken.
+        let err = bind(&addr, ReuseOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+}
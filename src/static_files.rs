@@ -0,0 +1,721 @@
+//! Serving static files, from disk or from any other [`FileProvider`].
+
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    conditional, date, negotiate,
+    provider::{DiskFiles, FileProvider},
+    request::{Method, Request},
+    response::Response,
+    uri,
+};
+
+/// Serves the files of a [`FileProvider`], by default a directory on disk.
+///
+/// Files on disk are streamed rather than read into memory, with their
+/// `Content-Length` taken from the file's metadata. Every response carries
+/// a `Content-Type` guessed from the file extension, and `ETag` and
+/// `Last-Modified` validators so that conditional and range requests work.
+///
+/// Files can be compressed ahead of time: when `style.css.br` or
+/// `style.css.gz` sits next to `style.css` and the client accepts brotli
+/// or gzip, the compressed file is served in its place, with the original
+/// file's `Content-Type`.
+///
+/// For single-page apps, whose client-side router handles paths that
+/// aren't files, see [`StaticFiles::spa_fallback`].
+pub struct StaticFiles {
+    provider: Box<dyn FileProvider>,
+    spa_fallback: Option<String>,
+    api_prefix: Option<String>,
+}
+
+impl StaticFiles {
+    /// Serve the files below the directory `root`.
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles::with_provider(DiskFiles::new(root))
+    }
+
+    /// Serve the files of `provider`, e.g. a
+    /// [`MemoryFiles`](crate::provider::MemoryFiles) in tests.
+    pub fn with_provider(provider: impl FileProvider + 'static) -> StaticFiles {
+        StaticFiles {
+            provider: Box::new(provider),
+            spa_fallback: None,
+            api_prefix: None,
+        }
+    }
+
+    /// Answer `GET` and `HEAD` requests for files that don't exist with
+    /// the file at `path` instead, typically `index.html`, with a `200 OK`
+    /// rather than a `404 Not Found`.
+    ///
+    /// This is what single-page apps need: a link or reload to
+    /// `/some/page` loads the app, and its client-side router takes it
+    /// from there. Paths under the [API prefix](StaticFiles::api_prefix)
+    /// still get a `404`.
+    pub fn spa_fallback(mut self, path: &str) -> StaticFiles {
+        self.spa_fallback = Some(path.to_string());
+        self
+    }
+
+    /// Leave requests for paths below `prefix`, like `/api`, out of the
+    /// [single-page app fallback](StaticFiles::spa_fallback), so a missing
+    /// API route is a `404` rather than a page of HTML.
+    ///
+    /// The prefix is matched by whole segments, so `/api` covers `/api`
+    /// and `/api/users` but not `/apiary`.
+    pub fn api_prefix(mut self, prefix: &str) -> StaticFiles {
+        self.api_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Answer `request` with the file at `path`, relative to the root.
+    ///
+    /// `path` uses forward slashes, like the path of a URL, and `..` never
+    /// leads out of the root. Missing files and directories get `404 Not
+    /// Found`, and files that can't be read get `403 Forbidden`. When the
+    /// process has run out of file descriptors, the client gets `503
+    /// Service Unavailable` with a `Retry-After` to try again shortly.
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        let response = serve(&*self.provider, request, path);
+        match &self.spa_fallback {
+            Some(index) if response.status() == 404 && self.is_client_route(request) => {
+                serve(&*self.provider, request, index)
+            }
+            _ => response,
+        }
+    }
+
+    /// Whether `request` may be for a route of a single-page app.
+    fn is_client_route(&self, request: &Request) -> bool {
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return false;
+        }
+        match &self.api_prefix {
+            Some(prefix) => !request
+                .path()
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+            None => true,
+        }
+    }
+
+    /// Build the `200 OK` response for the file at `path`, without looking
+    /// at the request's conditional headers.
+    ///
+    /// This is handy for error pages, which are files too but should be
+    /// sent with another status and never turned into a `304`.
+    pub fn open(&self, path: &str) -> io::Result<Response> {
+        open(&*self.provider, path)
+    }
+}
+
+/// Answer `request` with the file at `path` from `provider`, like
+/// [`StaticFiles::serve`].
+pub fn serve(provider: &dyn FileProvider, request: &Request, path: &str) -> Response {
+    match open_negotiated(provider, request, path) {
+        Ok(response) => conditional::evaluate(request, response),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Response::text(404, "404 Not Found"),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            Response::text(403, "403 Forbidden")
+        }
+        Err(err) if is_out_of_descriptors(&err) => {
+            // Open files are closed as responses finish, so this is worth
+            // retrying shortly rather than being a failure of the server.
+            eprintln!("Out of file descriptors opening {path:?}: {err}");
+            let retry_after = date::retry_after(Duration::from_secs(1));
+            Response::text(503, "503 Service Unavailable").with_header("Retry-After", &retry_after)
+        }
+        Err(err) => {
+            eprintln!("Failed to open {path:?}: {err}");
+            Response::text(500, "500 Internal Server Error")
+        }
+    }
+}
+
+/// Whether opening a file failed because the process or the system has
+/// too many files open.
+fn is_out_of_descriptors(err: &io::Error) -> bool {
+    // EMFILE and ENFILE share their numbers on every Unix we care about.
+    // Windows reports ERROR_TOO_MANY_OPEN_FILES.
+    #[cfg(unix)]
+    const DESCRIPTOR_ERRORS: &[i32] = &[23, 24];
+    #[cfg(windows)]
+    const DESCRIPTOR_ERRORS: &[i32] = &[4];
+    #[cfg(not(any(unix, windows)))]
+    const DESCRIPTOR_ERRORS: &[i32] = &[];
+
+    err.raw_os_error()
+        .is_some_and(|code| DESCRIPTOR_ERRORS.contains(&code))
+}
+
+/// Build the `200 OK` response for the file at `path` from `provider`,
+/// like [`StaticFiles::open`].
+pub fn open(provider: &dyn FileProvider, path: &str) -> io::Result<Response> {
+    open_with(provider, path, Method::Get)
+}
+
+/// Like [`open`], for a request with `method`.
+///
+/// A `HEAD` response is never sent with its body, so the file isn't even
+/// opened when its metadata says all the headers need. The response then
+/// has no body, only the `Content-Length` of the file.
+fn open_with(provider: &dyn FileProvider, path: &str, method: Method) -> io::Result<Response> {
+    let relative = resolve(path).ok_or(io::ErrorKind::NotFound)?;
+    let metadata = provider.metadata(&relative)?;
+    if !metadata.is_file {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+
+    let mut response = Response::new(200).with_header("Content-Type", content_type(&relative));
+    let len = if method == Method::Head && metadata.modified.is_some() {
+        response.set_header("Content-Length", &metadata.len.to_string());
+        metadata.len
+    } else {
+        let body = provider.open(&relative)?;
+        let len = body.len().unwrap_or(metadata.len);
+        response.set_body(body);
+        len
+    };
+
+    if let Some(modified) = metadata.modified {
+        // The size and modification time change whenever the contents
+        // do, which makes them a cheap entity tag that doesn't require
+        // reading the file.
+        let nanos = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos());
+        response.set_header("ETag", &format!("\"{len:x}-{nanos:x}\""));
+        response.set_header("Last-Modified", &date::format_http_date(modified));
+    } else if let Some(bytes) = response.body().as_bytes() {
+        // Without a modification time, fall back to hashing the contents,
+        // which is cheap for files that are in memory anyway.
+        let etag = conditional::etag(bytes);
+        response.set_header("ETag", &etag);
+    }
+
+    Ok(response)
+}
+
+/// The precompressed variants a file may have, as pairs of content coding
+/// and file name suffix, most preferred first.
+const ENCODED_VARIANTS: &[(&str, &str)] = &[("br", ".br"), ("gzip", ".gz")];
+
+/// Like [`open`], but serve a precompressed variant of the file instead if
+/// there is one the client accepts.
+fn open_negotiated(
+    provider: &dyn FileProvider,
+    request: &Request,
+    path: &str,
+) -> io::Result<Response> {
+    // Variants only stand in for a file that exists itself.
+    if !is_file(provider, path) {
+        return open_with(provider, path, request.method());
+    }
+
+    let mut available: Vec<_> = ENCODED_VARIANTS
+        .iter()
+        .filter(|(_, suffix)| is_file(provider, &format!("{path}{suffix}")))
+        .map(|&(coding, _)| coding)
+        .collect();
+    if available.is_empty() {
+        return open_with(provider, path, request.method());
+    }
+    // The file itself is chosen over the variants if the client likes
+    // `identity` better.
+    available.push("identity");
+
+    let chosen = negotiate::encoding(request.header("Accept-Encoding"), &available)
+        .and_then(|coding| ENCODED_VARIANTS.iter().find(|(name, _)| *name == coding));
+    let mut response = match chosen {
+        Some(&(coding, suffix)) => {
+            let mut response = open_with(provider, &format!("{path}{suffix}"), request.method())?;
+            let original = resolve(path).ok_or(io::ErrorKind::NotFound)?;
+            response.set_header("Content-Type", content_type(&original));
+            response.set_header("Content-Encoding", coding);
+            response
+        }
+        None => open_with(provider, path, request.method())?,
+    };
+
+    // Which file is sent depends on the request's `Accept-Encoding`, and
+    // caches need to know that, whichever file it turns out to be.
+    response.add_vary("Accept-Encoding");
+    Ok(response)
+}
+
+/// Whether `path` is a regular file of `provider`.
+fn is_file(provider: &dyn FileProvider, path: &str) -> bool {
+    resolve(path)
+        .and_then(|relative| provider.metadata(&relative).ok())
+        .is_some_and(|metadata| metadata.is_file)
+}
+
+/// Turn a URL-style path into a relative path for a provider.
+///
+/// The path is normalized like a request path first, so `..` can't climb
+/// above the provider's root. It isn't percent-decoded: request paths,
+/// and the parameters captured from them, have been decoded already. After that only plain file names are allowed
+/// between the slashes, so drive prefixes and backslashes can't be used to
+/// escape it either.
+fn resolve(path: &str) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for segment in uri::normalize_path(&format!("/{path}")).split('/') {
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if !segment.contains('\\') => {
+                resolved.push(name);
+            }
+            _ => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// Guess the `Content-Type` of a file from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::SystemTime,
+    };
+
+    use super::*;
+    use crate::{provider::Metadata, request::Version, response::Body};
+
+    /// A gigabyte-sized file that counts how often it is opened, and
+    /// can't actually be read.
+    struct HugeFile {
+        opened: AtomicUsize,
+    }
+
+    impl FileProvider for HugeFile {
+        fn metadata(&self, _path: &Path) -> io::Result<Metadata> {
+            Ok(Metadata {
+                len: 1 << 30,
+                modified: Some(SystemTime::UNIX_EPOCH),
+                is_file: true,
+            })
+        }
+
+        fn open(&self, _path: &Path) -> io::Result<Body> {
+            self.opened.fetch_add(1, Ordering::SeqCst);
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    fn head(provider: &dyn FileProvider, request: Request) -> String {
+        let response = serve(provider, &request, "/huge.bin");
+        let mut output = Vec::new();
+        response
+            .write_for(&mut output, Method::Head, Version::Http11, 0)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    /// A writer that keeps what it is given, and the size of the largest
+    /// single write.
+    #[derive(Default)]
+    struct Recorder {
+        written: Vec<u8>,
+        largest_write: usize,
+    }
+
+    impl io::Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.largest_write = self.largest_write.max(buf.len());
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A temporary directory for one test, removed when dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("static-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn streams_files_in_small_chunks() {
+        let dir = TempDir::new("stream");
+        let contents: Vec<u8> = (0..4 << 20).map(|i: u32| (i % 251) as u8).collect();
+        std::fs::write(dir.0.join("big.bin"), &contents).unwrap();
+
+        let files = StaticFiles::new(&dir.0);
+        let response = files.serve(&Request::new(Method::Get, "/big.bin"), "big.bin");
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("application/octet-stream")
+        );
+        // The file is still on disk, not in memory.
+        assert!(matches!(response.body(), Body::File(_, len) if *len == 4 << 20));
+
+        let mut recorder = Recorder::default();
+        response.write_to(&mut recorder).unwrap();
+        assert!(recorder.written.ends_with(&contents));
+        let head =
+            String::from_utf8_lossy(&recorder.written[..recorder.written.len() - contents.len()]);
+        assert!(head.contains("Content-Length: 4194304\r\n"));
+        assert!(
+            recorder.largest_write <= 64 * 1024,
+            "wrote {} bytes at once",
+            recorder.largest_write
+        );
+    }
+
+    /// A provider whose every file is there but fails to open.
+    struct Failing(fn() -> io::Error);
+
+    impl FileProvider for Failing {
+        fn metadata(&self, _path: &Path) -> io::Result<Metadata> {
+            Ok(Metadata {
+                len: 1,
+                modified: None,
+                is_file: true,
+            })
+        }
+
+        fn open(&self, _path: &Path) -> io::Result<Body> {
+            Err((self.0)())
+        }
+    }
+
+    fn site() -> StaticFiles {
+        StaticFiles::with_provider(
+            crate::MemoryFiles::new()
+                .with_file("index.html", "<h1>home</h1>")
+                .with_file("css/site.css", "body {}"),
+        )
+    }
+
+    fn get(files: &StaticFiles, path: &str) -> Response {
+        files.serve(&Request::new(Method::Get, "/"), path)
+    }
+
+    #[test]
+    fn serves_files_from_memory() {
+        let response = get(&site(), "css/site.css");
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert!(response.header("ETag").is_some());
+        assert!(response.header("Last-Modified").is_some());
+        assert_eq!(response.body().as_bytes(), Some(&b"body {}"[..]));
+
+        assert_eq!(get(&site(), "/index.html").status(), 200);
+        assert_eq!(get(&site(), "css/../index.html").status(), 200);
+    }
+
+    #[test]
+    fn missing_files_and_directories_are_not_found() {
+        for path in ["missing.html", "css", "css/", "", "css/site.css/x"] {
+            assert_eq!(get(&site(), path).status(), 404, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn paths_cannot_leave_the_root() {
+        assert_eq!(
+            resolve("../../etc/passwd"),
+            Some(PathBuf::from("etc/passwd"))
+        );
+        assert_eq!(
+            resolve("css/../../index.html"),
+            Some(PathBuf::from("index.html"))
+        );
+        assert_eq!(
+            resolve("./css//site.css"),
+            Some(PathBuf::from("css/site.css"))
+        );
+        assert_eq!(resolve("..\\index.html"), None);
+        assert_eq!(resolve("css\\..\\..\\secret"), None);
+
+        let files = StaticFiles::with_provider(
+            crate::MemoryFiles::new().with_file("public/index.html", "public"),
+        );
+        assert_eq!(get(&files, "../public/index.html").status(), 200);
+        assert_eq!(get(&files, "..\\public\\index.html").status(), 404);
+    }
+
+    #[test]
+    fn answers_errors_opening_files() {
+        let forbidden =
+            StaticFiles::with_provider(Failing(|| io::ErrorKind::PermissionDenied.into()));
+        assert_eq!(get(&forbidden, "secret.txt").status(), 403);
+
+        let failing = StaticFiles::with_provider(Failing(|| io::Error::other("disk on fire")));
+        assert_eq!(get(&failing, "index.html").status(), 500);
+
+        #[cfg(unix)]
+        {
+            // EMFILE, too many open files.
+            let exhausted =
+                StaticFiles::with_provider(Failing(|| io::Error::from_raw_os_error(24)));
+            let response = get(&exhausted, "index.html");
+            assert_eq!(response.status(), 503);
+            assert!(response.header("Retry-After").is_some());
+        }
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body().as_bytes().unwrap()).unwrap()
+    }
+
+    fn precompressed() -> StaticFiles {
+        StaticFiles::with_provider(
+            crate::MemoryFiles::new()
+                .with_file("style.css", "plain css")
+                .with_file("style.css.br", "brotli css")
+                .with_file("style.css.gz", "gzip css")
+                .with_file("app.js", "plain js")
+                .with_file("app.js.gz", "gzip js")
+                .with_file("orphan.css.gz", "gzip orphan"),
+        )
+    }
+
+    fn get_encoded(files: &StaticFiles, path: &str, accept_encoding: Option<&str>) -> Response {
+        let request = Request::new(Method::Get, "/");
+        let request = match accept_encoding {
+            Some(accept_encoding) => request.with_header("Accept-Encoding", accept_encoding),
+            None => request,
+        };
+        files.serve(&request, path)
+    }
+
+    #[test]
+    fn serves_the_precompressed_variant_the_client_accepts() {
+        let files = precompressed();
+        for (accept_encoding, coding, contents) in [
+            ("gzip, br", "br", "brotli css"),
+            ("gzip", "gzip", "gzip css"),
+            ("br;q=0.5, gzip", "gzip", "gzip css"),
+            ("*", "br", "brotli css"),
+        ] {
+            let response = get_encoded(&files, "style.css", Some(accept_encoding));
+            assert_eq!(response.header("Content-Encoding"), Some(coding));
+            assert_eq!(
+                response.header("Content-Type"),
+                Some("text/css; charset=utf-8")
+            );
+            assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+            assert_eq!(body(&response), contents, "{accept_encoding}");
+        }
+
+        let response = get_encoded(&files, "app.js", Some("br, gzip"));
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(body(&response), "gzip js");
+    }
+
+    #[test]
+    fn falls_back_to_the_file_itself() {
+        let files = precompressed();
+        for accept_encoding in [
+            None,
+            Some("deflate"),
+            Some("gzip;q=0.5, identity"),
+            Some("br;q=0, gzip;q=0"),
+        ] {
+            let response = get_encoded(&files, "style.css", accept_encoding);
+            assert_eq!(response.header("Content-Encoding"), None);
+            assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+            assert_eq!(body(&response), "plain css", "{accept_encoding:?}");
+        }
+
+        // Without variants, nothing varies.
+        let files = StaticFiles::with_provider(crate::MemoryFiles::new().with_file("a.txt", "a"));
+        let response = get_encoded(&files, "a.txt", Some("gzip"));
+        assert_eq!(response.header("Vary"), None);
+
+        // A variant never stands in for a file that doesn't exist.
+        let files = precompressed();
+        assert_eq!(
+            get_encoded(&files, "orphan.css", Some("gzip")).status(),
+            404
+        );
+    }
+
+    #[test]
+    fn head_never_opens_the_file() {
+        let provider = HugeFile {
+            opened: AtomicUsize::new(0),
+        };
+
+        let output = head(&provider, Request::new(Method::Head, "/huge.bin"));
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("Content-Length: 1073741824\r\n"));
+        assert!(output.contains("ETag: \"40000000-0\"\r\n"));
+        assert!(output.ends_with("\r\n\r\n"));
+        assert_eq!(provider.opened.load(Ordering::SeqCst), 0);
+
+        let get = serve(
+            &provider,
+            &Request::new(Method::Get, "/huge.bin"),
+            "/huge.bin",
+        );
+        assert_eq!(get.status(), 500);
+        assert_eq!(provider.opened.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn head_answers_ranges_with_the_length_of_the_part() {
+        let provider = HugeFile {
+            opened: AtomicUsize::new(0),
+        };
+        let request = Request::new(Method::Head, "/huge.bin").with_header("Range", "bytes=100-199");
+
+        let output = head(&provider, request);
+        assert!(output.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(output.contains("Content-Length: 100\r\n"));
+        assert!(output.contains("Content-Range: bytes 100-199/1073741824\r\n"));
+        assert_eq!(provider.opened.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn head_gets_the_headers_of_get() {
+        let dir = std::env::temp_dir().join(format!("static-head-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("page.html"), "<p>hello</p>").unwrap();
+        let files = StaticFiles::new(&dir);
+
+        let write = |method| {
+            let response = files.serve(&Request::new(method, "/page.html"), "/page.html");
+            let mut output = Vec::new();
+            response
+                .write_for(&mut output, method, Version::Http11, 0)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let get = write(Method::Get);
+        let head = write(Method::Head);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(get, format!("{head}<p>hello</p>"));
+        assert!(head.contains("Content-Length: 12\r\n"));
+    }
+
+    #[test]
+    fn serves_files_with_encoded_names() {
+        let files = StaticFiles::with_provider(
+            crate::MemoryFiles::new().with_file("docs/a b.txt", "spaced"),
+        );
+        let router = crate::Router::new().get("/*path", move |request, _| {
+            files.serve(request, request.param("path").unwrap())
+        });
+
+        let input = "GET /docs/a%20b.txt HTTP/1.1\r\n\r\n\
+                     GET /docs/x/%2e%2e/a%20b.txt HTTP/1.1\r\n\r\n\
+                     GET /docs/x%2F..%2Fa%20b.txt HTTP/1.1\r\n\r\n";
+        let mut output = Vec::new();
+        crate::serve_connection(input.as_bytes(), &mut output, &router);
+
+        let output = String::from_utf8(output).unwrap();
+        let statuses: Vec<&str> = output
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| &response[..3])
+            .collect();
+        assert_eq!(statuses, ["200", "200", "400"]);
+        assert_eq!(output.matches("\r\n\r\nspaced").count(), 2);
+    }
+
+    fn spa() -> StaticFiles {
+        site().spa_fallback("index.html").api_prefix("/api/")
+    }
+
+    fn request(files: &StaticFiles, method: Method, target: &str) -> Response {
+        let request = Request::new(method, target);
+        files.serve(&request, request.path())
+    }
+
+    #[test]
+    fn serves_the_app_for_client_side_routes() {
+        for target in ["/some/spa/route", "/settings?tab=2", "/apiary"] {
+            let response = request(&spa(), Method::Get, target);
+            assert_eq!(response.status(), 200, "{target}");
+            assert_eq!(body(&response), "<h1>home</h1>", "{target}");
+        }
+        assert_eq!(
+            request(&spa(), Method::Head, "/some/spa/route").status(),
+            200
+        );
+
+        // Files that exist are still served as themselves.
+        let response = request(&spa(), Method::Get, "/css/site.css");
+        assert_eq!(body(&response), "body {}");
+    }
+
+    #[test]
+    fn api_routes_and_other_methods_are_not_found() {
+        for target in ["/api", "/api/missing", "/api/users/7"] {
+            assert_eq!(
+                request(&spa(), Method::Get, target).status(),
+                404,
+                "{target}"
+            );
+        }
+        assert_eq!(
+            request(&spa(), Method::Post, "/some/spa/route").status(),
+            404
+        );
+
+        // Without the fallback, client-side routes aren't found either.
+        assert_eq!(
+            request(&site(), Method::Get, "/some/spa/route").status(),
+            404
+        );
+    }
+}
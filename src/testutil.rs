@@ -0,0 +1,276 @@
+//! Helpers for testing servers, enabled with the `testutil` feature.
+//!
+//! [`TestClient`] sends requests to a running [`Server`](crate::Server)
+//! and parses the responses, so tests don't have to write and read raw
+//! bytes. [`read_response`] parses responses from any reader, such as the
+//! output of [`serve_connection`](crate::serve_connection).
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+use crate::request::Method;
+
+/// A minimal HTTP/1.1 client talking to one address.
+///
+/// Every request goes over a fresh connection that is closed after the
+/// response, which keeps tests independent of each other.
+#[derive(Debug, Clone, Copy)]
+pub struct TestClient {
+    addr: SocketAddr,
+}
+
+impl TestClient {
+    /// Create a client sending its requests to `addr`, usually the
+    /// [`local_addr`](crate::Server::local_addr) of a server bound to
+    /// port 0.
+    pub fn new(addr: SocketAddr) -> TestClient {
+        TestClient { addr }
+    }
+
+    /// Send a `GET` request for `path`.
+    pub fn get(&self, path: &str) -> io::Result<TestResponse> {
+        self.send(Method::Get, path, &[], &[])
+    }
+
+    /// Send a request with the given headers and body.
+    ///
+    /// `Host`, `Connection: close` and, for a non-empty body,
+    /// `Content-Length` are added automatically.
+    pub fn send(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<TestResponse> {
+        let mut stream = TcpStream::connect(self.addr)?;
+
+        let mut head = format!("{method} {path} HTTP/1.1\r\nHost: {}\r\n", self.addr);
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        read_response(&mut BufReader::new(stream))
+    }
+}
+
+/// A response read by a [`TestClient`] or [`read_response`].
+#[derive(Debug, Clone)]
+pub struct TestResponse {
+    /// The status code.
+    pub status: u16,
+    /// The headers, in the order they were received.
+    pub headers: Vec<(String, String)>,
+    /// The body, with any chunked transfer encoding removed.
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    /// The value of the first header called `name`, compared
+    /// case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Read one response from `reader`.
+///
+/// The body is read according to `Content-Length`, chunked transfer
+/// encoding, or else until the end of the stream. Call this repeatedly to
+/// read the responses to pipelined requests.
+pub fn read_response<R: BufRead>(reader: &mut R) -> io::Result<TestResponse> {
+    let status_line = read_line(reader)?;
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid(format!("bad status line {status_line:?}")))?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("bad header line {line:?}")))?;
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    let mut response = TestResponse {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+
+    if matches!(status, 100..=199 | 204 | 304) {
+        return Ok(response);
+    }
+
+    if let Some(length) = response.header("Content-Length") {
+        let length = length
+            .parse()
+            .map_err(|_| invalid(format!("bad content-length {length:?}")))?;
+        response.body = vec![0; length];
+        reader.read_exact(&mut response.body)?;
+    } else if response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        response.body = read_chunked(reader)?;
+    } else {
+        reader.read_to_end(&mut response.body)?;
+    }
+
+    Ok(response)
+}
+
+/// Read a chunked body up to and including the final empty chunk.
+fn read_chunked<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| invalid(format!("bad chunk size {line:?}")))?;
+
+        if size == 0 {
+            // Skip the trailers, if any, up to the blank line.
+            while !read_line(reader)?.is_empty() {}
+            return Ok(body);
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_line(reader)?.is_empty() {
+            return Err(invalid("chunk longer than its size".to_string()));
+        }
+    }
+}
+
+/// Read a line without its line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let trimmed = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(trimmed);
+    Ok(line)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{response::Body, serve_connection, Response, Router, Server, ServerConfig};
+
+    #[test]
+    fn sends_requests_to_a_server() {
+        let router = Router::new()
+            .get("/", |_, _| Response::text(200, "index"))
+            .post("/echo", |request, _| {
+                Response::text(201, String::from_utf8_lossy(request.body()))
+                    .with_header("X-Seen", request.header("X-Test").unwrap_or("none"))
+            });
+        let server = Server::bind(ServerConfig::new("127.0.0.1:0"), router).unwrap();
+        let client = TestClient::new(server.local_addr().unwrap());
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        let response = client.get("/").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "index");
+        assert_eq!(response.header("content-length"), Some("5"));
+
+        let response = client
+            .send(Method::Post, "/echo", &[("X-Test", "yes")], b"hello")
+            .unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("X-Seen"), Some("yes"));
+        assert_eq!(response.text(), "hello");
+
+        assert_eq!(client.get("/missing").unwrap().status, 404);
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn reads_pipelined_responses() {
+        let router = Router::new()
+            .get("/sized", |_, _| Response::text(200, "sized"))
+            .get("/chunked", |_, _| {
+                Response::new(200).with_body(Body::Reader(Box::new(&b"chunked"[..])))
+            })
+            .get("/empty", |_, _| Response::new(204));
+        let input = "GET /sized HTTP/1.1\r\n\r\n\
+                     GET /chunked HTTP/1.1\r\n\r\n\
+                     GET /empty HTTP/1.1\r\n\r\n\
+                     GET /sized HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &router);
+
+        let mut reader = output.as_slice();
+        let responses: Vec<TestResponse> = (0..4)
+            .map(|_| read_response(&mut reader).unwrap())
+            .collect();
+        assert!(reader.is_empty());
+
+        assert_eq!(responses[0].text(), "sized");
+        assert_eq!(responses[1].header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(responses[1].text(), "chunked");
+        assert_eq!(responses[2].status, 204);
+        assert!(responses[2].body.is_empty());
+        assert_eq!(responses[3].header("Connection"), Some("close"));
+    }
+
+    #[test]
+    fn reads_bodies_up_to_the_end_of_the_stream() {
+        let input = b"HTTP/1.1 200 OK\r\nX-A: 1\r\n\r\nuntil the end";
+        let response = read_response(&mut &input[..]).unwrap();
+        assert_eq!(response.headers, [("X-A".to_string(), "1".to_string())]);
+        assert_eq!(response.text(), "until the end");
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        for input in [
+            "HTTP/1.1 OK\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nno colon\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: five\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n0\r\n\r\n",
+        ] {
+            let err = read_response(&mut input.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{input:?}");
+        }
+
+        let truncated = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort";
+        let err = read_response(&mut truncated.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,197 @@
+//! A thread that runs jobs once their time has come, used by
+//! [`ThreadPool::execute_with_retry`](crate::ThreadPool::execute_with_retry)
+//! and [`ThreadPool::schedule_interval`](crate::ThreadPool::schedule_interval)
+//! to wait between runs without holding on to a worker.
+//!
+//! Scheduled jobs wait in a heap ordered by when they are due. The timer
+//! thread sleeps on a condition variable until the earliest one is due, or
+//! until a job that is due even sooner is scheduled. The jobs run on the
+//! timer thread itself, so they should only hand work on, e.g. by queueing
+//! it on the pool.
+//!
+//! Due times are read from the pool's [`Clock`]. A clock whose time jumps,
+//! like a [`ManualClock`](crate::clock::ManualClock), wakes the thread up
+//! whenever it does, so jobs that became due run right away.
+
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    io,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{clock::Clock, Job};
+
+/// The timer thread, stopped and joined when this is dropped.
+pub(crate) struct Timer {
+    handle: TimerHandle,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// A way to schedule jobs on a [`Timer`] from any thread.
+#[derive(Clone)]
+pub(crate) struct TimerHandle {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    clock: Arc<dyn Clock>,
+}
+
+struct State {
+    entries: BinaryHeap<Entry>,
+    /// Counts the scheduled jobs, so jobs due at the same instant run in
+    /// the order they were scheduled.
+    next_seq: u64,
+    closed: bool,
+}
+
+struct Entry {
+    due: Instant,
+    seq: u64,
+    job: Job,
+}
+
+impl Timer {
+    pub(crate) fn start(clock: Arc<dyn Clock>) -> io::Result<Timer> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                entries: BinaryHeap::new(),
+                next_seq: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            clock,
+        });
+
+        // Holding the lock while notifying makes sure the thread is either
+        // waiting already, or hasn't read the time yet.
+        let woken = Arc::downgrade(&shared);
+        shared.clock.on_advance(Box::new(move || {
+            if let Some(shared) = woken.upgrade() {
+                let _state = shared.lock();
+                shared.changed.notify_one();
+            }
+        }));
+
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name("timer".to_string())
+            .spawn(move || thread_shared.run())?;
+
+        Ok(Timer {
+            handle: TimerHandle { shared },
+            thread: Some(thread),
+        })
+    }
+
+    pub(crate) fn handle(&self) -> &TimerHandle {
+        &self.handle
+    }
+}
+
+impl Drop for Timer {
+    /// Stop the timer thread. Jobs that aren't due yet are dropped without
+    /// running.
+    fn drop(&mut self) {
+        self.handle.shared.lock().closed = true;
+        self.handle.shared.changed.notify_one();
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl TimerHandle {
+    /// Run `job` on the timer thread once `delay` has passed.
+    ///
+    /// Jobs scheduled after the timer was stopped are dropped.
+    pub(crate) fn schedule(&self, delay: Duration, job: Job) {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return;
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry {
+            due: self.shared.clock.now() + delay,
+            seq,
+            job,
+        });
+        drop(state);
+
+        // The new job may be due before the one the thread is waiting for.
+        self.shared.changed.notify_one();
+    }
+}
+
+impl Shared {
+    fn run(&self) {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return;
+            }
+
+            let now = self.clock.now();
+            match state.entries.peek() {
+                Some(entry) if entry.due <= now => {
+                    let entry = state.entries.pop().expect("an entry was peeked");
+                    // Jobs run without the lock, so scheduling never waits
+                    // for one, and a panicking job can't poison it.
+                    drop(state);
+                    (entry.job)();
+                    state = self.lock();
+                }
+                Some(entry) => {
+                    let timeout = entry.due - now;
+                    state = self
+                        .changed
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .0;
+                }
+                None => {
+                    state = self
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// `BinaryHeap` is a max-heap, so entries compare in reverse: the entry due
+// first is the greatest.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
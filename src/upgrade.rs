@@ -0,0 +1,240 @@
+//! Handing connections over to other protocols with `Upgrade`.
+//!
+//! A client asks to switch protocols by sending `Connection: upgrade` and
+//! an `Upgrade` header listing the protocols it would like, in order of
+//! preference. When one of them has a handler registered with
+//! [`Server::upgrade`](crate::server::Server::upgrade), the server answers
+//! `101 Switching Protocols` and gives the handler the raw connection. From
+//! then on the connection no longer speaks HTTP, so the server forgets
+//! about it.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    sync::Arc,
+};
+
+use crate::{request::Request, response::Response};
+
+/// A handler taking over a connection for an upgraded protocol.
+pub(crate) type UpgradeHandler = Arc<dyn Fn(&Request, Upgraded) + Send + Sync>;
+
+/// The registered upgrade handlers, keyed by lowercase protocol name.
+pub(crate) type UpgradeHandlers = HashMap<String, UpgradeHandler>;
+
+/// A connection that switched to another protocol.
+///
+/// Reading returns whatever the client sent after the upgrade request
+/// first, including bytes the server had already buffered, and then reads
+/// from the socket. Writing goes straight to the socket.
+pub struct Upgraded {
+    io: Box<dyn Io + Send>,
+}
+
+impl Upgraded {
+    /// Wrap the buffered reader of a connection whose inner stream can
+    /// also be written to.
+    pub(crate) fn new<T: Read + Write + Send + 'static>(reader: BufReader<T>) -> Upgraded {
+        Upgraded {
+            io: Box::new(reader),
+        }
+    }
+}
+
+/// Reading through the buffer, writing straight to the inner stream.
+trait Io: BufRead {
+    fn write_inner(&mut self, buf: &[u8]) -> io::Result<usize>;
+    fn flush_inner(&mut self) -> io::Result<()>;
+}
+
+impl<T: Read + Write> Io for BufReader<T> {
+    fn write_inner(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush_inner(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl BufRead for Upgraded {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.io.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.io.consume(amt)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write_inner(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush_inner()
+    }
+}
+
+/// Find the first protocol in the request's `Upgrade` header that has a
+/// registered handler.
+///
+/// Requests that don't list `upgrade` in their `Connection` header aren't
+/// asking to switch, whatever their `Upgrade` header says.
+pub(crate) fn find_handler<'h>(
+    request: &Request,
+    handlers: &'h UpgradeHandlers,
+) -> Option<(&'h str, &'h UpgradeHandler)> {
+    if !request.has_token("Connection", "upgrade") {
+        return None;
+    }
+
+    request.header("Upgrade")?.split(',').find_map(|protocol| {
+        handlers
+            .get_key_value(&protocol.trim().to_ascii_lowercase())
+            .map(|(name, handler)| (name.as_str(), handler))
+    })
+}
+
+/// The response accepting the switch to `protocol`.
+pub(crate) fn switching_protocols(protocol: &str) -> Response {
+    Response::new(101)
+        .with_header("Connection", "Upgrade")
+        .with_header("Upgrade", protocol)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpStream, sync::Mutex, thread};
+
+    use super::*;
+    use crate::{
+        request::Method,
+        router::Router,
+        server::{Server, ServerConfig},
+    };
+
+    /// A connection reading from a fixed input and recording what is
+    /// written to it.
+    struct Pipe {
+        input: io::Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn handlers(protocols: &[&str]) -> UpgradeHandlers {
+        protocols
+            .iter()
+            .map(|protocol| {
+                let handler: UpgradeHandler = Arc::new(|_, _| {});
+                (protocol.to_string(), handler)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn picks_the_first_registered_protocol() {
+        let handlers = handlers(&["echo", "chat"]);
+        let request = Request::new(Method::Get, "/")
+            .with_header("Connection", "keep-alive, Upgrade")
+            .with_header("Upgrade", "h2c, Chat, echo");
+        let (protocol, _) = find_handler(&request, &handlers).unwrap();
+        assert_eq!(protocol, "chat");
+
+        let unknown = Request::new(Method::Get, "/")
+            .with_header("Connection", "upgrade")
+            .with_header("Upgrade", "h2c");
+        assert!(find_handler(&unknown, &handlers).is_none());
+
+        // Without `Connection: upgrade` the client isn't asking.
+        let not_asking = Request::new(Method::Get, "/").with_header("Upgrade", "echo");
+        assert!(find_handler(&not_asking, &handlers).is_none());
+    }
+
+    #[test]
+    fn reads_buffered_bytes_first_and_writes_to_the_stream() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let pipe = Pipe {
+            input: io::Cursor::new(b"GET / HTTP/1.1\r\n\r\nhello".to_vec()),
+            output: Arc::clone(&output),
+        };
+        let mut reader = BufReader::new(pipe);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        reader.read_line(&mut line).unwrap();
+
+        let mut upgraded = Upgraded::new(reader);
+        let mut rest = String::new();
+        upgraded.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "hello");
+        upgraded.write_all(b"world").unwrap();
+        assert_eq!(*output.lock().unwrap(), b"world");
+    }
+
+    #[test]
+    fn hands_the_connection_to_the_handler() {
+        let router = Router::new().get("/", |_, _| Response::text(200, "plain http"));
+        let server = Server::bind(ServerConfig::new("127.0.0.1:0"), router)
+            .unwrap()
+            .upgrade("echo", |request, mut connection| {
+                assert_eq!(request.path(), "/chat");
+                let mut line = String::new();
+                connection.read_line(&mut line).unwrap();
+                connection
+                    .write_all(line.to_uppercase().as_bytes())
+                    .unwrap();
+            });
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || server.run());
+
+        // The first line of the new protocol comes along with the
+        // request.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET /chat HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: ECHO\r\n\r\nhi there\n",
+            )
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(output.contains("Upgrade: echo\r\n"));
+        assert!(output.ends_with("\r\n\r\nHI THERE\n"));
+
+        // Other requests are still HTTP.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nUpgrade: echo\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        assert!(output.ends_with("\r\n\r\nplain http"));
+
+        shutdown.shutdown();
+        running.join().unwrap().unwrap();
+    }
+}
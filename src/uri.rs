@@ -0,0 +1,280 @@
+//! Request targets, split into their parts.
+
+use std::{convert::Infallible, fmt};
+
+/// The target of a request, like `/search?q=rust`, split into its path,
+/// query and fragment.
+///
+/// The path is percent-decoded and then normalized as the target is
+/// parsed: empty segments from repeated slashes are dropped, `.` segments
+/// are removed and `..` removes the segment before it, but never climbs
+/// above the root. Routing and anything else that looks at the path sees
+/// the decoded, normalized version, so `/a/./b`, `/a/x/../b`, `//a//b`
+/// and `/a/%2e%2e/a/b` all reach the route for `/a/b`, and `/a%20b` the
+/// one for `/a b`. The path is decoded only once, so `%252e` is a literal
+/// `%2e`. The target as the client sent it is still available from
+/// [`Uri::as_str`].
+///
+/// ```
+/// use rust_server::Uri;
+///
+/// assert_eq!(Uri::parse("//a///b").path(), "/a/b");
+/// assert_eq!(Uri::parse("/a/x/../b/?q=1").path(), "/a/b/");
+/// assert_eq!(Uri::parse("/docs/%2e%2e/%2e%2e/etc/passwd").path(), "/etc/passwd");
+/// assert_eq!(Uri::parse("/caf%C3%A9/a%20b.txt").path(), "/café/a b.txt");
+/// ```
+///
+/// A segment that would decode to a NUL byte or a slash can't be told
+/// apart from a shorter or a longer path once decoded, so
+/// [`Uri::try_parse`] turns it down, as the server does for requests.
+/// [`Uri::parse`] leaves such segments as they were sent.
+///
+/// Targets that aren't a path, like the `*` of `OPTIONS *` or the
+/// `host:port` of a `CONNECT` request, are left as they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uri {
+    raw: String,
+    path: String,
+    query: Option<String>,
+    fragment: Option<String>,
+}
+
+/// Why the path of a request target can't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidPath {
+    /// A segment contains a NUL byte, raw or as `%00`, which ends a path
+    /// for the C functions behind most file systems.
+    NulByte,
+    /// A segment contains `%2F`, a slash that isn't a separator.
+    EncodedSlash,
+}
+
+impl InvalidPath {
+    pub(crate) fn reason(self) -> &'static str {
+        match self {
+            InvalidPath::NulByte => "nul byte in path",
+            InvalidPath::EncodedSlash => "encoded slash in path",
+        }
+    }
+}
+
+impl fmt::Display for InvalidPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+impl std::error::Error for InvalidPath {}
+
+impl Uri {
+    /// Split `target` into its parts, and decode and normalize the path.
+    ///
+    /// Segments that [`Uri::try_parse`] would turn down are kept as they
+    /// are, still percent-encoded, and the rest of the path is decoded.
+    pub fn parse(target: &str) -> Uri {
+        let lenient = |segment: &str| {
+            Ok::<_, Infallible>(decode_segment(segment).unwrap_or_else(|_| segment.to_string()))
+        };
+        match Uri::split(target, lenient) {
+            Ok(uri) => uri,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Like [`Uri::parse`], but fail if a segment of the path decodes to
+    /// a NUL byte or a slash.
+    pub fn try_parse(target: &str) -> Result<Uri, InvalidPath> {
+        Uri::split(target, decode_segment)
+    }
+
+    /// Split `target` into its parts, decoding every segment of the path
+    /// with `decode` before normalizing it.
+    fn split<E>(target: &str, mut decode: impl FnMut(&str) -> Result<String, E>) -> Result<Uri, E> {
+        let (rest, fragment) = match target.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment.to_string())),
+            None => (target, None),
+        };
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (rest, None),
+        };
+
+        // Targets that aren't a path are left alone.
+        let path = if path.starts_with('/') {
+            let decoded = path
+                .split('/')
+                .map(&mut decode)
+                .collect::<Result<Vec<_>, E>>()?;
+            normalize_path(&decoded.join("/"))
+        } else {
+            path.to_string()
+        };
+
+        Ok(Uri {
+            raw: target.to_string(),
+            path,
+            query,
+            fragment,
+        })
+    }
+
+    /// The normalized path, without the query string or fragment.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The query string, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// The fragment, without the leading `#`.
+    ///
+    /// Browsers keep fragments to themselves, so this is only ever set
+    /// for targets from other clients.
+    pub fn fragment(&self) -> Option<&str> {
+        self.fragment.as_deref()
+    }
+
+    /// The target exactly as it was given.
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+}
+
+impl fmt::Display for Uri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+/// Percent-decode one segment of a path, turning down the ones that would
+/// decode to a NUL byte or a slash. Bytes that aren't UTF-8 are replaced.
+fn decode_segment(segment: &str) -> Result<String, InvalidPath> {
+    let decoded = percent_decode(segment);
+    if decoded.contains(&0) {
+        return Err(InvalidPath::NulByte);
+    }
+    if decoded.contains(&b'/') {
+        return Err(InvalidPath::EncodedSlash);
+    }
+    Ok(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Percent-decode `part`. Escapes that aren't valid are kept as they are.
+pub(crate) fn percent_decode(part: &str) -> Vec<u8> {
+    let bytes = part.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Normalize a path as described on [`Uri`]. The path has to be decoded
+/// already, it isn't decoded again.
+///
+/// A trailing slash, or a trailing `.` or `..` segment, leaves a slash at
+/// the end, since `/docs/` and `/docs` may well be different resources.
+pub(crate) fn normalize_path(path: &str) -> String {
+    let Some(rest) = path.strip_prefix('/') else {
+        return path.to_string();
+    };
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in rest.split('/') {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapses_repeated_slashes() {
+        assert_eq!(Uri::parse("//a///b").path(), "/a/b");
+        assert_eq!(Uri::parse("///").path(), "/");
+        assert_eq!(Uri::parse("/a//").path(), "/a/");
+    }
+
+    #[test]
+    fn normalizes_the_decoded_segments() {
+        assert_eq!(Uri::parse("/a/%2e%2e/b").path(), "/b");
+        assert_eq!(Uri::parse("/a/%2E/b").path(), "/a/b");
+        assert_eq!(Uri::parse("/%2e%2e/%2e%2e/etc").path(), "/etc");
+        assert_eq!(Uri::parse("/a/.%2e/b").path(), "/b");
+    }
+
+    #[test]
+    fn decodes_only_once() {
+        assert_eq!(Uri::parse("/a/%252e%252e/b").path(), "/a/%2e%2e/b");
+        assert_eq!(Uri::parse("/100%25").path(), "/100%");
+    }
+
+    #[test]
+    fn keeps_invalid_escapes_and_plus_signs() {
+        assert_eq!(Uri::parse("/a%2/b%zz/c%").path(), "/a%2/b%zz/c%");
+        assert_eq!(Uri::parse("/a+b").path(), "/a+b");
+    }
+
+    #[test]
+    fn leaves_the_query_and_the_raw_target_alone() {
+        let uri = Uri::parse("/a%20b?q=c%20d#e%20f");
+        assert_eq!(uri.path(), "/a b");
+        assert_eq!(uri.query(), Some("q=c%20d"));
+        assert_eq!(uri.fragment(), Some("e%20f"));
+        assert_eq!(uri.as_str(), "/a%20b?q=c%20d#e%20f");
+    }
+
+    #[test]
+    fn turns_down_nul_bytes_and_encoded_slashes() {
+        assert_eq!(Uri::try_parse("/secret%00.png"), Err(InvalidPath::NulByte));
+        assert_eq!(Uri::try_parse("/secret\0.png"), Err(InvalidPath::NulByte));
+        assert_eq!(Uri::try_parse("/..%2Fetc"), Err(InvalidPath::EncodedSlash));
+        assert_eq!(Uri::try_parse("/..%2fetc"), Err(InvalidPath::EncodedSlash));
+
+        // Parsing leniently keeps those segments encoded.
+        assert_eq!(Uri::parse("/a%20b/..%2Fetc").path(), "/a b/..%2Fetc");
+    }
+
+    #[test]
+    fn leaves_targets_that_arent_paths_alone() {
+        assert_eq!(Uri::parse("*").path(), "*");
+        assert_eq!(Uri::parse("example.com:443").path(), "example.com:443");
+    }
+}
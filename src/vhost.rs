@@ -0,0 +1,117 @@
+//! Serving several sites from one server, picked by the `Host` header.
+
+use std::{collections::HashMap, io::Read};
+
+use crate::{
+    request::{Request, Version},
+    response::Response,
+    router::Service,
+};
+
+/// A [`Service`] that hands every request to the service registered for
+/// the host it names in its `Host` header, like a router per site.
+///
+/// Host names are compared case-insensitively and without the port, so
+/// `Example.com:8080` goes to the service for `example.com`. A request
+/// for any other host goes to the [default](VirtualHosts::default_host),
+/// and without one gets `421 Misdirected Request`.
+///
+/// HTTP/1.1 requires every request to say which host it is for, so one
+/// without a `Host` header gets `400 Bad Request`. HTTP/1.0 requests may
+/// leave it out, and go to the default.
+///
+/// ```
+/// use rust_server::{serve_connection, Router, VirtualHosts};
+///
+/// let hosts = VirtualHosts::new()
+///     .host("blog.example.com", Router::new().get("/", |_, _| "the blog"))
+///     .host("shop.example.com", Router::new().get("/", |_, _| "the shop"))
+///     .default_host(Router::new().get("/", |_, _| "the default"));
+///
+/// let input = "GET / HTTP/1.1\r\nHost: blog.example.com\r\n\r\n\
+///              GET / HTTP/1.1\r\nHost: SHOP.example.com:8080\r\n\r\n\
+///              GET / HTTP/1.1\r\nHost: other.example.com\r\n\r\n\
+///              GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+/// let mut output = Vec::new();
+/// serve_connection(input.as_bytes(), &mut output, &hosts);
+///
+/// let output = String::from_utf8(output).unwrap();
+/// let bodies: Vec<&str> = output
+///     .split("HTTP/1.1 ")
+///     .skip(1)
+///     .map(|response| response.rsplit("\r\n").next().unwrap())
+///     .collect();
+/// assert_eq!(bodies, ["the blog", "the shop", "the default", "400 Bad Request: missing Host header"]);
+/// ```
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts: HashMap<String, Box<dyn Service>>,
+    default: Option<Box<dyn Service>>,
+}
+
+impl VirtualHosts {
+    /// Create a set of hosts without any, which answers every request
+    /// with `421 Misdirected Request`.
+    pub fn new() -> VirtualHosts {
+        VirtualHosts::default()
+    }
+
+    /// Serve the requests for `name`, like `example.com`, with `service`.
+    ///
+    /// Registering the same name twice replaces the earlier service.
+    pub fn host(mut self, name: &str, service: impl Service) -> VirtualHosts {
+        self.hosts
+            .insert(name.to_ascii_lowercase(), Box::new(service));
+        self
+    }
+
+    /// Serve the requests for hosts without a service of their own with
+    /// `service`.
+    pub fn default_host(mut self, service: impl Service) -> VirtualHosts {
+        self.default = Some(Box::new(service));
+        self
+    }
+
+    /// The service for `request`, or the response for a request that has
+    /// none.
+    fn find(&self, request: &Request) -> Result<&dyn Service, Response> {
+        let host = match request.header("Host") {
+            Some(host) => host_name(host).to_ascii_lowercase(),
+            None if request.version() == Version::Http11 => {
+                return Err(Response::text(400, "400 Bad Request: missing Host header"));
+            }
+            None => String::new(),
+        };
+        self.hosts
+            .get(&host)
+            .or(self.default.as_ref())
+            .map(|service| &**service)
+            .ok_or_else(|| Response::text(421, "421 Misdirected Request"))
+    }
+}
+
+impl Service for VirtualHosts {
+    fn handle(&self, request: &mut Request) -> Response {
+        match self.find(request) {
+            Ok(service) => service.handle(request),
+            Err(response) => response,
+        }
+    }
+
+    fn handle_streaming(&self, request: &mut Request, body: &mut dyn Read) -> Response {
+        match self.find(request) {
+            Ok(service) => service.handle_streaming(request, body),
+            Err(response) => response,
+        }
+    }
+}
+
+/// The host name of a `Host` header, without the port. IPv6 addresses
+/// keep their brackets.
+fn host_name(host: &str) -> &str {
+    let host = host.trim();
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}